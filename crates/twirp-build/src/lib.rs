@@ -1,5 +1,8 @@
+use std::collections::HashSet;
 use std::fmt::Write;
 
+use prost_types::method_options::IdempotencyLevel;
+
 /// Generates twirp services for protobuf rpc service definitions.
 ///
 /// In your `build.rs`, using `prost_build`, you can wire in the twirp
@@ -8,20 +11,97 @@ use std::fmt::Write;
 /// Add a call to `.service_generator(twirp_build::service_generator())` in
 /// main() of `build.rs`.
 pub fn service_generator() -> Box<ServiceGenerator> {
-    Box::new(ServiceGenerator {})
+    Box::default()
+}
+
+#[derive(Debug, Default)]
+pub struct ServiceGenerator {
+    raw_requests: HashSet<String>,
+    mocks: bool,
+    mocks_cfg: Option<String>,
+}
+
+impl ServiceGenerator {
+    /// Generate the rpc `method` (fully qualified, e.g. `example.v1.Proxy.Forward`) to take the
+    /// request undecoded, as a `twirp::http::Request<twirp::bytes::Bytes>`. Handlers that only
+    /// forward the payload skip decoding and re-encoding it; others can decode it with
+    /// `twirp::server::DecodeRequest`. The handler returns a `twirp::server::RawResponse`, so it
+    /// can also send the response already encoded. Clients still send and receive messages.
+    pub fn raw_request(mut self: Box<Self>, method: impl Into<String>) -> Box<Self> {
+        self.raw_requests.insert(method.into());
+        self
+    }
+
+    /// Also generate a `<Service>Mock` builder for each service, for fakes made of a closure per
+    /// method: `twirp::test::MockService::for_::<HaberdasherApiMock>().on_make_hat(...)`. The
+    /// generated code requires twirp's `test-support` feature.
+    pub fn mocks(mut self: Box<Self>) -> Box<Self> {
+        self.mocks = true;
+        self
+    }
+
+    /// Like [`mocks`](Self::mocks), but only compile the generated builders when the `cfg`
+    /// predicate holds, e.g. `feature = "fakes"` for a feature that also enables twirp's
+    /// `test-support`, or `test` when the fakes are only used by the crate's own unit tests.
+    pub fn mocks_cfg(mut self: Box<Self>, cfg: impl Into<String>) -> Box<Self> {
+        self.mocks = true;
+        self.mocks_cfg = Some(cfg.into());
+        self
+    }
 }
 
-pub struct ServiceGenerator;
+const RAW_REQUEST_TYPE: &str = "twirp::http::Request<twirp::bytes::Bytes>";
 
 impl prost_build::ServiceGenerator for ServiceGenerator {
     fn generate(&mut self, service: prost_build::Service, buf: &mut String) {
         let service_name = service.name;
         let service_fqn = format!("{}.{}", service.package, service.proto_name);
+        // Routes are registered with the paths emitted here, so catch clashes while generating
+        // the code rather than when the router is built.
+        let mut paths = HashSet::new();
+        for m in &service.methods {
+            assert!(
+                paths.insert(&m.proto_name),
+                "rpc `{}` is defined more than once in service `{service_fqn}`",
+                m.proto_name
+            );
+        }
+        // The types handlers get the request as and return the response as.
+        let types: Vec<(&str, String)> = service
+            .methods
+            .iter()
+            .map(|m| {
+                let fqn = format!("{service_fqn}.{}", m.proto_name);
+                match self.raw_requests.contains(&fqn) {
+                    true => (
+                        RAW_REQUEST_TYPE,
+                        format!("twirp::server::RawResponse<{}>", m.output_type),
+                    ),
+                    false => (m.input_type.as_str(), m.output_type.clone()),
+                }
+            })
+            .collect();
         writeln!(buf).unwrap();
 
         writeln!(buf, "pub use twirp;").unwrap();
         writeln!(buf).unwrap();
         writeln!(buf, "pub const SERVICE_FQN: &str = \"/{service_fqn}\";").unwrap();
+        let methods: Vec<String> = service
+            .methods
+            .iter()
+            .map(|m| format!("{:?}", m.proto_name))
+            .collect();
+        writeln!(
+            buf,
+            "/// The names of the service's rpcs, e.g. for `twirp::server::routes::RouteTable`."
+        )
+        .unwrap();
+        writeln!(
+            buf,
+            "pub const METHODS: &[&str] = &[{}];",
+            methods.join(", ")
+        )
+        .unwrap();
 
         //
         // generate the twirp server
@@ -29,11 +109,11 @@ impl prost_build::ServiceGenerator for ServiceGenerator {
         writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
         writeln!(buf, "pub trait {} {{", service_name).unwrap();
         writeln!(buf, "    type Error;").unwrap();
-        for m in &service.methods {
+        for (m, (req_type, resp_type)) in service.methods.iter().zip(&types) {
             writeln!(
                 buf,
                 "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, Self::Error>;",
-                m.name, m.input_type, m.output_type,
+                m.name, req_type, resp_type,
             )
             .unwrap();
         }
@@ -45,11 +125,11 @@ impl prost_build::ServiceGenerator for ServiceGenerator {
         writeln!(buf, "    T: {service_name} + Sync + Send").unwrap();
         writeln!(buf, "{{").unwrap();
         writeln!(buf, "    type Error = T::Error;\n").unwrap();
-        for m in &service.methods {
+        for (m, (req_type, resp_type)) in service.methods.iter().zip(&types) {
             writeln!(
                 buf,
                 "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, Self::Error> {{",
-                m.name, m.input_type, m.output_type,
+                m.name, req_type, resp_type,
             )
                 .unwrap();
             writeln!(buf, "        T::{}(&*self, ctx, req).await", m.name).unwrap();
@@ -57,6 +137,24 @@ impl prost_build::ServiceGenerator for ServiceGenerator {
         }
         writeln!(buf, "}}").unwrap();
 
+        writeln!(buf, "#[twirp::async_trait::async_trait]").unwrap();
+        writeln!(buf, "impl<T> {service_name} for twirp::SwappableService<T>").unwrap();
+        writeln!(buf, "where").unwrap();
+        writeln!(buf, "    T: {service_name} + Sync + Send").unwrap();
+        writeln!(buf, "{{").unwrap();
+        writeln!(buf, "    type Error = T::Error;\n").unwrap();
+        for (m, (req_type, resp_type)) in service.methods.iter().zip(&types) {
+            writeln!(
+                buf,
+                "    async fn {}(&self, ctx: twirp::Context, req: {}) -> Result<{}, Self::Error> {{",
+                m.name, req_type, resp_type,
+            )
+            .unwrap();
+            writeln!(buf, "        self.load().{}(ctx, req).await", m.name).unwrap();
+            writeln!(buf, "    }}").unwrap();
+        }
+        writeln!(buf, "}}").unwrap();
+
         // add_service
         writeln!(
             buf,
@@ -65,28 +163,63 @@ where
     T: {service_name} + Clone + Send + Sync + 'static,
     <T as {service_name}>::Error: twirp::IntoTwirpResponse,
 {{
-    twirp::details::TwirpRouterBuilder::new(api)"#,
+    router_builder(api).build()
+}}
+
+/// Like `router`, but requests that don't match any rpc are passed to `fallback`.
+pub fn router_with_fallback<T, H, X>(api: T, fallback: H) -> twirp::Router
+where
+    T: {service_name} + Clone + Send + Sync + 'static,
+    <T as {service_name}>::Error: twirp::IntoTwirpResponse,
+    H: twirp::axum::handler::Handler<X, T>,
+    X: 'static,
+{{
+    router_builder(api).build_with_fallback(fallback)
+}}
+
+/// A handler that calls `api` in-process, for `twirp::ClientBuilder::direct`.
+pub fn direct_handler<T>(api: T) -> twirp::direct::DirectService
+where
+    T: {service_name} + Clone + Send + Sync + 'static,
+    <T as {service_name}>::Error: twirp::IntoTwirpResponse,
+{{
+    router_builder(api).build_direct()
+}}
+
+/// The builder behind `router`, for configuring the service's routes before building them.
+pub fn router_builder<T>(api: T) -> twirp::details::TwirpRouterBuilder<T>
+where
+    T: {service_name} + Clone + Send + Sync + 'static,
+    <T as {service_name}>::Error: twirp::IntoTwirpResponse,
+{{
+    twirp::details::TwirpRouterBuilder::new(SERVICE_FQN, api)"#,
         )
         .unwrap();
-        for m in &service.methods {
+        for (m, (req_type, _)) in service.methods.iter().zip(&types) {
             let uri = &m.proto_name;
-            let req_type = &m.input_type;
             let rust_method_name = &m.name;
+            // Methods without side effects can also be called with GET, except with raw requests.
+            let route = if *req_type == RAW_REQUEST_TYPE {
+                "route_raw"
+            } else if m.options.idempotency_level() == IdempotencyLevel::NoSideEffects {
+                "route_no_side_effects"
+            } else {
+                "route"
+            };
             writeln!(
                 buf,
-                r#"        .route("/{uri}", |api: T, ctx: twirp::Context, req: {req_type}| async move {{
+                r#"        .{route}("/{uri}", |api: T, ctx: twirp::Context, req: {req_type}| async move {{
             api.{rust_method_name}(ctx, req).await
         }})"#,
             )
             .unwrap();
         }
-        writeln!(
-            buf,
-            r#"
-        .build()
-}}"#
-        )
-        .unwrap();
+        writeln!(buf, "}}").unwrap();
+
+        if self.mocks {
+            let cfg = self.mocks_cfg.as_deref();
+            generate_mock(&service_name, &service.methods, cfg, buf);
+        }
 
         //
         // generate the twirp client
@@ -126,7 +259,7 @@ where
             .unwrap();
             writeln!(
                 buf,
-                r#"    self.request("{}/{}", req).await"#,
+                r#"    self.request_with_codec("{}/{}", req).await"#,
                 service_fqn, m.proto_name
             )
             .unwrap();
@@ -135,3 +268,63 @@ where
         writeln!(buf, "}}").unwrap();
     }
 }
+
+// A typed builder of `twirp::test::MockService` for the service.
+fn generate_mock(
+    service_name: &str,
+    methods: &[prost_build::Method],
+    cfg: Option<&str>,
+    buf: &mut String,
+) {
+    let mock = format!("{service_name}Mock");
+    let cfg = cfg
+        .map(|cfg| format!("\n#[cfg({cfg})]"))
+        .unwrap_or_default();
+    writeln!(
+        buf,
+        r#"
+/// A fake `{service_name}` for tests, answering each method with a closure.{cfg}
+pub struct {mock}(twirp::test::MockService);
+{cfg}
+impl From<twirp::test::MockService> for {mock} {{
+    fn from(mock: twirp::test::MockService) -> Self {{
+        Self(mock)
+    }}
+}}
+{cfg}
+impl twirp::test::MockBuilder for {mock} {{
+    const SERVICE_FQN: &'static str = SERVICE_FQN;
+}}
+{cfg}
+impl {mock} {{"#,
+    )
+    .unwrap();
+    for m in methods {
+        writeln!(
+            buf,
+            r#"    pub fn on_{}<F>(self, f: F) -> Self
+    where
+        F: Fn({}) -> Result<{}, twirp::TwirpErrorResponse> + Clone + Send + Sync + 'static,
+    {{
+        Self(self.0.on("/{}", f))
+    }}
+"#,
+            m.name, m.input_type, m.output_type, m.proto_name,
+        )
+        .unwrap();
+    }
+    writeln!(
+        buf,
+        r#"    /// The router of the fake, to be mounted at `SERVICE_FQN` like `router()`.
+    pub fn build_router(self) -> twirp::Router {{
+        self.0.build_router()
+    }}
+
+    /// A handler that calls the fake in-process, for `twirp::ClientBuilder::direct`.
+    pub fn build_direct(self) -> twirp::direct::DirectService {{
+        self.0.build_direct()
+    }}
+}}"#,
+    )
+    .unwrap();
+}
@@ -0,0 +1,116 @@
+//! Benchmarks for the request path: body encoding and decoding, route dispatch, and whole
+//! requests through the router and the direct client.
+//!
+//! Run with `cargo bench -p twirp --features test-support`.
+
+use axum::body::Body;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use http::Request;
+use http_body_util::BodyExt;
+use prost::Message;
+use tower::ServiceExt;
+use twirp::test::*;
+use twirp::{Client, ClientBuilder};
+
+/// Representative message sizes: a small request, and a mid-sized one with a large field.
+const SIZES: [usize; 2] = [16, 64 * 1024];
+
+fn message(size: usize) -> PingRequest {
+    PingRequest {
+        name: "x".repeat(size),
+    }
+}
+
+fn request(uri: &str, content_type: &str, body: Vec<u8>) -> Request<Body> {
+    Request::post(uri)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .expect("valid request")
+}
+
+async fn call(router: &axum::Router, req: Request<Body>) -> usize {
+    let resp = router.clone().oneshot(req).await.expect("infallible");
+    assert!(resp.status().is_success());
+    resp.into_body()
+        .collect()
+        .await
+        .expect("body")
+        .to_bytes()
+        .len()
+}
+
+fn bench_requests(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().expect("runtime");
+    let router = test_api_router();
+    let mut group = c.benchmark_group("request");
+    for size in SIZES {
+        let msg = message(size);
+        group.throughput(Throughput::Bytes(size as u64));
+
+        let proto = msg.encode_to_vec();
+        group.bench_with_input(BenchmarkId::new("protobuf", size), &proto, |b, body| {
+            b.to_async(&rt).iter(|| {
+                call(
+                    &router,
+                    request(
+                        "/twirp/test.TestAPI/Ping",
+                        "application/protobuf",
+                        body.clone(),
+                    ),
+                )
+            })
+        });
+
+        let json = serde_json::to_vec(&msg).expect("json");
+        group.bench_with_input(BenchmarkId::new("json", size), &json, |b, body| {
+            b.to_async(&rt).iter(|| {
+                call(
+                    &router,
+                    request("/twirp/test.TestAPI/Ping", "application/json", body.clone()),
+                )
+            })
+        });
+
+        let client = direct_client();
+        group.bench_with_input(BenchmarkId::new("direct", size), &msg, |b, msg| {
+            b.to_async(&rt)
+                .iter(|| async { client.ping(msg.clone()).await.expect("ping") })
+        });
+    }
+    group.finish();
+}
+
+fn direct_client() -> Client {
+    let base_url = url::Url::parse("http://bench.local/twirp/").expect("url");
+    ClientBuilder::new(base_url, reqwest::Client::new())
+        .direct("bench.local", test_api_direct_handler())
+        .build()
+        .expect("client")
+}
+
+fn bench_routing(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().expect("runtime");
+    let mut group = c.benchmark_group("routing");
+    for services in [1, 100] {
+        let router = (0..services).fold(axum::Router::new(), |router, i| {
+            router.nest(
+                &format!("/twirp/test{i}.TestAPI"),
+                test_api_service_router(),
+            )
+        });
+        let path = format!("/twirp/test{}.TestAPI/Ping", services - 1);
+        let body = message(16).encode_to_vec();
+        group.bench_function(BenchmarkId::new("services", services), |b| {
+            b.to_async(&rt).iter(|| {
+                call(
+                    &router,
+                    request(&path, "application/protobuf", body.clone()),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_requests, bench_routing);
+criterion_main!(benches);
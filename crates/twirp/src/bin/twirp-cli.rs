@@ -0,0 +1,183 @@
+//! Call any Twirp method, given the file descriptor set of its service.
+//!
+//! Usage:
+//!
+//! - `twirp-cli <descriptor set> list` lists the services and their methods.
+//! - `twirp-cli <descriptor set> call <base URL> <package.Service/Method> [-H 'name: value']...`
+//!   reads the request as JSON from stdin and prints the JSON response, e.g.
+//!   `echo '{"inches": 12}' | twirp-cli haberdasher.bin call http://localhost:3000/twirp/ example.v1.Haberdasher/MakeHat`.
+//!
+//! The descriptor set is a file written by `protoc --descriptor_set_out` (with
+//! `--include_imports`) or `prost_build::Config::file_descriptor_set_path`. Twirp errors are
+//! printed to stderr as JSON, and the exit code is 1.
+
+use std::process::ExitCode;
+
+use twirp::prost_reflect::prost::Message;
+use twirp::prost_reflect::{DescriptorPool, DynamicMessage, MethodDescriptor, SerializeOptions};
+use twirp::reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use twirp::url::Url;
+use twirp::TwirpErrorResponse;
+
+const USAGE: &str = "usage: twirp-cli <descriptor set> list
+       twirp-cli <descriptor set> call <base URL> <package.Service/Method> [-H 'name: value']...";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (path, command) = match args.as_slice() {
+        [path, command, ..] => (path, command.as_str()),
+        _ => return usage(),
+    };
+    let pool = match std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| DescriptorPool::decode(&bytes[..]).map_err(|e| e.to_string()))
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            eprintln!("{path}: invalid descriptor set: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    match (command, &args[2..]) {
+        ("list", []) => {
+            list(&pool);
+            ExitCode::SUCCESS
+        }
+        ("call", [base_url, method, headers @ ..]) => call(&pool, base_url, method, headers),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("{USAGE}");
+    ExitCode::from(2)
+}
+
+fn list(pool: &DescriptorPool) {
+    for service in pool.services() {
+        println!("{}", service.full_name());
+        for method in service.methods() {
+            let stream = |streaming: bool| if streaming { "stream " } else { "" };
+            println!(
+                "  {}({}{}) returns ({}{})",
+                method.name(),
+                stream(method.is_client_streaming()),
+                method.input().full_name(),
+                stream(method.is_server_streaming()),
+                method.output().full_name(),
+            );
+        }
+    }
+}
+
+fn call(pool: &DescriptorPool, base_url: &str, method: &str, args: &[String]) -> ExitCode {
+    let Some(desc) = find_method(pool, method) else {
+        eprintln!("{method}: no such method");
+        return ExitCode::from(2);
+    };
+    let url = match Url::parse(base_url).and_then(|url| url.join(method)) {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("{base_url}: invalid URL: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    let Some(headers) = parse_headers(args) else {
+        return usage();
+    };
+    let request = match read_request(&desc) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("invalid request: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the runtime");
+    match runtime.block_on(send(url, headers, &desc, request)) {
+        Ok(response) => {
+            let options = SerializeOptions::new().use_proto_field_name(true);
+            let mut serializer = serde_json::Serializer::pretty(std::io::stdout());
+            response
+                .serialize_with_options(&mut serializer, &options)
+                .expect("response can be serialized");
+            println!();
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            let err = serde_json::to_string_pretty(&err).expect("error can be serialized");
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// The method named `package.Service/Method`.
+fn find_method(pool: &DescriptorPool, name: &str) -> Option<MethodDescriptor> {
+    let (service, method) = name.trim_start_matches('/').split_once('/')?;
+    pool.get_service_by_name(service)?
+        .methods()
+        .find(|m| m.name() == method)
+}
+
+// The headers given with `-H 'name: value'`, or `None` if any argument is something else.
+fn parse_headers(args: &[String]) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "-H" {
+            return None;
+        }
+        let (name, value) = args.next()?.split_once(':')?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+        let value = HeaderValue::from_str(value.trim()).ok()?;
+        headers.append(name, value);
+    }
+    Some(headers)
+}
+
+// The request message, as JSON on stdin.
+fn read_request(method: &MethodDescriptor) -> Result<DynamicMessage, Box<dyn std::error::Error>> {
+    let json = std::io::read_to_string(std::io::stdin())?;
+    let mut deserializer = serde_json::Deserializer::from_str(&json);
+    let request = DynamicMessage::deserialize(method.input(), &mut deserializer)?;
+    deserializer.end()?;
+    Ok(request)
+}
+
+async fn send(
+    url: Url,
+    headers: HeaderMap,
+    method: &MethodDescriptor,
+    request: DynamicMessage,
+) -> Result<DynamicMessage, TwirpErrorResponse> {
+    let resp = twirp::reqwest::Client::new()
+        .post(url)
+        .headers(headers)
+        .header(CONTENT_TYPE, "application/protobuf")
+        .body(request.encode_to_vec())
+        .send()
+        .await
+        .map_err(|e| twirp::unavailable("request failed").with_meta("error", e))?;
+    let status = resp.status();
+    let is_json = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|ct| ct == "application/json");
+    let body = resp
+        .bytes()
+        .await
+        .map_err(|e| twirp::unavailable("failed to read the response").with_meta("error", e))?;
+    if !status.is_success() {
+        return Err(match serde_json::from_slice(&body) {
+            Ok(err) if is_json => err,
+            _ => twirp::unknown(format!("HTTP {status}"))
+                .with_meta("body", String::from_utf8_lossy(&body)),
+        });
+    }
+    DynamicMessage::decode(method.output(), body)
+        .map_err(|e| twirp::malformed("invalid response").with_meta("error", e))
+}
@@ -0,0 +1,41 @@
+//! Check running Twirp servers against the spec. See `twirp::test::conformance`.
+//!
+//! Usage: `twirp-conformance <method URL>...`, e.g.
+//! `twirp-conformance http://localhost:3000/twirp/example.v1.Haberdasher/MakeHat`.
+
+use std::process::ExitCode;
+
+use twirp::test::conformance;
+use twirp::url::Url;
+
+fn main() -> ExitCode {
+    let urls: Vec<String> = std::env::args().skip(1).collect();
+    if urls.is_empty() {
+        eprintln!("usage: twirp-conformance <method URL>...");
+        return ExitCode::from(2);
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the runtime");
+    let client = twirp::reqwest::Client::new();
+
+    let mut ok = true;
+    for url in urls {
+        let url = match Url::parse(&url) {
+            Ok(url) => url,
+            Err(e) => {
+                eprintln!("{url}: invalid URL: {e}");
+                return ExitCode::from(2);
+            }
+        };
+        let report = runtime.block_on(conformance::check_url(&client, &url));
+        println!("{url}\n{report}");
+        ok &= report.is_ok();
+    }
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,128 @@
+//! Reuse of the buffers that protobuf messages are encoded into.
+//!
+//! Each thread keeps one `BytesMut` that messages are encoded into and then split off as
+//! [`Bytes`]. Once the `Bytes` of earlier messages have been dropped (i.e. the response or request
+//! has been sent), the next message reclaims their memory instead of asking the allocator for a
+//! new buffer. This cuts allocator pressure for services handling many mid-sized messages; large
+//! messages (over 1 MiB) are encoded into their own buffer so the pool doesn't hold on to them.
+//!
+//! The `Bytes` of a pooled message shares its buffer with the other messages encoded on the same
+//! thread, so it keeps the whole buffer (at least 64 KiB) allocated for as long as it lives, even
+//! for a message of a few bytes. The crate only holds them while a request or response is in
+//! flight. Code that keeps one for longer, e.g. caches the response of a
+//! [`DirectHandler`](crate::direct::DirectHandler) or a message decoded from it with `bytes`
+//! fields sharing the buffer, should copy it out with [`Bytes::copy_from_slice`] first.
+//!
+//! [`stats`] reports how often the pool could be used.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::{Bytes, BytesMut};
+
+/// The size of the buffers the pool allocates.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Messages larger than this aren't encoded with the pool.
+const MAX_POOLED_LEN: usize = 1024 * 1024;
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static BUFFER: RefCell<BytesMut> = RefCell::new(BytesMut::new());
+}
+
+/// Counters for the encode buffer pool, from [`stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Messages encoded into memory that was already allocated.
+    pub hits: u64,
+    /// Messages that needed a new allocation.
+    pub misses: u64,
+}
+
+impl PoolStats {
+    /// The fraction of messages encoded without a new allocation, or `None` before any message
+    /// has been encoded.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
+/// The pool counters, summed over all threads since the process started.
+pub fn stats() -> PoolStats {
+    PoolStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+    }
+}
+
+/// Encode `m` into a pooled buffer. See the [module documentation](self) about keeping the result.
+pub(crate) fn encode<T: prost::Message>(m: T) -> Bytes {
+    let len = m.encoded_len();
+    if len > MAX_POOLED_LEN {
+        MISSES.fetch_add(1, Ordering::Relaxed);
+        return encode_into(BytesMut::with_capacity(len), m);
+    }
+    BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        if buffer.capacity() >= len || buffer.try_reclaim(len) {
+            HITS.fetch_add(1, Ordering::Relaxed);
+        } else {
+            MISSES.fetch_add(1, Ordering::Relaxed);
+            *buffer = BytesMut::with_capacity(len.max(CHUNK_SIZE));
+        }
+        m.encode(&mut *buffer)
+            .expect("can only fail if buffer does not have capacity");
+        assert_eq!(buffer.len(), len);
+        // The rest of the buffer's capacity stays in the pool for the next message.
+        buffer.split().freeze()
+    })
+}
+
+fn encode_into<T: prost::Message>(mut data: BytesMut, m: T) -> Bytes {
+    let len = data.capacity();
+    m.encode(&mut data)
+        .expect("can only fail if buffer does not have capacity");
+    assert_eq!(data.len(), len);
+    data.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::test::PingRequest;
+
+    #[test]
+    fn test_encode_reuses_buffer() {
+        let message = PingRequest {
+            name: "hi".repeat(100),
+        };
+        // The counters are global and other tests encode too, so only compare on this thread.
+        let first = encode(message.clone());
+        let before = stats();
+        let second = encode(message.clone());
+        assert_eq!(first, second);
+        assert!(stats().hits > before.hits);
+
+        drop((first, second));
+        let large = PingRequest {
+            name: "x".repeat(MAX_POOLED_LEN + 1),
+        };
+        let before = stats();
+        let bytes = encode(large.clone());
+        assert_eq!(bytes.len(), large.encoded_len());
+        assert!(stats().misses > before.misses);
+    }
+
+    #[test]
+    fn test_hit_rate() {
+        assert_eq!(PoolStats::default().hit_rate(), None);
+        let stats = PoolStats { hits: 3, misses: 1 };
+        assert_eq!(stats.hit_rate(), Some(0.75));
+    }
+}
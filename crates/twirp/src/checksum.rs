@@ -0,0 +1,121 @@
+//! SHA-256 checksums of request and response bodies, for deployments where something between
+//! clients and servers has been known to corrupt payloads.
+//!
+//! Enable it on a router with
+//! [`TwirpRouterBuilder::checksums`](crate::details::TwirpRouterBuilder::checksums) and on a
+//! client with [`ClientBuilder::checksums`](crate::ClientBuilder::checksums). Both sides then send
+//! the hex-encoded SHA-256 of each body (as it is on the wire, after compression) in the
+//! `x-content-sha256` header, and check the header of the bodies they receive. A mismatch is
+//! reported as a `dataloss` error.
+//!
+//! Requires the `checksum` feature.
+
+use std::fmt::Write;
+
+use http::{HeaderMap, HeaderValue};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{error, GenericError, TwirpErrorResponse};
+
+/// The header with the checksum of a body.
+pub(crate) const X_CONTENT_SHA256: &str = "x-content-sha256";
+
+/// Checksum settings. See the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Checksums {
+    require: bool,
+}
+
+impl Checksums {
+    /// Send checksums, and check them when the peer sends them.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also reject bodies that come without a checksum.
+    pub fn require(mut self, require: bool) -> Self {
+        self.require = require;
+        self
+    }
+
+    /// Check `body` against the checksum in `headers`.
+    pub(crate) fn verify(&self, headers: &HeaderMap, body: &[u8]) -> Result<(), ChecksumError> {
+        let Some(expected) = headers.get(X_CONTENT_SHA256) else {
+            return match self.require {
+                true => Err(ChecksumError::Missing),
+                false => Ok(()),
+            };
+        };
+        let actual = sha256(body);
+        if !expected.as_bytes().eq_ignore_ascii_case(actual.as_bytes()) {
+            return Err(ChecksumError::Mismatch {
+                expected: String::from_utf8_lossy(expected.as_bytes()).into_owned(),
+                actual: actual.to_str().unwrap_or_default().to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Why a body failed its checksum check.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChecksumError {
+    #[error("body has no {X_CONTENT_SHA256} header")]
+    Missing,
+    #[error("body has sha256 {actual}, but {X_CONTENT_SHA256} is {expected}")]
+    Mismatch { expected: String, actual: String },
+}
+
+impl From<ChecksumError> for TwirpErrorResponse {
+    fn from(err: ChecksumError) -> Self {
+        error::dataloss("body checksum mismatch")
+            .with_meta("error", &err)
+            .with_source(err)
+    }
+}
+
+/// The `x-content-sha256` header value for `body`.
+pub(crate) fn sha256(body: &[u8]) -> HeaderValue {
+    let digest = Sha256::digest(body);
+    let mut hex = String::with_capacity(2 * digest.len());
+    for byte in digest {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    HeaderValue::try_from(hex).expect("hex is a valid header value")
+}
+
+/// The error to respond with for a request that failed with `err` before reaching the handler:
+/// `dataloss` for checksum errors.
+pub(crate) fn request_error(err: GenericError) -> Result<TwirpErrorResponse, GenericError> {
+    err.downcast::<ChecksumError>().map(|err| (*err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TwirpErrorCode;
+
+    #[test]
+    fn test_verify() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Checksums::new().verify(&headers, b"body"), Ok(()));
+        assert_eq!(
+            Checksums::new().require(true).verify(&headers, b"body"),
+            Err(ChecksumError::Missing)
+        );
+
+        headers.insert(X_CONTENT_SHA256, sha256(b"body"));
+        assert_eq!(
+            headers[X_CONTENT_SHA256],
+            "230d8358dc8e8890b4c58deeb62912ee2f20357ae92a5cc861b98e68fe31acb5"
+        );
+        assert_eq!(Checksums::new().verify(&headers, b"body"), Ok(()));
+        let err = Checksums::new().verify(&headers, b"bodz").unwrap_err();
+        assert!(matches!(err, ChecksumError::Mismatch { .. }));
+
+        let err = TwirpErrorResponse::from(err);
+        assert_eq!(err.code, TwirpErrorCode::Dataloss);
+    }
+}
@@ -1,14 +1,33 @@
+use std::marker::PhantomData;
 use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, SystemTime};
 use std::vec;
 
 use async_trait::async_trait;
-use reqwest::header::{InvalidHeaderValue, CONTENT_TYPE};
+use bytes::Bytes;
+use reqwest::header::{
+    HeaderMap, HeaderValue, InvalidHeaderValue, ACCEPT, CONTENT_TYPE, RETRY_AFTER,
+};
 use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use thiserror::Error;
+use tower::Service;
 use url::Url;
 
-use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF};
-use crate::{serialize_proto_message, GenericError, TwirpErrorResponse};
+use crate::codec::{self, Codec, Format, MessageType, ProtobufCodec};
+use crate::direct::{DirectHandler, RequestHandlers};
+use crate::headers::{
+    CONTENT_TYPE_JSON, CONTENT_TYPE_STREAM_PROTOBUF, CONTENT_TYPE_X_PROTOBUF, TWIRP_TIMEOUT,
+};
+use crate::stream::{read_stream, FrameCodec, MessageStream};
+use crate::{serialize_proto_message, Compatibility, Context, GenericError, TwirpErrorResponse};
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+#[cfg(feature = "tracing")]
+pub mod trace;
 
 #[derive(Debug, Error)]
 #[non_exhaustive]
@@ -17,6 +36,8 @@ pub enum ClientError {
     InvalidHeader(#[from] InvalidHeaderValue),
     #[error("base_url must end in /, but got: {0}")]
     InvalidBaseUrl(Url),
+    #[error("base_url must end in /twirp/ for Twirp v5 servers, but got: {0}")]
+    InvalidV5BaseUrl(Url),
     #[error(transparent)]
     InvalidUrl(#[from] url::ParseError),
     #[error(
@@ -34,8 +55,17 @@ pub enum ClientError {
     MalformedResponse(String),
     #[error(transparent)]
     ProtoDecodeError(#[from] prost::DecodeError),
+    /// A custom [`Codec`] failed to encode a request or decode a response.
+    #[error("codec error: {0}")]
+    CodecError(GenericError),
+    /// The request couldn't be sent or the response couldn't be received (e.g. the connection
+    /// was refused). Timeouts are reported as [`ClientError::Timeout`].
     #[error(transparent)]
-    ReqwestError(#[from] reqwest::Error),
+    ReqwestError(reqwest::Error),
+    /// The request timed out, e.g. because of a timeout configured on the `reqwest::Client`.
+    #[error("request timed out: {0}")]
+    Timeout(#[source] reqwest::Error),
+    /// The server responded with a Twirp error.
     #[error("twirp error: {0:?}")]
     TwirpError(TwirpErrorResponse),
 
@@ -44,12 +74,88 @@ pub enum ClientError {
     MiddlewareError(#[from] GenericError),
 }
 
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            ClientError::Timeout(err)
+        } else {
+            ClientError::ReqwestError(err)
+        }
+    }
+}
+
+impl From<TwirpErrorResponse> for ClientError {
+    fn from(err: TwirpErrorResponse) -> Self {
+        ClientError::TwirpError(err)
+    }
+}
+
+impl ClientError {
+    /// The error the server responded with, if the request got that far.
+    pub fn twirp_error(&self) -> Option<&TwirpErrorResponse> {
+        match self {
+            ClientError::TwirpError(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Whether the request failed in transit (connection errors and timeouts) rather than being
+    /// answered by the server.
+    pub fn is_transport(&self) -> bool {
+        matches!(self, ClientError::ReqwestError(_) | ClientError::Timeout(_))
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, ClientError::Timeout(_))
+    }
+}
+
+/// Turn a failed call into the error to return from a service that made it, e.g. when proxying
+/// requests. Errors from the server are passed on as they are; transport errors become
+/// `unavailable` (or `deadline_exceeded` for timeouts), and anything else becomes `internal`.
+impl From<ClientError> for TwirpErrorResponse {
+    fn from(err: ClientError) -> Self {
+        match err {
+            ClientError::TwirpError(err) => err,
+            ClientError::Timeout(_) => {
+                crate::deadline_exceeded("upstream request timed out").with_source(err)
+            }
+            ClientError::ReqwestError(_) => {
+                crate::unavailable("upstream request failed").with_source(err)
+            }
+            err => crate::internal("upstream request failed").with_source(err),
+        }
+    }
+}
+
 pub type Result<T, E = ClientError> = std::result::Result<T, E>;
 
+// Keep the specific error variants for the errors of the built-in codecs.
+fn codec_error(err: GenericError) -> ClientError {
+    let err = match err.downcast::<prost::DecodeError>() {
+        Ok(err) => return ClientError::ProtoDecodeError(*err),
+        Err(err) => err,
+    };
+    match err.downcast::<serde_json::Error>() {
+        Ok(err) => ClientError::JsonDecodeError(*err),
+        Err(err) => ClientError::CodecError(err),
+    }
+}
+
 pub struct ClientBuilder {
     base_url: Url,
     http_client: reqwest::Client,
     middleware: Vec<Box<dyn Middleware>>,
+    transport: Option<Arc<dyn Transport>>,
+    compatibility: Compatibility,
+    codec: Arc<dyn Codec>,
+    handlers: RequestHandlers,
+    #[cfg(feature = "zstd")]
+    zstd: Option<crate::compression::Zstd>,
+    #[cfg(feature = "gzip")]
+    gzip: Option<crate::compression::Gzip>,
+    #[cfg(feature = "checksum")]
+    checksums: Option<crate::checksum::Checksums>,
 }
 
 impl ClientBuilder {
@@ -57,10 +163,73 @@ impl ClientBuilder {
         Self {
             base_url,
             middleware: vec![],
+            transport: None,
             http_client,
+            compatibility: Compatibility::default(),
+            codec: Arc::new(ProtobufCodec),
+            handlers: RequestHandlers::new(),
+            #[cfg(feature = "zstd")]
+            zstd: None,
+            #[cfg(feature = "gzip")]
+            gzip: None,
+            #[cfg(feature = "checksum")]
+            checksums: None,
         }
     }
 
+    /// Compress requests with zstd and ask for compressed responses. The server must support zstd
+    /// too. See [`crate::compression`].
+    #[cfg(feature = "zstd")]
+    pub fn zstd(mut self, zstd: crate::compression::Zstd) -> Self {
+        self.zstd = Some(zstd);
+        self
+    }
+
+    /// Ask for gzip-compressed responses, and compress requests with gzip unless
+    /// [zstd](Self::zstd) is enabled too. The server must support gzip too. To only ask for
+    /// compressed responses, set [`min_size`](crate::compression::Gzip::min_size) to
+    /// `usize::MAX`. See [`crate::compression`].
+    #[cfg(feature = "gzip")]
+    pub fn gzip(mut self, gzip: crate::compression::Gzip) -> Self {
+        self.gzip = Some(gzip);
+        self
+    }
+
+    /// Send checksums of request bodies, and check the checksums of response bodies. The server
+    /// must support checksums too. See [`crate::checksum`].
+    #[cfg(feature = "checksum")]
+    pub fn checksums(mut self, checksums: crate::checksum::Checksums) -> Self {
+        self.checksums = Some(checksums);
+        self
+    }
+
+    /// Encode requests (and decode responses) with `codec` instead of protobuf. The server must
+    /// support the codec too. See [`crate::codec`].
+    pub fn codec<C: Codec>(mut self, codec: C) -> Self {
+        self.codec = Arc::new(codec);
+        self
+    }
+
+    /// Set the version of the Twirp protocol to stay compatible with. See [`Compatibility`].
+    pub fn compatibility(mut self, compatibility: Compatibility) -> Self {
+        self.compatibility = compatibility;
+        self
+    }
+
+    /// Call `handler` in-process for requests to its service on `host`, instead of making HTTP
+    /// requests. See [`crate::direct`].
+    pub fn direct<H: DirectHandler>(mut self, host: &str, handler: H) -> Self {
+        self.handlers.add(host, handler);
+        self
+    }
+
+    /// Send requests with `transport` instead of the `reqwest::Client`, which still builds them.
+    /// The middleware runs before the transport. See [`Transport`].
+    pub fn transport<T: Transport>(mut self, transport: T) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
     /// Add middleware to the client that will be called on each request.
     /// Middlewares are invoked in the order they are added as part of the
     /// request cycle.
@@ -71,14 +240,29 @@ impl ClientBuilder {
         let mut mw = self.middleware;
         mw.push(Box::new(middleware));
         Self {
-            base_url: self.base_url,
-            http_client: self.http_client,
             middleware: mw,
+            ..self
         }
     }
 
     pub fn build(self) -> Result<Client> {
-        Client::new(self.base_url, self.http_client, self.middleware)
+        Client::with_options(
+            self.http_client,
+            ClientRef {
+                base_url: self.base_url,
+                middlewares: self.middleware,
+                transport: self.transport,
+                compatibility: self.compatibility,
+                codec: self.codec,
+                handlers: self.handlers,
+                #[cfg(feature = "zstd")]
+                zstd: self.zstd,
+                #[cfg(feature = "gzip")]
+                gzip: self.gzip,
+                #[cfg(feature = "checksum")]
+                checksums: self.checksums,
+            },
+        )
     }
 }
 
@@ -92,11 +276,73 @@ pub struct Client {
     http_client: reqwest::Client,
     inner: Arc<ClientRef>,
     host: Option<String>,
+    headers: HeaderMap,
+    timeout: Option<Duration>,
 }
 
 struct ClientRef {
     base_url: Url,
     middlewares: Vec<Box<dyn Middleware>>,
+    transport: Option<Arc<dyn Transport>>,
+    compatibility: Compatibility,
+    codec: Arc<dyn Codec>,
+    handlers: RequestHandlers,
+    #[cfg(feature = "zstd")]
+    zstd: Option<crate::compression::Zstd>,
+    #[cfg(feature = "gzip")]
+    gzip: Option<crate::compression::Gzip>,
+    #[cfg(feature = "checksum")]
+    checksums: Option<crate::checksum::Checksums>,
+}
+
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+impl ClientRef {
+    // The encodings the client accepts responses in, most preferred first.
+    fn encodings(&self) -> Vec<&'static str> {
+        let mut encodings = vec![];
+        #[cfg(feature = "zstd")]
+        if self.zstd.is_some() {
+            encodings.push(crate::compression::ZSTD);
+        }
+        #[cfg(feature = "gzip")]
+        if self.gzip.is_some() {
+            encodings.push(crate::compression::GZIP);
+        }
+        encodings
+    }
+
+    // Compress a request body with the preferred encoding, if it is large enough.
+    fn compress(&self, body: &[u8]) -> std::io::Result<Option<(&'static str, Bytes)>> {
+        #[cfg(feature = "zstd")]
+        if let Some(zstd) = &self.zstd {
+            return Ok(zstd.compress(body)?.map(|c| (crate::compression::ZSTD, c)));
+        }
+        #[cfg(feature = "gzip")]
+        if let Some(gzip) = &self.gzip {
+            return Ok(gzip.compress(body)?.map(|c| (crate::compression::GZIP, c)));
+        }
+        Ok(None)
+    }
+
+    // Decompress a response body sent with the `Content-Encoding` in `headers`, if it is one the
+    // client accepts.
+    fn decompress(&self, headers: &HeaderMap, body: Bytes) -> std::io::Result<Bytes> {
+        use crate::compression::is_encoded;
+
+        #[cfg(feature = "zstd")]
+        if let Some(zstd) = &self.zstd {
+            if is_encoded(headers, crate::compression::ZSTD) {
+                return zstd.decompress(&body);
+            }
+        }
+        #[cfg(feature = "gzip")]
+        if let Some(gzip) = &self.gzip {
+            if is_encoded(headers, crate::compression::GZIP) {
+                return gzip.decompress(&body);
+            }
+        }
+        Ok(body)
+    }
 }
 
 impl std::fmt::Debug for Client {
@@ -119,18 +365,25 @@ impl Client {
         http_client: reqwest::Client,
         middlewares: Vec<Box<dyn Middleware>>,
     ) -> Result<Self> {
-        if base_url.path().ends_with('/') {
-            Ok(Client {
-                http_client,
-                inner: Arc::new(ClientRef {
-                    base_url,
-                    middlewares,
-                }),
-                host: None,
-            })
-        } else {
-            Err(ClientError::InvalidBaseUrl(base_url))
+        let mut builder = ClientBuilder::new(base_url, http_client);
+        builder.middleware = middlewares;
+        builder.build()
+    }
+
+    fn with_options(http_client: reqwest::Client, inner: ClientRef) -> Result<Self> {
+        if !inner.base_url.path().ends_with('/') {
+            return Err(ClientError::InvalidBaseUrl(inner.base_url));
+        }
+        if inner.compatibility == Compatibility::V5 && !inner.base_url.path().ends_with("/twirp/") {
+            return Err(ClientError::InvalidV5BaseUrl(inner.base_url));
         }
+        Ok(Client {
+            http_client,
+            inner: Arc::new(inner),
+            host: None,
+            headers: HeaderMap::new(),
+            timeout: None,
+        })
     }
 
     /// Creates a `twirp::Client` with the default `reqwest::ClientBuilder`.
@@ -149,14 +402,97 @@ impl Client {
     /// one, but with a different host in the base URL.
     pub fn with_host(&self, host: &str) -> Self {
         Self {
-            http_client: self.http_client.clone(),
-            inner: self.inner.clone(),
             host: Some(host.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Creates a new `twirp::Client` with the same configuration as the current one, that also
+    /// sends `headers` with every request.
+    pub fn with_headers(&self, headers: HeaderMap) -> Self {
+        let mut client = self.clone();
+        client.headers.extend(headers);
+        client
+    }
+
+    /// Creates a new `twirp::Client` for the calls a handler makes while handling the request of
+    /// `ctx`: they carry its [propagation headers](Context::propagation_headers), e.g.
+    /// `client.with_context(&ctx).make_hat(req)`. Calls to [direct handlers](ClientBuilder::direct)
+    /// get them in their `Context`.
+    pub fn with_context(&self, ctx: &Context) -> Self {
+        self.with_headers(ctx.propagation_headers())
+    }
+
+    /// Creates a new `twirp::Client` whose requests must be answered within `timeout`. The
+    /// timeout is sent as the `Twirp-Timeout` header, so the server stops handling a request when
+    /// it runs out (see [`Context::deadline`]), and the client stops waiting for the response.
+    /// A shorter timeout propagated [from a context](Self::with_context) is kept.
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        effective_timeout(self.timeout, &self.headers)
+    }
+
+    // The headers the client sends, with its timeout as `Twirp-Timeout`.
+    fn request_headers(&self) -> HeaderMap {
+        let mut headers = self.headers.clone();
+        if let Some(timeout) = self.timeout() {
+            let millis = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+            headers.insert(TWIRP_TIMEOUT, HeaderValue::from(millis));
+        }
+        headers
+    }
+
+    // Start a request to `url` with the client's headers and timeout.
+    fn post(&self, url: Url) -> reqwest::RequestBuilder {
+        let req = self.http_client.post(url).headers(self.request_headers());
+        match self.timeout() {
+            Some(timeout) => req.timeout(timeout),
+            None => req,
         }
     }
 
     /// Make an HTTP twirp request.
+    ///
+    /// The request is encoded as protobuf, with the client's [codec](ClientBuilder::codec) applied
+    /// on top (e.g. [encryption](crate::encryption)), so calls with a JSON codec fail with a
+    /// [`ClientError::CodecError`] without being sent; use
+    /// [`request_with_codec`](Self::request_with_codec) for those. Requests to a service with a
+    /// [direct handler](ClientBuilder::direct) are handled in-process instead.
     pub async fn request<I, O>(&self, path: &str, body: I) -> Result<O>
+    where
+        I: prost::Message,
+        O: prost::Message + Default,
+    {
+        self.unary(path, body, codec::encode_proto, codec::decode_proto)
+            .await
+    }
+
+    /// Make an HTTP twirp request in the format of the client's [codec](ClientBuilder::codec),
+    /// JSON included. Messages must implement serde's `Serialize` and `DeserializeOwned` as well as
+    /// `prost::Message`, as the messages generated by `twirp-build` do. Generated clients call
+    /// this.
+    pub async fn request_with_codec<I, O>(&self, path: &str, body: I) -> Result<O>
+    where
+        I: prost::Message + Serialize,
+        O: prost::Message + Default + DeserializeOwned,
+    {
+        self.unary(path, body, codec::encode_message, codec::decode_message)
+            .await
+    }
+
+    async fn unary<I, O>(
+        &self,
+        path: &str,
+        body: I,
+        encode: fn(&dyn Codec, MessageType<'_>, I) -> std::result::Result<Bytes, GenericError>,
+        decode: fn(&dyn Codec, MessageType<'_>, Bytes) -> std::result::Result<O, GenericError>,
+    ) -> Result<O>
     where
         I: prost::Message,
         O: prost::Message + Default,
@@ -165,44 +501,401 @@ impl Client {
         if let Some(host) = &self.host {
             url.set_host(Some(host))?
         };
+        if let Some((handler, method)) = self.direct_handler(&url) {
+            let resp = handler
+                .handle(method, self.direct_context(), serialize_proto_message(body))
+                .await?;
+            return Ok(O::decode(resp)?);
+        }
         let path = url.path().to_string();
-        let req = self
-            .http_client
-            .post(url)
-            .header(CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
-            .body(serialize_proto_message(body))
-            .build()?;
+        let codec = self.inner.codec.as_ref();
+        let body = encode(codec, MessageType::from_path(&path, false), body)
+            .map_err(ClientError::CodecError)?;
+        let req = self.post(url).header(CONTENT_TYPE, codec.content_type());
+        let req = self.set_body(req, body)?.build()?;
 
         // Create and execute the middleware handlers
-        let next = Next::new(&self.http_client, &self.inner.middlewares);
+        let next = self.next();
         let resp = next.run(req).await?;
 
-        // These have to be extracted because reading the body consumes `Response`.
-        let status = resp.status();
-        let content_type = resp.headers().get(CONTENT_TYPE).cloned();
-
-        // TODO: Include more info in the error cases: request path, content-type, etc.
-        match (status, content_type) {
-            (status, Some(ct)) if status.is_success() && ct.as_bytes() == CONTENT_TYPE_PROTOBUF => {
-                O::decode(resp.bytes().await?).map_err(|e| e.into())
+        let content_type = resp.headers().get(CONTENT_TYPE);
+        let compatibility = self.inner.compatibility;
+        match ResponseBody::classify(resp.status(), content_type, codec, compatibility, &path)? {
+            ResponseBody::Message => {
+                let body = self.read_body(resp).await?;
+                let ty = MessageType::from_path(&path, true);
+                decode(codec, ty, body).map_err(codec_error)
             }
-            (status, Some(ct))
-                if (status.is_client_error() || status.is_server_error())
-                    && ct.as_bytes() == CONTENT_TYPE_JSON =>
+            ResponseBody::Error => Err(ClientError::TwirpError(read_twirp_error(resp).await?)),
+        }
+    }
+
+    // Set the body of `req`, compressed and checksummed if the client is configured to.
+    fn set_body(
+        &self,
+        req: reqwest::RequestBuilder,
+        body: bytes::Bytes,
+    ) -> Result<reqwest::RequestBuilder> {
+        #[cfg(any(feature = "zstd", feature = "gzip"))]
+        let (req, body) = {
+            use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+
+            let encodings = self.inner.encodings();
+            let req = match encodings.is_empty() {
+                true => req,
+                false => req.header(ACCEPT_ENCODING, encodings.join(", ")),
+            };
+            match self
+                .inner
+                .compress(&body)
+                .map_err(|e| ClientError::CodecError(e.into()))?
             {
-                Err(ClientError::TwirpError(serde_json::from_slice(
-                    &resp.bytes().await?,
-                )?))
+                Some((encoding, compressed)) => {
+                    (req.header(CONTENT_ENCODING, encoding), compressed)
+                }
+                None => (req, body),
+            }
+        };
+        #[cfg(feature = "checksum")]
+        let req = match self.inner.checksums {
+            Some(_) => req.header(
+                crate::checksum::X_CONTENT_SHA256,
+                crate::checksum::sha256(&body),
+            ),
+            None => req,
+        };
+        Ok(req.body(body))
+    }
+
+    // Read the body of `resp`, checking its checksum and decompressing it if needed.
+    async fn read_body(&self, resp: reqwest::Response) -> Result<bytes::Bytes> {
+        // Only the headers that describe the body are needed once it has been read.
+        #[cfg(any(feature = "zstd", feature = "gzip", feature = "checksum"))]
+        let headers = {
+            let mut headers = HeaderMap::new();
+            #[cfg(any(feature = "zstd", feature = "gzip"))]
+            if let Some(value) = resp.headers().get(reqwest::header::CONTENT_ENCODING) {
+                headers.insert(reqwest::header::CONTENT_ENCODING, value.clone());
             }
-            (status, ct) => Err(ClientError::HttpError {
-                status,
-                msg: "unknown error".to_string(),
-                path,
-                content_type: ct
-                    .map(|x| x.to_str().unwrap_or_default().to_string())
-                    .unwrap_or_default(),
-            }),
+            #[cfg(feature = "checksum")]
+            if let Some(value) = resp.headers().get(crate::checksum::X_CONTENT_SHA256) {
+                headers.insert(crate::checksum::X_CONTENT_SHA256, value.clone());
+            }
+            headers
+        };
+        let body = resp.bytes().await?;
+        #[cfg(feature = "checksum")]
+        if let Some(checksums) = &self.inner.checksums {
+            checksums
+                .verify(&headers, &body)
+                .map_err(|err| ClientError::TwirpError(err.into()))?;
         }
+        #[cfg(any(feature = "zstd", feature = "gzip"))]
+        let body = self
+            .inner
+            .decompress(&headers, body)
+            .map_err(|e| ClientError::CodecError(e.into()))?;
+        Ok(body)
+    }
+
+    // The context of a call to a direct handler, with the headers the client sends, so the
+    // handler's router enforces the client's timeout as it would over HTTP.
+    fn direct_context(&self) -> Context {
+        Context::default().with_headers(self.request_headers())
+    }
+
+    // The direct handler for the service at `url` (`.../<service>/<method>`), and the method.
+    fn direct_handler<'a>(&'a self, url: &'a Url) -> Option<(&'a dyn DirectHandler, &'a str)> {
+        if self.inner.handlers.is_empty() {
+            return None;
+        }
+        let mut segments = url.path_segments()?.rev();
+        let method = segments.next()?;
+        let service = segments.next()?;
+        let handler = self.inner.handlers.get(url.host_str()?, service)?;
+        Some((handler.as_ref(), method))
+    }
+
+    /// Make a request to a server-streaming RPC (see [`crate::stream`]), returning the stream of
+    /// responses. Errors that occur once the stream has started are yielded as its last item.
+    ///
+    /// Streamed responses are always protobuf, so the client's [codec](ClientBuilder::codec) must
+    /// work on protobuf too: it encodes the request and the payload of each frame of the response,
+    /// and calls with a JSON codec fail with a [`ClientError::CodecError`] without being sent.
+    /// Responses are classified like those of [`request`](Self::request), so a server that serves
+    /// the method as a unary RPC (e.g. a Twirp v5 server, with
+    /// [`Compatibility::V5`]) gives a stream of its one message.
+    pub async fn request_stream<I, O>(&self, path: &str, body: I) -> Result<MessageStream<O>>
+    where
+        I: prost::Message,
+        O: prost::Message + Default + 'static,
+    {
+        let mut url = self.inner.base_url.join(path)?;
+        if let Some(host) = &self.host {
+            url.set_host(Some(host))?
+        };
+        let path = url.path().to_string();
+        let codec = self.inner.codec.as_ref();
+        if codec.format() != Format::Protobuf {
+            let msg = format!(
+                "server-streaming calls need a protobuf codec, not {}",
+                codec.content_type()
+            );
+            return Err(ClientError::CodecError(msg.into()));
+        }
+        let ty = MessageType::from_path(&path, false);
+        let body = codec
+            .encode_as(ty, serialize_proto_message(body))
+            .map_err(ClientError::CodecError)?;
+        let req = self
+            .post(url)
+            .header(CONTENT_TYPE, codec.content_type())
+            .header(ACCEPT, CONTENT_TYPE_STREAM_PROTOBUF)
+            .body(body)
+            .build()?;
+
+        let next = self.next();
+        let resp = next.run(req).await?;
+
+        let content_type = resp.headers().get(CONTENT_TYPE);
+        let ty = MessageType::from_path(&path, true);
+        if resp.status().is_success()
+            && content_type
+                .is_some_and(|ct| ct.as_bytes() == CONTENT_TYPE_STREAM_PROTOBUF.as_bytes())
+        {
+            let codec = FrameCodec::new(self.inner.codec.clone(), ty);
+            return Ok(read_stream(resp, codec));
+        }
+        let compatibility = self.inner.compatibility;
+        match ResponseBody::classify(resp.status(), content_type, codec, compatibility, &path)? {
+            // A server that serves the method as a unary RPC (e.g. a Twirp v5 server) answers
+            // with a single message.
+            ResponseBody::Message => {
+                let body = self.read_body(resp).await?;
+                let msg = codec::decode_proto(codec, ty, body).map_err(codec_error);
+                Ok(Box::pin(futures::stream::once(async { msg })))
+            }
+            ResponseBody::Error => Err(ClientError::TwirpError(read_twirp_error(resp).await?)),
+        }
+    }
+
+    /// Send `req` to the path of its URI (relative to the base URL, e.g. `example.v1.Haberdasher/MakeHat`)
+    /// through the client's middleware, with the client's headers, and read the whole response.
+    /// Responses with any status are returned as they are. This is the call path of the client's
+    /// [`tower::Service`] implementation.
+    pub async fn send(&self, req: http::Request<Bytes>) -> Result<http::Response<Bytes>> {
+        let (parts, body) = req.into_parts();
+        let path = parts.uri.path().trim_start_matches('/');
+        let mut url = self.inner.base_url.join(path)?;
+        if let Some(host) = &self.host {
+            url.set_host(Some(host))?
+        };
+        let req = self.post(url).headers(parts.headers).body(body).build()?;
+
+        let next = self.next();
+        read_response(next.run(req).await?).await
+    }
+
+    // The chain of the client's middleware, ending with its transport.
+    fn next(&self) -> Next<'_> {
+        Next::new(&self.http_client, &self.inner.middlewares)
+            .with_transport(self.inner.transport.as_deref())
+    }
+
+    /// A [`tower::Service`] that calls the method at `path` (as in [`request`](Self::request)),
+    /// so that tower middleware (e.g. `Timeout`, `Retry` or `ConcurrencyLimit`) can wrap the calls
+    /// to one method.
+    pub fn method_service<I, O>(&self, path: &str) -> MethodService<I, O> {
+        MethodService {
+            client: self.clone(),
+            path: Arc::from(path),
+            _messages: PhantomData,
+        }
+    }
+}
+
+/// Clients are a [`tower::Service`] of undecoded requests: see [`Client::send`]. Use
+/// [`Client::method_service`] for a service of the messages of a method.
+impl Service<http::Request<Bytes>> for Client {
+    type Response = http::Response<Bytes>;
+    type Error = ClientError;
+    type Future = BoxFuture<'static, Result<http::Response<Bytes>>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<Bytes>) -> Self::Future {
+        let client = self.clone();
+        Box::pin(async move { client.send(req).await })
+    }
+}
+
+/// A [`tower::Service`] that calls one method, created with [`Client::method_service`].
+pub struct MethodService<I, O> {
+    client: Client,
+    path: Arc<str>,
+    _messages: PhantomData<fn(I) -> O>,
+}
+
+impl<I, O> Clone for MethodService<I, O> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            path: self.path.clone(),
+            _messages: PhantomData,
+        }
+    }
+}
+
+impl<I, O> std::fmt::Debug for MethodService<I, O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MethodService")
+            .field("client", &self.client)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl<I, O> Service<I> for MethodService<I, O>
+where
+    I: prost::Message + Serialize + Send + 'static,
+    O: prost::Message + Default + DeserializeOwned + Send + 'static,
+{
+    type Response = O;
+    type Error = ClientError;
+    type Future = BoxFuture<'static, Result<O>>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: I) -> Self::Future {
+        let client = self.client.clone();
+        let path = self.path.clone();
+        Box::pin(async move { client.request_with_codec(&path, req).await })
+    }
+}
+
+// What the body of a response to a unary call holds, going by its status and content type.
+enum ResponseBody {
+    Message,
+    Error,
+}
+
+impl ResponseBody {
+    // A message in the format of `codec` (or `application/x-protobuf`, from Twirp v5 servers) or
+    // a Twirp error; any other response to the call to `path` is an `HttpError`.
+    fn classify(
+        status: StatusCode,
+        content_type: Option<&HeaderValue>,
+        codec: &dyn Codec,
+        compatibility: Compatibility,
+        path: &str,
+    ) -> Result<Self> {
+        let content_type = content_type.map(HeaderValue::as_bytes);
+        let is_codec = |ct: &[u8]| {
+            codec.matches(ct)
+                || (compatibility == Compatibility::V5
+                    && codec.format() == Format::Protobuf
+                    && ct == CONTENT_TYPE_X_PROTOBUF)
+        };
+        if status.is_success() && content_type.is_some_and(is_codec) {
+            return Ok(Self::Message);
+        }
+        if (status.is_client_error() || status.is_server_error())
+            && content_type == Some(CONTENT_TYPE_JSON)
+        {
+            return Ok(Self::Error);
+        }
+        Err(ClientError::HttpError {
+            status,
+            msg: "unknown error".to_string(),
+            path: path.to_string(),
+            content_type: content_type
+                .and_then(|ct| std::str::from_utf8(ct).ok())
+                .unwrap_or_default()
+                .to_string(),
+        })
+    }
+}
+
+// Read the Twirp error in an error response. A `Retry-After` header is kept as the error's
+// `retry_after` meta value, unless the server sent one.
+async fn read_twirp_error(resp: reqwest::Response) -> Result<TwirpErrorResponse> {
+    let retry_after = resp.headers().get(RETRY_AFTER).cloned();
+    parse_twirp_error(retry_after.as_ref(), resp.bytes().await?)
+}
+
+// The error in the JSON `body` of an error response with the `Retry-After` header `retry_after`.
+fn parse_twirp_error(retry_after: Option<&HeaderValue>, body: Bytes) -> Result<TwirpErrorResponse> {
+    let retry_after = retry_after
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after);
+    let mut err: TwirpErrorResponse = crate::deserialize_json(body).map_err(codec_error)?;
+    if let Some(retry_after) = retry_after {
+        if err.retry_after().is_none() {
+            err = err.with_retry_after(retry_after);
+        }
+    }
+    Ok(err)
+}
+
+// `Retry-After` is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+// The time allowed for a request: the one set with `with_timeout`, or the one propagated from a
+// context in `headers` if it is shorter.
+fn effective_timeout(timeout: Option<Duration>, headers: &HeaderMap) -> Option<Duration> {
+    let propagated = headers
+        .get(TWIRP_TIMEOUT)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .map(Duration::from_millis);
+    match (timeout, propagated) {
+        (Some(timeout), Some(propagated)) => Some(timeout.min(propagated)),
+        (timeout, propagated) => timeout.or(propagated),
+    }
+}
+
+// Read the whole body of `resp`.
+async fn read_response(mut resp: reqwest::Response) -> Result<http::Response<Bytes>> {
+    let headers = std::mem::take(resp.headers_mut());
+    let (status, version) = (resp.status(), resp.version());
+    let mut response = http::Response::new(resp.bytes().await?);
+    *response.status_mut() = status;
+    *response.version_mut() = version;
+    *response.headers_mut() = headers;
+    Ok(response)
+}
+
+/// What sends the requests of a [`Client`] once its middleware has run, e.g. a hyper client with
+/// a custom connector, or an in-memory transport in tests (see
+/// [`TestServer::transport`](crate::test::TestServer::transport)). Set it with
+/// [`ClientBuilder::transport`]; by default requests are sent with the client's
+/// `reqwest::Client`, which implements this trait too.
+///
+/// Responses are read whole, so server-streaming responses only start to be read once they end.
+/// The timeout of the client (see [`Client::with_timeout`]) is sent as the `Twirp-Timeout`
+/// header, but the transport has to enforce it.
+#[async_trait]
+pub trait Transport: Send + Sync + 'static {
+    async fn execute(&self, req: http::Request<Bytes>) -> Result<http::Response<Bytes>>;
+}
+
+#[async_trait]
+impl Transport for reqwest::Client {
+    async fn execute(&self, req: http::Request<Bytes>) -> Result<http::Response<Bytes>> {
+        let resp = reqwest::Client::execute(self, reqwest::Request::try_from(req)?).await?;
+        read_response(resp).await
     }
 }
 
@@ -231,6 +924,7 @@ where
 pub struct Next<'a> {
     client: &'a reqwest::Client,
     middlewares: &'a [Box<dyn Middleware>],
+    transport: Option<&'a dyn Transport>,
 }
 
 pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
@@ -240,13 +934,32 @@ impl<'a> Next<'a> {
         Next {
             client,
             middlewares,
+            transport: None,
         }
     }
 
+    pub(crate) fn with_transport(mut self, transport: Option<&'a dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
     pub fn run(mut self, req: reqwest::Request) -> BoxFuture<'a, Result<reqwest::Response>> {
         if let Some((current, rest)) = self.middlewares.split_first() {
             self.middlewares = rest;
             Box::pin(current.handle(req, self))
+        } else if let Some(transport) = self.transport {
+            Box::pin(async move {
+                let req = http::Request::<reqwest::Body>::try_from(req)?;
+                let (parts, body) = req.into_parts();
+                // The client only sends buffered bodies, but middleware could set any.
+                let body = body.as_bytes().map(Bytes::copy_from_slice).ok_or_else(|| {
+                    GenericError::from("transports only send buffered request bodies")
+                })?;
+                let resp = transport
+                    .execute(http::Request::from_parts(parts, body))
+                    .await?;
+                Ok(resp.into())
+            })
         } else {
             Box::pin(async move { self.client.execute(req).await.map_err(ClientError::from) })
         }
@@ -284,6 +997,20 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_v5_base_url() {
+        let build = |url| {
+            ClientBuilder::new(Url::parse(url).unwrap(), reqwest::Client::new())
+                .compatibility(Compatibility::V5)
+                .build()
+        };
+        assert!(build("http://localhost:3001/twirp/").is_ok());
+        assert_eq!(
+            build("http://localhost:3001/api/").unwrap_err().to_string(),
+            "base_url must end in /twirp/ for Twirp v5 servers, but got: http://localhost:3001/api/",
+        );
+    }
+
     #[tokio::test]
     async fn test_routes() {
         let base_url = Url::parse("http://localhost:3001/twirp/").unwrap();
@@ -303,17 +1030,435 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_standard_client() {
-        let h = run_test_server(3002).await;
-        let base_url = Url::parse("http://localhost:3002/twirp/").unwrap();
+    async fn test_transport_errors() {
+        let client =
+            Client::from_base_url(Url::parse("http://localhost:1/twirp/").unwrap()).unwrap();
+        let err = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert!(err.is_transport());
+        assert!(err.twirp_error().is_none());
+        let err = TwirpErrorResponse::from(err);
+        assert_eq!(err.code, crate::TwirpErrorCode::Unavailable);
+
+        let err = ClientError::from(crate::not_found("no hat"));
+        assert!(!err.is_transport());
+        assert_eq!(TwirpErrorResponse::from(err), crate::not_found("no hat"));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let retry_after = parse_retry_after(&date).unwrap();
+        assert!(retry_after > Duration::from_secs(50) && retry_after <= Duration::from_secs(60));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_header() {
+        let router = axum::Router::new().fallback(|| async {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(CONTENT_TYPE, "application/json"), (RETRY_AFTER, "30")],
+                r#"{"code": "unavailable", "msg": "busy"}"#,
+            )
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
         let client = Client::from_base_url(base_url).unwrap();
+        let err = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap_err();
+        let err = err.twirp_error().unwrap();
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(30)));
+        h.abort()
+    }
+
+    #[tokio::test]
+    async fn test_json_codec() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(listener, test_api_router()).await });
+
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .codec(crate::codec::JsonCodec)
+            .with(AssertJson)
+            .build()
+            .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "json".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "json");
+        h.abort()
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_zstd() {
+        use crate::compression::Zstd;
+
+        let router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .zstd(Zstd::new().min_size(0))
+                .build(),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .zstd(Zstd::new().min_size(0).level(9))
+            .with(AssertZstd)
+            .build()
+            .unwrap();
+        let name = "hat ".repeat(100);
+        let resp = client
+            .ping(PingRequest { name: name.clone() })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, name);
+
+        // Without the `zstd` option, neither side compresses.
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = Client::from_base_url(base_url).unwrap();
+        let resp = client
+            .ping(PingRequest { name: name.clone() })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, name);
+        h.abort()
+    }
+
+    #[cfg(feature = "zstd")]
+    struct AssertZstd;
+
+    #[cfg(feature = "zstd")]
+    #[async_trait]
+    impl Middleware for AssertZstd {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            use reqwest::header::CONTENT_ENCODING;
+
+            assert_eq!(req.headers()[CONTENT_ENCODING], "zstd");
+            let resp = next.run(req).await?;
+            assert_eq!(resp.headers()[CONTENT_ENCODING], "zstd");
+            Ok(resp)
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_gzip() {
+        use crate::compression::Gzip;
+
+        let router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .gzip(Gzip::new().min_size(0))
+                .build(),
+        );
+        let server = spawn_server(router).await;
+        let client = ClientBuilder::new(server.url("/twirp/"), reqwest::Client::new())
+            .gzip(Gzip::new().min_size(0).level(9))
+            .with(AssertGzip)
+            .build()
+            .unwrap();
+        let name = "hat ".repeat(100);
+        let resp = client
+            .ping(PingRequest { name: name.clone() })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, name);
+        server.shutdown().await.unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    struct AssertGzip;
+
+    #[cfg(feature = "gzip")]
+    #[async_trait]
+    impl Middleware for AssertGzip {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+
+            assert_eq!(req.headers()[CONTENT_ENCODING], "gzip");
+            assert_eq!(req.headers()[ACCEPT_ENCODING], "gzip");
+            let resp = next.run(req).await?;
+            assert_eq!(resp.headers()[CONTENT_ENCODING], "gzip");
+            Ok(resp)
+        }
+    }
+
+    #[cfg(feature = "checksum")]
+    #[tokio::test]
+    async fn test_checksums() {
+        use crate::checksum::Checksums;
+
+        let router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .checksums(Checksums::new().require(true))
+                .build(),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = ClientBuilder::new(base_url.clone(), reqwest::Client::new())
+            .checksums(Checksums::new().require(true))
+            .build()
+            .unwrap();
         let resp = client
+            .ping(PingRequest {
+                name: "checked".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "checked");
+
+        // The server requires checksums.
+        let client = Client::from_base_url(base_url.clone()).unwrap();
+        let err = client.ping(PingRequest::default()).await.unwrap_err();
+        assert_eq!(
+            err.twirp_error().unwrap().code,
+            crate::TwirpErrorCode::Dataloss
+        );
+
+        // A response corrupted on the way is rejected.
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .checksums(Checksums::new())
+            .with(CorruptChecksum)
+            .build()
+            .unwrap();
+        let err = client.ping(PingRequest::default()).await.unwrap_err();
+        assert_eq!(
+            err.twirp_error().unwrap().code,
+            crate::TwirpErrorCode::Dataloss
+        );
+        h.abort()
+    }
+
+    #[cfg(feature = "checksum")]
+    struct CorruptChecksum;
+
+    #[cfg(feature = "checksum")]
+    #[async_trait]
+    impl Middleware for CorruptChecksum {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            let resp = next.run(req).await?;
+            let mut resp = http::Response::from(resp);
+            resp.headers_mut().insert(
+                crate::checksum::X_CONTENT_SHA256,
+                http::HeaderValue::from_static("00"),
+            );
+            Ok(resp.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_context() {
+        let router =
+            axum::Router::new().nest("/twirp/test.TestAPI", test_api_router_builder().build());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let h = tokio::spawn(async move { axum::serve(listener, router).await });
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "req-1".parse().unwrap());
+        headers.insert("traceparent", "00-abc-def-01".parse().unwrap());
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        let ctx = Context::default().with_headers(headers);
+
+        let base_url = Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .with(AssertPropagated)
+            .build()
+            .unwrap();
+        let resp = client
+            .with_context(&ctx)
             .ping(PingRequest {
                 name: "hi".to_string(),
             })
             .await
             .unwrap();
-        assert_eq!(&resp.name, "hi");
+        assert_eq!(resp.name, "hi");
         h.abort()
     }
+
+    #[tokio::test]
+    async fn test_transport() {
+        let server = TestServer::new(
+            axum::Router::new().nest("/twirp/test.TestAPI", test_api_router_builder().build()),
+        );
+        let client = ClientBuilder::new(TestServer::base_url(), reqwest::Client::new())
+            .with(AssertJson)
+            .codec(crate::codec::JsonCodec)
+            .transport(server.transport())
+            .build()
+            .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+
+        let err = client
+            .request_with_codec::<_, PingResponse>("test.TestAPI/Boom", PingRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.twirp_error().unwrap().msg, "boom!");
+
+        // Without serde, messages can't be sent as JSON.
+        let err = client
+            .request::<_, PingResponse>("test.TestAPI/Ping", PingRequest::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClientError::CodecError(_)), "{err:?}");
+    }
+
+    struct AssertPropagated;
+
+    #[async_trait]
+    impl Middleware for AssertPropagated {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            assert_eq!(req.headers()["x-request-id"], "req-1");
+            assert_eq!(req.headers()["traceparent"], "00-abc-def-01");
+            assert!(!req.headers().contains_key("authorization"));
+            next.run(req).await
+        }
+    }
+
+    struct AssertJson;
+
+    #[async_trait]
+    impl Middleware for AssertJson {
+        async fn handle(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            assert_eq!(req.headers()[CONTENT_TYPE], "application/json");
+            let resp = next.run(req).await?;
+            assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+            Ok(resp)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_standard_client() {
+        let server = spawn_server(test_api_router()).await;
+        let client = Client::from_base_url(server.url("/twirp/")).unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "hi".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(&resp.name, "hi");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tower_service() {
+        use tower::ServiceExt;
+
+        let server = spawn_server(test_api_router()).await;
+        let client = Client::from_base_url(server.url("/twirp/")).unwrap();
+
+        let req = http::Request::post("/test.TestAPI/Ping")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Bytes::from_static(br#"{"name": "raw"}"#))
+            .unwrap();
+        let resp = client.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp: PingResponse = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(resp.name, "raw");
+
+        let ping = client.method_service::<PingRequest, PingResponse>("test.TestAPI/Ping");
+        let req = PingRequest {
+            name: "typed".to_string(),
+        };
+        assert_eq!(ping.oneshot(req).await.unwrap().name, "typed");
+        let boom = client.method_service::<PingRequest, PingResponse>("test.TestAPI/Boom");
+        let err = boom.oneshot(PingRequest::default()).await.unwrap_err();
+        assert_eq!(
+            err.twirp_error().unwrap().code,
+            crate::TwirpErrorCode::Internal
+        );
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        // Answers with the time it has left, or sleeps for longer than that.
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |(), ctx: Context, req: PingRequest| async move {
+                let remaining = ctx.time_remaining().expect("a deadline").as_millis();
+                if req.name == "sleep" {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+                Ok::<_, TwirpErrorResponse>(PingResponse {
+                    name: remaining.to_string(),
+                })
+            })
+            .build();
+        let server = TestServer::new(axum::Router::new().nest("/twirp/test.TestAPI", router));
+        let ping = |client: Client, name: &str| {
+            let req = PingRequest {
+                name: name.to_string(),
+            };
+            async move {
+                client
+                    .request::<_, PingResponse>("test.TestAPI/Ping", req)
+                    .await
+            }
+        };
+
+        let client = server.client().with_timeout(Duration::from_secs(5));
+        let remaining: u64 = ping(client.clone(), "")
+            .await
+            .unwrap()
+            .name
+            .parse()
+            .unwrap();
+        assert!((4000..=5000).contains(&remaining), "{remaining}");
+
+        // A shorter deadline propagated from the handler's context wins.
+        let mut extensions = http::Extensions::new();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        extensions.insert(crate::context::Deadline(deadline));
+        let ctx = Context::new(extensions, Default::default());
+        let remaining: u64 = ping(client.with_context(&ctx), "")
+            .await
+            .unwrap()
+            .name
+            .parse()
+            .unwrap();
+        assert!(remaining <= 1000, "{remaining}");
+
+        let client = server.client().with_timeout(Duration::from_millis(50));
+        let err = ping(client, "sleep").await.unwrap_err();
+        assert_eq!(
+            err.twirp_error().unwrap().code,
+            crate::TwirpErrorCode::DeadlineExceeded
+        );
+    }
 }
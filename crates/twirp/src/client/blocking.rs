@@ -0,0 +1,243 @@
+//! A synchronous Twirp client, for programs that don't run an async runtime, like CLI tools and
+//! build scripts.
+//!
+//! [`Client`] mirrors the request API of the async [`crate::Client`], on top of
+//! `reqwest::blocking`:
+//!
+//! ```
+//! use twirp::client::blocking::Client;
+//!
+//! # fn make_hat<Size, Hat>(size: Size) -> twirp::Result<Hat>
+//! # where
+//! #     Size: prost::Message + serde::Serialize,
+//! #     Hat: prost::Message + Default + serde::de::DeserializeOwned,
+//! # {
+//! let base_url = twirp::url::Url::parse("http://localhost:3000/twirp/").unwrap();
+//! let client = Client::from_base_url(base_url)?;
+//! let hat: Hat = client.request("example.v1.Haberdasher/MakeHat", size)?;
+//! # Ok(hat) }
+//! ```
+//!
+//! Like `reqwest::blocking`, it must not be used from within an async runtime. Middleware,
+//! transports, direct handlers, compression and checksums are only supported by the async client.
+//!
+//! Requires the `blocking` feature.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use url::Url;
+
+use super::{codec_error, effective_timeout, parse_twirp_error, ClientError, ResponseBody, Result};
+use crate::codec::{self, Codec, MessageType, ProtobufCodec};
+use crate::headers::TWIRP_TIMEOUT;
+use crate::{Compatibility, Context};
+
+/// A Twirp HTTP client that blocks the current thread until responses arrive. See the
+/// [module documentation](self).
+///
+/// Like the async client, it is cheap to clone and to reconfigure with the `with_` methods.
+#[derive(Clone, Debug)]
+pub struct Client {
+    http_client: reqwest::blocking::Client,
+    base_url: Url,
+    codec: Arc<dyn Codec>,
+    host: Option<String>,
+    headers: HeaderMap,
+    timeout: Option<Duration>,
+}
+
+impl Client {
+    /// Creates a client for the services under `base_url`, which must end in `/`.
+    ///
+    /// The underlying `reqwest::blocking::Client` holds a connection pool internally, so it is
+    /// advised that you create one and **reuse** it.
+    pub fn new(base_url: Url, http_client: reqwest::blocking::Client) -> Result<Self> {
+        if !base_url.path().ends_with('/') {
+            return Err(ClientError::InvalidBaseUrl(base_url));
+        }
+        Ok(Self {
+            http_client,
+            base_url,
+            codec: Arc::new(ProtobufCodec),
+            host: None,
+            headers: HeaderMap::new(),
+            timeout: None,
+        })
+    }
+
+    /// Creates a client with the default `reqwest::blocking::Client`.
+    pub fn from_base_url(base_url: Url) -> Result<Self> {
+        Self::new(base_url, reqwest::blocking::Client::new())
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    /// Creates a new client with the same configuration as the current one, but that encodes
+    /// requests (and decodes responses) with `codec` instead of protobuf. See [`crate::codec`].
+    pub fn with_codec<C: Codec>(&self, codec: C) -> Self {
+        Self {
+            codec: Arc::new(codec),
+            ..self.clone()
+        }
+    }
+
+    /// Creates a new client with the same configuration as the current one, but with a different
+    /// host in the base URL.
+    pub fn with_host(&self, host: &str) -> Self {
+        Self {
+            host: Some(host.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// Creates a new client with the same configuration as the current one, that also sends
+    /// `headers` with every request.
+    pub fn with_headers(&self, headers: HeaderMap) -> Self {
+        let mut client = self.clone();
+        client.headers.extend(headers);
+        client
+    }
+
+    /// Creates a new client for the calls made while handling the request of `ctx`, like
+    /// [`crate::Client::with_context`].
+    pub fn with_context(&self, ctx: &Context) -> Self {
+        self.with_headers(ctx.propagation_headers())
+    }
+
+    /// Creates a new client whose requests must be answered within `timeout`, like
+    /// [`crate::Client::with_timeout`].
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self.clone()
+        }
+    }
+
+    /// Make an HTTP twirp request to `path`, relative to the base URL (e.g.
+    /// `example.v1.Haberdasher/MakeHat`), and wait for the response.
+    pub fn request<I, O>(&self, path: &str, body: I) -> Result<O>
+    where
+        I: prost::Message + Serialize,
+        O: prost::Message + Default + DeserializeOwned,
+    {
+        let url = self.url(path)?;
+        let path = url.path().to_string();
+        let codec = self.codec.as_ref();
+        let body = codec::encode_message(codec, MessageType::from_path(&path, false), body)
+            .map_err(ClientError::CodecError)?;
+        let resp = self
+            .post(url)
+            .header(CONTENT_TYPE, codec.content_type())
+            .body(body)
+            .send()?;
+
+        let content_type = resp.headers().get(CONTENT_TYPE);
+        let compatibility = Compatibility::default();
+        match ResponseBody::classify(resp.status(), content_type, codec, compatibility, &path)? {
+            ResponseBody::Message => {
+                let ty = MessageType::from_path(&path, true);
+                codec::decode_message(codec, ty, resp.bytes()?).map_err(codec_error)
+            }
+            ResponseBody::Error => {
+                let retry_after = resp.headers().get(RETRY_AFTER).cloned();
+                let err = parse_twirp_error(retry_after.as_ref(), resp.bytes()?)?;
+                Err(ClientError::TwirpError(err))
+            }
+        }
+    }
+
+    /// Send `req` to the path of its URI (relative to the base URL), with the client's headers,
+    /// and read the whole response, like [`crate::Client::send`].
+    pub fn send(&self, req: http::Request<Bytes>) -> Result<http::Response<Bytes>> {
+        let (parts, body) = req.into_parts();
+        let url = self.url(parts.uri.path().trim_start_matches('/'))?;
+        let mut resp = self.post(url).headers(parts.headers).body(body).send()?;
+        let headers = std::mem::take(resp.headers_mut());
+        let (status, version) = (resp.status(), resp.version());
+        let mut response = http::Response::new(resp.bytes()?);
+        *response.status_mut() = status;
+        *response.version_mut() = version;
+        *response.headers_mut() = headers;
+        Ok(response)
+    }
+
+    fn url(&self, path: &str) -> Result<Url> {
+        let mut url = self.base_url.join(path)?;
+        if let Some(host) = &self.host {
+            url.set_host(Some(host))?
+        };
+        Ok(url)
+    }
+
+    // Start a request to `url` with the client's headers and timeout.
+    fn post(&self, url: Url) -> reqwest::blocking::RequestBuilder {
+        let mut headers = self.headers.clone();
+        let req = self.http_client.post(url);
+        let Some(timeout) = effective_timeout(self.timeout, &self.headers) else {
+            return req.headers(headers);
+        };
+        let millis = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+        headers.insert(TWIRP_TIMEOUT, HeaderValue::from(millis));
+        req.headers(headers).timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::JsonCodec;
+    use crate::test::{test_api_router_builder, PingRequest, PingResponse};
+
+    // Serve the test API on a thread with its own runtime, since the client blocks this one.
+    fn serve() -> Url {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let router =
+                axum::Router::new().nest("/twirp/test.TestAPI", test_api_router_builder().build());
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                axum::serve(listener, router).await
+            })
+        });
+        Url::parse(&format!("http://{addr}/twirp/")).unwrap()
+    }
+
+    #[test]
+    fn test_blocking_client() {
+        let client = Client::from_base_url(serve()).unwrap();
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp: PingResponse = client.request("test.TestAPI/Ping", req.clone()).unwrap();
+        assert_eq!(resp.name, "hi");
+        let resp: PingResponse = client
+            .with_codec(JsonCodec)
+            .request("test.TestAPI/Ping", req.clone())
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+
+        let err = client
+            .request::<_, PingResponse>("test.TestAPI/Boom", req)
+            .unwrap_err();
+        assert_eq!(err.twirp_error().unwrap().msg, "boom!");
+
+        let base_url = Url::parse("http://localhost/twirp").unwrap();
+        assert!(matches!(
+            Client::from_base_url(base_url),
+            Err(ClientError::InvalidBaseUrl(_))
+        ));
+    }
+}
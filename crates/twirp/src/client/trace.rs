@@ -0,0 +1,242 @@
+//! `tracing` spans for the calls a client makes, with the W3C trace context propagated to the
+//! server.
+//!
+//! The [`TracingClient`] middleware runs each call in a `twirp.client` span, with the RPC path
+//! (e.g. `example.v1.Haberdasher/MakeHat`) as `otel.name`, the `rpc.system`, `rpc.service` and
+//! `rpc.method` fields, and the `http.response.status_code` of the response. It sets the
+//! `traceparent` header of the request to a new span of the trace the call is part of: the one in
+//! the request's `traceparent` header, e.g. as propagated with
+//! [`Client::with_context`](crate::Client::with_context), or a new trace. The trace and span ids
+//! are recorded in the span as `trace_id` and `span_id`, so that the logs of both sides of a call
+//! can be correlated. `tracestate` is passed along as it is.
+//!
+//! ```
+//! use twirp::client::trace::TracingClient;
+//! use twirp::{Client, ClientBuilder};
+//!
+//! # fn build_client() -> twirp::Result<Client> {
+//! let base_url = twirp::url::Url::parse("http://localhost:3000/twirp/").unwrap();
+//! ClientBuilder::new(base_url, twirp::reqwest::Client::new())
+//!     .with(TracingClient)
+//!     .build()
+//! # }
+//! ```
+//!
+//! Add it before the middleware that should run in the span. With an OpenTelemetry pipeline,
+//! prefer [`OtelClient`](crate::otel::OtelClient), which propagates the context of the current
+//! OpenTelemetry span instead.
+//!
+//! Requires the `tracing` feature.
+
+use std::fmt;
+
+use async_trait::async_trait;
+use reqwest::header::HeaderValue;
+use tracing::Instrument;
+
+use crate::context::split_route;
+use crate::headers::{TRACEPARENT, TRACESTATE};
+use crate::{Middleware, Next, Result};
+
+/// Client middleware that runs calls in a `tracing` span and propagates their trace context. See
+/// the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingClient;
+
+#[async_trait]
+impl Middleware for TracingClient {
+    async fn handle(&self, mut req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        let parent = req
+            .headers()
+            .get(TRACEPARENT)
+            .and_then(|value| TraceParent::parse(value.to_str().ok()?));
+        let traceparent = match parent {
+            Some(parent) => parent.child(),
+            None => {
+                // A malformed `traceparent` starts a new trace, without the old state.
+                req.headers_mut().remove(TRACESTATE);
+                TraceParent::root()
+            }
+        };
+        let value = HeaderValue::try_from(traceparent.to_string())
+            .expect("traceparents are valid header values");
+        req.headers_mut().insert(TRACEPARENT, value);
+
+        let (service, method) = split_route(req.url().path()).unwrap_or_default();
+        let span = tracing::info_span!(
+            "twirp.client",
+            otel.name = format_args!("{service}/{method}"),
+            otel.kind = "client",
+            rpc.system = "twirp",
+            rpc.service = service,
+            rpc.method = method,
+            trace_id = format_args!("{:032x}", traceparent.trace_id),
+            span_id = format_args!("{:016x}", traceparent.span_id),
+            http.response.status_code = tracing::field::Empty,
+        );
+
+        let res = next.run(req).instrument(span.clone()).await;
+        match &res {
+            Ok(resp) => {
+                span.record("http.response.status_code", resp.status().as_u16());
+            }
+            Err(err) => {
+                tracing::debug!(parent: &span, error = %err, "twirp call failed");
+            }
+        }
+        res
+    }
+}
+
+/// A version 00 W3C `traceparent`: `00-<trace id>-<span id>-<flags>`, in lowercase hex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct TraceParent {
+    trace_id: u128,
+    span_id: u64,
+    flags: u8,
+}
+
+impl TraceParent {
+    /// A new, sampled trace.
+    fn root() -> Self {
+        Self {
+            trace_id: uuid::Uuid::new_v4().as_u128(),
+            span_id: new_span_id(),
+            flags: 0x01,
+        }
+    }
+
+    /// A new span in the same trace.
+    fn child(self) -> Self {
+        Self {
+            span_id: new_span_id(),
+            ..self
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        fn hex<const LEN: usize>(field: &str) -> Option<&str> {
+            let valid = field.len() == LEN
+                && field
+                    .bytes()
+                    .all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+            valid.then_some(field)
+        }
+
+        let mut fields = value.trim().split('-');
+        let version = hex::<2>(fields.next()?)?;
+        let trace_id = u128::from_str_radix(hex::<32>(fields.next()?)?, 16).ok()?;
+        let span_id = u64::from_str_radix(hex::<16>(fields.next()?)?, 16).ok()?;
+        let flags = u8::from_str_radix(hex::<2>(fields.next()?)?, 16).ok()?;
+        // Later versions may add fields, version 00 has none.
+        let valid = version != "ff"
+            && (version != "00" || fields.next().is_none())
+            && trace_id != 0
+            && span_id != 0;
+        valid.then_some(Self {
+            trace_id,
+            span_id,
+            flags,
+        })
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.flags
+        )
+    }
+}
+
+// 64 random bits. The fixed version and variant bits of a v4 UUID are in different halves, so
+// XORing the halves makes up for them. Zero is not a valid span id.
+fn new_span_id() -> u64 {
+    loop {
+        let (high, low) = uuid::Uuid::new_v4().as_u64_pair();
+        if high ^ low != 0 {
+            return high ^ low;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::{PingRequest, PingResponse, TestServer};
+    use crate::{ClientBuilder, Context, TwirpErrorResponse};
+
+    #[test]
+    fn test_traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let parent = TraceParent::parse(value).unwrap();
+        assert_eq!(parent.to_string(), value);
+        let child = parent.child();
+        assert_eq!(child.trace_id, parent.trace_id);
+        assert_ne!(child.span_id, parent.span_id);
+        assert_eq!(child.flags, 0x01);
+
+        for invalid in [
+            "",
+            "00-abc-def-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceParent::parse(invalid), None, "{invalid}");
+        }
+        // Future versions may have more fields.
+        let future = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra";
+        assert!(TraceParent::parse(future).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tracing_client() {
+        // Echoes the trace context the server received.
+        let router = TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                let header = |name| ctx.header(name).map(|v| v.to_str().unwrap().to_string());
+                let name = format!(
+                    "{} {}",
+                    header(TRACEPARENT).unwrap_or_default(),
+                    header(TRACESTATE).unwrap_or_default()
+                );
+                Ok::<_, TwirpErrorResponse>(PingResponse { name })
+            })
+            .build();
+        let server = TestServer::new(axum::Router::new().nest("/twirp/test.TestAPI", router));
+        let client = ClientBuilder::new(TestServer::base_url(), reqwest::Client::new())
+            .with(TracingClient)
+            .with(server.transport())
+            .build()
+            .unwrap();
+
+        let resp: PingResponse = client
+            .request("test.TestAPI/Ping", PingRequest::default())
+            .await
+            .unwrap();
+        let (traceparent, tracestate) = resp.name.split_once(' ').unwrap();
+        assert!(TraceParent::parse(traceparent).is_some(), "{traceparent}");
+        assert_eq!(tracestate, "");
+
+        let mut headers = http::HeaderMap::new();
+        let parent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        headers.insert(TRACEPARENT, HeaderValue::from_static(parent));
+        headers.insert(TRACESTATE, HeaderValue::from_static("vendor=1"));
+        let ctx = Context::default().with_headers(headers);
+        let resp: PingResponse = client
+            .with_context(&ctx)
+            .request("test.TestAPI/Ping", PingRequest::default())
+            .await
+            .unwrap();
+        let (traceparent, tracestate) = resp.name.split_once(' ').unwrap();
+        let child = TraceParent::parse(traceparent).unwrap();
+        assert_eq!(child.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_ne!(child.span_id, 0x00f067aa0ba902b7);
+        assert_eq!(tracestate, "vendor=1");
+    }
+}
@@ -0,0 +1,307 @@
+//! Pluggable wire formats for request and response messages.
+//!
+//! A [`Codec`] translates between the bytes on the wire and one of the two formats messages can
+//! be (de)serialized to, protobuf or JSON. The built-in [`ProtobufCodec`] and [`JsonCodec`] are
+//! what Twirp uses by default; other codecs can be added to a router with
+//! [`TwirpRouterBuilder::codec`](crate::details::TwirpRouterBuilder::codec) and used by clients
+//! with [`ClientBuilder::codec`](crate::ClientBuilder::codec). For example, a codec that adds
+//! schema-registry framing works on protobuf:
+//!
+//! ```
+//! use bytes::Bytes;
+//! use twirp::codec::{Codec, Format};
+//! use twirp::GenericError;
+//!
+//! #[derive(Debug)]
+//! struct Framed {
+//!     schema_id: u8,
+//! }
+//!
+//! impl Codec for Framed {
+//!     fn content_type(&self) -> &str {
+//!         "application/vnd.example.framed+protobuf"
+//!     }
+//!
+//!     fn format(&self) -> Format {
+//!         Format::Protobuf
+//!     }
+//!
+//!     fn decode(&self, mut body: Bytes) -> Result<Bytes, GenericError> {
+//!         match body.first() {
+//!             // Slicing `Bytes` doesn't copy the message.
+//!             Some(id) if *id == self.schema_id => Ok(body.split_off(1)),
+//!             _ => Err("unknown schema".into()),
+//!         }
+//!     }
+//!
+//!     fn encode(&self, message: Bytes) -> Result<Bytes, GenericError> {
+//!         Ok([&[self.schema_id], &message[..]].concat().into())
+//!     }
+//! }
+//! ```
+//!
+//! Codecs for other serialization formats (e.g. CBOR) can work on JSON, converting to and from
+//! `serde_json::Value`. Codecs that need to know the type of the message, like
+//! [`ProtoJsonCodec`](crate::protojson::ProtoJsonCodec), implement
+//! [`decode_as`](Codec::decode_as) and [`encode_as`](Codec::encode_as) instead. Server-streaming
+//! RPCs use the codec's format as it is, without calling `encode`.
+
+use std::fmt::Debug;
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::context::split_route;
+use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF};
+use crate::{deserialize_json, serialize_json, serialize_proto_message, GenericError};
+
+/// The format that a [`Codec`] converts messages to and from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Format {
+    /// The protobuf binary encoding.
+    Protobuf,
+    /// The JSON encoding.
+    Json,
+}
+
+/// Which message a codec converts: the request or the response of a method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MessageType<'a> {
+    /// The fully qualified name of the service, e.g. `example.v1.Haberdasher`.
+    pub service: &'a str,
+    /// The name of the method, e.g. `MakeHat`.
+    pub method: &'a str,
+    /// Whether the message is the method's response rather than its request.
+    pub response: bool,
+}
+
+impl<'a> MessageType<'a> {
+    /// The request message of `service`'s `method`.
+    pub const fn request(service: &'a str, method: &'a str) -> Self {
+        Self {
+            service,
+            method,
+            response: false,
+        }
+    }
+
+    /// The response message of `service`'s `method`.
+    pub const fn response(service: &'a str, method: &'a str) -> Self {
+        Self {
+            service,
+            method,
+            response: true,
+        }
+    }
+
+    /// The message of the method at the end of a URL path like
+    /// `/twirp/example.v1.Haberdasher/MakeHat`.
+    pub(crate) fn from_path(path: &'a str, response: bool) -> Self {
+        let (service, method) = split_route(path).unwrap_or(("", path));
+        Self {
+            service,
+            method,
+            response,
+        }
+    }
+}
+
+/// A wire format for messages. See the [module documentation](self).
+pub trait Codec: Debug + Send + Sync + 'static {
+    /// The content type of requests and responses in this format.
+    fn content_type(&self) -> &str;
+
+    /// Whether requests with the `Content-Type` header `content_type` use this codec.
+    fn matches(&self, content_type: &[u8]) -> bool {
+        content_type == self.content_type().as_bytes()
+    }
+
+    /// The format messages are converted to before [`encode`](Self::encode), and from after
+    /// [`decode`](Self::decode).
+    fn format(&self) -> Format;
+
+    /// Turn a body received on the wire into a message in [`format`](Self::format).
+    fn decode(&self, body: Bytes) -> Result<Bytes, GenericError> {
+        Ok(body)
+    }
+
+    /// Turn a message in [`format`](Self::format) into the body sent on the wire.
+    fn encode(&self, message: Bytes) -> Result<Bytes, GenericError> {
+        Ok(message)
+    }
+
+    /// Like [`decode`](Self::decode), for a body that holds the message `ty`. Routers and clients
+    /// call this; by default it calls `decode`.
+    fn decode_as(&self, ty: MessageType<'_>, body: Bytes) -> Result<Bytes, GenericError> {
+        let _ = ty;
+        self.decode(body)
+    }
+
+    /// Like [`encode`](Self::encode), for the message `ty`. Routers and clients call this; by
+    /// default it calls `encode`.
+    fn encode_as(&self, ty: MessageType<'_>, message: Bytes) -> Result<Bytes, GenericError> {
+        let _ = ty;
+        self.encode(message)
+    }
+}
+
+/// Messages encoded as protobuf, with the content type `application/protobuf`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProtobufCodec;
+
+impl Codec for ProtobufCodec {
+    fn content_type(&self) -> &str {
+        "application/protobuf"
+    }
+
+    fn matches(&self, content_type: &[u8]) -> bool {
+        content_type == CONTENT_TYPE_PROTOBUF
+    }
+
+    fn format(&self) -> Format {
+        Format::Protobuf
+    }
+}
+
+/// Messages encoded as JSON, with the content type `application/json`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn matches(&self, content_type: &[u8]) -> bool {
+        content_type == CONTENT_TYPE_JSON
+    }
+
+    fn format(&self) -> Format {
+        Format::Json
+    }
+}
+
+/// Serialize `message`, which only supports protobuf, for the wire with `codec`.
+pub(crate) fn encode_proto<M>(
+    codec: &dyn Codec,
+    ty: MessageType<'_>,
+    message: M,
+) -> Result<Bytes, GenericError>
+where
+    M: prost::Message,
+{
+    if codec.format() != Format::Protobuf {
+        let msg = format!(
+            "messages without serde support need a protobuf codec, not {}",
+            codec.content_type()
+        );
+        return Err(msg.into());
+    }
+    codec.encode_as(ty, serialize_proto_message(message))
+}
+
+/// Deserialize a message that only supports protobuf, received on the wire with `codec`.
+pub(crate) fn decode_proto<M>(
+    codec: &dyn Codec,
+    ty: MessageType<'_>,
+    body: Bytes,
+) -> Result<M, GenericError>
+where
+    M: prost::Message + Default,
+{
+    Ok(M::decode(codec.decode_as(ty, body)?)?)
+}
+
+/// Serialize `message` for the wire with `codec`.
+pub(crate) fn encode_message<M>(
+    codec: &dyn Codec,
+    ty: MessageType<'_>,
+    message: M,
+) -> Result<Bytes, GenericError>
+where
+    M: prost::Message + Serialize,
+{
+    let data = match codec.format() {
+        Format::Protobuf => serialize_proto_message(message),
+        Format::Json => serialize_json(&message)?,
+    };
+    codec.encode_as(ty, data)
+}
+
+/// Deserialize a message received on the wire with `codec`.
+pub(crate) fn decode_message<M>(
+    codec: &dyn Codec,
+    ty: MessageType<'_>,
+    body: Bytes,
+) -> Result<M, GenericError>
+where
+    M: prost::Message + Default + DeserializeOwned,
+{
+    let data = codec.decode_as(ty, body)?;
+    let message = match codec.format() {
+        // Decoding from `Bytes` lets `bytes` fields share the buffer instead of copying.
+        Format::Protobuf => M::decode(data)?,
+        Format::Json => deserialize_json(data)?,
+    };
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::PingRequest;
+
+    const PING: MessageType<'static> = MessageType::request("test.TestAPI", "Ping");
+
+    #[derive(Debug)]
+    struct Reversed;
+
+    impl Codec for Reversed {
+        fn content_type(&self) -> &str {
+            "application/x-reversed-json"
+        }
+
+        fn format(&self) -> Format {
+            Format::Json
+        }
+
+        fn decode(&self, body: Bytes) -> Result<Bytes, GenericError> {
+            Ok(body.iter().rev().copied().collect())
+        }
+
+        fn encode(&self, message: Bytes) -> Result<Bytes, GenericError> {
+            Ok(message.iter().rev().copied().collect())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let message = PingRequest {
+            name: "hi".to_string(),
+        };
+        for codec in [&ProtobufCodec as &dyn Codec, &JsonCodec, &Reversed] {
+            let bytes = encode_message(codec, PING, message.clone()).unwrap();
+            let decoded: PingRequest = decode_message(codec, PING, bytes).unwrap();
+            assert_eq!(decoded, message);
+        }
+        assert_eq!(
+            encode_message(&Reversed, PING, message).unwrap(),
+            &br#"}"ih":"eman"{"#[..]
+        );
+    }
+
+    #[test]
+    fn test_message_type_from_path() {
+        assert_eq!(
+            MessageType::from_path("/twirp/test.TestAPI/Ping", true),
+            MessageType::response("test.TestAPI", "Ping")
+        );
+        assert_eq!(
+            MessageType::from_path("Ping", false),
+            MessageType::request("", "Ping")
+        );
+    }
+}
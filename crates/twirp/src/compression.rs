@@ -0,0 +1,383 @@
+//! zstd and gzip compression of request and response bodies (`Content-Encoding: zstd` or
+//! `gzip`).
+//!
+//! Enable zstd on a router with
+//! [`TwirpRouterBuilder::zstd`](crate::details::TwirpRouterBuilder::zstd) and on a client with
+//! [`ClientBuilder::zstd`](crate::ClientBuilder::zstd). Clients compress requests and ask for
+//! compressed responses with `Accept-Encoding: zstd`; servers decompress such requests and
+//! compress their responses for clients that accept it. Each side compresses at its own
+//! [level](Zstd::level). Bodies smaller than [`Zstd::min_size`] are sent as they are.
+//!
+//! Both sides can share a [dictionary](Zstd::dictionary), which gives much better ratios for small
+//! messages; a peer without it can't read the compressed bodies.
+//!
+//! [`Gzip`] works the same way, for peers such as browsers and other Twirp implementations that
+//! don't speak zstd; enable it on a router with
+//! [`TwirpRouterBuilder::gzip`](crate::details::TwirpRouterBuilder::gzip). A router with both
+//! answers with zstd when the client accepts it. Compressed bodies, errors included, are
+//! decompressed with a limit on their size, so a small body can't expand to fill the server's
+//! memory.
+//!
+//! Requires the `zstd` or the `gzip` feature.
+
+#[cfg(feature = "gzip")]
+use std::io::Write;
+use std::io::{self, Read};
+#[cfg(feature = "zstd")]
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
+use http::HeaderMap;
+
+use crate::server::budget::TooLarge;
+use crate::GenericError;
+#[cfg(feature = "zstd")]
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/// The `Content-Encoding` of zstd-compressed bodies.
+#[cfg(feature = "zstd")]
+pub(crate) const ZSTD: &str = "zstd";
+
+/// The `Content-Encoding` of gzip-compressed bodies.
+#[cfg(feature = "gzip")]
+pub(crate) const GZIP: &str = "gzip";
+
+const DEFAULT_MIN_SIZE: usize = 1024;
+const DEFAULT_MAX_DECOMPRESSED_LEN: usize = 64 * 1024 * 1024;
+
+/// zstd compression settings. See the [module documentation](self).
+///
+/// Requires the `zstd` feature.
+#[cfg(feature = "zstd")]
+#[derive(Clone)]
+pub struct Zstd {
+    level: i32,
+    min_size: usize,
+    max_decompressed_len: usize,
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+#[cfg(feature = "zstd")]
+struct Dictionary {
+    raw: Vec<u8>,
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+#[cfg(feature = "zstd")]
+impl Dictionary {
+    fn new(raw: Vec<u8>, level: i32) -> Self {
+        Self {
+            encoder: EncoderDictionary::copy(&raw, level),
+            decoder: DecoderDictionary::copy(&raw),
+            raw,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl std::fmt::Debug for Zstd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Zstd")
+            .field("level", &self.level)
+            .field("min_size", &self.min_size)
+            .field("max_decompressed_len", &self.max_decompressed_len)
+            .field("dictionary", &self.dictionary.as_ref().map(|d| d.raw.len()))
+            .finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Self {
+        Self {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            min_size: DEFAULT_MIN_SIZE,
+            max_decompressed_len: DEFAULT_MAX_DECOMPRESSED_LEN,
+            dictionary: None,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Zstd {
+    /// Compress at the default level (3), bodies of 1 KiB and more.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The compression level, from 1 (fastest) to 22 (smallest).
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        if let Some(dictionary) = self.dictionary.take() {
+            self.dictionary = Some(Arc::new(Dictionary::new(dictionary.raw.clone(), level)));
+        }
+        self
+    }
+
+    /// Only compress bodies of at least `min_size` bytes. Defaults to 1 KiB.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Reject compressed bodies that decompress to more than `len` bytes. Defaults to 64 MiB.
+    pub fn max_decompressed_len(mut self, len: usize) -> Self {
+        self.max_decompressed_len = len;
+        self
+    }
+
+    /// Compress with `dictionary` (e.g. trained with `zstd --train` on sample messages). The
+    /// peer must use the same dictionary.
+    pub fn dictionary(mut self, dictionary: impl Into<Vec<u8>>) -> Self {
+        self.dictionary = Some(Arc::new(Dictionary::new(dictionary.into(), self.level)));
+        self
+    }
+
+    /// Compress `data` if it is large enough, returning `None` otherwise.
+    pub(crate) fn compress(&self, data: &[u8]) -> io::Result<Option<Bytes>> {
+        if data.len() < self.min_size {
+            return Ok(None);
+        }
+        let mut compressor = match &self.dictionary {
+            Some(dictionary) => {
+                zstd::bulk::Compressor::with_prepared_dictionary(&dictionary.encoder)?
+            }
+            None => zstd::bulk::Compressor::new(self.level)?,
+        };
+        Ok(Some(compressor.compress(data)?.into()))
+    }
+
+    pub(crate) fn decompress(&self, data: &[u8]) -> io::Result<Bytes> {
+        self.decompress_at_most(data, self.max_decompressed_len)
+    }
+
+    pub(crate) fn decompress_request(
+        &self,
+        data: &[u8],
+        max_request_size: Option<usize>,
+    ) -> Result<Bytes, GenericError> {
+        decompress_request(
+            |max_len| self.decompress_at_most(data, max_len),
+            self.max_decompressed_len,
+            max_request_size,
+        )
+    }
+
+    fn decompress_at_most(&self, data: &[u8], max_len: usize) -> io::Result<Bytes> {
+        let decoder = match &self.dictionary {
+            Some(dictionary) => {
+                zstd::stream::read::Decoder::with_prepared_dictionary(data, &dictionary.decoder)?
+            }
+            None => zstd::stream::read::Decoder::with_buffer(data)?,
+        };
+        read_limited(decoder, max_len)
+    }
+}
+
+/// gzip compression settings. See the [module documentation](self).
+///
+/// Requires the `gzip` feature.
+#[cfg(feature = "gzip")]
+#[derive(Clone, Debug)]
+pub struct Gzip {
+    level: u32,
+    min_size: usize,
+    max_decompressed_len: usize,
+}
+
+#[cfg(feature = "gzip")]
+impl Default for Gzip {
+    fn default() -> Self {
+        Self {
+            level: 6,
+            min_size: DEFAULT_MIN_SIZE,
+            max_decompressed_len: DEFAULT_MAX_DECOMPRESSED_LEN,
+        }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Gzip {
+    /// Compress at the default level (6), bodies of 1 KiB and more.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The compression level, from 1 (fastest) to 9 (smallest).
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Only compress bodies of at least `min_size` bytes. Defaults to 1 KiB.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Reject compressed bodies that decompress to more than `len` bytes. Defaults to 64 MiB.
+    pub fn max_decompressed_len(mut self, len: usize) -> Self {
+        self.max_decompressed_len = len;
+        self
+    }
+
+    /// Compress `data` if it is large enough, returning `None` otherwise.
+    pub(crate) fn compress(&self, data: &[u8]) -> io::Result<Option<Bytes>> {
+        if data.len() < self.min_size {
+            return Ok(None);
+        }
+        let level = flate2::Compression::new(self.level);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::with_capacity(data.len() / 4), level);
+        encoder.write_all(data)?;
+        Ok(Some(encoder.finish()?.into()))
+    }
+
+    pub(crate) fn decompress(&self, data: &[u8]) -> io::Result<Bytes> {
+        read_limited(
+            flate2::read::GzDecoder::new(data),
+            self.max_decompressed_len,
+        )
+    }
+
+    pub(crate) fn decompress_request(
+        &self,
+        data: &[u8],
+        max_request_size: Option<usize>,
+    ) -> Result<Bytes, GenericError> {
+        decompress_request(
+            |max_len| read_limited(flate2::read::GzDecoder::new(data), max_len),
+            self.max_decompressed_len,
+            max_request_size,
+        )
+    }
+}
+
+/// The error for a body that decompresses to more than the limit.
+#[derive(Debug, thiserror::Error)]
+#[error("body decompresses to more than {0} bytes")]
+struct TooLong(usize);
+
+// Read all of a decompressing `reader`, or fail if that is more than `max_len` bytes.
+fn read_limited(reader: impl Read, max_len: usize) -> io::Result<Bytes> {
+    // Read one byte past the limit to tell a body at the limit from one over it.
+    let mut decompressed = vec![];
+    reader
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, TooLong(max_len)));
+    }
+    Ok(decompressed.into())
+}
+
+// Decompress a request body with `decompress`, which fails past the length it is given. A router's
+// `max_request_size` applies to the decompressed body too, when it is the lower limit.
+#[cfg_attr(not(any(feature = "zstd", feature = "gzip")), allow(dead_code))]
+fn decompress_request(
+    decompress: impl FnOnce(usize) -> io::Result<Bytes>,
+    max_decompressed_len: usize,
+    max_request_size: Option<usize>,
+) -> Result<Bytes, GenericError> {
+    match max_request_size {
+        Some(limit) if limit < max_decompressed_len => decompress(limit).map_err(|err| {
+            if err.get_ref().is_some_and(|err| err.is::<TooLong>()) {
+                TooLarge(limit).into()
+            } else {
+                err.into()
+            }
+        }),
+        _ => Ok(decompress(max_decompressed_len)?),
+    }
+}
+
+/// Whether the body of a message with `headers` is compressed with `coding`.
+pub(crate) fn is_encoded(headers: &HeaderMap, coding: &str) -> bool {
+    headers
+        .get(CONTENT_ENCODING)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(coding.as_bytes()))
+}
+
+/// Whether a request with `headers` accepts responses compressed with `coding`.
+pub(crate) fn accepts(headers: &HeaderMap, coding: &str) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|accepted| {
+            let mut params = accepted.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            // A quality of 0 means "not acceptable".
+            let rejected = params
+                .filter_map(|p| p.split_once('='))
+                .filter(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                .any(|(_, q)| q.trim().parse::<f32>().is_ok_and(|q| q <= 0.0));
+            name.eq_ignore_ascii_case(coding) && !rejected
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_round_trip() {
+        let data = "hat ".repeat(1000);
+        for zstd in [
+            Zstd::new(),
+            Zstd::new().level(19),
+            Zstd::new().dictionary("hat hat hat").level(1),
+        ] {
+            let compressed = zstd.compress(data.as_bytes()).unwrap().unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(zstd.decompress(&compressed).unwrap(), data.as_bytes());
+        }
+        assert_eq!(Zstd::new().compress(b"small").unwrap(), None);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_decompression_limit() {
+        let zstd = Zstd::new().max_decompressed_len(100);
+        let compressed = zstd.compress(&[0; 4096]).unwrap().unwrap();
+        let err = zstd.decompress(&compressed).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(zstd.decompress(b"not zstd").is_err());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_gzip() {
+        let data = "hat ".repeat(1000);
+        let gzip = Gzip::new().max_decompressed_len(data.len());
+        let compressed = gzip.compress(data.as_bytes()).unwrap().unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(gzip.decompress(&compressed).unwrap(), data.as_bytes());
+        assert_eq!(Gzip::new().compress(b"small").unwrap(), None);
+
+        let err = gzip.max_decompressed_len(100).decompress(&compressed);
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(Gzip::new().decompress(b"not gzip").is_err());
+    }
+
+    #[test]
+    fn test_accepts() {
+        let accepts_zstd = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+            accepts(&headers, "zstd")
+        };
+        assert!(accepts_zstd("zstd"));
+        assert!(accepts_zstd("gzip, ZSTD;q=0.5"));
+        assert!(!accepts_zstd("gzip, br"));
+        assert!(!accepts_zstd("zstd;q=0"));
+        assert!(!accepts_zstd("zstd; Q = 0.0000"));
+        assert!(accepts_zstd("zstd;q=0.001"));
+        assert!(!accepts(&HeaderMap::new(), "zstd"));
+    }
+}
@@ -0,0 +1,393 @@
+//! The unary RPCs of the [Connect protocol](https://connectrpc.com/docs/protocol), next to Twirp.
+//!
+//! Connect and Twirp unary calls are close enough to be served by the same handlers: both POST a
+//! message to `<prefix>/<package>.<Service>/<Method>`, as protobuf or JSON, and return a message
+//! or a JSON error. They differ in the protobuf content type (`application/proto`), the
+//! timeout header (`Connect-Timeout-Ms`) and the shape and HTTP status of errors.
+//!
+//! On a server, [`ConnectLayer`] translates Connect requests to Twirp on the way in and the
+//! responses back on the way out, so browser `connect-web` clients and Connect gateways can call
+//! the same routes as Twirp clients. Twirp requests pass through untouched:
+//!
+//! ```
+//! use twirp::connect::ConnectLayer;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router) -> Router {
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .layer(ConnectLayer);
+//! # app }
+//! ```
+//!
+//! On a client, the [`ConnectClient`] middleware makes the client speak Connect, to call
+//! Connect services that don't serve Twirp:
+//!
+//! ```
+//! use twirp::connect::ConnectClient;
+//! use twirp::{ClientBuilder, Client};
+//!
+//! # fn build_client() -> twirp::Result<Client> {
+//! let base_url = twirp::url::Url::parse("http://localhost:8080/").unwrap();
+//! ClientBuilder::new(base_url, twirp::reqwest::Client::new())
+//!     .with(ConnectClient)
+//!     .build()
+//! # }
+//! ```
+//!
+//! Only unary POST requests are supported, not GET requests or streaming. Error details and Twirp
+//! error meta values are not carried over, as Connect has no equivalent to meta values other than
+//! protobuf error details. JSON messages are encoded with the messages' serde implementations,
+//! like Twirp JSON requests.
+//!
+//! Requires the `connect` feature.
+
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use axum::body::Body;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::header::CONTENT_TYPE;
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use http_body_util::BodyExt;
+use hyper::{Request, Response};
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+
+use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF, TWIRP_TIMEOUT};
+use crate::{ClientError, Middleware, Next, TwirpErrorCode, TwirpErrorResponse};
+
+/// The header that marks Connect requests, with the protocol version.
+pub const CONNECT_PROTOCOL_VERSION: HeaderName =
+    HeaderName::from_static("connect-protocol-version");
+/// The time the client allows for a request, in milliseconds, like `Twirp-Timeout`.
+pub const CONNECT_TIMEOUT_MS: HeaderName = HeaderName::from_static("connect-timeout-ms");
+/// The content type of protobuf messages in Connect.
+const CONTENT_TYPE_PROTO: &[u8] = b"application/proto";
+
+/// A Connect error, as sent in the body of error responses.
+#[derive(Debug, Serialize, Deserialize)]
+struct ConnectError {
+    code: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    message: String,
+}
+
+/// The Connect code for a Twirp error code. Custom codes are `unknown`.
+pub fn connect_code(code: &TwirpErrorCode) -> &'static str {
+    match code {
+        TwirpErrorCode::Malformed => "invalid_argument",
+        TwirpErrorCode::BadRoute => "unimplemented",
+        TwirpErrorCode::Dataloss => "data_loss",
+        code => code.spec_code().unwrap_or("unknown"),
+    }
+}
+
+/// The Twirp error code for a Connect code. Codes that aren't in the Connect spec are `unknown`.
+pub fn from_connect_code(code: &str) -> TwirpErrorCode {
+    match code {
+        "data_loss" => TwirpErrorCode::Dataloss,
+        "malformed" | "bad_route" | "dataloss" => TwirpErrorCode::Unknown,
+        code => match TwirpErrorCode::from_twirp_code(code) {
+            TwirpErrorCode::Custom(_) => TwirpErrorCode::Unknown,
+            code => code,
+        },
+    }
+}
+
+/// The HTTP status Connect servers send errors with `code` (a Connect code) with.
+pub fn connect_status(code: &str) -> StatusCode {
+    match code {
+        "canceled" => StatusCode::from_u16(499).expect("valid status"),
+        "invalid_argument" | "failed_precondition" | "out_of_range" => StatusCode::BAD_REQUEST,
+        "deadline_exceeded" => StatusCode::GATEWAY_TIMEOUT,
+        "not_found" => StatusCode::NOT_FOUND,
+        "already_exists" | "aborted" => StatusCode::CONFLICT,
+        "permission_denied" => StatusCode::FORBIDDEN,
+        "unauthenticated" => StatusCode::UNAUTHORIZED,
+        "resource_exhausted" => StatusCode::TOO_MANY_REQUESTS,
+        "unimplemented" => StatusCode::NOT_IMPLEMENTED,
+        "unavailable" => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Whether `headers` are those of a Connect request: Connect clients send the protocol version,
+// but it's optional, so the protobuf content type is recognized too.
+fn is_connect(headers: &HeaderMap) -> bool {
+    headers.contains_key(CONNECT_PROTOCOL_VERSION)
+        || headers
+            .get(CONTENT_TYPE)
+            .is_some_and(|ct| ct.as_bytes() == CONTENT_TYPE_PROTO)
+}
+
+// Replace the content type `from` with `to`.
+fn replace_content_type(headers: &mut HeaderMap, from: &[u8], to: &'static [u8]) {
+    if headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|ct| ct.as_bytes() == from)
+    {
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_bytes(to).expect("valid header value"),
+        );
+    }
+}
+
+// Whether `headers` (of an error response) have a JSON body.
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|ct| ct.as_bytes() == CONTENT_TYPE_JSON)
+}
+
+/// Layer that applies the [`Connect`] middleware. See the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectLayer;
+
+impl<S> Layer<S> for ConnectLayer {
+    type Service = Connect<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Connect { inner }
+    }
+}
+
+/// Middleware that serves Connect requests with a Twirp service.
+#[derive(Clone, Debug)]
+pub struct Connect<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for Connect<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if !is_connect(req.headers()) {
+            return Box::pin(self.inner.call(req));
+        }
+        let headers = req.headers_mut();
+        replace_content_type(headers, CONTENT_TYPE_PROTO, CONTENT_TYPE_PROTOBUF);
+        if let Some(timeout) = headers.get(CONNECT_TIMEOUT_MS).cloned() {
+            headers.entry(TWIRP_TIMEOUT).or_insert(timeout);
+        }
+
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            if resp.status().is_success() {
+                replace_content_type(
+                    resp.headers_mut(),
+                    CONTENT_TYPE_PROTOBUF,
+                    CONTENT_TYPE_PROTO,
+                );
+                return Ok(resp);
+            }
+            Ok(to_connect_error(resp).await)
+        })
+    }
+}
+
+// Rewrite a Twirp error response as a Connect error response. Other responses are returned as
+// they are.
+async fn to_connect_error(resp: Response<Body>) -> Response<Body> {
+    if !is_json(resp.headers()) {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return Response::from_parts(parts, Body::empty()),
+    };
+    let Ok(err) = serde_json::from_slice::<TwirpErrorResponse>(&body) else {
+        return Response::from_parts(parts, Body::from(body));
+    };
+    let code = connect_code(&err.code);
+    let err = ConnectError {
+        code: code.to_string(),
+        message: err.msg,
+    };
+    let body = serde_json::to_vec(&err).expect("error can be serialized");
+    parts.status = connect_status(code);
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// Client middleware that makes Twirp requests as Connect requests, and reads Connect responses.
+/// See the [module documentation](self).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectClient;
+
+#[async_trait]
+impl Middleware for ConnectClient {
+    async fn handle(
+        &self,
+        mut req: reqwest::Request,
+        next: Next<'_>,
+    ) -> crate::Result<reqwest::Response> {
+        let headers = req.headers_mut();
+        replace_content_type(headers, CONTENT_TYPE_PROTOBUF, CONTENT_TYPE_PROTO);
+        headers.insert(CONNECT_PROTOCOL_VERSION, HeaderValue::from_static("1"));
+        if let Some(timeout) = headers.remove(TWIRP_TIMEOUT) {
+            headers.insert(CONNECT_TIMEOUT_MS, timeout);
+        }
+
+        let mut resp = next.run(req).await?;
+        if resp.status().is_success() {
+            replace_content_type(
+                resp.headers_mut(),
+                CONTENT_TYPE_PROTO,
+                CONTENT_TYPE_PROTOBUF,
+            );
+            return Ok(resp);
+        }
+        if !is_json(resp.headers()) {
+            return Ok(resp);
+        }
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = resp.bytes().await?;
+        let err: ConnectError = serde_json::from_slice(&body).map_err(ClientError::from)?;
+        let err = TwirpErrorResponse::new(from_connect_code(&err.code), err.message);
+        let mut resp = http::Response::new(Bytes::from(
+            serde_json::to_vec(&err).expect("error can be serialized"),
+        ));
+        *resp.status_mut() = status;
+        *resp.headers_mut() = headers;
+        resp.headers_mut().remove(http::header::CONTENT_LENGTH);
+        Ok(resp.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{
+        decode_response, spawn_server, test_api_router, PingRequest, PingResponse, TestApiClient,
+    };
+    use crate::ClientBuilder;
+
+    fn connect_request(
+        path: &str,
+        content_type: &'static str,
+        body: impl Into<Body>,
+    ) -> Request<Body> {
+        Request::post(path)
+            .header(CONTENT_TYPE, content_type)
+            .header(CONNECT_PROTOCOL_VERSION, "1")
+            .body(body.into())
+            .unwrap()
+    }
+
+    async fn read_connect_error(resp: Response<Body>) -> (StatusCode, ConnectError) {
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_server() {
+        let mut router = test_api_router().layer(ConnectLayer);
+        let ping = PingRequest {
+            name: "connect".to_string(),
+        };
+
+        let req = connect_request(
+            "/twirp/test.TestAPI/Ping",
+            "application/proto",
+            crate::serialize_proto_message(ping.clone()),
+        );
+        let mut resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/proto");
+        resp.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/protobuf"),
+        );
+        let resp: PingResponse = decode_response(resp).await.unwrap();
+        assert_eq!(resp.name, "connect");
+
+        let req = connect_request(
+            "/twirp/test.TestAPI/Ping",
+            "application/json",
+            serde_json::to_vec(&ping).unwrap(),
+        );
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/json");
+        let resp: PingResponse = decode_response(resp).await.unwrap();
+        assert_eq!(resp.name, "connect");
+
+        let req = connect_request("/twirp/test.TestAPI/Boom", "application/json", "{}");
+        let (status, err) = read_connect_error(router.call(req).await.unwrap()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.code, "internal");
+        assert_eq!(err.message, "boom!");
+
+        let req = connect_request("/twirp/test.TestAPI/Pong", "application/json", "{}");
+        let (status, err) = read_connect_error(router.call(req).await.unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(err.code, "unimplemented");
+
+        // Twirp requests are untouched.
+        let req = crate::test::encode_request(
+            "/twirp/test.TestAPI/Boom",
+            &ping,
+            crate::codec::Format::Protobuf,
+        );
+        let err = decode_response::<PingResponse>(router.call(req).await.unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let server = spawn_server(test_api_router().layer(ConnectLayer)).await;
+        let client = ClientBuilder::new(server.url("/twirp/"), reqwest::Client::new())
+            .with(ConnectClient)
+            .build()
+            .unwrap();
+        let resp = client
+            .ping(PingRequest {
+                name: "connect".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "connect");
+        let err = client.boom(PingRequest::default()).await.unwrap_err();
+        let err = err.twirp_error().unwrap();
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+        assert_eq!(err.msg, "boom!");
+        server.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_codes() {
+        for code in [
+            TwirpErrorCode::Canceled,
+            TwirpErrorCode::NotFound,
+            TwirpErrorCode::Unavailable,
+            TwirpErrorCode::Dataloss,
+        ] {
+            assert_eq!(from_connect_code(connect_code(&code)), code);
+        }
+        assert_eq!(connect_code(&TwirpErrorCode::Malformed), "invalid_argument");
+        assert_eq!(from_connect_code("nope"), TwirpErrorCode::Unknown);
+        assert_eq!(connect_status("canceled").as_u16(), 499);
+        assert_eq!(
+            connect_status("deadline_exceeded"),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+}
@@ -1,6 +1,319 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use http::Extensions;
+use http::header::{self, AsHeaderName, IntoHeaderName};
+use http::{Extensions, HeaderMap, HeaderValue, Version};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::error::{self, TwirpErrorResponse};
+use crate::headers::{
+    IDEMPOTENCY_KEY, TRACEPARENT, TRACESTATE, TWIRP_ATTEMPT, TWIRP_TIMEOUT, X_TENANT_ID,
+};
+use crate::server::request_id::{RequestId, X_REQUEST_ID};
+
+/// The deadline of a request, as a request extension.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Deadline(pub(crate) Instant);
+
+/// The `rpc` a request is for, as a request extension. Routers add it when they route the request
+/// to a method, and direct handlers when they are called.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RpcMethod {
+    /// The fully qualified name of the service, e.g. `example.v1.Haberdasher`.
+    pub service: &'static str,
+    /// The name of the method, e.g. `MakeHat`.
+    pub method: &'static str,
+}
+
+impl RpcMethod {
+    pub(crate) fn new(service_fqn: &'static str, url: &'static str) -> Self {
+        Self {
+            service: service_fqn.trim_start_matches('/'),
+            method: url.trim_start_matches('/'),
+        }
+    }
+}
+
+/// The service and method of a Twirp route, `[<prefix>]/<package>.<Service>/<Method>`.
+pub(crate) fn split_route(path: &str) -> Option<(&str, &str)> {
+    let (rest, method) = path.rsplit_once('/')?;
+    let service = rest.rsplit('/').next().unwrap_or_default();
+    Some((service, method))
+}
+
+impl std::fmt::Display for RpcMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.service, self.method)
+    }
+}
+
+/// The client end of the connection a request came in on, as a request extension.
+///
+/// The remote address is taken from `ConnectInfo<SocketAddr>` when the app is served with
+/// [`into_make_service_with_connect_info`](axum::Router::into_make_service_with_connect_info).
+/// Servers that terminate TLS themselves can add the details of the TLS session by inserting a
+/// `PeerInfo` into the request extensions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PeerInfo {
+    /// The address of the client, or of the last proxy in front of the server.
+    pub remote_addr: Option<SocketAddr>,
+    /// The server name the client asked for with TLS SNI.
+    pub tls_server_name: Option<String>,
+    /// The subject of the certificate the client authenticated with over TLS.
+    pub tls_peer_subject: Option<String>,
+    /// The HTTP version of the request. Always set by the router.
+    pub protocol: Option<Version>,
+}
+
+impl PeerInfo {
+    /// A client connected from `remote_addr`.
+    pub fn new(remote_addr: SocketAddr) -> Self {
+        Self {
+            remote_addr: Some(remote_addr),
+            ..Default::default()
+        }
+    }
+
+    /// Add the details of the TLS session.
+    pub fn with_tls(mut self, server_name: Option<String>, peer_subject: Option<String>) -> Self {
+        self.tls_server_name = server_name;
+        self.tls_peer_subject = peer_subject;
+        self
+    }
+}
+
+/// The `tracing` span of a request, as a request extension. Handlers run in it.
+///
+/// Besides the service, method and request id, the span gets the Twirp error code of failed
+/// requests (`rpc.twirp.error_code`) and, once the response is written, its status
+/// (`http.response.status_code`) and the phases of its [`Timings`](crate::server::Timings)
+/// (`timings.received`, `timings.parsed`, `timings.handled` and `timings.written`).
+#[cfg(feature = "tracing")]
+#[derive(Clone, Debug)]
+pub(crate) struct RequestSpan(tracing::Span);
+
+#[cfg(feature = "tracing")]
+impl RequestSpan {
+    /// Start the span of a request, with the `rpc` and request id in `extensions`, and add it to
+    /// them.
+    pub(crate) fn start(extensions: &mut Extensions) -> Self {
+        use tracing::field::Empty;
+
+        let rpc = extensions.get::<RpcMethod>();
+        let span = tracing::info_span!(
+            "twirp",
+            rpc.service = rpc.map(|rpc| rpc.service),
+            rpc.method = rpc.map(|rpc| rpc.method),
+            request_id = Empty,
+            rpc.twirp.error_code = Empty,
+            http.response.status_code = Empty,
+            timings.received = Empty,
+            timings.parsed = Empty,
+            timings.handled = Empty,
+            timings.written = Empty,
+        );
+        if let Some(request_id) = extensions.get::<RequestId>() {
+            span.record("request_id", request_id.as_str());
+        }
+        extensions.insert(Self(span.clone()));
+        Self(span)
+    }
+
+    pub(crate) fn instrument<F: Future>(&self, fut: F) -> tracing::instrument::Instrumented<F> {
+        tracing::Instrument::instrument(fut, self.0.clone())
+    }
+
+    /// Record that the request failed with `err`. Errors the server is responsible for (with a
+    /// 5xx status) are logged at the `ERROR` level, others at `DEBUG`.
+    pub(crate) fn error(&self, err: &TwirpErrorResponse) {
+        let code = err.code.twirp_code();
+        self.0.record("rpc.twirp.error_code", code);
+        if err.http_status().is_server_error() {
+            tracing::error!(parent: &self.0, code, msg = %err.msg, "twirp request failed");
+        } else {
+            tracing::debug!(parent: &self.0, code, msg = %err.msg, "twirp request failed");
+        }
+    }
+
+    /// Record the response's `status` and the phases of the request in `timings`.
+    pub(crate) fn finish(&self, timings: &crate::server::Timings, status: http::StatusCode) {
+        self.0.record("http.response.status_code", status.as_u16());
+        let phases = [
+            ("timings.received", timings.received()),
+            ("timings.parsed", timings.parsed()),
+            ("timings.handled", timings.response_handled()),
+            ("timings.written", timings.response_written()),
+        ];
+        for (field, duration) in phases {
+            if let Some(duration) = duration {
+                self.0.record(field, tracing::field::debug(duration));
+            }
+        }
+    }
+}
+
+/// Without the `tracing` feature, requests have no span.
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct RequestSpan;
+
+#[cfg(not(feature = "tracing"))]
+impl RequestSpan {
+    pub(crate) fn start(_extensions: &mut Extensions) -> Self {
+        Self
+    }
+
+    pub(crate) fn instrument<F: Future>(&self, fut: F) -> F {
+        fut
+    }
+
+    pub(crate) fn error(&self, _err: &TwirpErrorResponse) {}
+
+    pub(crate) fn finish(&self, _timings: &crate::server::Timings, _status: http::StatusCode) {}
+}
+
+/// What a handler set about its response through its [`Context`] (e.g. with
+/// [`Context::set_response_header`]), as a response extension.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ResponseOverrides {
+    headers: HeaderMap,
+    error_meta: Vec<(String, String)>,
+}
+
+impl ResponseOverrides {
+    /// Add the meta values to `err`, the error the handler returned.
+    pub(crate) fn apply_to_error(&self, err: &mut TwirpErrorResponse) {
+        for (key, value) in &self.error_meta {
+            err.meta.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+
+    /// Add the headers to `headers`, except those that describe the body, which only the router
+    /// sets.
+    pub(crate) fn apply_headers(&self, headers: &mut HeaderMap) {
+        let body_headers = [
+            header::CONTENT_TYPE,
+            header::CONTENT_LENGTH,
+            header::CONTENT_ENCODING,
+        ];
+        for name in self
+            .headers
+            .keys()
+            .filter(|name| !body_headers.contains(name))
+        {
+            headers.remove(name);
+            for value in self.headers.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// The cancellation signal of a request, as a request extension: set to `true` when the request
+/// is cancelled.
+#[derive(Clone, Debug)]
+pub(crate) struct Cancellation(watch::Receiver<bool>);
+
+/// Cancels a request when dropped, unless it was [disarmed](Self::disarm) because the handler
+/// returned. Requests are dropped when the client disconnects, and their handlers are dropped
+/// when they run past the deadline.
+#[derive(Debug)]
+pub(crate) struct CancelOnDrop(Option<watch::Sender<bool>>);
+
+impl CancelOnDrop {
+    /// Add the cancellation signal to `extensions`, which become the handler's [`Context`].
+    pub(crate) fn new(extensions: &mut Extensions) -> Self {
+        let (tx, rx) = watch::channel(false);
+        extensions.insert(Cancellation(rx));
+        Self(Some(tx))
+    }
+
+    /// Leave the request uncancelled, because its handler returned.
+    pub(crate) fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(tx) = self.0.take() {
+            tx.send_replace(true);
+        }
+    }
+}
+
+/// A typed key for a value in the extensions of a request or response, defined with
+/// [`define_context_key!`](crate::define_context_key). Values are stored under their key rather
+/// than their type, so middleware and handlers that each keep a `String` don't overwrite each
+/// other's.
+pub trait ContextKey: Sized + Send + Sync + 'static {
+    /// The type of the value.
+    type Value: Clone + Send + Sync + 'static;
+
+    /// The name of the key, for debugging.
+    const NAME: &'static str;
+
+    /// Get the value of this key from `extensions`.
+    fn get<'a>(&self, extensions: &'a Extensions) -> Option<&'a Self::Value> {
+        extensions.get::<Keyed<Self>>().map(|keyed| &keyed.0)
+    }
+
+    /// Set the value of this key in `extensions`, e.g. those of a request in middleware, returning
+    /// the previous value.
+    fn insert(&self, extensions: &mut Extensions, value: Self::Value) -> Option<Self::Value> {
+        extensions
+            .insert(Keyed::<Self>(value, PhantomData))
+            .map(|keyed| keyed.0)
+    }
+}
+
+// A value stored under the key `K`: a distinct extension type for each key.
+struct Keyed<K: ContextKey>(K::Value, PhantomData<fn() -> K>);
+
+impl<K: ContextKey> Clone for Keyed<K> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+/// Define a [`ContextKey`]: a constant to get and set a value of the given type in a [`Context`]
+/// or in the extensions of a request.
+///
+/// ```
+/// use twirp::context::ContextKey;
+/// use twirp::{define_context_key, Context, Extensions};
+///
+/// define_context_key!(pub REQUEST_ID: String);
+/// define_context_key!(pub TENANT: String);
+///
+/// // e.g. in middleware, with `req.extensions_mut()`:
+/// let mut extensions = Extensions::new();
+/// REQUEST_ID.insert(&mut extensions, "req-1".to_string());
+/// TENANT.insert(&mut extensions, "acme".to_string());
+///
+/// let ctx = Context::new(extensions, Default::default());
+/// assert_eq!(ctx.get_key(&REQUEST_ID).unwrap(), "req-1");
+/// assert_eq!(ctx.get_key(&TENANT).unwrap(), "acme");
+/// ```
+#[macro_export]
+macro_rules! define_context_key {
+    ($(#[$attr:meta])* $vis:vis $name:ident : $value:ty) => {
+        $(#[$attr])*
+        #[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        $vis struct $name;
+
+        impl $crate::context::ContextKey for $name {
+            type Value = $value;
+            const NAME: &'static str = ::std::stringify!($name);
+        }
+    };
+}
 
 /// Context allows passing information between twirp rpc handlers and http middleware by providing
 /// access to extensions on the `http::Request` and `http::Response`.
@@ -10,6 +323,7 @@ use http::Extensions;
 #[derive(Default)]
 pub struct Context {
     extensions: Extensions,
+    headers: HeaderMap,
     resp_extensions: Arc<Mutex<Extensions>>,
 }
 
@@ -17,10 +331,120 @@ impl Context {
     pub fn new(extensions: Extensions, resp_extensions: Arc<Mutex<Extensions>>) -> Self {
         Self {
             extensions,
+            headers: HeaderMap::new(),
             resp_extensions,
         }
     }
 
+    /// Set the request headers.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Get a request header, e.g. `ctx.header("authorization")`. If the header was sent more than
+    /// once, the first value.
+    pub fn header<K: AsHeaderName>(&self, name: K) -> Option<&HeaderValue> {
+        self.headers.get(name)
+    }
+
+    /// The request headers. For calls to [direct handlers](crate::direct), the headers the client
+    /// would have sent.
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Which attempt at the call this request is, starting from 1, if the client says so with a
+    /// `Twirp-Attempt` header. Clients that retry calls should set it (see
+    /// [`headers::TWIRP_ATTEMPT`](crate::headers::TWIRP_ATTEMPT)), along with an
+    /// [idempotency key](Self::idempotency_key).
+    pub fn attempt(&self) -> Option<u32> {
+        self.header(TWIRP_ATTEMPT)?.to_str().ok()?.parse().ok()
+    }
+
+    /// Whether the request is a retry of a call made before, i.e. its [attempt](Self::attempt) is
+    /// 2 or more. The earlier attempts may or may not have reached this or another server.
+    pub fn is_retry(&self) -> bool {
+        self.attempt().is_some_and(|attempt| attempt > 1)
+    }
+
+    /// The `Idempotency-Key` of the call, which is the same for all its attempts: servers can use
+    /// it to avoid doing the work of a call twice.
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.header(IDEMPOTENCY_KEY)?.to_str().ok()
+    }
+
+    /// The headers to send with calls made to other services while handling this request, so
+    /// that they are part of the same request (see [`Client::with_context`](crate::Client::with_context)):
+    ///
+    /// - the request id (`x-request-id`), from the [`RequestId`] or the request header,
+    /// - the W3C trace context (`traceparent` and `tracestate`),
+    /// - the time left until the [deadline](Self::deadline), as `Twirp-Timeout`,
+    /// - the tenant (`x-tenant-id`).
+    ///
+    /// The time left is measured when this is called.
+    pub fn propagation_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let request_id = self
+            .get::<RequestId>()
+            .and_then(|id| HeaderValue::from_str(id.as_str()).ok())
+            .or_else(|| self.header(X_REQUEST_ID).cloned());
+        if let Some(request_id) = request_id {
+            headers.insert(X_REQUEST_ID, request_id);
+        }
+        for name in [TRACEPARENT, TRACESTATE, X_TENANT_ID] {
+            for value in self.headers.get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        if let Some(remaining) = self.time_remaining() {
+            headers.insert(
+                TWIRP_TIMEOUT,
+                HeaderValue::from(remaining.as_millis() as u64),
+            );
+        }
+        headers
+    }
+
+    /// Set a response header, e.g. `Cache-Control`, replacing any value set before. The headers
+    /// that describe the body (`Content-Type`, `Content-Length` and `Content-Encoding`) are set by
+    /// the router, and can't be set here.
+    pub fn set_response_header<K: IntoHeaderName>(&self, name: K, value: HeaderValue) {
+        self.override_response(|overrides| {
+            overrides.headers.insert(name, value);
+        });
+    }
+
+    /// Keep the response out of caches (`Cache-Control: no-store`), e.g. when it depends on who is
+    /// asking.
+    pub fn set_uncacheable(&self) {
+        self.set_response_header(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
+
+    /// Tell the client when to try again with a `Retry-After` header, in whole seconds (rounded
+    /// up). If the handler fails, the error also gets the `retry_after` meta value (see
+    /// [`TwirpErrorResponse::with_retry_after`]).
+    pub fn set_retry_after(&self, duration: Duration) {
+        let secs = error::retry_after_secs(duration);
+        self.set_response_header(header::RETRY_AFTER, HeaderValue::from(secs));
+        self.set_error_meta("retry_after", secs);
+    }
+
+    /// Add a meta value to the error, if the handler fails, e.g. details of work done before it
+    /// failed. Meta values of the error itself take precedence.
+    pub fn set_error_meta<K: ToString, V: ToString>(&self, key: K, value: V) {
+        self.override_response(|overrides| {
+            overrides
+                .error_meta
+                .push((key.to_string(), value.to_string()));
+        });
+    }
+
+    fn override_response(&self, f: impl FnOnce(&mut ResponseOverrides)) {
+        let mut resp_extensions = self.resp_extensions.lock().expect("mutex poisoned");
+        f(resp_extensions.get_or_insert_default::<ResponseOverrides>());
+    }
+
     /// Get a request extension.
     pub fn get<T>(&self) -> Option<&T>
     where
@@ -29,6 +453,111 @@ impl Context {
         self.extensions.get::<T>()
     }
 
+    /// The `rpc` being served, for labelling logs and metrics without knowing the service.
+    pub fn rpc_method(&self) -> Option<RpcMethod> {
+        self.extensions.get::<RpcMethod>().copied()
+    }
+
+    /// The fully qualified name of the service being served, e.g. `example.v1.Haberdasher`.
+    pub fn service(&self) -> Option<&'static str> {
+        self.rpc_method().map(|rpc| rpc.service)
+    }
+
+    /// The name of the method being served, e.g. `MakeHat`.
+    pub fn method(&self) -> Option<&'static str> {
+        self.rpc_method().map(|rpc| rpc.method)
+    }
+
+    pub(crate) fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// The client end of the connection the request came in on. `None` for calls to
+    /// [direct handlers](crate::direct).
+    pub fn peer(&self) -> Option<&PeerInfo> {
+        self.extensions.get::<PeerInfo>()
+    }
+
+    /// The address of the client, if the server knows it. See [`PeerInfo`].
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer()?.remote_addr
+    }
+
+    /// The `tracing` span the handler runs in, with the service, method and request id (see
+    /// [`RequestIdLayer`](crate::server::request_id::RequestIdLayer)) as fields. Work the handler
+    /// spawns can be instrumented with it too, so that its events are correlated with the request.
+    /// Once the response is written, the span also gets its status, Twirp error code and timings.
+    /// Disabled outside of routers.
+    ///
+    /// Requires the `tracing` feature.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> tracing::Span {
+        self.get::<RequestSpan>()
+            .map_or_else(tracing::Span::none, |span| span.0.clone())
+    }
+
+    /// The OpenTelemetry context of the request, with its span and baggage, as set by
+    /// [`OtelLayer`](crate::otel::OtelLayer). Handlers already run with it as the current
+    /// context; work they spawn can be attached to it too. An empty context without the layer.
+    ///
+    /// Requires the `otel` feature.
+    #[cfg(feature = "otel")]
+    pub fn otel_context(&self) -> opentelemetry::Context {
+        self.get::<opentelemetry::Context>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Get the value of a [`ContextKey`] from the request extensions.
+    pub fn get_key<K: ContextKey>(&self, key: &K) -> Option<&K::Value> {
+        key.get(&self.extensions)
+    }
+
+    /// Set the value of a [`ContextKey`] in the response extensions.
+    pub fn insert_key<K: ContextKey>(&self, key: &K, value: K::Value) -> Option<K::Value> {
+        key.insert(
+            &mut self.resp_extensions.lock().expect("mutex poisoned"),
+            value,
+        )
+    }
+
+    /// When the request has to be answered by, if it has a deadline: the `Twirp-Timeout` header
+    /// sent by the client (in milliseconds), or the router's
+    /// [timeout](crate::details::TwirpRouterBuilder::timeout) if that is shorter. Handlers still
+    /// running at the deadline are stopped, and the client gets a `deadline_exceeded` error.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.extensions.get::<Deadline>().map(|deadline| deadline.0)
+    }
+
+    /// The time left until the [deadline](Self::deadline), e.g. to use as the timeout of calls
+    /// to other services. Zero once the deadline has passed.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.deadline()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Resolves when the request is cancelled: the client disconnected, or the handler ran past
+    /// the [deadline](Self::deadline). Either way, the handler itself is dropped, so this is for
+    /// work it started in the background (e.g. with `tokio::spawn`), which can stop when nobody
+    /// is waiting for the answer. Never resolves once the handler has returned.
+    pub fn cancelled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let cancellation = self.get::<Cancellation>().cloned();
+        async move {
+            if let Some(Cancellation(mut rx)) = cancellation {
+                if rx.wait_for(|cancelled| *cancelled).await.is_ok() {
+                    return;
+                }
+            }
+            std::future::pending().await
+        }
+    }
+
+    /// Whether the request has been [cancelled](Self::cancelled).
+    pub fn is_cancelled(&self) -> bool {
+        self.get::<Cancellation>()
+            .is_some_and(|Cancellation(rx)| *rx.borrow())
+    }
+
     /// Insert a response extension.
     pub fn insert<T>(&self, val: T) -> Option<T>
     where
@@ -40,3 +569,93 @@ impl Context {
             .insert(val)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    define_context_key!(USER: String);
+    define_context_key!(ROLE: String);
+
+    #[tokio::test]
+    async fn test_propagation_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_REQUEST_ID, HeaderValue::from_static("from-header"));
+        headers.insert(TRACEPARENT, HeaderValue::from_static("00-abc-def-01"));
+        headers.insert(X_TENANT_ID, HeaderValue::from_static("acme"));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("secret"));
+        let ctx = Context::default().with_headers(headers.clone());
+        let propagated = ctx.propagation_headers();
+        assert_eq!(propagated.len(), 3);
+        assert_eq!(propagated[X_REQUEST_ID], "from-header");
+        assert_eq!(propagated[TRACEPARENT], "00-abc-def-01");
+        assert_eq!(propagated[X_TENANT_ID], "acme");
+
+        let mut extensions = Extensions::new();
+        extensions.insert(RequestId("from-layer".to_string()));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        extensions.insert(Deadline(deadline));
+        let ctx = Context::new(extensions, Default::default()).with_headers(headers);
+        let propagated = ctx.propagation_headers();
+        assert_eq!(propagated[X_REQUEST_ID], "from-layer");
+        let timeout: u64 = propagated[TWIRP_TIMEOUT].to_str().unwrap().parse().unwrap();
+        assert!(timeout > 9000 && timeout <= 10000, "{timeout}");
+    }
+
+    #[test]
+    fn test_attempt() {
+        let ctx = Context::default();
+        assert_eq!(ctx.attempt(), None);
+        assert!(!ctx.is_retry());
+        assert_eq!(ctx.idempotency_key(), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(TWIRP_ATTEMPT, HeaderValue::from_static("1"));
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("order-42"));
+        let ctx = Context::default().with_headers(headers.clone());
+        assert_eq!(ctx.attempt(), Some(1));
+        assert!(!ctx.is_retry());
+        assert_eq!(ctx.idempotency_key(), Some("order-42"));
+
+        headers.insert(TWIRP_ATTEMPT, HeaderValue::from_static("3"));
+        let ctx = Context::default().with_headers(headers.clone());
+        assert_eq!(ctx.attempt(), Some(3));
+        assert!(ctx.is_retry());
+
+        headers.insert(TWIRP_ATTEMPT, HeaderValue::from_static("third"));
+        assert_eq!(Context::default().with_headers(headers).attempt(), None);
+    }
+
+    #[test]
+    fn test_split_route() {
+        let route = Some(("example.v1.Haberdasher", "MakeHat"));
+        assert_eq!(split_route("/twirp/example.v1.Haberdasher/MakeHat"), route);
+        assert_eq!(split_route("/example.v1.Haberdasher/MakeHat"), route);
+        assert_eq!(split_route("/MakeHat"), Some(("", "MakeHat")));
+        assert_eq!(split_route("MakeHat"), None);
+    }
+
+    #[test]
+    fn test_context_keys() {
+        let mut extensions = Extensions::new();
+        assert_eq!(USER.insert(&mut extensions, "alice".to_string()), None);
+        ROLE.insert(&mut extensions, "admin".to_string());
+        extensions.insert("untyped".to_string());
+        assert_eq!(
+            USER.insert(&mut extensions, "bob".to_string()).as_deref(),
+            Some("alice")
+        );
+
+        let resp_extensions = Arc::new(Mutex::new(Extensions::new()));
+        let ctx = Context::new(extensions, resp_extensions.clone());
+        assert_eq!(ctx.get_key(&USER).unwrap(), "bob");
+        assert_eq!(ctx.get_key(&ROLE).unwrap(), "admin");
+        assert_eq!(ctx.get::<String>().unwrap(), "untyped");
+
+        ctx.insert_key(&ROLE, "viewer".to_string());
+        let resp_extensions = resp_extensions.lock().unwrap();
+        assert_eq!(ROLE.get(&resp_extensions).unwrap(), "viewer");
+        assert_eq!(USER.get(&resp_extensions), None);
+        assert_eq!(USER::NAME, "USER");
+    }
+}
@@ -1,29 +1,51 @@
 //! Undocumented features that are public for use in generated code (see `twirp-build`).
 
 use std::future::Future;
+use std::sync::Arc;
 
 use axum::extract::{Request, State};
-use axum::Router;
+use axum::handler::Handler;
+use axum::routing::MethodRouter;
+use axum::{Extension, Router};
+use bytes::Bytes;
+use futures::Stream;
 
-use crate::{server, Context, IntoTwirpResponse};
+use crate::codec::Codec;
+use crate::context::RpcMethod;
+use crate::direct::{self, DirectService};
+use crate::server::{AllowedMethods, RawResponse, RouterConfig};
+use crate::{server, Compatibility, Context, IntoTwirpResponse, Redactor};
 
 /// Builder object used by generated code to build a Twirp service.
 ///
 /// The type `S` is something like `Arc<MyExampleApiServer>`, which can be cheaply cloned for each
 /// incoming request, providing access to the Rust value that actually implements the RPCs.
 pub struct TwirpRouterBuilder<S> {
+    service_fqn: &'static str,
+    methods: Vec<&'static str>,
     service: S,
     router: Router<S>,
+    config: RouterConfig,
+    direct_routes: Vec<(&'static str, direct::DirectRoute)>,
 }
 
 impl<S> TwirpRouterBuilder<S>
 where
     S: Clone + Send + Sync + 'static,
 {
-    pub fn new(service: S) -> Self {
+    /// Start building the router for the service named `service_fqn` (the generated
+    /// `SERVICE_FQN`, e.g. `/example.v1.Haberdasher`).
+    pub fn new(service_fqn: &'static str, service: S) -> Self {
         TwirpRouterBuilder {
+            service_fqn,
+            methods: vec![],
             service,
             router: Router::new(),
+            config: RouterConfig {
+                service_fqn,
+                ..Default::default()
+            },
+            direct_routes: vec![],
         }
     }
 
@@ -31,29 +53,259 @@ where
     ///
     /// The generated code passes a closure that calls the method, like
     /// `|api: Arc<HaberdasherApiServer>, req: MakeHatRequest| async move { api.make_hat(req) }`.
-    pub fn route<F, Fut, Req, Res, Err>(self, url: &str, f: F) -> Self
+    pub fn route<F, Fut, Req, Res, Err>(self, url: &'static str, f: F) -> Self
     where
         F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
-        Fut: Future<Output = Result<Res, Err>> + Send,
-        Req: prost::Message + Default + serde::de::DeserializeOwned,
-        Res: prost::Message + serde::Serialize,
-        Err: IntoTwirpResponse,
+        Fut: Future<Output = Result<Res, Err>> + Send + 'static,
+        Req: prost::Message + Default + serde::de::DeserializeOwned + 'static,
+        Res: prost::Message + serde::Serialize + Send + 'static,
+        Err: IntoTwirpResponse + 'static,
+    {
+        self.add_route(url, f, AllowedMethods::Post)
+    }
+
+    /// Add a handler for an `rpc` marked with `option idempotency_level = NO_SIDE_EFFECTS`.
+    ///
+    /// Besides `POST`, these methods can be called with `GET`, passing the request message in the
+    /// query string, so that responses can be cached by CDNs and the methods are easy to call
+    /// with `curl`.
+    pub fn route_no_side_effects<F, Fut, Req, Res, Err>(self, url: &'static str, f: F) -> Self
+    where
+        F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<Res, Err>> + Send + 'static,
+        Req: prost::Message + Default + serde::de::DeserializeOwned + 'static,
+        Res: prost::Message + serde::Serialize + Send + 'static,
+        Err: IntoTwirpResponse + 'static,
+    {
+        self.add_route(url, f, AllowedMethods::GetAndPost)
+    }
+
+    fn add_route<F, Fut, Req, Res, Err>(
+        mut self,
+        url: &'static str,
+        f: F,
+        allowed: AllowedMethods,
+    ) -> Self
+    where
+        F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<Res, Err>> + Send + 'static,
+        Req: prost::Message + Default + serde::de::DeserializeOwned + 'static,
+        Res: prost::Message + serde::Serialize + Send + 'static,
+        Err: IntoTwirpResponse + 'static,
+    {
+        self.direct_routes.push((
+            url.trim_start_matches('/'),
+            direct::route(self.service.clone(), f.clone()),
+        ));
+
+        let handler = move |State(api): State<S>, req: Request| async move {
+            server::handle_request(api, req, f).await
+        };
+        let mut method_router = axum::routing::post(handler.clone());
+        if allowed == AllowedMethods::GetAndPost {
+            method_router = method_router.get(handler);
+        }
+        let method_router = method_router
+            .options(move |req: Request| server::options_handler(req, allowed))
+            .fallback(move |req: Request| server::method_not_allowed_handler(req, allowed));
+        self.add_method_router(url, method_router)
+    }
+
+    /// Add a handler for an `rpc` that gets the request with its body undecoded, e.g. to forward
+    /// the payload elsewhere without decoding and re-encoding it. The handler can decode the
+    /// message with [`DecodeRequest::decode`](crate::server::DecodeRequest::decode), and can
+    /// return the response already encoded with
+    /// [`RawResponse::Encoded`](crate::server::RawResponse::Encoded). Only `POST` is allowed, even
+    /// for methods without side effects.
+    pub fn route_raw<F, Fut, Res, Err>(mut self, url: &'static str, f: F) -> Self
+    where
+        F: Fn(S, Context, http::Request<Bytes>) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<RawResponse<Res>, Err>> + Send + 'static,
+        Res: prost::Message + serde::Serialize + Send + 'static,
+        Err: IntoTwirpResponse + 'static,
     {
+        let allowed = AllowedMethods::Post;
+        self.direct_routes.push((
+            url.trim_start_matches('/'),
+            direct::raw_route(self.service.clone(), f.clone()),
+        ));
+
+        let method_router =
+            axum::routing::post(move |State(api): State<S>, req: Request| async move {
+                server::handle_raw_request(api, req, f).await
+            })
+            .options(move |req: Request| server::options_handler(req, allowed))
+            .fallback(move |req: Request| server::method_not_allowed_handler(req, allowed));
+        self.add_method_router(url, method_router)
+    }
+
+    fn add_method_router(self, url: &'static str, method_router: MethodRouter<S>) -> Self {
+        let mut methods = self.methods;
+        methods.push(url.trim_start_matches('/'));
+        let method_router = method_router.layer(Extension(RpcMethod::new(self.service_fqn, url)));
         TwirpRouterBuilder {
+            service_fqn: self.service_fqn,
+            methods,
             service: self.service,
-            router: self.router.route(
-                url,
-                axum::routing::post(move |State(api): State<S>, req: Request| async move {
-                    server::handle_request(api, req, f).await
-                }),
-            ),
+            config: self.config,
+            router: self.router.route(url, method_router),
+            direct_routes: self.direct_routes,
         }
     }
 
+    /// Add a handler for a server-streaming `rpc` to the router. The handler resolves to a stream
+    /// of responses; see [`crate::stream`] for how they are sent.
+    pub fn route_server_streaming<F, Fut, Req, St, Res, Err>(self, url: &'static str, f: F) -> Self
+    where
+        F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+        Fut: Future<Output = Result<St, Err>> + Send,
+        St: Stream<Item = Result<Res, Err>> + Send + 'static,
+        Req: prost::Message + Default + serde::de::DeserializeOwned,
+        Res: prost::Message + serde::Serialize + 'static,
+        Err: IntoTwirpResponse + 'static,
+    {
+        let allowed = AllowedMethods::Post;
+        let method_router =
+            axum::routing::post(move |State(api): State<S>, req: Request| async move {
+                server::handle_streaming_request(api, req, f).await
+            })
+            .options(move |req: Request| server::options_handler(req, allowed))
+            .fallback(move |req: Request| server::method_not_allowed_handler(req, allowed));
+        self.add_method_router(url, method_router)
+    }
+
+    /// Set the version of the Twirp protocol to stay compatible with. See [`Compatibility`].
+    pub fn compatibility(mut self, compatibility: Compatibility) -> Self {
+        self.config.compatibility = compatibility;
+        self
+    }
+
+    /// Send the underlying Rust error of failed requests (see
+    /// [`TwirpErrorResponse::rust_error`](crate::TwirpErrorResponse::rust_error)) to clients in
+    /// the `debug` meta value. This exposes internals, so only enable it in environments where
+    /// all callers are trusted, e.g. staging.
+    pub fn debug_errors(mut self, enabled: bool) -> Self {
+        self.config.debug_errors = enabled;
+        self
+    }
+
+    /// Add the service, method and (with
+    /// [`RequestIdLayer`](crate::server::request_id::RequestIdLayer)) request id to the `meta` of
+    /// the errors this router sends, as `service`, `method` and `request_id`. Values set by the
+    /// handler are kept.
+    pub fn annotate_errors(mut self, enabled: bool) -> Self {
+        self.config.annotate_errors = enabled;
+        self
+    }
+
+    /// Accept requests in the format of `codec` (matched by content type), in addition to
+    /// protobuf and JSON. Responses use the same codec as the request. See [`crate::codec`].
+    pub fn codec<C: Codec>(mut self, codec: C) -> Self {
+        self.config.codecs.push(Arc::new(codec));
+        self
+    }
+
+    /// Decompress zstd-compressed requests and compress responses for clients that accept it.
+    /// See [`crate::compression`].
+    #[cfg(feature = "zstd")]
+    pub fn zstd(mut self, zstd: crate::compression::Zstd) -> Self {
+        self.config.zstd = Some(zstd);
+        self
+    }
+
+    /// Decompress gzip-compressed requests and compress responses for clients that accept gzip
+    /// but not zstd. See [`crate::compression`].
+    #[cfg(feature = "gzip")]
+    pub fn gzip(mut self, gzip: crate::compression::Gzip) -> Self {
+        self.config.gzip = Some(gzip);
+        self
+    }
+
+    /// Send checksums of response bodies, and check the checksums of request bodies. See
+    /// [`crate::checksum`].
+    #[cfg(feature = "checksum")]
+    pub fn checksums(mut self, checksums: crate::checksum::Checksums) -> Self {
+        self.config.checksums = Some(checksums);
+        self
+    }
+
+    /// Count the request and response bodies this router holds against `budget`, shedding
+    /// requests that go over it. See [`server::budget`].
+    pub fn memory_budget(mut self, budget: server::budget::MemoryBudget) -> Self {
+        self.config.memory_budget = Some(budget);
+        self
+    }
+
+    /// Reject requests with bodies longer than `bytes` with a `resource_exhausted` error, without
+    /// reading the rest of the body. The limit applies both to the body as sent and, for
+    /// compressed bodies, to the decompressed body.
+    pub fn max_request_size(mut self, bytes: usize) -> Self {
+        self.config.max_request_size = Some(bytes);
+        self
+    }
+
+    /// Serialize responses of at least `bytes` (as protobuf) on a blocking thread, so that
+    /// serializing very large messages doesn't hold up other requests on the same runtime worker.
+    pub fn blocking_serialization_threshold(mut self, bytes: usize) -> Self {
+        self.config.blocking_serialization_threshold = Some(bytes);
+        self
+    }
+
+    /// Stop handlers that run for longer than `timeout` and respond with `deadline_exceeded`.
+    /// Requests can set a shorter timeout with the `Twirp-Timeout` header, but not a longer one.
+    /// Handlers can check how much time they have left with [`Context::time_remaining`].
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Scrub the errors this router sends with `redactor`, before the
+    /// [global redactor](crate::set_global_redactor), if any.
+    pub fn redactor(mut self, redactor: Redactor) -> Self {
+        self.config.redactor = Some(redactor);
+        self
+    }
+
+    /// Call `hooks` at each phase of the requests this router serves; see
+    /// [`server::hooks`](crate::server::hooks). Hooks added later are called after those added
+    /// earlier.
+    pub fn hooks(mut self, hooks: impl crate::server::hooks::ServerHooks) -> Self {
+        self.config.hooks.push(hooks);
+        self
+    }
+
     /// Finish building the axum router.
     pub fn build(self) -> axum::Router {
+        self.build_with_fallback(server::not_found_handler)
+    }
+
+    /// Finish building a handler that calls the service in-process, instead of an axum router.
+    /// See [`crate::direct`], and [`DirectService`] for which of the builder's settings apply.
+    pub fn build_direct(self) -> DirectService {
+        let config = RouterConfig {
+            methods: self.methods,
+            ..self.config
+        };
+        DirectService::new(self.service_fqn, self.direct_routes, config)
+    }
+
+    /// Finish building the axum router, using `fallback` for requests that don't match any `rpc`
+    /// instead of [`not_found_handler`](server::not_found_handler).
+    ///
+    /// To chain to another router, pass a handler that forwards the request to it, e.g.
+    /// `move |req: Request| other.clone().oneshot(req)`.
+    pub fn build_with_fallback<H, T>(self, fallback: H) -> axum::Router
+    where
+        H: Handler<T, S>,
+        T: 'static,
+    {
+        let config = RouterConfig {
+            methods: self.methods,
+            ..self.config
+        };
         self.router
-            .fallback(crate::server::not_found_handler)
+            .fallback(fallback)
+            .layer(Extension(Arc::new(config)))
             .with_state(self.service)
     }
 }
@@ -0,0 +1,344 @@
+//! Calling Twirp services in-process, without HTTP.
+//!
+//! A [`DirectHandler`] handles calls to one service given the protobuf-encoded request. Handlers
+//! for generated services are built with `<service>::direct_handler(api)` (see
+//! [`TwirpRouterBuilder::build_direct`](crate::details::TwirpRouterBuilder::build_direct)), and
+//! added to a client with [`ClientBuilder::direct`](crate::ClientBuilder::direct). The client then
+//! calls the handler for requests to its host, skipping the network, the middlewares and codecs:
+//! this is useful in tests, and for services deployed together in one binary.
+//!
+//! ```
+//! # use twirp::direct::DirectHandler;
+//! # use twirp::{Client, ClientBuilder};
+//! # fn example(haberdasher: impl DirectHandler) -> twirp::Result<Client> {
+//! // `haberdasher` is e.g. `haberdash::direct_handler(api)`.
+//! let base_url = url::Url::parse("http://haberdasher.local/twirp/")?;
+//! ClientBuilder::new(base_url, reqwest::Client::new())
+//!     .direct("haberdasher.local", haberdasher)
+//!     .build()
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+
+use crate::context::RpcMethod;
+use crate::server::{self, RawResponse, RouterConfig};
+use crate::{error, serialize_proto_message, Context, IntoTwirpResponse, TwirpErrorResponse};
+
+/// A service that is called in-process. See the [module documentation](self).
+#[async_trait]
+pub trait DirectHandler: Send + Sync + 'static {
+    /// The fully qualified name of the service, e.g. `example.v1.Haberdasher`.
+    fn service(&self) -> &str;
+
+    /// Handle a call to `method` (e.g. `MakeHat`) with the protobuf-encoded request `req`,
+    /// returning the protobuf-encoded response.
+    async fn handle(
+        &self,
+        method: &str,
+        ctx: Context,
+        req: Bytes,
+    ) -> Result<Bytes, TwirpErrorResponse>;
+}
+
+pub(crate) type DirectRoute = Arc<
+    dyn Fn(Context, Bytes) -> BoxFuture<'static, Result<Bytes, TwirpErrorResponse>> + Send + Sync,
+>;
+
+pub(crate) fn route<S, F, Fut, Req, Res, Err>(service: S, f: F) -> DirectRoute
+where
+    S: Clone + Send + Sync + 'static,
+    F: Fn(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<Res, Err>> + Send + 'static,
+    Req: prost::Message + Default + 'static,
+    Res: prost::Message + 'static,
+    Err: IntoTwirpResponse + 'static,
+{
+    Arc::new(move |ctx, body| {
+        let service = service.clone();
+        let f = f.clone();
+        Box::pin(async move {
+            let req = Req::decode(body).map_err(|err| {
+                error::malformed("bad request")
+                    .with_meta("error", &err)
+                    .with_source(err)
+            })?;
+            let res = f(service, ctx, req)
+                .await
+                .map_err(|err| err.into_twirp_response().into_body())?;
+            Ok(serialize_proto_message(res))
+        })
+    })
+}
+
+pub(crate) fn raw_route<S, F, Fut, Res, Err>(service: S, f: F) -> DirectRoute
+where
+    S: Clone + Send + Sync + 'static,
+    F: Fn(S, Context, http::Request<Bytes>) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<RawResponse<Res>, Err>> + Send + 'static,
+    Res: prost::Message + 'static,
+    Err: IntoTwirpResponse + 'static,
+{
+    Arc::new(move |ctx, body| {
+        let service = service.clone();
+        let f = f.clone();
+        Box::pin(async move {
+            let mut req = http::Request::new(body);
+            req.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                http::HeaderValue::from_static("application/protobuf"),
+            );
+            let res = f(service, ctx, req)
+                .await
+                .map_err(|err| err.into_twirp_response().into_body())?;
+            // The request is protobuf, so an encoded response is too.
+            match res {
+                RawResponse::Message(res) => Ok(serialize_proto_message(res)),
+                RawResponse::Encoded(data) => Ok(data),
+            }
+        })
+    })
+}
+
+/// The [`DirectHandler`] for a generated service, built with
+/// [`TwirpRouterBuilder::build_direct`](crate::details::TwirpRouterBuilder::build_direct).
+///
+/// Calls go through the router's [hooks](crate::server::hooks), timeout (shortened by the
+/// client's `Twirp-Timeout` header, as over HTTP), `max_request_size` and error handling (error
+/// annotation, debug errors, redaction, Twirp v5 compatibility), as they would over HTTP. There is
+/// no HTTP request, so codecs, compression, checksums and the memory budget don't apply, and layers
+/// added to the router aren't run. Server-streaming RPCs can't be called directly, and return
+/// `bad_route`.
+#[derive(Clone)]
+pub struct DirectService {
+    service: &'static str,
+    routes: HashMap<&'static str, DirectRoute>,
+    config: Arc<RouterConfig>,
+}
+
+impl DirectService {
+    pub(crate) fn new(
+        service_fqn: &'static str,
+        routes: Vec<(&'static str, DirectRoute)>,
+        config: RouterConfig,
+    ) -> Self {
+        Self {
+            service: service_fqn.trim_start_matches('/'),
+            routes: routes.into_iter().collect(),
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl std::fmt::Debug for DirectService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut methods: Vec<_> = self.routes.keys().collect();
+        methods.sort();
+        f.debug_struct("DirectService")
+            .field("service", &self.service)
+            .field("methods", &methods)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl DirectHandler for DirectService {
+    fn service(&self) -> &str {
+        self.service
+    }
+
+    async fn handle(
+        &self,
+        method: &str,
+        ctx: Context,
+        req: Bytes,
+    ) -> Result<Bytes, TwirpErrorResponse> {
+        match self.routes.get_key_value(method) {
+            Some((method, route)) => {
+                let rpc = RpcMethod {
+                    service: self.service,
+                    method,
+                };
+                server::handle_direct(&self.config, rpc, ctx, req, route).await
+            }
+            None => Err(error::bad_route(format!(
+                "no method `{method}` in service `{}`",
+                self.service
+            ))),
+        }
+    }
+}
+
+/// The direct handlers of a client, by host and service.
+#[derive(Clone, Default)]
+pub struct RequestHandlers {
+    // Nested rather than keyed by `{host}/{service}`, so that lookups don't allocate.
+    handlers: HashMap<String, HashMap<String, Arc<dyn DirectHandler>>>,
+}
+
+impl std::fmt::Debug for RequestHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let services: HashMap<_, Vec<_>> = self
+            .handlers
+            .iter()
+            .map(|(host, services)| (host, services.keys().collect()))
+            .collect();
+        f.debug_struct("RequestHandlers")
+            .field("handlers", &services)
+            .finish()
+    }
+}
+
+impl RequestHandlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle calls to the service of `handler` on `host` with `handler`, replacing any handler
+    /// that was added for them before.
+    pub fn add<H: DirectHandler>(&mut self, host: &str, handler: H) {
+        self.handlers
+            .entry(host.to_string())
+            .or_default()
+            .insert(handler.service().to_string(), Arc::new(handler));
+    }
+
+    /// The handler for calls to `service` on `host`, if any.
+    pub fn get(&self, host: &str, service: &str) -> Option<&Arc<dyn DirectHandler>> {
+        self.handlers.get(host)?.get(service)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+    use crate::test::*;
+    use crate::{ClientBuilder, TwirpErrorCode};
+
+    fn client() -> crate::Client {
+        let base_url = Url::parse("http://test.local/twirp/").unwrap();
+        ClientBuilder::new(base_url, reqwest::Client::new())
+            .direct("test.local", test_api_direct_handler())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_direct_call() {
+        let resp = client()
+            .ping(PingRequest {
+                name: "direct".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "direct");
+
+        let err = client().boom(PingRequest::default()).await.unwrap_err();
+        assert_eq!(err.twirp_error().unwrap().code, TwirpErrorCode::Internal);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method() {
+        let err = client()
+            .request::<_, PingResponse>("test.TestAPI/Pong", PingRequest::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.twirp_error().unwrap().code, TwirpErrorCode::BadRoute);
+
+        let err = test_api_direct_handler()
+            .handle("Ping", Context::default(), Bytes::from_static(b"\xff"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+    }
+
+    #[tokio::test]
+    async fn test_router_config() {
+        use crate::server::hooks::{HookInfo, ServerHooks};
+
+        #[derive(Clone, Default)]
+        struct Errors(Arc<std::sync::Mutex<Vec<String>>>);
+
+        impl ServerHooks for Errors {
+            fn error(&self, info: &HookInfo, err: &TwirpErrorResponse) {
+                let event = format!("{} {}", info.rpc, err.code.twirp_code());
+                self.0.lock().unwrap().push(event);
+            }
+        }
+
+        let errors = Errors::default();
+        let handler = test_api_router_builder()
+            .annotate_errors(true)
+            .max_request_size(4)
+            .hooks(errors.clone())
+            .build_direct();
+
+        let err = handler
+            .handle("Boom", Context::default(), Bytes::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.meta["service"], "test.TestAPI");
+        assert_eq!(err.meta["method"], "Boom");
+
+        let req = serialize_proto_message(PingRequest {
+            name: "too long".to_string(),
+        });
+        let err = handler
+            .handle("Ping", Context::default(), req)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::ResourceExhausted);
+
+        assert_eq!(
+            *errors.0.lock().unwrap(),
+            [
+                "test.TestAPI/Boom internal",
+                "test.TestAPI/Ping resource_exhausted"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_timeout() {
+        let handler = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |_: (), _: Context, req: PingRequest| async move {
+                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                Ok::<_, TwirpErrorResponse>(PingResponse { name: req.name })
+            })
+            .build_direct();
+        let base_url = Url::parse("http://test.local/twirp/").unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .direct("test.local", handler)
+            .build()
+            .unwrap()
+            .with_timeout(std::time::Duration::from_millis(50));
+
+        let err = client.ping(PingRequest::default()).await.unwrap_err();
+        assert_eq!(
+            err.twirp_error().unwrap().code,
+            TwirpErrorCode::DeadlineExceeded
+        );
+    }
+
+    #[test]
+    fn test_request_handlers() {
+        let mut handlers = RequestHandlers::new();
+        assert!(handlers.is_empty());
+        handlers.add("test.local", test_api_direct_handler());
+        assert!(handlers.get("test.local", "test.TestAPI").is_some());
+        assert!(handlers.get("other.local", "test.TestAPI").is_none());
+        assert!(handlers.get("test.local", "test.OtherAPI").is_none());
+    }
+}
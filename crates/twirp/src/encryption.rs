@@ -0,0 +1,372 @@
+//! End-to-end encryption of message bodies, for services that trust each other but talk over a
+//! plaintext transport.
+//!
+//! [`Encrypted`] wraps another [`Codec`] and encrypts what it encodes with XChaCha20-Poly1305.
+//! Add it to a router with [`TwirpRouterBuilder::codec`](crate::details::TwirpRouterBuilder::codec)
+//! and to clients with [`ClientBuilder::codec`](crate::ClientBuilder::codec), with the same keys on
+//! both sides:
+//!
+//! ```
+//! use twirp::codec::ProtobufCodec;
+//! use twirp::encryption::Encrypted;
+//!
+//! # let key = [7; 32];
+//! // `key` is a 32-byte secret shared by the services, e.g. from a secret store.
+//! let codec = Encrypted::new(ProtobufCodec, "2024-06", key);
+//! ```
+//!
+//! Each body starts with the id of the key it was encrypted with, so keys can be rotated: add the
+//! new key everywhere with [`Encrypted::decryption_key`] first, then switch to encrypting with it.
+//! The id, the content type and the type of the message (the service, the method, and whether it is
+//! the request or the response) are authenticated along with the message, so a body can't be
+//! replayed as another message.
+//!
+//! The responses of [server-streaming RPCs](crate::stream) are encrypted frame by frame, the
+//! trailer included. Their JSON form, server-sent events, can't be encrypted, so routers reject
+//! streaming requests in an encrypted JSON codec.
+//!
+//! Requires the `encryption` feature.
+
+use std::collections::HashMap;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::codec::{Codec, Format, MessageType};
+use crate::GenericError;
+
+/// The version of the body layout: `[version][key id length][key id][nonce][ciphertext]`.
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+
+/// A codec that encrypts the bodies of another codec. See the [module documentation](self).
+pub struct Encrypted<C> {
+    inner: C,
+    content_type: String,
+    key_id: String,
+    keys: HashMap<String, XChaCha20Poly1305>,
+}
+
+impl<C: Codec> Encrypted<C> {
+    /// Encrypt the bodies of `inner` with the 32-byte `key`, identified by `key_id`.
+    ///
+    /// The content type is `application/vnd.twirp.encrypted+protobuf` (or `+json`, depending on
+    /// the format of `inner`).
+    ///
+    /// # Panics
+    ///
+    /// If `key_id` is longer than 255 bytes.
+    pub fn new(inner: C, key_id: impl Into<String>, key: [u8; 32]) -> Self {
+        let suffix = match inner.format() {
+            Format::Protobuf => "protobuf",
+            Format::Json => "json",
+        };
+        let content_type = format!("application/vnd.twirp.encrypted+{suffix}");
+        let key_id = key_id.into();
+        Self {
+            inner,
+            content_type,
+            keys: HashMap::new(),
+            key_id: key_id.clone(),
+        }
+        .decryption_key(key_id, key)
+    }
+
+    /// Also decrypt bodies encrypted with `key`, e.g. while keys are being rotated.
+    ///
+    /// # Panics
+    ///
+    /// If `key_id` is longer than 255 bytes.
+    pub fn decryption_key(mut self, key_id: impl Into<String>, key: [u8; 32]) -> Self {
+        let key_id = key_id.into();
+        assert!(key_id.len() <= u8::MAX as usize, "key id is too long");
+        self.keys
+            .insert(key_id, XChaCha20Poly1305::new(&key.into()));
+        self
+    }
+
+    /// Use `content_type` instead of the default one.
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    // Authenticate the content type, key id and message type along with the message, so that a
+    // body can't be passed off as another kind. `encode` and `decode` don't know the message type.
+    fn aad(&self, key_id: &[u8], ty: Option<MessageType<'_>>) -> Vec<u8> {
+        let mut aad = [self.content_type.as_bytes(), &[0], key_id].concat();
+        if let Some(ty) = ty {
+            let kind: &[u8] = if ty.response { b"response" } else { b"request" };
+            aad.extend(
+                [
+                    &[0],
+                    ty.service.as_bytes(),
+                    &[0],
+                    ty.method.as_bytes(),
+                    &[0],
+                    kind,
+                ]
+                .concat(),
+            );
+        }
+        aad
+    }
+
+    fn decrypt(&self, ty: Option<MessageType<'_>>, body: Bytes) -> Result<Bytes, GenericError> {
+        let (version, rest) = body.split_first().ok_or("empty body")?;
+        if *version != VERSION {
+            return Err(format!("unsupported encryption version {version}").into());
+        }
+        let (key_id_len, rest) = rest.split_first().ok_or("truncated body")?;
+        let key_id_len = *key_id_len as usize;
+        if rest.len() < key_id_len + NONCE_LEN {
+            return Err("truncated body".into());
+        }
+        let (key_id, rest) = rest.split_at(key_id_len);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let cipher = std::str::from_utf8(key_id)
+            .ok()
+            .and_then(|key_id| self.keys.get(key_id))
+            .ok_or_else(|| format!("unknown key id {:?}", String::from_utf8_lossy(key_id)))?;
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &self.aad(key_id, ty),
+        };
+        let message = cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| "body could not be decrypted")?;
+        Ok(message.into())
+    }
+
+    fn encrypt(&self, ty: Option<MessageType<'_>>, message: Bytes) -> Result<Bytes, GenericError> {
+        let cipher = &self.keys[&self.key_id];
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &message,
+            aad: &self.aad(self.key_id.as_bytes(), ty),
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| "message could not be encrypted")?;
+
+        let mut body =
+            BytesMut::with_capacity(2 + self.key_id.len() + NONCE_LEN + ciphertext.len());
+        body.put_u8(VERSION);
+        body.put_u8(self.key_id.len() as u8);
+        body.put_slice(self.key_id.as_bytes());
+        body.put_slice(&nonce);
+        body.put_slice(&ciphertext);
+        Ok(body.freeze())
+    }
+}
+
+impl<C> std::fmt::Debug for Encrypted<C>
+where
+    C: std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut key_ids: Vec<_> = self.keys.keys().collect();
+        key_ids.sort();
+        f.debug_struct("Encrypted")
+            .field("inner", &self.inner)
+            .field("content_type", &self.content_type)
+            .field("key_id", &self.key_id)
+            .field("key_ids", &key_ids)
+            .finish()
+    }
+}
+
+impl<C: Codec> Codec for Encrypted<C> {
+    fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    fn format(&self) -> Format {
+        self.inner.format()
+    }
+
+    fn decode(&self, body: Bytes) -> Result<Bytes, GenericError> {
+        self.inner.decode(self.decrypt(None, body)?)
+    }
+
+    fn encode(&self, message: Bytes) -> Result<Bytes, GenericError> {
+        self.encrypt(None, self.inner.encode(message)?)
+    }
+
+    fn decode_as(&self, ty: MessageType<'_>, body: Bytes) -> Result<Bytes, GenericError> {
+        self.inner.decode_as(ty, self.decrypt(Some(ty), body)?)
+    }
+
+    fn encode_as(&self, ty: MessageType<'_>, message: Bytes) -> Result<Bytes, GenericError> {
+        self.encrypt(Some(ty), self.inner.encode_as(ty, message)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::codec::{decode_message, encode_message, JsonCodec, MessageType, ProtobufCodec};
+    use crate::test::*;
+    use crate::ClientBuilder;
+
+    const PING: MessageType<'static> = MessageType::request("test.TestAPI", "Ping");
+
+    fn ping() -> PingRequest {
+        PingRequest {
+            name: "secret".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let codec = Encrypted::new(JsonCodec, "a", [1; 32]);
+        assert_eq!(codec.content_type(), "application/vnd.twirp.encrypted+json");
+        let body = encode_message(&codec, PING, ping()).unwrap();
+        assert!(!body.windows(6).any(|w| w == b"secret"));
+        let decoded: PingRequest = decode_message(&codec, PING, body.clone()).unwrap();
+        assert_eq!(decoded, ping());
+
+        // Each body has its own nonce.
+        assert_ne!(encode_message(&codec, PING, ping()).unwrap(), body);
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let old = Encrypted::new(ProtobufCodec, "old", [1; 32]);
+        let new = Encrypted::new(ProtobufCodec, "new", [2; 32]).decryption_key("old", [1; 32]);
+        let body = encode_message(&old, PING, ping()).unwrap();
+        let decoded: PingRequest = decode_message(&new, PING, body).unwrap();
+        assert_eq!(decoded, ping());
+
+        let body = encode_message(&new, PING, ping()).unwrap();
+        let err = decode_message::<PingRequest>(&old, PING, body).unwrap_err();
+        assert_eq!(err.to_string(), r#"unknown key id "new""#);
+    }
+
+    #[test]
+    fn test_tampering() {
+        let codec = Encrypted::new(ProtobufCodec, "a", [1; 32]);
+        let body = encode_message(&codec, PING, ping()).unwrap();
+
+        let mut tampered = body.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decode_message::<PingRequest>(&codec, PING, tampered.into()).is_err());
+
+        let wrong_key = Encrypted::new(ProtobufCodec, "a", [2; 32]);
+        assert!(decode_message::<PingRequest>(&wrong_key, PING, body.clone()).is_err());
+
+        // The content type is authenticated too.
+        let other =
+            Encrypted::new(ProtobufCodec, "a", [1; 32]).with_content_type("application/other");
+        assert!(decode_message::<PingRequest>(&other, PING, body.clone()).is_err());
+
+        // So is the type of the message: a request can't be replayed as the response, or as the
+        // request of another method.
+        let response = MessageType::response("test.TestAPI", "Ping");
+        assert!(decode_message::<PingRequest>(&codec, response, body.clone()).is_err());
+        let response_body = encode_message(&codec, response, ping()).unwrap();
+        assert!(decode_message::<PingRequest>(&codec, PING, response_body).is_err());
+        let other_method = MessageType::request("test.TestAPI", "Wear");
+        assert!(decode_message::<PingRequest>(&codec, other_method, body.clone()).is_err());
+
+        for len in 0..body.len() {
+            assert!(decode_message::<PingRequest>(&codec, PING, body.slice(..len)).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_router_and_client() {
+        let codec = || Encrypted::new(ProtobufCodec, "a", [1; 32]);
+        let router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder().codec(codec()).build(),
+        );
+        let server = spawn_server(router).await;
+
+        let client = ClientBuilder::new(server.url("/twirp/"), reqwest::Client::new())
+            .codec(codec())
+            .build()
+            .unwrap();
+        let resp = client.ping(ping()).await.unwrap();
+        assert_eq!(resp.name, "secret");
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_streaming() {
+        use futures::StreamExt;
+        use tower::Service;
+
+        use crate::details::TwirpRouterBuilder;
+        use crate::{Context, TwirpErrorResponse};
+
+        let codec = || Encrypted::new(ProtobufCodec, "a", [1; 32]);
+        let router = move || {
+            let router = TwirpRouterBuilder::new("/test.TestAPI", Arc::new(TestApiServer))
+                .codec(codec())
+                .codec(Encrypted::new(JsonCodec, "a", [1; 32]))
+                .route_server_streaming(
+                    "/Count",
+                    |_api: Arc<TestApiServer>, _ctx: Context, req: PingRequest| async move {
+                        let names = (1..=2).map(move |i| PingResponse {
+                            name: format!("{}-{i}", req.name),
+                        });
+                        Ok::<_, TwirpErrorResponse>(futures::stream::iter(names.map(Ok)))
+                    },
+                )
+                .build();
+            axum::Router::new().nest("/twirp/test.TestAPI", router)
+        };
+
+        // Each frame is encrypted, the trailer included.
+        let body = encode_message(
+            &codec(),
+            MessageType::request("test.TestAPI", "Count"),
+            ping(),
+        );
+        let req = http::Request::post("/twirp/test.TestAPI/Count")
+            .header(http::header::CONTENT_TYPE, codec().content_type())
+            .body(axum::body::Body::from(body.unwrap()))
+            .unwrap();
+        let resp = router().call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{resp:?}");
+        let body = http_body_util::BodyExt::collect(resp.into_body())
+            .await
+            .unwrap()
+            .to_bytes();
+        assert!(!body.windows(6).any(|w| w == b"secret"));
+        assert!(!body.ends_with(&[1, 0, 0, 0, 0]), "plaintext trailer");
+
+        let server = spawn_server(router()).await;
+        let client = ClientBuilder::new(server.url("/twirp/"), reqwest::Client::new())
+            .codec(codec())
+            .build()
+            .unwrap();
+        let names: Vec<_> = client
+            .request_stream::<_, PingResponse>("test.TestAPI/Count", ping())
+            .await
+            .unwrap()
+            .map(|res| res.unwrap().name)
+            .collect()
+            .await;
+        assert_eq!(names, ["secret-1", "secret-2"]);
+        server.shutdown().await.unwrap();
+
+        // Server-sent events can't be encrypted.
+        let codec = Encrypted::new(JsonCodec, "a", [1; 32]);
+        let body = encode_message(
+            &codec,
+            MessageType::request("test.TestAPI", "Count"),
+            ping(),
+        );
+        let req = http::Request::post("/twirp/test.TestAPI/Count")
+            .header(http::header::CONTENT_TYPE, codec.content_type())
+            .body(axum::body::Body::from(body.unwrap()))
+            .unwrap();
+        let resp = router().call(req).await.unwrap();
+        assert_eq!(resp.status(), 400);
+    }
+}
@@ -1,12 +1,24 @@
 //! Implement [Twirp](https://twitchtv.github.io/twirp/) error responses
 
-use std::collections::HashMap;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use axum::body::Body;
 use axum::response::IntoResponse;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
 use http::header::{self, HeaderMap, HeaderValue};
 use hyper::{Response, StatusCode};
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ClientError;
 
 /// Trait for user-defined error types that can be converted to Twirp responses.
 pub trait IntoTwirpResponse {
@@ -47,42 +59,61 @@ macro_rules! twirp_error_codes {
             ($konst:ident, $num:expr, $phrase:ident);
         )+
     ) => {
-        /// A Twirp error code as defined by <https://twitchtv.github.io/twirp/docs/spec_v7.html>.
-        #[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
-        #[serde(field_identifier, rename_all = "snake_case")]
+        /// A Twirp error code as defined by <https://twitchtv.github.io/twirp/docs/spec_v7.html>,
+        /// or an application-defined [custom code](CustomErrorCode).
+        #[derive(Clone, Debug, PartialEq, Eq)]
         #[non_exhaustive]
         pub enum TwirpErrorCode {
             $(
                 $(#[$docs])*
                 $konst,
             )+
+            /// A code that is not part of the Twirp spec.
+            Custom(CustomErrorCode),
         }
 
         impl TwirpErrorCode {
             pub fn http_status_code(&self) -> StatusCode {
-                match *self {
+                match self {
                     $(
                         TwirpErrorCode::$konst => $num,
                     )+
+                    TwirpErrorCode::Custom(code) => code.http_status_code(),
+                }
+            }
+
+            pub fn twirp_code(&self) -> &str {
+                match self {
+                    TwirpErrorCode::Custom(code) => code.name(),
+                    code => code.spec_code().unwrap_or_default(),
                 }
             }
 
-            pub fn twirp_code(&self) -> &'static str {
-                match *self {
+            // The name of a code from the spec. Custom codes have none.
+            pub(crate) fn spec_code(&self) -> Option<&'static str> {
+                match self {
                     $(
-                        TwirpErrorCode::$konst => stringify!($phrase),
+                        TwirpErrorCode::$konst => Some(stringify!($phrase)),
                     )+
+                    TwirpErrorCode::Custom(_) => None,
+                }
+            }
+
+            /// The code with the given string form. Codes that are neither in the spec nor
+            /// [registered](register_error_code) are kept as custom codes with status 500.
+            pub fn from_twirp_code(code: &str) -> Self {
+                match code {
+                    $(
+                        stringify!($phrase) => TwirpErrorCode::$konst,
+                    )+
+                    code => custom_error_code(code),
                 }
             }
         }
 
         $(
         pub fn $phrase<T: ToString>(msg: T) -> TwirpErrorResponse {
-            TwirpErrorResponse {
-                code: TwirpErrorCode::$konst,
-                msg: msg.to_string(),
-                meta: Default::default(),
-            }
+            TwirpErrorResponse::new(TwirpErrorCode::$konst, msg)
         }
         )+
     }
@@ -158,6 +189,88 @@ twirp_error_codes! {
     (Dataloss, StatusCode::INTERNAL_SERVER_ERROR, dataloss);
 }
 
+impl TwirpErrorCode {
+    /// Whether a request that failed with this code may succeed if it is retried (with a backoff).
+    /// Errors with other codes won't go away by retrying the same request.
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            *self,
+            TwirpErrorCode::Unavailable
+                | TwirpErrorCode::ResourceExhausted
+                | TwirpErrorCode::Aborted
+        )
+    }
+
+    /// Whether the error is the server's fault (e.g. `internal`, `unavailable`). This is the
+    /// opposite of [`is_client_error`](Self::is_client_error).
+    pub fn is_server_error(&self) -> bool {
+        match self {
+            TwirpErrorCode::Custom(code) => code.http_status_code().is_server_error(),
+            code => matches!(
+                code,
+                TwirpErrorCode::Unknown
+                    | TwirpErrorCode::DeadlineExceeded
+                    | TwirpErrorCode::Unimplemented
+                    | TwirpErrorCode::Internal
+                    | TwirpErrorCode::Unavailable
+                    | TwirpErrorCode::Dataloss
+            ),
+        }
+    }
+
+    /// Whether the error is caused by the request (e.g. `invalid_argument`, `not_found`).
+    pub fn is_client_error(&self) -> bool {
+        !self.is_server_error()
+    }
+
+    /// The closest gRPC status code (e.g. `5` for `not_found`).
+    pub fn to_grpc_status(&self) -> i32 {
+        match *self {
+            TwirpErrorCode::Canceled => 1,
+            TwirpErrorCode::Unknown | TwirpErrorCode::Custom(_) => 2,
+            TwirpErrorCode::InvalidArgument | TwirpErrorCode::Malformed => 3,
+            TwirpErrorCode::DeadlineExceeded => 4,
+            TwirpErrorCode::NotFound => 5,
+            TwirpErrorCode::AlreadyExists => 6,
+            TwirpErrorCode::PermissionDenied => 7,
+            TwirpErrorCode::ResourceExhausted => 8,
+            TwirpErrorCode::FailedPrecondition => 9,
+            TwirpErrorCode::Aborted => 10,
+            TwirpErrorCode::OutOfRange => 11,
+            TwirpErrorCode::Unimplemented | TwirpErrorCode::BadRoute => 12,
+            TwirpErrorCode::Internal => 13,
+            TwirpErrorCode::Unavailable => 14,
+            TwirpErrorCode::Dataloss => 15,
+            TwirpErrorCode::Unauthenticated => 16,
+        }
+    }
+
+    /// The Twirp error code for a gRPC status code. `OK` (`0`) is not an error and gives `None`;
+    /// codes that gRPC doesn't define are `unknown`.
+    pub fn from_grpc_status(code: i32) -> Option<Self> {
+        let code = match code {
+            0 => return None,
+            1 => TwirpErrorCode::Canceled,
+            3 => TwirpErrorCode::InvalidArgument,
+            4 => TwirpErrorCode::DeadlineExceeded,
+            5 => TwirpErrorCode::NotFound,
+            6 => TwirpErrorCode::AlreadyExists,
+            7 => TwirpErrorCode::PermissionDenied,
+            8 => TwirpErrorCode::ResourceExhausted,
+            9 => TwirpErrorCode::FailedPrecondition,
+            10 => TwirpErrorCode::Aborted,
+            11 => TwirpErrorCode::OutOfRange,
+            12 => TwirpErrorCode::Unimplemented,
+            13 => TwirpErrorCode::Internal,
+            14 => TwirpErrorCode::Unavailable,
+            15 => TwirpErrorCode::Dataloss,
+            16 => TwirpErrorCode::Unauthenticated,
+            _ => TwirpErrorCode::Unknown,
+        };
+        Some(code)
+    }
+}
+
 impl Serialize for TwirpErrorCode {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -167,26 +280,647 @@ impl Serialize for TwirpErrorCode {
     }
 }
 
-// Twirp error responses are always JSON
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+impl<'de> Deserialize<'de> for TwirpErrorCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let code = std::borrow::Cow::<'de, str>::deserialize(deserializer)?;
+        Ok(TwirpErrorCode::from_twirp_code(&code))
+    }
+}
+
+/// An error code that is not part of the Twirp spec, for organizations with their own error
+/// taxonomy. Custom codes are sent like the standard ones, as their name. Both servers and clients
+/// [register](register_error_code) them with their HTTP status; unregistered codes use 500.
+///
+/// ```
+/// use http::StatusCode;
+/// use twirp::{CustomErrorCode, TwirpErrorCode, TwirpErrorResponse};
+///
+/// const PAYMENT_REQUIRED: CustomErrorCode = CustomErrorCode::new("payment_required");
+///
+/// twirp::register_error_code(PAYMENT_REQUIRED, StatusCode::PAYMENT_REQUIRED);
+/// let err = TwirpErrorResponse::new(TwirpErrorCode::Custom(PAYMENT_REQUIRED), "out of credits");
+/// assert_eq!(err.code.http_status_code(), 402);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomErrorCode {
+    name: Cow<'static, str>,
+}
+
+impl CustomErrorCode {
+    /// A code sent as `name`. `name` should be `snake_case` and must not be one of the codes in
+    /// the spec.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name: Cow::Borrowed(name),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn http_status_code(&self) -> StatusCode {
+        CUSTOM_CODES
+            .read()
+            .expect("lock poisoned")
+            .get(self.name())
+            .copied()
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+// The registered custom codes, by name, with their status.
+static CUSTOM_CODES: RwLock<BTreeMap<Cow<'static, str>, StatusCode>> = RwLock::new(BTreeMap::new());
+
+/// Register a custom error code with its HTTP status. Registering a code again replaces its
+/// status.
+pub fn register_error_code(code: CustomErrorCode, status: StatusCode) {
+    CUSTOM_CODES
+        .write()
+        .expect("lock poisoned")
+        .insert(code.name, status);
+}
+
+// A code that isn't in the spec, as received from a peer. Unregistered codes are kept as they
+// are, without a status.
+fn custom_error_code(name: &str) -> TwirpErrorCode {
+    if name.is_empty() {
+        return TwirpErrorCode::Unknown;
+    }
+    let codes = CUSTOM_CODES.read().expect("lock poisoned");
+    let name = match codes.get_key_value(name) {
+        Some((Cow::Borrowed(name), _)) => Cow::Borrowed(*name),
+        _ => Cow::Owned(name.to_string()),
+    };
+    TwirpErrorCode::Custom(CustomErrorCode { name })
+}
+
+/// The meta key that error details (see [`TwirpErrorResponse::with_detail`]) are sent under.
+pub const DETAILS_META_KEY: &str = "twirp_details";
+
+/// A Twirp error, sent as the JSON body of error responses.
+///
+/// Besides the fields that are sent, an error holds server-side details (an HTTP status override,
+/// its source and backtrace), so it can't be built with a struct literal: use
+/// [`TwirpErrorResponse::new`], the constructor functions (e.g. [`not_found`]) or
+/// [`TwirpErrorResponse::builder`], and add meta values with
+/// [`with_meta`](TwirpErrorResponse::with_meta). This is a breaking change in 0.8: earlier
+/// versions only had the public fields, and code that built errors with a struct literal has to
+/// switch to one of these.
+#[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct TwirpErrorResponse {
     pub code: TwirpErrorCode,
     pub msg: String,
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     #[serde(default)]
+    #[cfg_attr(
+        feature = "json-meta",
+        serde(deserialize_with = "deserialize_json_meta")
+    )]
     pub meta: HashMap<String, String>,
+    /// Overrides the HTTP status that `code` maps to.
+    #[serde(skip)]
+    http_status: Option<StatusCode>,
+    /// Server-side details, never sent to the client. Boxed, as most errors don't have any.
+    #[serde(skip)]
+    internals: Option<Box<Internals>>,
+}
+
+#[derive(Debug, Default)]
+struct Internals {
+    /// The underlying cause, for server-side error reporting.
+    source: Option<GenericError>,
+    /// Where an `internal` error was created, if backtraces are enabled.
+    backtrace: Option<Backtrace>,
+}
+
+// Backtraces are only captured when enabled with `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+fn capture_backtrace() -> Option<Backtrace> {
+    let backtrace = Backtrace::capture();
+    (backtrace.status() == BacktraceStatus::Captured).then_some(backtrace)
+}
+
+// Only the fields sent in the body take part in comparisons.
+impl PartialEq for TwirpErrorResponse {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code && self.msg == other.msg && self.meta == other.meta
+    }
+}
+
+impl Eq for TwirpErrorResponse {}
+
+impl fmt::Display for TwirpErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.twirp_code(), self.msg)
+    }
+}
+
+impl std::error::Error for TwirpErrorResponse {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.internals
+            .as_ref()?
+            .source
+            .as_deref()
+            .map(|source| source as &(dyn std::error::Error + 'static))
+    }
 }
 
 impl TwirpErrorResponse {
+    /// An error with the given code and message. For a fixed code, the constructor functions
+    /// (e.g. [`not_found`]) or the [`twirp_error!`](crate::twirp_error) macro are shorter.
+    ///
+    /// `internal` errors capture a backtrace when backtraces are enabled (see
+    /// [`backtrace`](Self::backtrace)).
+    pub fn new<T: ToString>(code: TwirpErrorCode, msg: T) -> Self {
+        let internals = match code {
+            TwirpErrorCode::Internal => capture_backtrace().map(|backtrace| {
+                Box::new(Internals {
+                    source: None,
+                    backtrace: Some(backtrace),
+                })
+            }),
+            _ => None,
+        };
+        TwirpErrorResponse {
+            code,
+            msg: msg.to_string(),
+            meta: Default::default(),
+            http_status: None,
+            internals,
+        }
+    }
+
+    /// Start building an error with the given code, e.g.
+    /// `TwirpErrorResponse::builder(TwirpErrorCode::NotFound).msg("no such hat").build()`.
+    pub fn builder(code: TwirpErrorCode) -> TwirpErrorBuilder {
+        TwirpErrorBuilder {
+            err: TwirpErrorResponse::new(code, ""),
+        }
+    }
+
+    /// Split the error into its fields, including the ones that aren't public.
+    pub fn into_parts(self) -> TwirpErrorParts {
+        let internals = self
+            .internals
+            .map(|internals| *internals)
+            .unwrap_or_default();
+        TwirpErrorParts {
+            code: self.code,
+            msg: self.msg,
+            meta: self.meta,
+            http_status: self.http_status,
+            source: internals.source,
+            backtrace: internals.backtrace,
+        }
+    }
+
+    /// Put an error back together from its fields.
+    pub fn from_parts(parts: TwirpErrorParts) -> Self {
+        TwirpErrorResponse {
+            code: parts.code,
+            msg: parts.msg,
+            meta: parts.meta,
+            http_status: parts.http_status,
+            internals: (parts.source.is_some() || parts.backtrace.is_some()).then(|| {
+                Box::new(Internals {
+                    source: parts.source,
+                    backtrace: parts.backtrace,
+                })
+            }),
+        }
+    }
+
+    /// The error to return when a call to another Twirp service failed, using the default
+    /// [`UpstreamErrorPolicy`]: the upstream code is kept, but not its message or meta.
+    pub fn from_upstream(err: ClientError) -> Self {
+        UpstreamErrorPolicy::default().apply(err)
+    }
+
     pub fn insert_meta(&mut self, key: String, value: String) -> Option<String> {
         self.meta.insert(key, value)
     }
 
+    /// Add a meta value.
+    pub fn with_meta<K: ToString, V: ToString>(mut self, key: K, value: V) -> Self {
+        self.meta.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Add several meta values.
+    pub fn with_meta_iter<I, K, V>(mut self, meta: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: ToString,
+        V: ToString,
+    {
+        self.meta.extend(
+            meta.into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+        self
+    }
+
+    /// Add all of `meta`, replacing existing values with the same keys.
+    pub fn extend_meta(&mut self, meta: HashMap<String, String>) {
+        self.meta.extend(meta);
+    }
+
+    /// Add a meta value under a [typed key](MetaKey).
+    pub fn with_typed_meta<T: ToString>(mut self, key: &MetaKey<T>, value: T) -> Self {
+        self.meta.insert(key.name.to_string(), value.to_string());
+        self
+    }
+
+    /// Read the meta value under a [typed key](MetaKey). Returns `None` if there is no value or
+    /// it doesn't parse as a `T`.
+    pub fn typed_meta<T: FromStr>(&self, key: &MetaKey<T>) -> Option<T> {
+        self.meta.get(key.name)?.parse().ok()
+    }
+
+    /// Send the error with `status` instead of the HTTP status its code maps to, e.g. 499 for
+    /// requests the client gave up on. The code in the body is unchanged.
+    pub fn with_http_status(mut self, status: StatusCode) -> Self {
+        self.http_status = Some(status);
+        self
+    }
+
+    /// The HTTP status the error is sent with.
+    pub fn http_status(&self) -> StatusCode {
+        self.http_status
+            .unwrap_or_else(|| self.code.http_status_code())
+    }
+
+    /// Ask the client to wait at least `duration` before retrying the request. Sent as the
+    /// `retry_after` meta value and a `Retry-After` header, in whole seconds (rounded up).
+    pub fn with_retry_after(mut self, duration: Duration) -> Self {
+        self.meta.insert(
+            "retry_after".to_string(),
+            retry_after_secs(duration).to_string(),
+        );
+        self
+    }
+
+    /// How long the client should wait before retrying, if the server said so, read from the
+    /// `retry_after` meta value.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let secs = self.meta.get("retry_after")?.parse().ok()?;
+        Some(Duration::from_secs(secs))
+    }
+
+    /// Whether the request may succeed if it is retried: either the error code is
+    /// [retriable](TwirpErrorCode::is_retriable), or the server asked the client to retry after
+    /// some time.
+    pub fn retriable(&self) -> bool {
+        self.code.is_retriable() || self.retry_after().is_some()
+    }
+
+    /// Record the error that caused this one, so that error reporters can see the whole chain
+    /// through [`std::error::Error::source`]. The source is not sent to the client.
+    pub fn with_source<E: Into<GenericError>>(mut self, source: E) -> Self {
+        self.internals.get_or_insert_with(Default::default).source = Some(source.into());
+        self
+    }
+
+    /// Where this error was created, for `internal` errors created while backtraces were enabled
+    /// with `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`. Never sent to the client.
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.internals.as_ref()?.backtrace.as_ref()
+    }
+
+    /// The chain of [sources](Self::with_source) as one message (e.g. `"query failed: connection
+    /// reset"`), for logs. It is only sent to clients by routers with
+    /// [`debug_errors`](crate::details::TwirpRouterBuilder::debug_errors) enabled.
+    pub fn rust_error(&self) -> Option<String> {
+        let mut source = std::error::Error::source(self)?;
+        let mut chain = source.to_string();
+        while let Some(next) = source.source() {
+            chain.push_str(": ");
+            chain.push_str(&next.to_string());
+            source = next;
+        }
+        Some(chain)
+    }
+
+    /// Insert a structured meta value. The Twirp spec only allows strings in `meta`, so the value
+    /// is sent as JSON text (strings are sent as they are).
+    #[cfg(feature = "json-meta")]
+    pub fn insert_meta_json<T>(
+        &mut self,
+        key: String,
+        value: &T,
+    ) -> Result<Option<String>, serde_json::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let value = match serde_json::to_value(value)? {
+            serde_json::Value::String(s) => s,
+            value => value.to_string(),
+        };
+        Ok(self.meta.insert(key, value))
+    }
+
+    /// Read a meta value inserted with [`insert_meta_json`](Self::insert_meta_json), or sent as
+    /// structured JSON by a peer, as a `T`. Plain string values can be read as any `T` that
+    /// deserializes from a string.
+    #[cfg(feature = "json-meta")]
+    pub fn meta_json<T>(&self, key: &str) -> Result<Option<T>, serde_json::Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let Some(value) = self.meta.get(key) else {
+            return Ok(None);
+        };
+        match serde_json::from_str(value) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => T::deserialize(serde_json::Value::String(value.clone()))
+                .map(Some)
+                .map_err(|_| err),
+        }
+    }
+
+    /// Attach a protobuf message with machine-readable details about the error (e.g. the
+    /// `google.rpc` error detail messages). Details are sent base64-encoded under the
+    /// `twirp_details` meta key; an error can carry several of them.
+    pub fn with_detail<M: prost::Message>(mut self, detail: M) -> Self {
+        let mut details = self
+            .meta
+            .get(DETAILS_META_KEY)
+            .and_then(|d| STANDARD.decode(d).ok())
+            .unwrap_or_default();
+        details.extend(detail.encode_length_delimited_to_vec());
+        self.meta
+            .insert(DETAILS_META_KEY.to_string(), STANDARD.encode(details));
+        self
+    }
+
+    /// The details attached with [`with_detail`](Self::with_detail), decoded as `T`s.
+    pub fn details<T: prost::Message + Default>(&self) -> Result<Vec<T>, ClientError> {
+        let Some(details) = self.meta.get(DETAILS_META_KEY) else {
+            return Ok(vec![]);
+        };
+        let bytes = STANDARD.decode(details).map_err(|err| {
+            ClientError::MalformedResponse(format!("invalid {DETAILS_META_KEY}: {err}"))
+        })?;
+        let mut buf = &bytes[..];
+        let mut decoded = vec![];
+        while !buf.is_empty() {
+            decoded.push(T::decode_length_delimited(&mut buf)?);
+        }
+        Ok(decoded)
+    }
+
+    /// Serialize the error as the body of a response, after applying the
+    /// [global redactor](set_global_redactor), if any.
     pub fn into_axum_body(self) -> Body {
-        let json =
-            serde_json::to_string(&self).expect("JSON serialization of an error should not fail");
-        Body::new(json)
+        Body::from(self.into_json())
+    }
+
+    fn into_json(mut self) -> Bytes {
+        if let Some(redactor) = GLOBAL_REDACTOR.read().expect("lock poisoned").as_ref() {
+            redactor.redact(&mut self);
+        }
+        crate::serialize_json(&self).expect("JSON serialization of an error should not fail")
+    }
+
+    /// Serialize the error once, for an error that is sent over and over again (e.g. when
+    /// rejecting requests under load). The [global redactor](set_global_redactor) is applied now,
+    /// not when the response is sent.
+    pub fn preserialize(self) -> PreserializedError {
+        let (parts, err) = self.into_twirp_response().into_parts();
+        PreserializedError {
+            status: parts.status,
+            retry_after: parts.headers.get(header::RETRY_AFTER).cloned(),
+            body: err.into_json(),
+        }
+    }
+}
+
+/// An error response that has already been serialized, from
+/// [`TwirpErrorResponse::preserialize`]. Responses built from it share the body.
+#[derive(Clone, Debug)]
+pub struct PreserializedError {
+    status: StatusCode,
+    retry_after: Option<HeaderValue>,
+    body: Bytes,
+}
+
+impl PreserializedError {
+    /// Build a response with the error.
+    pub fn to_response(&self) -> Response<Body> {
+        let mut resp = Response::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+        let headers = resp.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        if let Some(retry_after) = &self.retry_after {
+            headers.insert(header::RETRY_AFTER, retry_after.clone());
+        }
+        resp
+    }
+}
+
+impl IntoResponse for PreserializedError {
+    fn into_response(self) -> Response<Body> {
+        self.to_response()
+    }
+}
+
+/// Builder for [`TwirpErrorResponse`], created by [`TwirpErrorResponse::builder`].
+#[derive(Debug)]
+pub struct TwirpErrorBuilder {
+    err: TwirpErrorResponse,
+}
+
+impl TwirpErrorBuilder {
+    pub fn msg<T: ToString>(mut self, msg: T) -> Self {
+        self.err.msg = msg.to_string();
+        self
+    }
+
+    /// See [`TwirpErrorResponse::with_meta`].
+    pub fn meta<K: ToString, V: ToString>(mut self, key: K, value: V) -> Self {
+        self.err = self.err.with_meta(key, value);
+        self
+    }
+
+    /// See [`TwirpErrorResponse::with_retry_after`].
+    pub fn retry_after(mut self, duration: Duration) -> Self {
+        self.err = self.err.with_retry_after(duration);
+        self
+    }
+
+    /// See [`TwirpErrorResponse::with_source`].
+    pub fn source<E: Into<GenericError>>(mut self, source: E) -> Self {
+        self.err = self.err.with_source(source);
+        self
+    }
+
+    /// See [`TwirpErrorResponse::with_http_status`].
+    pub fn http_status(mut self, status: StatusCode) -> Self {
+        self.err = self.err.with_http_status(status);
+        self
+    }
+
+    pub fn build(self) -> TwirpErrorResponse {
+        self.err
+    }
+}
+
+/// The fields of a [`TwirpErrorResponse`]; see [`TwirpErrorResponse::into_parts`].
+#[derive(Debug)]
+pub struct TwirpErrorParts {
+    pub code: TwirpErrorCode,
+    pub msg: String,
+    pub meta: HashMap<String, String>,
+    /// See [`TwirpErrorResponse::with_http_status`].
+    pub http_status: Option<StatusCode>,
+    /// See [`TwirpErrorResponse::with_source`].
+    pub source: Option<GenericError>,
+    /// See [`TwirpErrorResponse::backtrace`].
+    pub backtrace: Option<Backtrace>,
+}
+
+/// How [`TwirpErrorResponse::from_upstream`] passes on errors from other services. Upstream
+/// messages and meta may mention internal hosts and services, so by default only the code, the
+/// `retry_after` hint and the error details are passed on. The original error is kept as the
+/// [source](TwirpErrorResponse::with_source), for logs.
+///
+/// ```
+/// use twirp::{ClientError, UpstreamErrorPolicy};
+///
+/// let policy = UpstreamErrorPolicy::new().allow_meta("argument").keep_message(true);
+/// # let upstream = ClientError::TwirpError(twirp::invalid_argument("bad size"));
+/// let err = policy.apply(upstream);
+/// ```
+#[derive(Clone, Debug)]
+pub struct UpstreamErrorPolicy {
+    allowed_meta: Vec<String>,
+    keep_message: bool,
+}
+
+impl Default for UpstreamErrorPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_meta: vec!["retry_after".to_string(), DETAILS_META_KEY.to_string()],
+            keep_message: false,
+        }
+    }
+}
+
+impl UpstreamErrorPolicy {
+    pub fn new() -> Self {
+        Self::default()
     }
+
+    /// Also pass on the meta value `key`.
+    pub fn allow_meta<K: ToString>(mut self, key: K) -> Self {
+        self.allowed_meta.push(key.to_string());
+        self
+    }
+
+    /// Pass on upstream messages as they are, instead of replacing them with a generic message.
+    pub fn keep_message(mut self, keep_message: bool) -> Self {
+        self.keep_message = keep_message;
+        self
+    }
+
+    pub fn apply(&self, err: ClientError) -> TwirpErrorResponse {
+        let ClientError::TwirpError(upstream) = err else {
+            // Transport errors etc. don't carry anything from the upstream service.
+            return TwirpErrorResponse::from(err);
+        };
+        let msg = if self.keep_message {
+            upstream.msg.clone()
+        } else {
+            format!(
+                "upstream service failed with {}",
+                upstream.code.twirp_code()
+            )
+        };
+        let mut err = TwirpErrorResponse::new(upstream.code.clone(), msg);
+        for key in &self.allowed_meta {
+            if let Some(value) = upstream.meta.get(key) {
+                err.meta.insert(key.clone(), value.clone());
+            }
+        }
+        err.with_source(upstream)
+    }
+}
+
+/// A meta key with the type of its value, so that services and their clients agree on how a
+/// value is written and read. Usually defined as a constant:
+///
+/// ```
+/// use twirp::MetaKey;
+///
+/// const MAX_LEN: MetaKey<usize> = MetaKey::new("max_len");
+///
+/// let err = twirp::invalid_argument("name is too long").with_typed_meta(&MAX_LEN, 64);
+/// assert_eq!(err.typed_meta(&MAX_LEN), Some(64));
+/// ```
+pub struct MetaKey<T> {
+    name: &'static str,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> MetaKey<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T> fmt::Debug for MetaKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MetaKey").field(&self.name).finish()
+    }
+}
+
+/// A function that scrubs errors before they are sent to clients, e.g. to remove secrets or
+/// stack traces from messages. Applied to every error a server sends, either for all routers (see
+/// [`set_global_redactor`]) or for one (see
+/// [`TwirpRouterBuilder::redactor`](crate::details::TwirpRouterBuilder::redactor)).
+#[derive(Clone)]
+pub struct Redactor(Arc<dyn Fn(&mut TwirpErrorResponse) + Send + Sync>);
+
+impl Redactor {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&mut TwirpErrorResponse) + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub fn redact(&self, err: &mut TwirpErrorResponse) {
+        (self.0)(err)
+    }
+}
+
+impl fmt::Debug for Redactor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Redactor").finish_non_exhaustive()
+    }
+}
+
+// The redactor applied when serializing any error; see `set_global_redactor`.
+static GLOBAL_REDACTOR: RwLock<Option<Redactor>> = RwLock::new(None);
+
+/// Apply `redactor` to every error response serialized in this process, after any per-router
+/// redactor. Replaces the previous global redactor; `None` removes it.
+pub fn set_global_redactor(redactor: Option<Redactor>) {
+    *GLOBAL_REDACTOR.write().expect("lock poisoned") = redactor;
 }
 
 impl IntoTwirpResponse for TwirpErrorResponse {
@@ -197,20 +931,154 @@ impl IntoTwirpResponse for TwirpErrorResponse {
             HeaderValue::from_static("application/json"),
         );
 
-        let code = self.code.http_status_code();
+        if let Some(retry_after) = self.retry_after() {
+            headers.insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(retry_after.as_secs()),
+            );
+        }
+
+        let code = self.http_status();
         (code, headers).into_response().map(|_| self)
     }
 }
 
+// Peers that don't follow the spec strictly may send structured `meta` values; keep them as JSON
+// text so they can be read with `meta_json`.
+#[cfg(feature = "json-meta")]
+fn deserialize_json_meta<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let meta = HashMap::<String, serde_json::Value>::deserialize(deserializer)?;
+    Ok(meta
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(s) => (key, s),
+            value => (key, value.to_string()),
+        })
+        .collect())
+}
+
+// `Retry-After` is expressed in whole seconds; round up so clients never retry too early.
+pub(crate) fn retry_after_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
 impl IntoResponse for TwirpErrorResponse {
     fn into_response(self) -> Response<Body> {
         self.into_twirp_response().map(|err| err.into_axum_body())
     }
 }
 
+/// Build a [`TwirpErrorResponse`] from a [`TwirpErrorCode`] variant, a message with
+/// [`format!`] arguments, and optionally meta values.
+///
+/// ```
+/// use twirp::{twirp_error, TwirpErrorCode};
+///
+/// let id = 42;
+/// let err = twirp_error!(NotFound, "user {id} not found");
+/// assert_eq!(err.code, TwirpErrorCode::NotFound);
+///
+/// let err = twirp_error!(
+///     InvalidArgument, "{} is too long", "name";
+///     meta { "argument" => "name", "max_len" => 64 }
+/// );
+/// assert_eq!(err.msg, "name is too long");
+/// assert_eq!(err.meta["max_len"], "64");
+/// ```
+#[macro_export]
+macro_rules! twirp_error {
+    ($code:ident, $fmt:literal $($rest:tt)*) => {
+        $crate::__twirp_error!(@args $code, $fmt, [] $($rest)*)
+    };
+}
+
+// Collects the format arguments up to the optional `; meta { ... }` block.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __twirp_error {
+    (@args $code:ident, $fmt:literal, [$($args:tt)*] ; meta { $($key:expr => $value:expr),* $(,)? }) => {{
+        #[allow(unused_mut)]
+        let mut err = $crate::TwirpErrorResponse::new(
+            $crate::TwirpErrorCode::$code,
+            ::std::format!($fmt $($args)*),
+        );
+        $(
+            err.insert_meta(::std::string::ToString::to_string(&$key), ::std::string::ToString::to_string(&$value));
+        )*
+        err
+    }};
+    (@args $code:ident, $fmt:literal, [$($args:tt)*]) => {
+        $crate::TwirpErrorResponse::new($crate::TwirpErrorCode::$code, ::std::format!($fmt $($args)*))
+    };
+    (@args $code:ident, $fmt:literal, [$($args:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::__twirp_error!(@args $code, $fmt, [$($args)* $next] $($rest)*)
+    };
+}
+
+// Conversions for errors that handlers commonly run into, so they can be returned with `?`. The
+// original error is kept as the source; see `TwirpErrorResponse::rust_error`.
+impl From<std::io::Error> for TwirpErrorResponse {
+    fn from(err: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+
+        let twirp_err = match err.kind() {
+            ErrorKind::NotFound => not_found("not found"),
+            ErrorKind::PermissionDenied => permission_denied("permission denied"),
+            ErrorKind::AlreadyExists => already_exists("already exists"),
+            ErrorKind::InvalidInput => invalid_argument("invalid input"),
+            ErrorKind::TimedOut => deadline_exceeded("timed out"),
+            _ => internal("I/O error"),
+        };
+        twirp_err.with_source(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for TwirpErrorResponse {
+    fn from(err: tokio::task::JoinError) -> Self {
+        let twirp_err = if err.is_cancelled() {
+            canceled("task was cancelled")
+        } else {
+            internal("task panicked")
+        };
+        twirp_err.with_source(err)
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for TwirpErrorResponse {
+    fn from(err: tokio::time::error::Elapsed) -> Self {
+        deadline_exceeded("deadline exceeded").with_source(err)
+    }
+}
+
+#[cfg(feature = "tonic")]
+impl From<TwirpErrorResponse> for tonic::Status {
+    fn from(err: TwirpErrorResponse) -> Self {
+        tonic::Status::new(tonic::Code::from(err.code.to_grpc_status()), err.msg)
+    }
+}
+
+#[cfg(feature = "tonic")]
+impl From<tonic::Status> for TwirpErrorResponse {
+    fn from(status: tonic::Status) -> Self {
+        // An `OK` status shouldn't be converted to an error in the first place.
+        let code = TwirpErrorCode::from_grpc_status(status.code() as i32)
+            .unwrap_or(TwirpErrorCode::Unknown);
+        TwirpErrorResponse::new(code, status.message())
+    }
+}
+
+impl From<http::Error> for TwirpErrorResponse {
+    fn from(err: http::Error) -> Self {
+        internal("invalid HTTP message").with_source(err)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::{TwirpErrorCode, TwirpErrorResponse};
+    use crate::{IntoTwirpResponse, TwirpErrorCode, TwirpErrorResponse};
 
     #[test]
     fn twirp_status_mapping() {
@@ -247,11 +1115,7 @@ mod test {
 
     #[test]
     fn twirp_error_response_serialization() {
-        let response = TwirpErrorResponse {
-            code: TwirpErrorCode::DeadlineExceeded,
-            msg: "test".to_string(),
-            meta: Default::default(),
-        };
+        let response = TwirpErrorResponse::new(TwirpErrorCode::DeadlineExceeded, "test");
 
         let result = serde_json::to_string(&response).unwrap();
         assert!(result.contains(r#""code":"deadline_exceeded""#));
@@ -260,4 +1124,370 @@ mod test {
         let result = serde_json::from_str(&result).unwrap();
         assert_eq!(response, result);
     }
+
+    #[test]
+    fn twirp_error_response_retry_after() {
+        let response = crate::unavailable("try later")
+            .with_retry_after(std::time::Duration::from_millis(1500))
+            .into_twirp_response();
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "2");
+        assert_eq!(response.body().meta["retry_after"], "2");
+    }
+
+    #[test]
+    fn twirp_error_response_source() {
+        use std::error::Error;
+
+        let io_err = std::io::Error::new(std::io::ErrorKind::Other, "disk on fire");
+        let response = crate::internal("storage failed").with_source(io_err);
+        assert_eq!(response.to_string(), "internal: storage failed");
+        assert_eq!(response.source().unwrap().to_string(), "disk on fire");
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(!json.contains("disk on fire"));
+        let result: TwirpErrorResponse = serde_json::from_str(&json).unwrap();
+        assert!(result.source().is_none());
+        assert_eq!(response, result);
+    }
+
+    #[tokio::test]
+    async fn twirp_error_response_from_runtime_errors() {
+        use std::io;
+
+        let err = TwirpErrorResponse::from(io::Error::new(io::ErrorKind::NotFound, "no hat"));
+        assert_eq!(err.code, TwirpErrorCode::NotFound);
+        assert_eq!(err.rust_error().as_deref(), Some("no hat"));
+        assert!(err.meta.is_empty());
+        let err = TwirpErrorResponse::from(io::Error::other("oops"));
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+
+        let elapsed = tokio::time::timeout(
+            std::time::Duration::from_millis(1),
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            TwirpErrorResponse::from(elapsed).code,
+            TwirpErrorCode::DeadlineExceeded
+        );
+
+        let join_err = tokio::spawn(async { panic!("boom") }).await.unwrap_err();
+        assert_eq!(
+            TwirpErrorResponse::from(join_err).code,
+            TwirpErrorCode::Internal
+        );
+
+        let http_err = http::Request::builder()
+            .uri("not a uri")
+            .body(())
+            .unwrap_err();
+        assert_eq!(
+            TwirpErrorResponse::from(http_err).code,
+            TwirpErrorCode::Internal
+        );
+    }
+
+    #[test]
+    fn twirp_error_retriable() {
+        assert!(TwirpErrorCode::Unavailable.is_retriable());
+        assert!(!TwirpErrorCode::InvalidArgument.is_retriable());
+        assert!(TwirpErrorCode::InvalidArgument.is_client_error());
+        assert!(TwirpErrorCode::Internal.is_server_error());
+        assert!(!TwirpErrorCode::Internal.is_client_error());
+
+        assert!(!crate::internal("boom").retriable());
+        let err = crate::internal("boom").with_retry_after(std::time::Duration::from_secs(3));
+        assert!(err.retriable());
+
+        // The client only sees the meta value.
+        let json = serde_json::to_string(&err).unwrap();
+        let err: TwirpErrorResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(err.retry_after(), Some(std::time::Duration::from_secs(3)));
+        assert!(err.retriable());
+    }
+
+    #[tokio::test]
+    async fn twirp_error_response_global_redactor() {
+        use http_body_util::BodyExt;
+
+        crate::set_global_redactor(Some(crate::Redactor::new(|err| {
+            err.msg = err.msg.replace("s3cr3t", "***");
+        })));
+        let body = crate::internal("token s3cr3t is invalid").into_axum_body();
+        crate::set_global_redactor(None);
+
+        let bytes = body.collect().await.unwrap().to_bytes();
+        let err: TwirpErrorResponse = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(err.msg, "token *** is invalid");
+    }
+
+    #[tokio::test]
+    async fn twirp_error_response_preserialize() {
+        use std::time::Duration;
+
+        use axum::response::IntoResponse;
+        use http_body_util::BodyExt;
+
+        let err = || crate::unavailable("overloaded").with_retry_after(Duration::from_secs(5));
+        let preserialized = err().preserialize();
+        for resp in [preserialized.to_response(), preserialized.into_response()] {
+            let expected = err().into_response();
+            assert_eq!(resp.status(), expected.status());
+            assert_eq!(resp.headers(), expected.headers());
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let expected = expected.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, expected);
+        }
+    }
+
+    #[test]
+    fn twirp_error_response_bulk_meta() {
+        use std::collections::HashMap;
+
+        let mut err = crate::invalid_argument("bad request")
+            .with_meta("attempt", 3)
+            .with_meta_iter([("field", "name"), ("reason", "too long")]);
+        err.extend_meta(HashMap::from([("reason".to_string(), "empty".to_string())]));
+        assert_eq!(err.meta.len(), 3);
+        assert_eq!(err.meta["attempt"], "3");
+        assert_eq!(err.meta["reason"], "empty");
+
+        const ATTEMPT: crate::MetaKey<u32> = crate::MetaKey::new("attempt");
+        const FIELD: crate::MetaKey<u32> = crate::MetaKey::new("field");
+        assert_eq!(err.typed_meta(&ATTEMPT), Some(3));
+        assert_eq!(err.typed_meta(&FIELD), None);
+        let err = err.with_typed_meta(&ATTEMPT, 4);
+        assert_eq!(err.meta["attempt"], "4");
+    }
+
+    #[test]
+    fn twirp_custom_error_codes() {
+        use crate::CustomErrorCode;
+        use http::StatusCode;
+
+        const QUOTA: CustomErrorCode = CustomErrorCode::new("quota_exceeded");
+        let err = TwirpErrorResponse::new(TwirpErrorCode::Custom(QUOTA), "no credits");
+        let json = serde_json::to_string(&err).unwrap();
+        assert!(json.contains(r#""code":"quota_exceeded""#));
+
+        // Unregistered codes round-trip, but don't know their status.
+        let received: TwirpErrorResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(received.code, TwirpErrorCode::Custom(QUOTA));
+        assert_eq!(received.code.http_status_code(), 500);
+
+        crate::register_error_code(QUOTA, StatusCode::PAYMENT_REQUIRED);
+        let received: TwirpErrorResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(received.code.http_status_code(), 402);
+        assert!(received.code.is_client_error());
+
+        let received: TwirpErrorResponse =
+            serde_json::from_str(r#"{"code": "not_found", "msg": ""}"#).unwrap();
+        assert_eq!(received.code, TwirpErrorCode::NotFound);
+        let received: TwirpErrorResponse =
+            serde_json::from_str(r#"{"code": "", "msg": ""}"#).unwrap();
+        assert_eq!(received.code, TwirpErrorCode::Unknown);
+
+        // Unregistered codes are not interned, so there is no limit on how many are received.
+        for i in 0..1000 {
+            let code = TwirpErrorCode::from_twirp_code(&format!("peer_code_{i}"));
+            assert_eq!(code.twirp_code(), format!("peer_code_{i}"));
+        }
+        assert!(!super::CUSTOM_CODES
+            .read()
+            .unwrap()
+            .contains_key("peer_code_0"));
+    }
+
+    #[test]
+    fn twirp_error_macro() {
+        let id = 7;
+        let err = crate::twirp_error!(NotFound, "user {id} not found");
+        assert_eq!(err, crate::not_found("user 7 not found"));
+
+        let err = crate::twirp_error!(
+            NotFound, "user {} not found in {region}", id, region = "eu";
+            meta { "resource" => "user", "id" => id, }
+        );
+        assert_eq!(err.msg, "user 7 not found in eu");
+        assert_eq!(err.meta["resource"], "user");
+        assert_eq!(err.meta["id"], "7");
+
+        let err = crate::twirp_error!(Internal, "boom"; meta {});
+        assert_eq!(err, crate::internal("boom"));
+    }
+
+    #[test]
+    fn twirp_grpc_status_mapping() {
+        assert_eq!(TwirpErrorCode::NotFound.to_grpc_status(), 5);
+        assert_eq!(TwirpErrorCode::Malformed.to_grpc_status(), 3);
+        assert_eq!(TwirpErrorCode::BadRoute.to_grpc_status(), 12);
+        assert_eq!(TwirpErrorCode::from_grpc_status(0), None);
+        assert_eq!(
+            TwirpErrorCode::from_grpc_status(16),
+            Some(TwirpErrorCode::Unauthenticated)
+        );
+        assert_eq!(
+            TwirpErrorCode::from_grpc_status(99),
+            Some(TwirpErrorCode::Unknown)
+        );
+        for code in 1..=16 {
+            let twirp_code = TwirpErrorCode::from_grpc_status(code).unwrap();
+            assert_eq!(twirp_code.to_grpc_status(), code);
+        }
+    }
+
+    #[cfg(feature = "tonic")]
+    #[test]
+    fn twirp_error_response_tonic_status() {
+        let status = tonic::Status::from(crate::permission_denied("no hats for you"));
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert_eq!(status.message(), "no hats for you");
+
+        let err = TwirpErrorResponse::from(tonic::Status::unavailable("backend down"));
+        assert_eq!(err.code, TwirpErrorCode::Unavailable);
+        assert_eq!(err.msg, "backend down");
+    }
+
+    #[test]
+    fn twirp_error_response_http_status() {
+        let response = crate::canceled("client went away")
+            .with_http_status(http::StatusCode::from_u16(499).unwrap())
+            .into_twirp_response();
+        assert_eq!(response.status(), 499);
+        assert_eq!(response.body().code, TwirpErrorCode::Canceled);
+        assert_eq!(crate::canceled("").into_twirp_response().status(), 408);
+    }
+
+    #[test]
+    fn twirp_error_response_builder() {
+        use std::error::Error;
+
+        let err = TwirpErrorResponse::builder(TwirpErrorCode::Unavailable)
+            .msg("try later")
+            .meta("region", "eu")
+            .retry_after(std::time::Duration::from_secs(5))
+            .source(std::io::Error::other("overloaded"))
+            .build();
+        assert_eq!(err.msg, "try later");
+        assert_eq!(err.meta["region"], "eu");
+        assert_eq!(err.retry_after(), Some(std::time::Duration::from_secs(5)));
+        assert!(err.source().is_some());
+
+        let mut parts = err.into_parts();
+        assert_eq!(parts.code, TwirpErrorCode::Unavailable);
+        parts.http_status = Some(http::StatusCode::TOO_MANY_REQUESTS);
+        let err = TwirpErrorResponse::from_parts(parts);
+        assert_eq!(err.http_status(), 429);
+        assert_eq!(err.rust_error().as_deref(), Some("overloaded"));
+    }
+
+    #[test]
+    fn twirp_error_response_from_upstream() {
+        use crate::{ClientError, UpstreamErrorPolicy};
+
+        let upstream = || {
+            ClientError::TwirpError(
+                crate::not_found("no hat at db-7.internal")
+                    .with_meta("host", "db-7.internal")
+                    .with_meta("resource", "hat")
+                    .with_retry_after(std::time::Duration::from_secs(2)),
+            )
+        };
+        let err = TwirpErrorResponse::from_upstream(upstream());
+        assert_eq!(err.code, TwirpErrorCode::NotFound);
+        assert_eq!(err.msg, "upstream service failed with not_found");
+        assert_eq!(err.meta.len(), 1);
+        assert_eq!(err.meta["retry_after"], "2");
+        assert_eq!(
+            err.rust_error().as_deref(),
+            Some("not_found: no hat at db-7.internal")
+        );
+
+        let err = UpstreamErrorPolicy::new()
+            .allow_meta("resource")
+            .keep_message(true)
+            .apply(upstream());
+        assert_eq!(err.msg, "no hat at db-7.internal");
+        assert_eq!(err.meta["resource"], "hat");
+        assert!(!err.meta.contains_key("host"));
+    }
+
+    #[test]
+    fn twirp_error_response_backtrace() {
+        use std::backtrace::{Backtrace, BacktraceStatus};
+
+        // Whether backtraces are captured depends on the environment the tests run in.
+        let enabled = Backtrace::capture().status() == BacktraceStatus::Captured;
+        assert_eq!(crate::internal("boom").backtrace().is_some(), enabled);
+        assert!(crate::not_found("no hat").backtrace().is_none());
+
+        let parts = crate::internal("boom").into_parts();
+        assert_eq!(parts.backtrace.is_some(), enabled);
+        let err = TwirpErrorResponse::from_parts(parts);
+        assert_eq!(err.backtrace().is_some(), enabled);
+    }
+
+    #[test]
+    fn twirp_error_response_details() {
+        use crate::test::{PingRequest, PingResponse};
+
+        let response = crate::invalid_argument("bad name")
+            .with_detail(PingResponse {
+                name: "first".to_string(),
+            })
+            .with_detail(PingResponse {
+                name: "second".to_string(),
+            });
+        let json = serde_json::to_string(&response).unwrap();
+        let response: TwirpErrorResponse = serde_json::from_str(&json).unwrap();
+        let details: Vec<PingResponse> = response.details().unwrap();
+        assert_eq!(details.len(), 2);
+        assert_eq!(details[1].name, "second");
+        assert!(crate::internal("none")
+            .details::<PingRequest>()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[cfg(feature = "json-meta")]
+    #[test]
+    fn twirp_error_response_json_meta() {
+        let mut response = crate::invalid_argument("bad fields");
+        response
+            .insert_meta_json("fields".to_string(), &["name", "size"])
+            .unwrap();
+        response
+            .insert_meta_json("argument".to_string(), "size")
+            .unwrap();
+        response.insert_meta("limit".to_string(), "10".to_string());
+
+        // Meta values are always sent as strings.
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["meta"]["fields"], r#"["name","size"]"#);
+        assert_eq!(json["meta"]["argument"], "size");
+
+        assert_eq!(
+            response.meta_json::<Vec<String>>("fields").unwrap(),
+            Some(vec!["name".to_string(), "size".to_string()])
+        );
+        assert_eq!(
+            response.meta_json::<String>("argument").unwrap(),
+            Some("size".to_string())
+        );
+        assert_eq!(response.meta_json::<u32>("limit").unwrap(), Some(10));
+        assert_eq!(response.meta_json::<u32>("missing").unwrap(), None);
+        assert!(response.meta_json::<u32>("fields").is_err());
+
+        // Structured values from lenient peers are accepted.
+        let response: TwirpErrorResponse = serde_json::from_str(
+            r#"{"code": "internal", "msg": "boom", "meta": {"ids": [1, 2], "host": "a"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            response.meta_json::<Vec<u32>>("ids").unwrap(),
+            Some(vec![1, 2])
+        );
+        assert_eq!(response.meta["host"], "a");
+    }
 }
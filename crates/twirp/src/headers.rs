@@ -1,2 +1,20 @@
 pub(crate) const CONTENT_TYPE_PROTOBUF: &[u8] = b"application/protobuf";
 pub(crate) const CONTENT_TYPE_JSON: &[u8] = b"application/json";
+/// Used by some older (Twirp v5 era) clients in place of `application/protobuf`.
+pub(crate) const CONTENT_TYPE_X_PROTOBUF: &[u8] = b"application/x-protobuf";
+/// The time the client allows for a request, in milliseconds. See [`crate::Context::deadline`].
+pub(crate) const TWIRP_TIMEOUT: &str = "twirp-timeout";
+/// Which attempt at a call a request is, starting from 1, for clients that retry. See
+/// [`crate::Context::attempt`].
+pub const TWIRP_ATTEMPT: &str = "twirp-attempt";
+/// A key that stays the same across the attempts at a call, so that servers can recognize a retry
+/// of a call they already handled. See [`crate::Context::idempotency_key`].
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// W3C trace context headers, forwarded by [`crate::Context::propagation_headers`].
+pub(crate) const TRACEPARENT: &str = "traceparent";
+pub(crate) const TRACESTATE: &str = "tracestate";
+/// The tenant a request is made on behalf of, forwarded by [`crate::Context::propagation_headers`].
+pub(crate) const X_TENANT_ID: &str = "x-tenant-id";
+/// Response content types of server-streaming RPCs, see [`crate::stream`].
+pub(crate) const CONTENT_TYPE_STREAM_PROTOBUF: &str = "application/twirp-stream+protobuf";
+pub(crate) const CONTENT_TYPE_EVENT_STREAM: &str = "text/event-stream";
@@ -1,8 +1,38 @@
+//! Twirp servers and clients, built on axum and reqwest. Services and clients are generated from
+//! `.proto` files with `twirp-build`.
+//!
+//! # The `reflect` feature
+//!
+//! Some modules work from the descriptors of the services rather than from generated code:
+//! [`protojson`], [`server::dynamic`], [`server::openapi`] and [`server::validation`]. They take
+//! a [`DescriptorPool`](prost_reflect::DescriptorPool), usually decoded with
+//! [`DescriptorPool::decode`](prost_reflect::DescriptorPool::decode) from a file descriptor set
+//! written at build time by `prost_build::Config::file_descriptor_set_path` (or by `protoc
+//! --descriptor_set_out --include_imports`).
+
+pub mod buffer;
+#[cfg(feature = "checksum")]
+pub mod checksum;
 pub mod client;
+pub mod codec;
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+pub mod compression;
+#[cfg(feature = "connect")]
+pub mod connect;
 pub mod context;
+pub mod direct;
+#[cfg(feature = "encryption")]
+pub mod encryption;
 pub mod error;
 pub mod headers;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "reflect")]
+pub mod protojson;
 pub mod server;
+pub mod stream;
+
+mod swappable;
 
 #[cfg(any(test, feature = "test-support"))]
 pub mod test;
@@ -10,32 +40,88 @@ pub mod test;
 #[doc(hidden)]
 pub mod details;
 
+use bytes::{BufMut, Bytes, BytesMut};
+
 pub use client::{Client, ClientBuilder, ClientError, Middleware, Next, Result};
 pub use context::Context;
 pub use error::*; // many constructors like `invalid_argument()`
 pub use http::Extensions;
+pub use swappable::SwappableService;
 
 // Re-export this crate's dependencies that users are likely to code against. These can be used to
 // import the exact versions of these libraries `twirp` is built with -- useful if your project is
 // so sprawling that it builds multiple versions of some crates.
+#[cfg(feature = "actix")]
+pub use actix_web;
 pub use async_trait;
 pub use axum;
+pub use bytes;
+pub use http;
+#[cfg(feature = "otel")]
+pub use opentelemetry;
+#[cfg(feature = "prometheus")]
+pub use prometheus;
+#[cfg(feature = "proptest")]
+pub use proptest;
+#[cfg(feature = "reflect")]
+pub use prost_reflect;
 pub use reqwest;
+#[cfg(feature = "tonic")]
+pub use tonic;
 pub use tower;
+#[cfg(feature = "tracing")]
+pub use tracing;
 pub use url;
 
 /// Re-export of `axum::Router`, the type that encapsulates a server-side implementation of a Twirp
 /// service.
 pub use axum::Router;
 
-pub(crate) fn serialize_proto_message<T>(m: T) -> Vec<u8>
+/// The version of the Twirp protocol a client or server should stay compatible with.
+///
+/// The default, [`Compatibility::V7`], follows the current spec. [`Compatibility::V5`] makes it
+/// possible to serve (or call) Twirp v5 peers while a fleet is migrated:
+///
+/// - Servers accept `application/x-protobuf` as a protobuf content type and report `malformed`
+///   errors, which v5 clients don't know, as `invalid_argument`.
+/// - Clients accept `application/x-protobuf` responses and require the base URL to use the fixed
+///   `/twirp/` prefix that v5 servers are mounted under.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compatibility {
+    V5,
+    #[default]
+    V7,
+}
+
+pub(crate) fn serialize_proto_message<T>(m: T) -> Bytes
 where
     T: prost::Message,
 {
-    let len = m.encoded_len();
-    let mut data = Vec::with_capacity(len);
-    m.encode(&mut data)
-        .expect("can only fail if buffer does not have capacity");
-    assert_eq!(data.len(), len);
-    data
+    buffer::encode(m)
+}
+
+/// Parse a JSON message or error, with simd-json if the `simd-json` feature is enabled.
+pub(crate) fn deserialize_json<T>(data: Bytes) -> Result<T, GenericError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    #[cfg(feature = "simd-json")]
+    {
+        // simd-json parses in place; this only copies if `data` is shared.
+        let mut data = Vec::from(data);
+        Ok(simd_json::serde::from_slice(&mut data)?)
+    }
+    #[cfg(not(feature = "simd-json"))]
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Serialize `value` as JSON straight into a buffer, without an intermediate `String`.
+pub(crate) fn serialize_json<T>(value: &T) -> serde_json::Result<Bytes>
+where
+    T: serde::Serialize + ?Sized,
+{
+    let mut writer = BytesMut::new().writer();
+    serde_json::to_writer(&mut writer, value)?;
+    Ok(writer.into_inner().freeze())
 }
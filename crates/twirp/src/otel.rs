@@ -0,0 +1,507 @@
+//! OpenTelemetry traces and metrics for Twirp servers and clients, following the OpenTelemetry
+//! semantic conventions for RPC.
+//!
+//! On a server, [`OtelLayer`] starts a `SERVER` span for each request, as a child of the caller's
+//! span (and with its baggage) as extracted from the request headers by the global propagator.
+//! Handlers run with the span's context as the current OpenTelemetry context, which they can
+//! also get with [`Context::otel_context`](crate::Context::otel_context), e.g. for work they
+//! spawn:
+//!
+//! ```
+//! use twirp::otel::OtelLayer;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router) -> Router {
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .layer(OtelLayer::new());
+//! # app }
+//! ```
+//!
+//! On a client, the [`OtelClient`] middleware starts a `CLIENT` span for each call, as a child of
+//! the current context, and injects it into the request headers. Calls made while handling a
+//! request served behind an `OtelLayer` are therefore part of the same trace:
+//!
+//! ```
+//! use twirp::otel::OtelClient;
+//! use twirp::{Client, ClientBuilder};
+//!
+//! # fn build_client() -> twirp::Result<Client> {
+//! let base_url = twirp::url::Url::parse("http://localhost:3000/twirp/").unwrap();
+//! ClientBuilder::new(base_url, twirp::reqwest::Client::new())
+//!     .with(OtelClient::new())
+//!     .build()
+//! # }
+//! ```
+//!
+//! Spans are named `{package}.{Service}/{Method}` and have the `rpc.system` (`twirp`),
+//! `rpc.service` and `rpc.method` attributes, and `rpc.twirp.error_code` for errors. Server spans
+//! get an event for each step of the request's [`Timings`]. Server spans are marked as errors for
+//! server errors (e.g. `internal`), client spans for all errors. The duration of calls is
+//! recorded in the `rpc.server.duration` and `rpc.client.duration` histograms, in milliseconds.
+//!
+//! Both use the global tracer and meter providers and text map propagator, unless given
+//! providers with `with_providers`; with the defaults, installing an exporter is all it takes.
+//! Error codes are read from the bodies of error responses, which are buffered to do so.
+//!
+//! Requires the `otel` feature.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use futures::future::BoxFuture;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Request, Response};
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::metrics::{Histogram, MeterProvider};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{
+    FutureExt, SpanKind, SpanRef, Status, TraceContextExt, Tracer, TracerProvider,
+};
+use opentelemetry::KeyValue;
+use tokio::time::Instant;
+use tower::{Layer, Service};
+
+use crate::context::split_route;
+use crate::server::{read_error_code, try_read_error_code, Timings};
+use crate::{Middleware, Next, TwirpErrorCode};
+
+/// The name of the tracers and meters.
+const INSTRUMENTATION_NAME: &str = "twirp";
+/// The attribute of spans and metrics with the error code of failed calls.
+const ERROR_CODE: &str = "rpc.twirp.error_code";
+
+// The tracer and the duration histogram of a server or client.
+#[derive(Clone)]
+struct Instruments {
+    tracer: Arc<BoxedTracer>,
+    duration: Histogram<f64>,
+}
+
+impl Instruments {
+    fn new<T, M>(tracer_provider: &T, meter_provider: &M, histogram: &'static str) -> Self
+    where
+        T: TracerProvider,
+        T::Tracer: Send + Sync + 'static,
+        <T::Tracer as Tracer>::Span: Send + Sync + 'static,
+        M: MeterProvider,
+    {
+        let tracer = tracer_provider.tracer(INSTRUMENTATION_NAME);
+        let duration = meter_provider
+            .meter(INSTRUMENTATION_NAME)
+            .f64_histogram(histogram)
+            .with_unit("ms")
+            .with_description("The duration of RPCs.")
+            .build();
+        Self {
+            tracer: Arc::new(BoxedTracer::new(Box::new(tracer))),
+            duration,
+        }
+    }
+
+    fn global(histogram: &'static str) -> Self {
+        Self {
+            tracer: Arc::new(global::tracer(INSTRUMENTATION_NAME)),
+            duration: global::meter(INSTRUMENTATION_NAME)
+                .f64_histogram(histogram)
+                .with_unit("ms")
+                .with_description("The duration of RPCs.")
+                .build(),
+        }
+    }
+
+    // Start the span of a call, as a child of `parent`.
+    fn start(
+        &self,
+        parent: &opentelemetry::Context,
+        kind: SpanKind,
+        rpc: &Rpc,
+        mut attributes: Vec<KeyValue>,
+    ) -> opentelemetry::Context {
+        attributes.extend(rpc.attributes());
+        let span = self
+            .tracer
+            .span_builder(format!("{}/{}", rpc.service, rpc.method))
+            .with_kind(kind)
+            .with_attributes(attributes)
+            .start_with_context(self.tracer.as_ref(), parent);
+        parent.with_span(span)
+    }
+
+    // Record the outcome of a call in its span, which is ended, and its duration.
+    fn finish(
+        &self,
+        span: SpanRef<'_>,
+        rpc: &Rpc,
+        error: Option<(TwirpErrorCode, bool)>,
+        start: Instant,
+    ) {
+        let mut attributes = rpc.attributes();
+        if let Some((code, is_error)) = error {
+            let code = code.twirp_code().to_string();
+            attributes.push(KeyValue::new(ERROR_CODE, code.clone()));
+            span.set_attribute(KeyValue::new(ERROR_CODE, code.clone()));
+            if is_error {
+                span.set_status(Status::error(code));
+            }
+        }
+        span.end();
+        let duration = start.elapsed().as_secs_f64() * 1000.0;
+        self.duration.record(duration, &attributes);
+    }
+}
+
+impl std::fmt::Debug for Instruments {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Instruments").finish_non_exhaustive()
+    }
+}
+
+// The service and method of a call.
+struct Rpc {
+    service: String,
+    method: String,
+}
+
+impl Rpc {
+    fn from_path(path: &str) -> Self {
+        let (service, method) = split_route(path).unwrap_or_default();
+        Self {
+            service: service.to_string(),
+            method: method.to_string(),
+        }
+    }
+
+    fn attributes(&self) -> Vec<KeyValue> {
+        vec![
+            KeyValue::new("rpc.system", "twirp"),
+            KeyValue::new("rpc.service", self.service.clone()),
+            KeyValue::new("rpc.method", self.method.clone()),
+        ]
+    }
+}
+
+/// Layer that applies the [`Otel`] middleware. See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct OtelLayer {
+    instruments: Instruments,
+}
+
+impl Default for OtelLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OtelLayer {
+    /// Trace and measure requests with the global tracer and meter providers.
+    pub fn new() -> Self {
+        Self {
+            instruments: Instruments::global("rpc.server.duration"),
+        }
+    }
+
+    /// Trace and measure requests with the given providers.
+    pub fn with_providers<T, M>(tracer_provider: &T, meter_provider: &M) -> Self
+    where
+        T: TracerProvider,
+        T::Tracer: Send + Sync + 'static,
+        <T::Tracer as Tracer>::Span: Send + Sync + 'static,
+        M: MeterProvider,
+    {
+        Self {
+            instruments: Instruments::new(tracer_provider, meter_provider, "rpc.server.duration"),
+        }
+    }
+}
+
+impl<S> Layer<S> for OtelLayer {
+    type Service = Otel<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Otel {
+            inner,
+            instruments: self.instruments.clone(),
+        }
+    }
+}
+
+/// Middleware that traces and measures the requests served by the inner service.
+#[derive(Clone, Debug)]
+pub struct Otel<S> {
+    inner: S,
+    instruments: Instruments,
+}
+
+impl<S> Service<Request<Body>> for Otel<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let start = Instant::now();
+        let start_time = SystemTime::now();
+        let rpc = Rpc::from_path(req.uri().path());
+        let parent =
+            global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+        let mut attributes = vec![];
+        if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            attributes.push(KeyValue::new("client.address", addr.ip().to_string()));
+            attributes.push(KeyValue::new("client.port", i64::from(addr.port())));
+        }
+        let cx = self
+            .instruments
+            .start(&parent, SpanKind::Server, &rpc, attributes);
+        req.extensions_mut().insert(cx.clone());
+
+        let instruments = self.instruments.clone();
+        let fut = self.inner.call(req).with_context(cx.clone());
+        Box::pin(async move {
+            let resp = fut.await?;
+            let (resp, code) = read_error_code(resp).await;
+            let span = cx.span();
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                i64::from(resp.status().as_u16()),
+            ));
+            if let Some(timings) = resp.extensions().get::<Timings>() {
+                let steps = [
+                    ("twirp.received", timings.received()),
+                    ("twirp.parsed", timings.parsed()),
+                    ("twirp.response_handled", timings.response_handled()),
+                    ("twirp.response_written", timings.response_written()),
+                ];
+                for (name, elapsed) in steps {
+                    if let Some(elapsed) = elapsed {
+                        span.add_event_with_timestamp(name, start_time + elapsed, vec![]);
+                    }
+                }
+            }
+            let error = code.map(|code| {
+                let is_error = code.is_server_error();
+                (code, is_error)
+            });
+            instruments.finish(span, &rpc, error, start);
+            Ok(resp)
+        })
+    }
+}
+
+/// Client middleware that traces and measures calls, and propagates their context to the
+/// server. See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct OtelClient {
+    instruments: Instruments,
+}
+
+impl Default for OtelClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OtelClient {
+    /// Trace and measure calls with the global tracer and meter providers.
+    pub fn new() -> Self {
+        Self {
+            instruments: Instruments::global("rpc.client.duration"),
+        }
+    }
+
+    /// Trace and measure calls with the given providers.
+    pub fn with_providers<T, M>(tracer_provider: &T, meter_provider: &M) -> Self
+    where
+        T: TracerProvider,
+        T::Tracer: Send + Sync + 'static,
+        <T::Tracer as Tracer>::Span: Send + Sync + 'static,
+        M: MeterProvider,
+    {
+        Self {
+            instruments: Instruments::new(tracer_provider, meter_provider, "rpc.client.duration"),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for OtelClient {
+    async fn handle(
+        &self,
+        mut req: reqwest::Request,
+        next: Next<'_>,
+    ) -> crate::Result<reqwest::Response> {
+        let start = Instant::now();
+        let rpc = Rpc::from_path(req.url().path());
+        let mut attributes = vec![];
+        if let Some(host) = req.url().host_str() {
+            attributes.push(KeyValue::new("server.address", host.to_string()));
+        }
+        if let Some(port) = req.url().port_or_known_default() {
+            attributes.push(KeyValue::new("server.port", i64::from(port)));
+        }
+        let parent = opentelemetry::Context::current();
+        let cx = self
+            .instruments
+            .start(&parent, SpanKind::Client, &rpc, attributes);
+        global::get_text_map_propagator(|p| {
+            p.inject_context(&cx, &mut HeaderInjector(req.headers_mut()))
+        });
+
+        let resp = match next.run(req).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                cx.span().set_status(Status::error(err.to_string()));
+                let code = err
+                    .twirp_error()
+                    .map_or(TwirpErrorCode::Unavailable, |e| e.code.clone());
+                self.instruments
+                    .finish(cx.span(), &rpc, Some((code, true)), start);
+                return Err(err);
+            }
+        };
+        let (resp, code) = client_error_code(resp).await?;
+        self.instruments
+            .finish(cx.span(), &rpc, code.map(|code| (code, true)), start);
+        Ok(resp)
+    }
+}
+
+// Like `read_error_code`, for the responses of a client.
+async fn client_error_code(
+    resp: reqwest::Response,
+) -> crate::Result<(reqwest::Response, Option<TwirpErrorCode>)> {
+    use reqwest::ResponseBuilderExt;
+
+    let url = resp.url().clone();
+    let (resp, code) = try_read_error_code(http::Response::from(resp)).await;
+    // Converting back only keeps the URL if it is in the extensions.
+    let (mut parts, body) = resp.into_parts();
+    let with_url = http::Response::builder()
+        .url(url)
+        .body(())
+        .expect("valid response");
+    parts.extensions.extend(with_url.into_parts().0.extensions);
+    Ok((http::Response::from_parts(parts, body).into(), code?))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        let name = HeaderName::from_bytes(key.as_bytes());
+        let value = HeaderValue::from_str(&value);
+        if let (Ok(name), Ok(value)) = (name, value) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+    use super::*;
+    use crate::test::{spawn_server, test_api_router, PingRequest, TestApiClient};
+    use crate::ClientBuilder;
+
+    // Keeps the spans it exports.
+    #[derive(Clone, Debug, Default)]
+    struct Spans(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Spans {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    impl Spans {
+        fn find(&self, name: &str, kind: SpanKind) -> SpanData {
+            let spans = self.0.lock().unwrap();
+            let span = spans.iter().find(|s| s.name == name && s.span_kind == kind);
+            span.cloned().unwrap()
+        }
+    }
+
+    fn attribute<'a>(span: &'a SpanData, key: &str) -> Option<&'a opentelemetry::Value> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| &kv.value)
+    }
+
+    #[tokio::test]
+    async fn test_spans() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let spans = Spans::default();
+        let tracer_provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(spans.clone())
+            .build();
+        let meter_provider = SdkMeterProvider::default();
+
+        let router =
+            test_api_router().layer(OtelLayer::with_providers(&tracer_provider, &meter_provider));
+        let server = spawn_server(router).await;
+        let client = ClientBuilder::new(server.url("/twirp/"), reqwest::Client::new())
+            .with(OtelClient::with_providers(
+                &tracer_provider,
+                &meter_provider,
+            ))
+            .build()
+            .unwrap();
+        client.ping(PingRequest::default()).await.unwrap();
+        client.boom(PingRequest::default()).await.unwrap_err();
+        server.shutdown().await.unwrap();
+
+        // The server spans are children of the client spans.
+        let client_span = spans.find("test.TestAPI/Ping", SpanKind::Client);
+        let server_span = spans.find("test.TestAPI/Ping", SpanKind::Server);
+        assert_eq!(
+            server_span.span_context.trace_id(),
+            client_span.span_context.trace_id()
+        );
+        assert_eq!(
+            server_span.parent_span_id,
+            client_span.span_context.span_id()
+        );
+        assert_eq!(attribute(&server_span, "rpc.system"), Some(&"twirp".into()));
+        assert_eq!(attribute(&server_span, "rpc.method"), Some(&"Ping".into()));
+        assert_eq!(server_span.status, Status::Unset);
+        let events: Vec<_> = server_span.events.iter().map(|e| e.name.clone()).collect();
+        assert!(events.contains(&"twirp.parsed".into()));
+
+        for kind in [SpanKind::Client, SpanKind::Server] {
+            let span = spans.find("test.TestAPI/Boom", kind);
+            assert_eq!(attribute(&span, ERROR_CODE), Some(&"internal".into()));
+            assert_eq!(span.status, Status::error("internal"));
+        }
+    }
+}
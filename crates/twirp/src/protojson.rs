@@ -0,0 +1,219 @@
+//! Messages encoded as [proto3 JSON](https://protobuf.dev/programming-guides/json/), the JSON
+//! that the Go and TypeScript Twirp implementations read and write.
+//!
+//! The JSON that `JsonCodec` writes is what serde derives for the generated structs, which differs
+//! from proto3 JSON: 64-bit integers are numbers rather than strings, enums are numbers rather than
+//! names, and well-known types like `Timestamp` are objects. [`ProtoJsonCodec`] transcodes
+//! between proto3 JSON and protobuf with the descriptors of the services instead, so it works with
+//! any message. Add it to a router with
+//! [`TwirpRouterBuilder::codec`](crate::details::TwirpRouterBuilder::codec), where it takes over
+//! `application/json` requests, and to clients with [`ClientBuilder::codec`](crate::ClientBuilder::codec):
+//!
+//! ```
+//! use twirp::prost_reflect::DescriptorPool;
+//! use twirp::protojson::ProtoJsonCodec;
+//!
+//! # fn codec(descriptors: &[u8]) -> ProtoJsonCodec {
+//! let pool = DescriptorPool::decode(descriptors).expect("valid descriptors");
+//! let codec = ProtoJsonCodec::new(pool);
+//! # codec }
+//! ```
+//!
+//! Requests for methods that aren't in the descriptor pool fail to decode. Server-streaming
+//! responses are not transcoded.
+//!
+//! Requires the [`reflect` feature](crate#the-reflect-feature).
+
+use bytes::Bytes;
+use prost::Message;
+use prost_reflect::{
+    DescriptorPool, DeserializeOptions, DynamicMessage, MessageDescriptor, SerializeOptions,
+};
+
+use crate::codec::{Codec, Format, MessageType};
+use crate::headers::CONTENT_TYPE_JSON;
+use crate::GenericError;
+
+/// Messages encoded as proto3 JSON, with the content type `application/json`. See the
+/// [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct ProtoJsonCodec {
+    pool: DescriptorPool,
+    serialize: SerializeOptions,
+    deserialize: DeserializeOptions,
+}
+
+impl ProtoJsonCodec {
+    /// Transcode the messages of the methods in `pool`.
+    pub fn new(pool: DescriptorPool) -> Self {
+        Self {
+            pool,
+            serialize: SerializeOptions::new(),
+            // Like the Go implementation, ignore the fields of newer versions of the message.
+            deserialize: DeserializeOptions::new().deny_unknown_fields(false),
+        }
+    }
+
+    /// Write fields with their names in the `.proto` file rather than in lowerCamelCase, like Go
+    /// servers do by default. Both are accepted when reading.
+    pub fn use_proto_field_names(mut self, enabled: bool) -> Self {
+        self.serialize = self.serialize.use_proto_field_name(enabled);
+        self
+    }
+
+    /// Write fields that have their default value too, rather than leaving them out.
+    pub fn emit_defaults(mut self, enabled: bool) -> Self {
+        self.serialize = self.serialize.skip_default_fields(!enabled);
+        self
+    }
+
+    fn descriptor(&self, ty: MessageType<'_>) -> Result<MessageDescriptor, GenericError> {
+        let method = self
+            .pool
+            .get_service_by_name(ty.service)
+            .and_then(|service| service.methods().find(|m| m.name() == ty.method))
+            .ok_or_else(|| format!("no descriptor for {}/{}", ty.service, ty.method))?;
+        Ok(match ty.response {
+            true => method.output(),
+            false => method.input(),
+        })
+    }
+}
+
+impl Codec for ProtoJsonCodec {
+    fn content_type(&self) -> &str {
+        "application/json"
+    }
+
+    fn matches(&self, content_type: &[u8]) -> bool {
+        content_type == CONTENT_TYPE_JSON
+    }
+
+    fn format(&self) -> Format {
+        Format::Protobuf
+    }
+
+    fn decode(&self, _body: Bytes) -> Result<Bytes, GenericError> {
+        Err("proto3 JSON needs the type of the message".into())
+    }
+
+    fn encode(&self, _message: Bytes) -> Result<Bytes, GenericError> {
+        Err("proto3 JSON needs the type of the message".into())
+    }
+
+    fn decode_as(&self, ty: MessageType<'_>, body: Bytes) -> Result<Bytes, GenericError> {
+        let desc = self.descriptor(ty)?;
+        let mut deserializer = serde_json::Deserializer::from_slice(&body);
+        let message =
+            DynamicMessage::deserialize_with_options(desc, &mut deserializer, &self.deserialize)?;
+        deserializer.end()?;
+        Ok(message.encode_to_vec().into())
+    }
+
+    fn encode_as(&self, ty: MessageType<'_>, message: Bytes) -> Result<Bytes, GenericError> {
+        let message = DynamicMessage::decode(self.descriptor(ty)?, message)?;
+        let mut serializer = serde_json::Serializer::new(vec![]);
+        message.serialize_with_options(&mut serializer, &self.serialize)?;
+        Ok(serializer.into_inner().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_reflect::Value;
+
+    use super::*;
+    use crate::test::{
+        descriptor_pool, test_api_router_builder, PingRequest, PingResponse, TestServer,
+    };
+    use crate::{ClientBuilder, ClientError};
+
+    #[test]
+    fn test_transcode() {
+        let codec = ProtoJsonCodec::new(descriptor_pool());
+        let mut hat =
+            DynamicMessage::new(descriptor_pool().get_message_by_name("test.Hat").unwrap());
+        hat.set_field_by_name("size_inches", Value::I64(12));
+        let hat = Bytes::from(hat.encode_to_vec());
+
+        let json = codec
+            .encode_as(MessageType::response("test.TestAPI", "Wear"), hat.clone())
+            .unwrap();
+        assert_eq!(json, r#"{"sizeInches":"12"}"#);
+        let json = codec
+            .clone()
+            .use_proto_field_names(true)
+            .emit_defaults(true)
+            .encode_as(MessageType::response("test.TestAPI", "Wear"), hat.clone())
+            .unwrap();
+        // Fields without presence (`parts`) are written with their default value.
+        assert_eq!(json, r#"{"parts":[],"size_inches":"12"}"#);
+
+        let body = Bytes::from_static(br#"{"size_inches": 12, "brim": "wide"}"#);
+        let decoded = codec
+            .decode_as(MessageType::request("test.TestAPI", "Wear"), body)
+            .unwrap();
+        assert_eq!(decoded, hat);
+
+        let err = codec
+            .decode_as(MessageType::request("test.TestAPI", "Nope"), "{}".into())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no descriptor for test.TestAPI/Nope");
+        assert!(codec.decode("{}".into()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let codec = ProtoJsonCodec::new(descriptor_pool());
+        let router = test_api_router_builder().codec(codec.clone()).build();
+        let server = TestServer::new(axum::Router::new().nest("/twirp/test.TestAPI", router));
+        let client = ClientBuilder::new(TestServer::base_url(), reqwest::Client::new())
+            .codec(codec)
+            .with(server.transport())
+            .build()
+            .unwrap();
+
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp: PingResponse = client.request("test.TestAPI/Ping", req).await.unwrap();
+        assert_eq!(resp.name, "hi");
+
+        let err = client
+            .request::<_, PingResponse>("test.TestAPI/Boom", PingRequest::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ClientError::CodecError(_)), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn test_router_get() {
+        use std::sync::Arc;
+
+        use tower::ServiceExt;
+
+        use crate::details::TwirpRouterBuilder;
+        use crate::test::{read_string_body, TestApi, TestApiServer};
+
+        let router = TwirpRouterBuilder::new("/test.TestAPI", Arc::new(TestApiServer))
+            .route_no_side_effects(
+                "/Ping",
+                |api: Arc<TestApiServer>, ctx: crate::Context, req: PingRequest| async move {
+                    api.ping(ctx, req).await
+                },
+            )
+            .codec(ProtoJsonCodec::new(descriptor_pool()))
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+
+        // `GET ?json=` requests are read and answered with the codec too: proto3 JSON leaves out
+        // fields with default values.
+        for query in ["", "?json=%7B%7D"] {
+            let req = http::Request::get(format!("/twirp/test.TestAPI/Ping{query}"))
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let resp = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(read_string_body(resp.into_body()).await, "{}", "{query}");
+        }
+    }
+}
@@ -3,55 +3,435 @@
 //! There is not much to see in the documentation here. This API is meant to be used with
 //! `twirp-build`. See <https://github.com/github/twirp-rs#usage> for details and an example.
 
+use std::collections::BTreeSet;
 use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 use axum::body::Body;
+use axum::extract::{ConnectInfo, OriginalUri};
 use axum::response::IntoResponse;
+use bytes::Bytes;
 use futures::Future;
-use http::Extensions;
-use http_body_util::BodyExt;
+use http::{Extensions, HeaderName, HeaderValue, Method, StatusCode};
 use hyper::{header, Request, Response};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use tokio::time::{Duration, Instant};
 
-use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF};
-use crate::{error, serialize_proto_message, Context, GenericError, IntoTwirpResponse};
+use self::budget::Reservation;
+use self::hooks::RequestHooks;
+use crate::codec::{self, Codec, Format, JsonCodec, MessageType, ProtobufCodec};
+use crate::context::{
+    split_route, CancelOnDrop, Deadline, PeerInfo, RequestSpan, ResponseOverrides, RpcMethod,
+};
+use crate::direct::DirectRoute;
+use crate::headers::{
+    CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF, CONTENT_TYPE_X_PROTOBUF, TWIRP_TIMEOUT,
+};
+use crate::{
+    error, Compatibility, Context, GenericError, IntoTwirpResponse, Redactor, TwirpErrorCode,
+    TwirpErrorResponse,
+};
 
-// TODO: Properly implement JsonPb (de)serialization as it is slightly different
-// than standard JSON.
-#[derive(Debug, Clone, Copy, Default)]
+#[cfg(feature = "actix")]
+pub mod actix;
+pub mod admission;
+pub mod batch;
+pub mod budget;
+pub mod canary;
+pub mod drain;
+#[cfg(feature = "reflect")]
+pub mod dynamic;
+#[cfg(feature = "tonic")]
+pub mod grpc;
+pub mod grpc_web;
+pub mod hooks;
+pub mod maintenance;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+pub mod mirror;
+#[cfg(feature = "reflect")]
+pub mod openapi;
+pub mod proxy;
+mod raw;
+pub mod request_id;
+pub mod routes;
+
+pub(crate) use raw::handle_raw_request;
+pub use raw::{DecodeRequest, RawResponse};
+#[cfg(feature = "reflect")]
+pub mod validation;
+
+// NB: `JsonPb` is the JSON serde derives for the generated structs, which is slightly different
+// from proto3 JSON. Routers that add a `protojson::ProtoJsonCodec` use it for JSON instead.
+#[derive(Debug, Clone, Default)]
 enum BodyFormat {
     #[default]
     JsonPb,
     Pb,
+    /// A codec added to the router with `TwirpRouterBuilder::codec`.
+    Custom(Arc<dyn Codec>),
 }
 
 impl BodyFormat {
-    fn from_content_type(req: &Request<Body>) -> BodyFormat {
-        match req
+    fn from_content_type(req: &Request<Body>, config: &RouterConfig) -> BodyFormat {
+        let content_type = req
             .headers()
             .get(header::CONTENT_TYPE)
-            .map(|x| x.as_bytes())
+            .map(|x| x.as_bytes());
+        Self::for_content_type(content_type, config)
+    }
+
+    fn for_content_type(content_type: Option<&[u8]>, config: &RouterConfig) -> BodyFormat {
+        if let Some(codec) =
+            content_type.and_then(|ct| config.codecs.iter().find(|c| c.matches(ct)))
         {
+            return BodyFormat::Custom(codec.clone());
+        }
+        match content_type {
             Some(CONTENT_TYPE_PROTOBUF) => BodyFormat::Pb,
+            Some(CONTENT_TYPE_X_PROTOBUF) if config.compatibility == Compatibility::V5 => {
+                BodyFormat::Pb
+            }
             _ => BodyFormat::JsonPb,
         }
     }
+
+    fn codec(&self) -> &dyn Codec {
+        match self {
+            BodyFormat::JsonPb => &JsonCodec,
+            BodyFormat::Pb => &ProtobufCodec,
+            BodyFormat::Custom(codec) => codec.as_ref(),
+        }
+    }
+}
+
+/// Settings for the routes of one service, configured through
+/// [`TwirpRouterBuilder`](crate::details::TwirpRouterBuilder). Passed to the handlers as a request
+/// extension.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RouterConfig {
+    pub(crate) compatibility: Compatibility,
+    pub(crate) service_fqn: &'static str,
+    pub(crate) methods: Vec<&'static str>,
+    pub(crate) debug_errors: bool,
+    pub(crate) annotate_errors: bool,
+    pub(crate) redactor: Option<Redactor>,
+    pub(crate) codecs: Vec<Arc<dyn Codec>>,
+    pub(crate) memory_budget: Option<budget::MemoryBudget>,
+    pub(crate) max_request_size: Option<usize>,
+    pub(crate) blocking_serialization_threshold: Option<usize>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) hooks: hooks::Hooks,
+    #[cfg(feature = "zstd")]
+    pub(crate) zstd: Option<crate::compression::Zstd>,
+    #[cfg(feature = "gzip")]
+    pub(crate) gzip: Option<crate::compression::Gzip>,
+    #[cfg(feature = "checksum")]
+    pub(crate) checksums: Option<crate::checksum::Checksums>,
+}
+
+impl RouterConfig {
+    fn from_request(req: &Request<Body>) -> Arc<RouterConfig> {
+        req.extensions()
+            .get::<Arc<RouterConfig>>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Add the request's [`Deadline`] to its extensions: the shorter of the request's
+    /// `Twirp-Timeout` and the router's timeout, from when the request started.
+    fn set_deadline(&self, req: &mut Request<Body>, timings: &Timings) -> Option<Instant> {
+        let deadline = self.deadline(req.headers(), timings)?;
+        req.extensions_mut().insert(Deadline(deadline));
+        Some(deadline)
+    }
+
+    /// The deadline of a request with `headers`.
+    fn deadline(&self, headers: &http::HeaderMap, timings: &Timings) -> Option<Instant> {
+        let requested = headers
+            .get(TWIRP_TIMEOUT)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map(Duration::from_millis);
+        // Clients can shorten the router's timeout, but not extend it.
+        let timeout = match (requested, self.timeout) {
+            (Some(requested), Some(timeout)) => requested.min(timeout),
+            (requested, timeout) => requested.or(timeout)?,
+        };
+        Some(timings.start + timeout)
+    }
+
+    /// The request details to add to errors, if enabled.
+    fn error_context(&self, req: &Request<Body>) -> ErrorContext {
+        let method = split_route(original_uri(req).path())
+            .map(|(_, method)| method)
+            .unwrap_or_default();
+        self.error_context_for(method, req.extensions())
+    }
+
+    fn error_context_for(&self, method: &str, extensions: &Extensions) -> ErrorContext {
+        if !self.annotate_errors {
+            return ErrorContext::default();
+        }
+        let mut context = vec![
+            (
+                "service",
+                self.service_fqn.trim_start_matches('/').to_string(),
+            ),
+            ("method", method.to_string()),
+        ];
+        if let Some(request_id) = extensions.get::<request_id::RequestId>() {
+            context.push(("request_id", request_id.to_string()));
+        }
+        ErrorContext(context)
+    }
+
+    /// Check the checksum of a request body, and decompress it if it was sent with a
+    /// `Content-Encoding` that this router supports, to at most `max_request_size` bytes.
+    #[cfg_attr(
+        not(any(feature = "zstd", feature = "gzip", feature = "checksum")),
+        allow(unused_variables)
+    )]
+    fn decode_body(&self, headers: &http::HeaderMap, body: Bytes) -> Result<Bytes, GenericError> {
+        #[cfg(feature = "checksum")]
+        if let Some(checksums) = &self.checksums {
+            checksums.verify(headers, &body)?;
+        }
+        #[cfg(feature = "zstd")]
+        if let Some(zstd) = &self.zstd {
+            if crate::compression::is_encoded(headers, crate::compression::ZSTD) {
+                return zstd.decompress_request(&body, self.max_request_size);
+            }
+        }
+        #[cfg(feature = "gzip")]
+        if let Some(gzip) = &self.gzip {
+            if crate::compression::is_encoded(headers, crate::compression::GZIP) {
+                return gzip.decompress_request(&body, self.max_request_size);
+            }
+        }
+        Ok(body)
+    }
+
+    /// Compress a response body for a client that accepts it and add its checksum, adding the
+    /// headers that describe the body to `headers`.
+    fn encode_body(
+        &self,
+        accept_encoding: AcceptEncoding,
+        body: Bytes,
+        headers: &mut http::HeaderMap,
+    ) -> Result<Bytes, GenericError> {
+        // The body depends on `Accept-Encoding` whenever the router can compress, including
+        // when this one isn't compressed, so caches mustn't serve it to other clients.
+        if self.compresses() {
+            headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        let body = match self.compress(accept_encoding, &body)? {
+            Some((encoding, compressed)) => {
+                let encoding = HeaderValue::from_static(encoding);
+                headers.insert(header::CONTENT_ENCODING, encoding);
+                compressed
+            }
+            None => body,
+        };
+        #[cfg(feature = "checksum")]
+        if self.checksums.is_some() {
+            let checksum = crate::checksum::sha256(&body);
+            headers.insert(crate::checksum::X_CONTENT_SHA256, checksum);
+        }
+        Ok(body)
+    }
+
+    /// Whether the router is configured to compress response bodies.
+    fn compresses(&self) -> bool {
+        #[allow(unused_mut)]
+        let mut compresses = false;
+        #[cfg(feature = "zstd")]
+        {
+            compresses |= self.zstd.is_some();
+        }
+        #[cfg(feature = "gzip")]
+        {
+            compresses |= self.gzip.is_some();
+        }
+        compresses
+    }
+
+    /// Compress a response body with the first encoding (zstd, then gzip) that both the router
+    /// and the client support, if the body is large enough.
+    #[cfg_attr(not(any(feature = "zstd", feature = "gzip")), allow(unused_variables))]
+    fn compress(
+        &self,
+        accept_encoding: AcceptEncoding,
+        body: &[u8],
+    ) -> std::io::Result<Option<(&'static str, Bytes)>> {
+        #[cfg(feature = "zstd")]
+        if let Some(zstd) = self.zstd.as_ref().filter(|_| accept_encoding.zstd) {
+            if let Some(compressed) = zstd.compress(body)? {
+                return Ok(Some((crate::compression::ZSTD, compressed)));
+            }
+        }
+        #[cfg(feature = "gzip")]
+        if let Some(gzip) = self.gzip.as_ref().filter(|_| accept_encoding.gzip) {
+            if let Some(compressed) = gzip.compress(body)? {
+                return Ok(Some((crate::compression::GZIP, compressed)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The content types of the request bodies the router accepts: those of its codecs, then
+    /// protobuf and JSON, in the order [`BodyFormat`] picks them.
+    fn accepted_content_types(&self) -> Vec<&str> {
+        let mut types: Vec<&str> = self.codecs.iter().map(|c| c.content_type()).collect();
+        types.push(ProtobufCodec.content_type());
+        if self.compatibility == Compatibility::V5 {
+            types.push("application/x-protobuf");
+        }
+        types.push(JsonCodec.content_type());
+        let mut seen = BTreeSet::new();
+        types.retain(|ct| seen.insert(*ct));
+        types
+    }
+
+    /// The `rpc` of a request, as added by the router.
+    fn rpc(&self, req: &Request<Body>) -> RpcMethod {
+        req.extensions()
+            .get::<RpcMethod>()
+            .copied()
+            .unwrap_or_else(|| RpcMethod::new(self.service_fqn, ""))
+    }
+
+    /// Tell the hooks about an error, and turn it into the response sent to the client.
+    fn fail(
+        &self,
+        context: &ErrorContext,
+        hooks: &RequestHooks<'_>,
+        timings: &Timings,
+        resp: Response<TwirpErrorResponse>,
+    ) -> Response<Body> {
+        hooks.error(timings, resp.body());
+        let resp = self.error_response(context, resp);
+        hooks.sent(timings, resp.status());
+        resp
+    }
+
+    /// Turn an error into the response sent to the client.
+    fn error_response(
+        &self,
+        context: &ErrorContext,
+        resp: Response<TwirpErrorResponse>,
+    ) -> Response<Body> {
+        resp.map(|mut err| {
+            self.prepare_error(context, &mut err);
+            err.into_axum_body()
+        })
+    }
+
+    /// Annotate, translate and redact an error before it is sent to the client.
+    fn prepare_error(&self, context: &ErrorContext, err: &mut TwirpErrorResponse) {
+        for (key, value) in &context.0 {
+            err.meta
+                .entry(key.to_string())
+                .or_insert_with(|| value.clone());
+        }
+        // Twirp v5 peers don't know the `malformed` code; `invalid_argument` is the closest code
+        // they understand and maps to the same HTTP status.
+        if self.compatibility == Compatibility::V5 && err.code == TwirpErrorCode::Malformed {
+            err.code = TwirpErrorCode::InvalidArgument;
+        }
+        if self.debug_errors {
+            if let Some(rust_error) = err.rust_error() {
+                err.insert_meta("debug".to_string(), rust_error);
+            }
+        }
+        if let Some(redactor) = &self.redactor {
+            redactor.redact(err);
+        }
+    }
+}
+
+// The compressed encodings, among those the crate is built with, that a request accepts for its
+// response.
+#[derive(Clone, Copy, Debug, Default)]
+struct AcceptEncoding {
+    #[cfg(feature = "zstd")]
+    zstd: bool,
+    #[cfg(feature = "gzip")]
+    gzip: bool,
+}
+
+impl AcceptEncoding {
+    #[cfg_attr(not(any(feature = "zstd", feature = "gzip")), allow(unused_variables))]
+    fn new(headers: &http::HeaderMap) -> Self {
+        Self {
+            #[cfg(feature = "zstd")]
+            zstd: crate::compression::accepts(headers, crate::compression::ZSTD),
+            #[cfg(feature = "gzip")]
+            gzip: crate::compression::accepts(headers, crate::compression::GZIP),
+        }
+    }
+}
+
+// The error for a request that couldn't be parsed: `malformed`, `unavailable` for bodies over the
+// memory budget, or `dataloss` for bodies that failed their checksum.
+fn parse_error(err: GenericError) -> TwirpErrorResponse {
+    let err = match err.downcast::<budget::OverBudget>() {
+        Ok(err) => return (*err).into(),
+        Err(err) => err,
+    };
+    let err = match err.downcast::<budget::TooLarge>() {
+        Ok(err) => return (*err).into(),
+        Err(err) => err,
+    };
+    #[cfg(feature = "checksum")]
+    let err = match crate::checksum::request_error(err) {
+        Ok(twirp_err) => return twirp_err,
+        Err(err) => err,
+    };
+    let mut twirp_err = error::malformed("bad request");
+    twirp_err.insert_meta("error".to_string(), err.to_string());
+    twirp_err
 }
 
+// The response to a request whose body couldn't be read, for layers that read it before the router.
+pub(crate) fn body_read_error(err: impl std::fmt::Display) -> Response<Body> {
+    let mut twirp_err = error::malformed("failed to read request body");
+    twirp_err.insert_meta("error".to_string(), err.to_string());
+    twirp_err.into_response()
+}
+
+// Read all of a request body for a layer that reads it before the router, or fail with the
+// response to send if it can't be read or is longer than `limit`.
+pub(crate) async fn read_request_body(
+    body: Body,
+    limit: Option<usize>,
+) -> Result<Bytes, Response<Body>> {
+    Reservation::new(None)
+        .collect(body, limit)
+        .await
+        .map_err(|err| match err.downcast::<budget::TooLarge>() {
+            Ok(err) => TwirpErrorResponse::from(*err).into_response(),
+            Err(err) => body_read_error(err),
+        })
+}
+
+// Request details added to the `meta` of errors by routers with `annotate_errors` enabled.
+#[derive(Debug, Default)]
+struct ErrorContext(Vec<(&'static str, String)>);
+
 /// Entry point used in code generated by `twirp-build`.
 pub(crate) async fn handle_request<S, F, Fut, Req, Resp, Err>(
     service: S,
-    req: Request<Body>,
+    mut req: Request<Body>,
     f: F,
 ) -> Response<Body>
 where
     F: FnOnce(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
     Fut: Future<Output = Result<Resp, Err>> + Send,
     Req: prost::Message + Default + serde::de::DeserializeOwned,
-    Resp: prost::Message + serde::Serialize,
+    Resp: prost::Message + serde::Serialize + Send + 'static,
     Err: IntoTwirpResponse,
 {
     let mut timings = req
@@ -60,7 +440,18 @@ where
         .copied()
         .unwrap_or_else(|| Timings::new(Instant::now()));
 
-    let (req, exts, resp_fmt) = match parse_request(req, &mut timings).await {
+    let config = RouterConfig::from_request(&req);
+    let rpc = config.rpc(&req);
+    let span = RequestSpan::start(req.extensions_mut());
+    let hooks = config.hooks.start(rpc, span.clone(), &timings);
+    let error_context = config.error_context(&req);
+    let accept_encoding = AcceptEncoding::new(req.headers());
+    let deadline = config.set_deadline(&mut req, &timings);
+    let cancel = CancelOnDrop::new(req.extensions_mut());
+    set_peer_info(&mut req);
+    let mut reservation = Reservation::new(config.memory_budget.as_ref());
+    let parsed = parse_request(req, &config, &mut timings, &mut reservation).await;
+    let (req, parts, resp_fmt) = match parsed {
         Ok(pair) => pair,
         Err(err) => {
             // TODO: Capture original error in the response extensions. E.g.:
@@ -68,83 +459,452 @@ where
             //     .lock()
             //     .expect("mutex poisoned")
             //     .insert(RequestError(err));
-            let mut twirp_err = error::malformed("bad request");
-            twirp_err.insert_meta("error".to_string(), err.to_string());
-            return twirp_err.into_response();
+            let err = parse_error(err).into_twirp_response();
+            return config.fail(&error_context, &hooks, &timings, err);
         }
     };
+    hooks.routed(&timings);
 
     let resp_exts = Arc::new(Mutex::new(Extensions::new()));
-    let ctx = Context::new(exts, resp_exts.clone());
-    let res = f(service, ctx, req).await;
+    let ctx = Context::new(parts.extensions, resp_exts.clone()).with_headers(parts.headers);
+    let handler = span.instrument(f(service, ctx, req));
+    let res = call_handler(deadline, cancel, handler).await;
+    let res = res.map(RawResponse::Message);
+    let reply = Reply {
+        rpc,
+        format: resp_fmt,
+        config: &config,
+        error_context: &error_context,
+        hooks,
+        accept_encoding,
+        resp_exts,
+        reservation,
+    };
+    reply.finish(res, timings).await
+}
+
+/// Entry point for calls to [direct handlers](crate::direct): runs `route` with the router's
+/// hooks, timeout, request size limit and error handling, as if the call had come over HTTP.
+pub(crate) async fn handle_direct(
+    config: &RouterConfig,
+    rpc: RpcMethod,
+    mut ctx: Context,
+    req: Bytes,
+    route: &DirectRoute,
+) -> Result<Bytes, TwirpErrorResponse> {
+    let mut timings = Timings::new(Instant::now());
+    let extensions = ctx.extensions_mut();
+    extensions.insert(rpc);
+    let span = RequestSpan::start(extensions);
+    let hooks = config.hooks.start(rpc, span.clone(), &timings);
+    let error_context = config.error_context_for(rpc.method, extensions);
+    let deadline = config.deadline(ctx.headers(), &timings);
+    if let Some(deadline) = deadline {
+        ctx.extensions_mut().insert(Deadline(deadline));
+    }
+    let cancel = CancelOnDrop::new(ctx.extensions_mut());
+    timings.set_received();
+
+    let res = match config.max_request_size {
+        Some(limit) if req.len() > limit => {
+            Err(TwirpErrorResponse::from(budget::TooLarge(limit)).into_twirp_response())
+        }
+        _ => {
+            timings.set_parsed();
+            hooks.routed(&timings);
+            call_handler(deadline, cancel, span.instrument(route(ctx, req))).await
+        }
+    };
     timings.set_response_handled();
+    match res {
+        Ok(resp) => {
+            hooks.prepared(&timings);
+            timings.set_response_written();
+            hooks.sent(&timings, StatusCode::OK);
+            Ok(resp)
+        }
+        Err(err) => {
+            let mut err = err.into_body();
+            hooks.error(&timings, &err);
+            config.prepare_error(&error_context, &mut err);
+            hooks.sent(&timings, err.http_status());
+            Err(err)
+        }
+    }
+}
+
+/// Complete the request's [`PeerInfo`] with what the router knows about the connection.
+fn set_peer_info(req: &mut Request<Body>) {
+    let connect_info = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let version = req.version();
+    let peer = req.extensions_mut().get_or_insert_default::<PeerInfo>();
+    peer.remote_addr = peer.remote_addr.or(connect_info);
+    peer.protocol = Some(version);
+}
+
+// Run a handler, failing with `deadline_exceeded` if it is still running at `deadline`. Converting
+// the error right away keeps `Err` (which may not be `Send`) out of the caller's future.
+async fn call_handler<Fut, T, Err>(
+    deadline: Option<Instant>,
+    cancel: CancelOnDrop,
+    handler: Fut,
+) -> Result<T, Response<TwirpErrorResponse>>
+where
+    Fut: Future<Output = Result<T, Err>>,
+    Err: IntoTwirpResponse,
+{
+    let res = match deadline {
+        Some(deadline) => match tokio::time::timeout_at(deadline, handler).await {
+            Ok(res) => res,
+            // Dropping `cancel` signals work the handler left running.
+            Err(elapsed) => return Err(TwirpErrorResponse::from(elapsed).into_twirp_response()),
+        },
+        None => handler.await,
+    };
+    cancel.disarm();
+    res.map_err(IntoTwirpResponse::into_twirp_response)
+}
+
+// What is needed to respond to a request once its handler returns.
+struct Reply<'a> {
+    format: BodyFormat,
+    config: &'a RouterConfig,
+    error_context: &'a ErrorContext,
+    rpc: RpcMethod,
+    hooks: RequestHooks<'a>,
+    accept_encoding: AcceptEncoding,
+    resp_exts: Arc<Mutex<Extensions>>,
+    reservation: Reservation,
+}
+
+impl Reply<'_> {
+    async fn finish<Resp>(
+        self,
+        res: Result<RawResponse<Resp>, Response<TwirpErrorResponse>>,
+        mut timings: Timings,
+    ) -> Response<Body>
+    where
+        Resp: prost::Message + serde::Serialize + Send + 'static,
+    {
+        timings.set_response_handled();
+        let res = res.map_err(|mut err| {
+            let resp_exts = self.resp_exts.lock().expect("mutex poisoned");
+            if let Some(overrides) = resp_exts.get::<ResponseOverrides>() {
+                overrides.apply_to_error(err.body_mut());
+            }
+            err
+        });
+        match &res {
+            Ok(_) => self.hooks.prepared(&timings),
+            Err(err) => self.hooks.error(&timings, err.body()),
+        }
+        let (config, error_context) = (self.config, self.error_context);
+        let written = write_response(
+            res,
+            self.rpc,
+            self.format,
+            config,
+            error_context,
+            self.accept_encoding,
+        );
+        let resp = match written.await {
+            Ok(resp) => resp,
+            Err(err) => {
+                // TODO: Capture original error in the response extensions.
+                let mut twirp_err = error::unknown("error serializing response");
+                twirp_err.insert_meta("error".to_string(), err.to_string());
+                let err = twirp_err.into_twirp_response();
+                return config.fail(error_context, &self.hooks, &timings, err);
+            }
+        };
+        timings.set_response_written();
+
+        let mut resp = match self.reservation.hold_response(resp) {
+            Ok(resp) => resp,
+            Err(err) => {
+                let err = TwirpErrorResponse::from(err).into_twirp_response();
+                return config.fail(error_context, &self.hooks, &timings, err);
+            }
+        };
+        add_response_extensions(&mut resp, &self.resp_exts);
+        resp.extensions_mut().insert(timings);
+        self.hooks.sent(&timings, resp.status());
+        resp
+    }
+}
+
+// Add what the handler set through its `Context` to the response.
+fn add_response_extensions(resp: &mut Response<Body>, resp_exts: &Mutex<Extensions>) {
+    let resp_exts = resp_exts.lock().expect("mutex poisoned").clone();
+    if let Some(overrides) = resp_exts.get::<ResponseOverrides>() {
+        overrides.apply_headers(resp.headers_mut());
+    }
+    resp.extensions_mut().extend(resp_exts);
+}
+
+/// The error code of an error response, read from its body, and the response with its body
+/// restored. Other responses are returned as they are, without reading their body.
+#[cfg(any(
+    test,
+    feature = "test-support",
+    feature = "otel",
+    feature = "prometheus"
+))]
+pub(crate) async fn read_error_code(
+    resp: Response<Body>,
+) -> (Response<Body>, Option<TwirpErrorCode>) {
+    let (resp, code) = try_read_error_code(resp).await;
+    (resp, code.ok().flatten())
+}
 
-    let mut resp = match write_response(res, resp_fmt) {
-        Ok(resp) => resp,
+/// Like [`read_error_code`], for any body. If the body can't be read, the response is returned
+/// with an empty body, along with the error.
+#[cfg(any(
+    test,
+    feature = "test-support",
+    feature = "otel",
+    feature = "prometheus"
+))]
+pub(crate) async fn try_read_error_code<B>(
+    resp: Response<B>,
+) -> (Response<B>, Result<Option<TwirpErrorCode>, B::Error>)
+where
+    B: hyper::body::Body + From<Bytes>,
+{
+    let is_json = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|ct| ct.as_bytes() == CONTENT_TYPE_JSON);
+    if resp.status().is_success() || !is_json {
+        return (resp, Ok(None));
+    }
+    let (parts, body) = resp.into_parts();
+    let body = match http_body_util::BodyExt::collect(body).await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => return (Response::from_parts(parts, B::from(Bytes::new())), Err(err)),
+    };
+    #[derive(serde::Deserialize)]
+    struct Code {
+        code: TwirpErrorCode,
+    }
+    let code = serde_json::from_slice::<Code>(&body).ok().map(|c| c.code);
+    (Response::from_parts(parts, B::from(body)), Ok(code))
+}
+
+/// Like [`handle_request`], for server-streaming RPCs (see [`crate::stream`]): `f` resolves to a
+/// stream of messages that are written to the response as they arrive.
+pub(crate) async fn handle_streaming_request<S, F, Fut, Req, St, Resp, Err>(
+    service: S,
+    mut req: Request<Body>,
+    f: F,
+) -> Response<Body>
+where
+    F: FnOnce(S, Context, Req) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<St, Err>> + Send,
+    St: futures::Stream<Item = Result<Resp, Err>> + Send + 'static,
+    Req: prost::Message + Default + serde::de::DeserializeOwned,
+    Resp: prost::Message + serde::Serialize + 'static,
+    Err: IntoTwirpResponse + 'static,
+{
+    let mut timings = req
+        .extensions()
+        .get::<Timings>()
+        .copied()
+        .unwrap_or_else(|| Timings::new(Instant::now()));
+
+    let config = RouterConfig::from_request(&req);
+    let span = RequestSpan::start(req.extensions_mut());
+    let rpc = config.rpc(&req);
+    let hooks = config.hooks.start(rpc, span.clone(), &timings);
+    let error_context = config.error_context(&req);
+    let deadline = config.set_deadline(&mut req, &timings);
+    let cancel = CancelOnDrop::new(req.extensions_mut());
+    set_peer_info(&mut req);
+    let mut reservation = Reservation::new(config.memory_budget.as_ref());
+    let parsed = parse_request(req, &config, &mut timings, &mut reservation).await;
+    let (req, parts, resp_fmt) = match parsed {
+        Ok(pair) => pair,
         Err(err) => {
-            // TODO: Capture original error in the response extensions.
-            let mut twirp_err = error::unknown("error serializing response");
-            twirp_err.insert_meta("error".to_string(), err.to_string());
-            return twirp_err.into_response();
+            let err = parse_error(err).into_twirp_response();
+            return config.fail(&error_context, &hooks, &timings, err);
+        }
+    };
+    hooks.routed(&timings);
+    let json = resp_fmt.codec().format() == Format::Json;
+    let frame_codec = match resp_fmt {
+        // Server-sent events can only hold plain JSON.
+        BodyFormat::Custom(codec) if json && !codec.matches(CONTENT_TYPE_JSON) => {
+            let err = error::malformed(format!(
+                "server-streaming responses can't be sent as {}",
+                codec.content_type()
+            ));
+            return config.fail(&error_context, &hooks, &timings, err.into_twirp_response());
         }
+        BodyFormat::Custom(codec) if !json => Some(crate::stream::FrameCodec::new(
+            codec,
+            MessageType::response(rpc.service, rpc.method),
+        )),
+        _ => None,
     };
-    timings.set_response_written();
 
-    resp.extensions_mut()
-        .extend(resp_exts.lock().expect("mutex poisoned").clone());
+    let resp_exts = Arc::new(Mutex::new(Extensions::new()));
+    let ctx = Context::new(parts.extensions, resp_exts.clone()).with_headers(parts.headers);
+    let handler = span.instrument(f(service, ctx, req));
+    let messages = match call_handler(deadline, cancel, handler).await {
+        Ok(messages) => messages,
+        Err(err) => return config.fail(&error_context, &hooks, &timings, err),
+    };
+    timings.set_response_handled();
+    hooks.prepared(&timings);
+
+    let mut resp = crate::stream::write_stream(messages, json, frame_codec);
+    add_response_extensions(&mut resp, &resp_exts);
     resp.extensions_mut().insert(timings);
+    hooks.sent(&timings, resp.status());
     resp
 }
 
 async fn parse_request<T>(
     req: Request<Body>,
+    config: &RouterConfig,
     timings: &mut Timings,
-) -> Result<(T, Extensions, BodyFormat), GenericError>
+    reservation: &mut Reservation,
+) -> Result<(T, http::request::Parts, BodyFormat), GenericError>
 where
     T: prost::Message + Default + DeserializeOwned,
 {
-    let format = BodyFormat::from_content_type(&req);
+    let rpc = config.rpc(&req);
+    let ty = MessageType::request(rpc.service, rpc.method);
+    if req.method() == Method::GET {
+        let (parts, _) = req.into_parts();
+        timings.set_received();
+        let (request, format) = parse_query(&parts.uri, config, ty)?;
+        timings.set_parsed();
+        return Ok((request, parts, format));
+    }
+
+    let format = BodyFormat::from_content_type(&req, config);
     let (parts, body) = req.into_parts();
-    let bytes = body.collect().await?.to_bytes();
+    let bytes = reservation.collect(body, config.max_request_size).await?;
+    let bytes = config.decode_body(&parts.headers, bytes)?;
     timings.set_received();
-    let request = match format {
-        BodyFormat::Pb => T::decode(&bytes[..])?,
-        BodyFormat::JsonPb => serde_json::from_slice(&bytes)?,
-    };
+    let request = codec::decode_message(format.codec(), ty, bytes)?;
     timings.set_parsed();
-    Ok((request, parts.extensions, format))
+    Ok((request, parts, format))
+}
+
+/// Decode the request message of a `GET` request (only allowed for methods without side effects)
+/// from the query string. The message is either base64-encoded protobuf in the `proto` parameter
+/// or JSON in the `json` parameter, and the response uses the same format. Without either
+/// parameter the request is the default (empty) message. Like request bodies, the messages are
+/// read with the router's codec for their content type.
+fn parse_query<T>(
+    uri: &http::Uri,
+    config: &RouterConfig,
+    ty: MessageType<'_>,
+) -> Result<(T, BodyFormat), GenericError>
+where
+    T: prost::Message + Default + DeserializeOwned,
+{
+    let json = BodyFormat::for_content_type(Some(CONTENT_TYPE_JSON), config);
+    let query = uri.query().unwrap_or_default();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        let (format, bytes) = match key.as_ref() {
+            "proto" => (
+                BodyFormat::for_content_type(Some(CONTENT_TYPE_PROTOBUF), config),
+                Bytes::from(decode_base64(&value)?),
+            ),
+            "json" => (json, Bytes::from(value.into_owned())),
+            _ => continue,
+        };
+        return Ok((codec::decode_message(format.codec(), ty, bytes)?, format));
+    }
+    Ok((T::default(), json))
+}
+
+// Accepts both the standard and URL-safe alphabets, with or without padding.
+fn decode_base64(value: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    use base64::alphabet::{STANDARD, URL_SAFE};
+    use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+    use base64::Engine;
+
+    let config =
+        GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+    let alphabet = if value.contains(['-', '_']) {
+        URL_SAFE
+    } else {
+        STANDARD
+    };
+    GeneralPurpose::new(&alphabet, config).decode(value)
 }
 
-fn write_response<T, Err>(
-    response: Result<T, Err>,
+async fn write_response<T>(
+    response: Result<RawResponse<T>, Response<TwirpErrorResponse>>,
+    rpc: RpcMethod,
     response_format: BodyFormat,
+    config: &RouterConfig,
+    error_context: &ErrorContext,
+    accept_encoding: AcceptEncoding,
 ) -> Result<Response<Body>, GenericError>
 where
-    T: prost::Message + Serialize,
-    Err: IntoTwirpResponse,
+    T: prost::Message + Serialize + Send + 'static,
 {
     let res = match response {
-        Ok(response) => match response_format {
-            BodyFormat::Pb => Response::builder()
-                .header(header::CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
-                .body(Body::from(serialize_proto_message(response)))?,
-            BodyFormat::JsonPb => {
-                let data = serde_json::to_string(&response)?;
-                Response::builder()
-                    .header(header::CONTENT_TYPE, CONTENT_TYPE_JSON)
-                    .body(Body::from(data))?
-            }
-        },
-        Err(err) => err.into_twirp_response().map(|err| err.into_axum_body()),
+        Ok(response) => {
+            let codec = response_format.codec();
+            let data = encode_response(response, rpc, &response_format, config).await?;
+            let mut headers = http::HeaderMap::new();
+            let data = config.encode_body(accept_encoding, data, &mut headers)?;
+            let mut resp = Response::builder()
+                .header(header::CONTENT_TYPE, codec.content_type())
+                .body(Body::from(data))?;
+            resp.headers_mut().extend(headers);
+            resp
+        }
+        Err(err) => config.error_response(error_context, err),
     };
     Ok(res)
 }
 
+/// Serialize a response message, on a blocking thread if it is larger than the router's
+/// [threshold](crate::details::TwirpRouterBuilder::blocking_serialization_threshold). Responses
+/// that are already encoded are returned as they are.
+async fn encode_response<T>(
+    response: RawResponse<T>,
+    rpc: RpcMethod,
+    format: &BodyFormat,
+    config: &RouterConfig,
+) -> Result<Bytes, GenericError>
+where
+    T: prost::Message + Serialize + Send + 'static,
+{
+    let response = match response {
+        RawResponse::Message(response) => response,
+        RawResponse::Encoded(data) => return Ok(data),
+    };
+    let ty = MessageType::response(rpc.service, rpc.method);
+    match config.blocking_serialization_threshold {
+        // The protobuf size is a cheap estimate of the size in other formats too.
+        Some(threshold) if response.encoded_len() >= threshold => {
+            let format = format.clone();
+            tokio::task::spawn_blocking(move || codec::encode_message(format.codec(), ty, response))
+                .await?
+        }
+        _ => codec::encode_message(format.codec(), ty, response),
+    }
+}
+
 /// Axum handler function that returns 404 Not Found with a Twirp JSON payload.
 ///
 /// `axum::Router`'s default fallback handler returns a 404 Not Found with no body content.
 /// Use this fallback instead for full Twirp compliance.
 ///
+/// Requests that use an HTTP method other than `POST` get the same response as the generated
+/// routes give them: a `bad_route` error that names the method and URL, with an `Allow` header.
+///
 /// # Usage
 ///
 /// ```
@@ -156,8 +916,234 @@ where
 ///     .fallback(twirp::server::not_found_handler);
 /// # app }
 /// ```
-pub async fn not_found_handler() -> Response<Body> {
-    error::bad_route("not found").into_response()
+///
+/// The error's `meta` describes the route that was attempted (`twirp_invalid_route`), the prefix
+/// it was made under (`twirp_prefix`) and, when a route with a similar name is served under that
+/// prefix, the route that was probably intended (`twirp_did_you_mean`). The candidates are the
+/// methods of the service whose router got the request, or the services of the
+/// [`RouteTable`](routes::RouteTable) that did; with a plain `axum::Router` fallback, only method
+/// names are suggested.
+pub async fn not_found_handler(req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::POST {
+        return method_not_allowed_handler(req, AllowedMethods::Post).await;
+    }
+    let path = original_uri(&req).path();
+    let mut twirp_err = error::bad_route("not found");
+    twirp_err.insert_meta("twirp_invalid_route".to_string(), format!("POST {path}"));
+    for (key, value) in route_diagnostics(&req, path) {
+        twirp_err.insert_meta(key.to_string(), value);
+    }
+    twirp_err.into_response()
+}
+
+// The full URI of the request, even when it was routed through nested routers.
+fn original_uri(req: &Request<Body>) -> &http::Uri {
+    req.extensions()
+        .get::<OriginalUri>()
+        .map(|OriginalUri(uri)| uri)
+        .unwrap_or(req.uri())
+}
+
+/// The Twirp services served by a router, for the suggestions of [`not_found_handler`]: the
+/// paths of the services (`{prefix}/{service}`). Added as a request extension by routers that
+/// serve several services.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RouteCandidates(BTreeSet<String>);
+
+impl RouteCandidates {
+    pub(crate) fn add_service(&mut self, service_path: String) {
+        self.0.insert(service_path);
+    }
+
+    // The served service under `prefix` most similar to `service`, if any is similar enough, as
+    // the route of `method` in it.
+    fn suggest(&self, prefix: &str, service: &str, method: &str) -> Option<String> {
+        let services = self
+            .0
+            .iter()
+            .filter_map(|path| path.strip_prefix(prefix)?.strip_prefix('/'))
+            .filter(|service| !service.contains('/'));
+        closest(service, services).map(|s| format!("{prefix}/{s}/{method}"))
+    }
+}
+
+fn route_diagnostics(req: &Request<Body>, path: &str) -> Vec<(&'static str, String)> {
+    let path = path.trim_matches('/');
+    let Some((service, method)) = split_route(path) else {
+        return vec![];
+    };
+    // What is left of the path before `<service>/<method>`.
+    let prefix = path[..path.len() - service.len() - method.len() - 1].trim_end_matches('/');
+    let prefix = match prefix {
+        "" => String::new(),
+        p => format!("/{p}"),
+    };
+
+    let mut diagnostics = vec![(
+        "twirp_prefix",
+        if prefix.is_empty() { "/" } else { &prefix }.to_string(),
+    )];
+    let config = req.extensions().get::<Arc<RouterConfig>>();
+    let suggestion = match config.filter(|config| !config.methods.is_empty()) {
+        // The request got to the router of a service, so it is for one of its methods.
+        Some(config) => closest(method, config.methods.iter().copied())
+            .map(|m| format!("{prefix}/{service}/{m}")),
+        None => req
+            .extensions()
+            .get::<Arc<RouteCandidates>>()
+            .and_then(|candidates| candidates.suggest(&prefix, service, method)),
+    };
+    if let Some(suggestion) = suggestion {
+        diagnostics.push(("twirp_did_you_mean", suggestion));
+    }
+    diagnostics
+}
+
+// The candidate most similar to `name` (other than `name` itself), if any is similar enough to be
+// a plausible typo.
+fn closest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let lowercase = name.to_lowercase();
+    let max_distance = (lowercase.chars().count() / 3).max(2);
+    candidates
+        .filter(|c| *c != name)
+        .map(|c| (edit_distance(&lowercase, &c.to_lowercase()), c))
+        .filter(|(d, _)| *d <= max_distance)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// The HTTP methods a Twirp route can be called with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AllowedMethods {
+    /// Every route accepts `POST`.
+    Post,
+    /// Methods without side effects can also be called with `GET`.
+    GetAndPost,
+}
+
+impl AllowedMethods {
+    fn allow_header(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            AllowedMethods::Post => "POST, OPTIONS",
+            AllowedMethods::GetAndPost => "GET, POST, OPTIONS",
+        })
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            AllowedMethods::Post => "only POST is allowed",
+            AllowedMethods::GetAndPost => "only GET and POST are allowed",
+        }
+    }
+}
+
+/// Handler for requests to a Twirp route that use an unsupported HTTP method. The Twirp spec
+/// requires these to be rejected with a `bad_route` error.
+pub(crate) async fn method_not_allowed_handler(
+    req: Request<Body>,
+    allowed: AllowedMethods,
+) -> Response<Body> {
+    let uri = original_uri(&req);
+    let mut twirp_err = error::bad_route(format!(
+        "unsupported method {} ({})",
+        req.method(),
+        allowed.description()
+    ));
+    twirp_err.insert_meta(
+        "twirp_invalid_route".to_string(),
+        format!("{} {}", req.method(), uri.path()),
+    );
+    let mut resp = twirp_err.into_response();
+    resp.headers_mut()
+        .insert(header::ALLOW, allowed.allow_header());
+    resp
+}
+
+/// Handler for `OPTIONS` requests to a Twirp route: advertises the allowed methods and the
+/// content types accepted in request bodies.
+///
+/// CORS preflight requests are usually answered by a CORS layer (e.g. `tower_http`'s
+/// `CorsLayer`) wrapping the router, in which case they never reach this handler.
+pub(crate) async fn options_handler(req: Request<Body>, allowed: AllowedMethods) -> Response<Body> {
+    let config = RouterConfig::from_request(&req);
+    let mut resp = StatusCode::NO_CONTENT.into_response();
+    let headers = resp.headers_mut();
+    headers.insert(header::ALLOW, allowed.allow_header());
+    if let Ok(accept_post) = HeaderValue::from_str(&config.accepted_content_types().join(", ")) {
+        headers.insert(HeaderName::from_static("accept-post"), accept_post);
+    }
+    resp
+}
+
+/// Serve `router` on `listener` until `signal` resolves, then shut down gracefully.
+///
+/// When `signal` resolves, `drain` starts: new requests are answered with `unavailable` (plus a
+/// `Retry-After` header) while in-flight requests get up to the drain window to finish. After that
+/// the listener is closed and open connections are given the chance to complete, until the window
+/// (counted from when `signal` resolved) is over. Requests still running then don't hold up the
+/// shutdown.
+///
+/// # Usage
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use twirp::server::drain::Drain;
+/// use twirp::Router;
+///
+/// # async fn run(app: Router) -> std::io::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
+/// let shutdown = async { tokio::signal::ctrl_c().await.expect("failed to listen for ctrl-c") };
+/// twirp::server::serve_with_shutdown(listener, app, shutdown, Drain::new(Duration::from_secs(10)))
+///     .await
+/// # }
+/// ```
+pub async fn serve_with_shutdown<F>(
+    listener: tokio::net::TcpListener,
+    router: axum::Router,
+    signal: F,
+    drain: drain::Drain,
+) -> std::io::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (started, deadline) = tokio::sync::oneshot::channel();
+    let window = drain.window();
+    let app = router
+        .layer(drain.layer())
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let server = std::future::IntoFuture::into_future(
+        axum::serve(listener, app).with_graceful_shutdown(async move {
+            signal.await;
+            let _ = started.send(Instant::now() + window);
+            drain.drain().await;
+        }),
+    );
+    // The graceful shutdown waits for every connection to close, however long its request takes.
+    let deadline = async move {
+        match deadline.await {
+            Ok(deadline) => tokio::time::sleep_until(deadline).await,
+            Err(_) => std::future::pending().await,
+        }
+    };
+    match futures::future::select(std::pin::pin!(server), std::pin::pin!(deadline)).await {
+        futures::future::Either::Left((result, _)) => result,
+        futures::future::Either::Right(((), _)) => Ok(()),
+    }
 }
 
 /// Contains timing information associated with a request.
@@ -243,13 +1229,40 @@ impl Timings {
     }
 }
 
+/// Deterministically selects a percentage of calls, spreading the selected calls evenly rather than
+/// in bursts. Used by layers that act on a fraction of traffic.
+#[derive(Debug)]
+pub(crate) struct Sampler {
+    ratio: f64,
+    count: AtomicU64,
+}
+
+impl Sampler {
+    pub(crate) fn new(percent: f64) -> Self {
+        Self {
+            ratio: percent.clamp(0.0, 100.0) / 100.0,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn sample(&self) -> bool {
+        let n = self.count.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.ratio).floor() > (n * self.ratio).floor()
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::direct::DirectHandler;
+    use crate::serialize_proto_message;
     use crate::test::*;
 
     use axum::middleware::{self, Next};
+    use bytes::Bytes;
+    use http_body_util::BodyExt;
+    use prost::Message;
     use tower::Service;
 
     fn timings() -> Timings {
@@ -259,14 +1272,168 @@ mod tests {
     #[tokio::test]
     async fn test_bad_route() {
         let mut router = test_api_router();
-        let req = Request::get("/nothing")
+        let req = Request::post("/nothing")
             .extension(timings())
             .body(Body::empty())
             .unwrap();
 
         let resp = router.call(req).await.unwrap();
         let data = read_err_body(resp.into_body()).await;
-        assert_eq!(data, error::bad_route("not found"));
+        let mut expected = error::bad_route("not found");
+        expected.insert_meta(
+            "twirp_invalid_route".to_string(),
+            "POST /nothing".to_string(),
+        );
+        assert_eq!(data, expected);
+    }
+
+    #[tokio::test]
+    async fn test_bad_route_diagnostics() {
+        // A service that is built but not served is never suggested.
+        let _internal = crate::details::TwirpRouterBuilder::new("/test.Internal", ())
+            .route("/Ping", |_, _: Context, req: PingRequest| async move {
+                Ok::<_, TwirpErrorResponse>(PingResponse { name: req.name })
+            })
+            .build();
+        let table = || {
+            routes::RouteTable::new("/twirp")
+                .service("test.TestAPI", TEST_API_METHODS, test_api_service_router())
+                .build()
+                .unwrap()
+        };
+
+        let cases = [
+            // Methods are suggested by the router of the service.
+            (
+                test_api_router(),
+                "/twirp/test.TestAPI/Pong",
+                Some("/twirp/test.TestAPI/Ping"),
+                "/twirp",
+            ),
+            // Services only by routers that know which services they serve.
+            (
+                test_api_router(),
+                "/twirp/test.TestApi/Ping",
+                None,
+                "/twirp",
+            ),
+            (
+                table(),
+                "/twirp/test.TestApi/Ping",
+                Some("/twirp/test.TestAPI/Ping"),
+                "/twirp",
+            ),
+            (table(), "/twirp/test.Internl/Ping", None, "/twirp"),
+            (table(), "/twirp/other.Service/Ping", None, "/twirp"),
+            (table(), "/test.TestApi/Ping", None, "/"),
+            (table(), "/other/test.TestAPI/Ping", None, "/other"),
+        ];
+        for (mut router, path, did_you_mean, prefix) in cases {
+            let req = Request::post(path)
+                .extension(timings())
+                .body(Body::empty())
+                .unwrap();
+            let resp = router.call(req).await.unwrap();
+            let data = read_err_body(resp.into_body()).await;
+            assert_eq!(data.code, crate::TwirpErrorCode::BadRoute);
+            assert_eq!(data.meta["twirp_invalid_route"], format!("POST {path}"));
+            assert_eq!(data.meta["twirp_prefix"], prefix);
+            assert_eq!(
+                data.meta.get("twirp_did_you_mean").map(String::as_str),
+                did_you_mean,
+                "{path}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_method() {
+        for path in ["/nothing", "/twirp/test.TestAPI/Ping"] {
+            let mut router = test_api_router();
+            let req = Request::get(path)
+                .extension(timings())
+                .body(Body::empty())
+                .unwrap();
+
+            let resp = router.call(req).await.unwrap();
+            assert_eq!(resp.status(), 404);
+            assert_eq!(resp.headers()[header::ALLOW], "POST, OPTIONS");
+            let data = read_err_body(resp.into_body()).await;
+            let mut expected = error::bad_route("unsupported method GET (only POST is allowed)");
+            expected.insert_meta("twirp_invalid_route".to_string(), format!("GET {path}"));
+            assert_eq!(data, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_fallback() {
+        let api = std::sync::Arc::new(TestApiServer);
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new("/test.TestAPI", api)
+                .route(
+                    "/Ping",
+                    |api: std::sync::Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                        api.ping(ctx, req).await
+                    },
+                )
+                .build_with_fallback(|| async { (StatusCode::IM_A_TEAPOT, "no such rpc") }),
+        );
+
+        let req = Request::post("/twirp/test.TestAPI/Nope")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(read_string_body(resp.into_body()).await, "no such rpc");
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+
+    #[tokio::test]
+    async fn test_options() {
+        let mut router = test_api_router();
+        let req = Request::options("/twirp/test.TestAPI/Ping")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), 204);
+        assert_eq!(resp.headers()[header::ALLOW], "POST, OPTIONS");
+        assert_eq!(
+            resp.headers()["accept-post"],
+            "application/protobuf, application/json"
+        );
+
+        // Routers also accept the content types of their codecs, and `x-protobuf` under V5.
+        #[derive(Debug)]
+        struct Framed;
+
+        impl Codec for Framed {
+            fn content_type(&self) -> &str {
+                "application/x-framed"
+            }
+
+            fn format(&self) -> Format {
+                Format::Protobuf
+            }
+        }
+
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .codec(Framed)
+                .compatibility(Compatibility::V5)
+                .build(),
+        );
+        let req = Request::options("/twirp/test.TestAPI/Ping")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(
+            resp.headers()["accept-post"],
+            "application/x-framed, application/protobuf, application/x-protobuf, application/json"
+        );
     }
 
     #[tokio::test]
@@ -278,6 +1445,419 @@ mod tests {
         assert_eq!(&data.name, "hi");
     }
 
+    #[cfg(feature = "checksum")]
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        use crate::checksum::{Checksums, X_CONTENT_SHA256};
+
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .checksums(Checksums::new())
+                .build(),
+        );
+        let req = gen_ping_request("hi");
+        let (mut parts, body) = req.into_parts();
+        parts
+            .headers
+            .insert(X_CONTENT_SHA256, HeaderValue::from_static("00"));
+        let resp = router.call(Request::from_parts(parts, body)).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.code, TwirpErrorCode::Dataloss);
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert!(resp.headers().contains_key(X_CONTENT_SHA256));
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |_, ctx: Context, req: PingRequest| async move {
+                let remaining = ctx.time_remaining().unwrap();
+                tokio::time::sleep(Duration::from_millis(req.name.parse().unwrap())).await;
+                Ok::<_, TwirpErrorResponse>(PingResponse {
+                    name: remaining.as_millis().to_string(),
+                })
+            })
+            .timeout(Duration::from_millis(100))
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+
+        let resp = router.call(gen_ping_request("0")).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        let remaining: u64 = data.name.parse().unwrap();
+        assert!(remaining > 50 && remaining <= 100, "{remaining}");
+
+        let resp = router.call(gen_ping_request("1000")).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::DeadlineExceeded);
+
+        // The client can shorten the router's timeout, but not extend it.
+        let (mut parts, body) = gen_ping_request("0").into_parts();
+        parts
+            .headers
+            .insert(TWIRP_TIMEOUT, HeaderValue::from_static("30"));
+        let resp = router.call(Request::from_parts(parts, body)).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        let remaining: u64 = data.name.parse().unwrap();
+        assert!(remaining <= 30, "{remaining}");
+
+        let (mut parts, body) = gen_ping_request("200").into_parts();
+        parts
+            .headers
+            .insert(TWIRP_TIMEOUT, HeaderValue::from_static("5000"));
+        let resp = router.call(Request::from_parts(parts, body)).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::DeadlineExceeded);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", move |_, ctx: Context, req: PingRequest| {
+                let tx = tx.clone();
+                async move {
+                    let cancelled = ctx.cancelled();
+                    tokio::spawn(async move {
+                        cancelled.await;
+                        let _ = tx.send(req.name);
+                    });
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    Ok::<_, TwirpErrorResponse>(PingResponse {
+                        name: ctx.is_cancelled().to_string(),
+                    })
+                }
+            })
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+
+        // Answered: never cancelled.
+        let resp = router.call(gen_ping_request("answered")).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "false");
+
+        // The client went away.
+        let call = router.call(gen_ping_request("disconnected"));
+        assert!(tokio::time::timeout(Duration::from_millis(20), call)
+            .await
+            .is_err());
+        assert_eq!(rx.recv().await.unwrap(), "disconnected");
+
+        // Past the deadline.
+        let (mut parts, body) = gen_ping_request("timed out").into_parts();
+        parts
+            .headers
+            .insert(TWIRP_TIMEOUT, HeaderValue::from_static("20"));
+        let resp = router.call(Request::from_parts(parts, body)).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::DeadlineExceeded);
+        assert_eq!(rx.recv().await.unwrap(), "timed out");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rpc_method() {
+        let builder = || {
+            crate::details::TwirpRouterBuilder::new("/test.TestAPI", ()).route(
+                "/Ping",
+                |_, ctx: Context, _: PingRequest| async move {
+                    assert_eq!(ctx.service(), Some("test.TestAPI"));
+                    assert_eq!(ctx.method(), Some("Ping"));
+                    Ok::<_, TwirpErrorResponse>(PingResponse {
+                        name: ctx.rpc_method().unwrap().to_string(),
+                    })
+                },
+            )
+        };
+
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", builder().build());
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "test.TestAPI/Ping");
+
+        let body = serialize_proto_message(PingRequest::default());
+        let resp = builder()
+            .build_direct()
+            .handle("Ping", Context::default(), body)
+            .await
+            .unwrap();
+        let data = PingResponse::decode(resp).unwrap();
+        assert_eq!(data.name, "test.TestAPI/Ping");
+    }
+
+    #[tokio::test]
+    async fn test_context_headers() {
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                let user = ctx.header("authorization").cloned();
+                ctx.set_response_header(
+                    header::CACHE_CONTROL,
+                    HeaderValue::from_static("no-cache"),
+                );
+                ctx.set_response_header("cache-control", HeaderValue::from_static("max-age=60"));
+                ctx.set_response_header(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("text/html"),
+                );
+                let user = user.ok_or_else(|| error::unauthenticated("who are you?"))?;
+                Ok::<_, TwirpErrorResponse>(PingResponse {
+                    name: user.to_str().unwrap().to_string(),
+                })
+            })
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+
+        let (mut parts, body) = gen_ping_request("hi").into_parts();
+        parts
+            .headers
+            .insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer me"));
+        let resp = router.call(Request::from_parts(parts, body)).await.unwrap();
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "max-age=60");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], CONTENT_TYPE_JSON);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "Bearer me");
+
+        // Errors get the headers too.
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "max-age=60");
+    }
+
+    #[tokio::test]
+    async fn test_response_overrides() {
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |_, ctx: Context, req: PingRequest| async move {
+                ctx.set_uncacheable();
+                ctx.set_retry_after(Duration::from_millis(1500));
+                ctx.set_error_meta("attempt", 3);
+                ctx.set_error_meta("shard", "a");
+                match req.name.as_str() {
+                    "fail" => Err(error::unavailable("try later").with_meta("shard", "b")),
+                    _ => Ok(PingResponse::default()),
+                }
+            })
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+
+        let resp = router.call(gen_ping_request("ok")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "no-store");
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "2");
+
+        let resp = router.call(gen_ping_request("fail")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "2");
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(2)));
+        assert_eq!(err.meta["attempt"], "3");
+        assert_eq!(err.meta["shard"], "b");
+    }
+
+    #[tokio::test]
+    async fn test_peer_info() {
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                let peer = ctx.peer().unwrap();
+                assert_eq!(peer.protocol, Some(http::Version::HTTP_11));
+                Ok::<_, TwirpErrorResponse>(PingResponse {
+                    name: format!("{:?} {:?}", ctx.remote_addr(), peer.tls_peer_subject),
+                })
+            })
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+        let addr: SocketAddr = ([10, 0, 0, 1], 4000).into();
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "None None");
+
+        let mut req = gen_ping_request("hi");
+        req.extensions_mut().insert(ConnectInfo(addr));
+        let resp = router.call(req).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "Some(10.0.0.1:4000) None");
+
+        // Added by a server that terminates TLS.
+        let mut req = gen_ping_request("hi");
+        let peer = PeerInfo::new(addr).with_tls(None, Some("CN=client".to_string()));
+        req.extensions_mut().insert(peer);
+        let resp = router.call(req).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, r#"Some(10.0.0.1:4000) Some("CN=client")"#);
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_shutdown() {
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                Ok::<_, TwirpErrorResponse>(PingResponse {
+                    name: format!("{:?}", ctx.remote_addr().map(|addr| addr.ip())),
+                })
+            })
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, signal) = tokio::sync::oneshot::channel::<()>();
+        let drain = drain::Drain::new(Duration::from_secs(1));
+        let server = tokio::spawn(serve_with_shutdown(
+            listener,
+            router,
+            async move {
+                let _ = signal.await;
+            },
+            drain,
+        ));
+
+        let base_url = url::Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = crate::Client::from_base_url(base_url).unwrap();
+        let resp = client.ping(PingRequest::default()).await.unwrap();
+        assert_eq!(resp.name, "Some(127.0.0.1)");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_serve_with_shutdown_deadline() {
+        let started = Arc::new(tokio::sync::Notify::new());
+        let handler_started = started.clone();
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", move |_, _: Context, _: PingRequest| {
+                let started = handler_started.clone();
+                async move {
+                    started.notify_one();
+                    std::future::pending::<Result<PingResponse, TwirpErrorResponse>>().await
+                }
+            })
+            .build();
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", router);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, signal) = tokio::sync::oneshot::channel::<()>();
+        let drain = drain::Drain::new(Duration::from_millis(100));
+        let server = tokio::spawn(serve_with_shutdown(
+            listener,
+            router,
+            async move {
+                let _ = signal.await;
+            },
+            drain,
+        ));
+
+        let base_url = url::Url::parse(&format!("http://{addr}/twirp/")).unwrap();
+        let client = crate::Client::from_base_url(base_url).unwrap();
+        let call = tokio::spawn(async move { client.ping(PingRequest::default()).await });
+        started.notified().await;
+
+        // The stuck request doesn't keep the server from shutting down.
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server shut down")
+            .unwrap()
+            .unwrap();
+        call.abort();
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_request_span() {
+        use std::fmt::Write;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+
+        // Records the fields of new spans.
+        #[derive(Clone, Default)]
+        struct Spans(Arc<Mutex<Vec<String>>>);
+
+        impl Visit for Spans {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                let mut spans = self.0.lock().unwrap();
+                let span = spans.last_mut().unwrap();
+                let _ = write!(span, " {}={:?}", field.name(), value);
+            }
+        }
+
+        impl tracing::Subscriber for Spans {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(attrs.metadata().name().to_string());
+                attrs.record(&mut self.clone());
+                Id::from_u64(1)
+            }
+            fn record(&self, _: &Id, values: &Record<'_>) {
+                values.record(&mut self.clone());
+            }
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &tracing::Event<'_>) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route("/Ping", |_, ctx: Context, _: PingRequest| async move {
+                assert!(ctx.span().id().is_some());
+                Ok::<_, TwirpErrorResponse>(PingResponse::default())
+            })
+            .route("/Boom", |_, _: Context, _: PingRequest| async move {
+                Err::<PingResponse, _>(error::not_found("no such hat"))
+            })
+            .build();
+        let mut router = axum::Router::new()
+            .nest("/twirp/test.TestAPI", router)
+            .layer(request_id::RequestIdLayer::new());
+
+        let spans = Spans::default();
+        let _guard = tracing::subscriber::set_default(spans.clone());
+        let mut req = gen_ping_request("hi");
+        req.headers_mut()
+            .insert("x-request-id", HeaderValue::from_static("req-1"));
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let span = spans.0.lock().unwrap().pop().unwrap();
+        assert!(
+            span.starts_with(
+                r#"twirp rpc.service="test.TestAPI" rpc.method="Ping" request_id="req-1" http.response.status_code=200 timings.received="#
+            ),
+            "{span}"
+        );
+        assert!(span.contains(" timings.written="), "{span}");
+
+        let mut req = gen_ping_request("hi");
+        *req.uri_mut() = "/twirp/test.TestAPI/Boom".parse().unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let span = spans.0.lock().unwrap().pop().unwrap();
+        assert!(
+            span.contains(r#" rpc.twirp.error_code="not_found" http.response.status_code=404"#),
+            "{span}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_serialization() {
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .blocking_serialization_threshold(4)
+                .build(),
+        );
+        for name in ["hi", "long enough"] {
+            let resp = router.call(gen_ping_request(name)).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, name);
+        }
+    }
+
     #[tokio::test]
     async fn test_ping_invalid_request() {
         let mut router = test_api_router();
@@ -297,9 +1877,243 @@ mod tests {
             "error".to_string(),
             "EOF while parsing a value at line 1 column 0".to_string(),
         );
+        // simd-json words its errors differently.
+        if cfg!(feature = "simd-json") {
+            expected.insert_meta("error".to_string(), data.meta["error"].clone());
+        }
         assert_eq!(data, expected);
     }
 
+    #[tokio::test]
+    async fn test_get_no_side_effects() {
+        use base64::Engine;
+
+        let api = std::sync::Arc::new(TestApiServer);
+        let mut router = crate::details::TwirpRouterBuilder::new("/test.TestAPI", api)
+            .route_no_side_effects(
+                "/Ping",
+                |api: std::sync::Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+
+        let req = Request::get("/Ping?json=%7B%22name%22%3A%22hi%22%7D")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "hi");
+
+        let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(
+            serialize_proto_message(PingRequest {
+                name: "proto".to_string(),
+            }),
+        );
+        let req = Request::get(format!("/Ping?proto={encoded}"))
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/protobuf");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(PingResponse::decode(body).unwrap().name, "proto");
+
+        let req = Request::put("/Ping").body(Body::empty()).unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.headers()[header::ALLOW], "GET, POST, OPTIONS");
+
+        // Methods with side effects still reject GET.
+        let mut router = test_api_router();
+        let req = Request::get("/twirp/test.TestAPI/Ping?json=%7B%7D")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_v5_compatibility() {
+        let api = std::sync::Arc::new(TestApiServer);
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new("/test.TestAPI", api)
+                .route(
+                    "/Ping",
+                    |api: std::sync::Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                        api.ping(ctx, req).await
+                    },
+                )
+                .compatibility(Compatibility::V5)
+                .build(),
+        );
+
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/x-protobuf")
+            .body(Body::from(serialize_proto_message(PingRequest {
+                name: "hi".to_string(),
+            })))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), 400);
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.code, TwirpErrorCode::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_debug_errors() {
+        let router = |debug_errors| {
+            let api = std::sync::Arc::new(TestApiServer);
+            axum::Router::new().nest(
+                "/twirp/test.TestAPI",
+                crate::details::TwirpRouterBuilder::new("/test.TestAPI", api)
+                    .route(
+                        "/Ping",
+                        |_: std::sync::Arc<TestApiServer>, _: Context, _: PingRequest| async move {
+                            Err::<PingResponse, _>(
+                                error::internal("storage failed")
+                                    .with_source(std::io::Error::other("disk on fire")),
+                            )
+                        },
+                    )
+                    .debug_errors(debug_errors)
+                    .build(),
+            )
+        };
+
+        let resp = router(false).call(gen_ping_request("hi")).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert!(!data.meta.contains_key("debug"));
+
+        let resp = router(true).call(gen_ping_request("hi")).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.msg, "storage failed");
+        assert_eq!(data.meta["debug"], "disk on fire");
+    }
+
+    #[tokio::test]
+    async fn test_annotate_errors() {
+        let api = std::sync::Arc::new(TestApiServer);
+        let mut router = axum::Router::new()
+            .nest(
+                "/twirp/test.TestAPI",
+                crate::details::TwirpRouterBuilder::new("/test.TestAPI", api)
+                    .route(
+                        "/Ping",
+                        |_: std::sync::Arc<TestApiServer>, _: Context, _: PingRequest| async move {
+                            Err::<PingResponse, _>(
+                                error::not_found("no such hat").with_meta("method", "custom"),
+                            )
+                        },
+                    )
+                    .annotate_errors(true)
+                    .build(),
+            )
+            .layer(request_id::RequestIdLayer::new());
+
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header("x-request-id", "req-1")
+            .header(header::CONTENT_TYPE, CONTENT_TYPE_JSON)
+            .body(Body::from("{}"))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.meta["service"], "test.TestAPI");
+        assert_eq!(data.meta["method"], "custom");
+        assert_eq!(data.meta["request_id"], "req-1");
+
+        // Errors from parsing the request are annotated too.
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .body(Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data.code, TwirpErrorCode::Malformed);
+        assert_eq!(data.meta["method"], "Ping");
+    }
+
+    #[tokio::test]
+    async fn test_custom_codec() {
+        #[derive(Debug)]
+        struct Yelling;
+
+        impl Codec for Yelling {
+            fn content_type(&self) -> &str {
+                "application/x-yelling"
+            }
+
+            fn format(&self) -> Format {
+                Format::Json
+            }
+
+            fn encode(&self, message: Bytes) -> Result<Bytes, GenericError> {
+                Ok(message.to_ascii_uppercase().into())
+            }
+        }
+
+        let api = std::sync::Arc::new(TestApiServer);
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new("/test.TestAPI", api)
+                .route(
+                    "/Ping",
+                    |api: std::sync::Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                        api.ping(ctx, req).await
+                    },
+                )
+                .codec(Yelling)
+                .build(),
+        );
+
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/x-yelling")
+            .body(Body::from(r#"{"name": "hi"}"#))
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "application/x-yelling"
+        );
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, r#"{"NAME":"HI"}"#);
+
+        // The built-in formats still work.
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "hi");
+    }
+
+    #[tokio::test]
+    async fn test_redactor() {
+        let api = std::sync::Arc::new(TestApiServer);
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            crate::details::TwirpRouterBuilder::new("/test.TestAPI", api)
+                .route(
+                    "/Ping",
+                    |_: std::sync::Arc<TestApiServer>, _: Context, _: PingRequest| async move {
+                        Err::<PingResponse, _>(error::internal("password=hunter2 rejected"))
+                    },
+                )
+                .redactor(Redactor::new(|err| {
+                    if err.msg.contains("password=") {
+                        err.msg = "[redacted]".to_string();
+                    }
+                }))
+                .build(),
+        );
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let data = read_err_body(resp.into_body()).await;
+        assert_eq!(data, error::internal("[redacted]"));
+    }
+
     #[tokio::test]
     async fn test_boom() {
         let mut router = test_api_router();
@@ -317,6 +2131,27 @@ mod tests {
         assert_eq!(data, error::internal("boom!"));
     }
 
+    // hyper sets `Content-Length` (instead of using chunked encoding) from exact size hints.
+    #[tokio::test]
+    async fn test_body_size_hint() {
+        use http_body_util::BodyExt;
+        use hyper::body::Body as _;
+
+        let mut router = test_api_router();
+        let ping = router.call(gen_ping_request("hi")).await.unwrap();
+        let req = Request::post("/twirp/test.TestAPI/Boom")
+            .extension(timings())
+            .body(Body::from(r#"{"name":"hi"}"#))
+            .unwrap();
+        let boom = router.call(req).await.unwrap();
+        for resp in [ping, boom] {
+            let body = resp.into_body();
+            let len = body.size_hint().exact().expect("exact size hint");
+            assert!(!body.is_end_stream());
+            assert_eq!(body.collect().await.unwrap().to_bytes().len() as u64, len);
+        }
+    }
+
     #[tokio::test]
     async fn test_middleware() {
         let mut router = test_api_router().layer(middleware::from_fn(request_id_middleware));
@@ -343,6 +2178,72 @@ mod tests {
         assert_eq!(&data.name, "hello-abcd");
     }
 
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_gzip() {
+        use crate::compression::Gzip;
+
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .gzip(Gzip::new().min_size(0).max_decompressed_len(4096))
+                .build(),
+        );
+        let gzip = Gzip::new().min_size(0);
+        let request = |name: &str| {
+            let ping = PingRequest {
+                name: name.to_string(),
+            };
+            let body = gzip.compress(&ping.encode_to_vec()).unwrap().unwrap();
+            Request::post("/twirp/test.TestAPI/Ping")
+                .header(header::CONTENT_TYPE, CONTENT_TYPE_PROTOBUF)
+                .header(header::CONTENT_ENCODING, "gzip")
+                .header(header::ACCEPT_ENCODING, "br, gzip")
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let name = "hat ".repeat(100);
+        let resp = router.call(request(&name)).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(resp.headers()[header::VARY], "accept-encoding");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let data = PingResponse::decode(gzip.decompress(&body).unwrap()).unwrap();
+        assert_eq!(data.name, name);
+
+        // Responses that aren't compressed still vary by the encodings the client accepts.
+        let mut req = request("hat");
+        req.headers_mut().remove(header::ACCEPT_ENCODING);
+        let resp = router.call(req).await.unwrap();
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(resp.headers()[header::VARY], "accept-encoding");
+
+        // Bodies that decompress to more than the limit are rejected.
+        let resp = router.call(request(&"hat ".repeat(2000))).await.unwrap();
+        assert_eq!(resp.status(), 400);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+        assert_eq!(
+            err.meta["error"],
+            "body decompresses to more than 4096 bytes"
+        );
+
+        // So are bodies that decompress to more than a lower `max_request_size`.
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .gzip(Gzip::new().min_size(0).max_decompressed_len(4096))
+                .max_request_size(1024)
+                .build(),
+        );
+        let resp = router.call(request(&"hat ".repeat(300))).await.unwrap();
+        assert_eq!(resp.status(), 429);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::ResourceExhausted);
+        assert_eq!(err.msg, "request body is larger than 1024 bytes");
+    }
+
     async fn request_id_middleware(
         mut request: http::Request<Body>,
         next: Next,
@@ -0,0 +1,208 @@
+//! Serving Twirp services from an [actix-web](https://actix.rs) app.
+//!
+//! [`scope`] mounts a Twirp router (e.g. the `router()` generated by `twirp-build`) under a path
+//! of an actix-web `App`. Requests are converted and handed to the router, and its responses
+//! converted back, so the services behave exactly as they do when served with axum: the same
+//! content types, error responses and status codes.
+//!
+//! ```
+//! use twirp::actix_web::App;
+//! use twirp::server::actix;
+//! use twirp::Router;
+//!
+//! # fn build_app(haberdash_router: Router) {
+//! // e.g. `haberdash::router(api)`.
+//! let twirp_routes = Router::new().nest("/example.v1.Haberdasher", haberdash_router);
+//! let app = App::new().service(actix::scope("/twirp", twirp_routes));
+//! # }
+//! ```
+//!
+//! Request bodies are read completely before the router is called, and bodies longer than 4 MiB
+//! are rejected with `resource_exhausted`; [`scope_with_max_request_size`] sets another limit.
+//! Handlers can read the client's address from [`Context::peer`](crate::Context::peer).
+//!
+//! Requires the `actix` feature.
+
+use std::net::SocketAddr;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::response::IntoResponse;
+use axum::Router;
+use bytes::BytesMut;
+use futures::StreamExt;
+use http::header::CONTENT_LENGTH;
+use http_body_util::BodyExt;
+use hyper::Response;
+use tower::ServiceExt;
+
+use super::budget::TooLarge;
+
+const DEFAULT_MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+
+/// An actix-web scope at `prefix` that serves every request under it with `router`. Paths are
+/// passed to the router relative to `prefix`. See the [module documentation](self).
+pub fn scope(prefix: &str, router: Router) -> actix_web::Scope {
+    scope_with_max_request_size(prefix, router, DEFAULT_MAX_REQUEST_SIZE)
+}
+
+/// Like [`scope`], but rejects request bodies longer than `bytes` instead of 4 MiB.
+pub fn scope_with_max_request_size(prefix: &str, router: Router, bytes: usize) -> actix_web::Scope {
+    let prefix = prefix.trim_end_matches('/').to_string();
+    web::scope(&prefix.clone()).default_service(web::to(
+        move |req: HttpRequest, payload: web::Payload| {
+            let router = router.clone();
+            let prefix = prefix.clone();
+            async move {
+                let resp = match to_http(&req, &prefix, payload, bytes).await {
+                    Ok(http_req) => match router.oneshot(http_req).await {
+                        Ok(resp) => resp,
+                        Err(never) => match never {},
+                    },
+                    Err(err) => err.into_response(),
+                };
+                to_actix(resp).await
+            }
+        },
+    ))
+}
+
+// The request for the router, with the path relative to `prefix`.
+async fn to_http(
+    req: &HttpRequest,
+    prefix: &str,
+    mut payload: web::Payload,
+    limit: usize,
+) -> Result<http::Request<Body>, crate::TwirpErrorResponse> {
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH.as_str())
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit as u64) {
+        return Err(TooLarge(limit).into());
+    }
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| {
+            crate::malformed("failed to read the request body").with_meta("error", e)
+        })?;
+        if body.len() + chunk.len() > limit {
+            return Err(TooLarge(limit).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let path = req.path().strip_prefix(prefix).unwrap_or(req.path());
+    let uri = match req.query_string() {
+        "" => path.to_string(),
+        query => format!("{path}?{query}"),
+    };
+    let mut builder = http::Request::builder()
+        .method(req.method().as_str())
+        .uri(uri);
+    for (name, value) in req.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    let mut http_req = builder.body(Body::from(body.freeze()))?;
+    if let Some(addr) = req.peer_addr() {
+        http_req
+            .extensions_mut()
+            .insert(ConnectInfo::<SocketAddr>(addr));
+    }
+    Ok(http_req)
+}
+
+// The router's response as an actix-web response. Responses of known length are sent as they
+// are; others (e.g. server streams) are streamed.
+async fn to_actix(resp: Response<Body>) -> HttpResponse {
+    let (parts, body) = resp.into_parts();
+    let status =
+        actix_web::http::StatusCode::from_u16(parts.status.as_u16()).expect("valid status code");
+    let mut builder = HttpResponse::build(status);
+    for (name, value) in &parts.headers {
+        if name != CONTENT_LENGTH {
+            builder.append_header((name.as_str(), value.as_bytes()));
+        }
+    }
+    if !parts.headers.contains_key(CONTENT_LENGTH) {
+        return builder.streaming(body.into_data_stream());
+    }
+    match body.collect().await {
+        Ok(body) => builder.body(body.to_bytes()),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    use super::*;
+    use crate::test::{test_api_service_router, PingRequest, PingResponse};
+    use crate::{TwirpErrorCode, TwirpErrorResponse};
+
+    fn routes() -> Router {
+        Router::new().nest("/test.TestAPI", test_api_service_router())
+    }
+
+    #[tokio::test]
+    async fn test_actix() {
+        let app = init_service(App::new().service(scope("/twirp", routes()))).await;
+        let ping = |path: &str| {
+            TestRequest::post()
+                .uri(path)
+                .insert_header(("content-type", "application/json"))
+                .set_payload(r#"{"name": "actix"}"#)
+                .to_request()
+        };
+
+        let resp = call_service(&app, ping("/twirp/test.TestAPI/Ping")).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let resp: PingResponse = serde_json::from_slice(&read_body(resp).await).unwrap();
+        assert_eq!(resp.name, "actix");
+
+        let resp = call_service(&app, ping("/twirp/test.TestAPI/Boom")).await;
+        assert_eq!(resp.status(), 500);
+        let err: TwirpErrorResponse = serde_json::from_slice(&read_body(resp).await).unwrap();
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+        assert_eq!(err.msg, "boom!");
+
+        let resp = call_service(&app, ping("/twirp/test.TestAPI/Pong")).await;
+        assert_eq!(resp.status(), 404);
+        let err: TwirpErrorResponse = serde_json::from_slice(&read_body(resp).await).unwrap();
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+
+        let req = TestRequest::post()
+            .uri("/twirp/test.TestAPI/Ping")
+            .insert_header(("content-type", "application/protobuf"))
+            .set_payload(crate::serialize_proto_message(PingRequest {
+                name: "proto".to_string(),
+            }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        let resp: PingResponse = prost::Message::decode(read_body(resp).await).unwrap();
+        assert_eq!(resp.name, "proto");
+    }
+
+    #[tokio::test]
+    async fn test_max_request_size() {
+        let app =
+            init_service(App::new().service(scope_with_max_request_size("/twirp", routes(), 16)))
+                .await;
+        let req = TestRequest::post()
+            .uri("/twirp/test.TestAPI/Ping")
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"name": "much too long"}"#)
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 429);
+        let err: TwirpErrorResponse = serde_json::from_slice(&read_body(resp).await).unwrap();
+        assert_eq!(err.code, TwirpErrorCode::ResourceExhausted);
+    }
+}
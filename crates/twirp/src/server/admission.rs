@@ -0,0 +1,325 @@
+//! Queue-based admission control for overloaded services.
+//!
+//! An [`AdmissionController`] lets a fixed number of requests run at a time. Requests beyond that
+//! wait in a queue; when the queue is full, or a request has waited longer than the configured
+//! maximum, the request is shed with an `unavailable` error and a `Retry-After` header instead of
+//! piling up. The controller's [`metrics`](AdmissionController::metrics) can be exported to watch
+//! how close a service is to its limits.
+//!
+//! # Usage
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use twirp::server::admission::AdmissionController;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router) -> Router {
+//! let admission = AdmissionController::new(64)
+//!     .max_queue_depth(256)
+//!     .max_wait(Duration::from_millis(500));
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .layer(admission.layer());
+//!
+//! // Later, e.g. from a metrics exporter:
+//! let metrics = admission.metrics();
+//! println!("{} running, {} queued", metrics.in_flight, metrics.queued);
+//! # app }
+//! ```
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Body;
+use futures::future::BoxFuture;
+use hyper::{Request, Response};
+use tokio::sync::Semaphore;
+use tower::{Layer, Service};
+
+use crate::error::{self, PreserializedError};
+
+const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(1);
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Shared admission policy and its state. Clones share the same limits and queue.
+#[derive(Clone, Debug)]
+pub struct AdmissionController {
+    config: Config,
+    state: Arc<State>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Config {
+    max_concurrency: usize,
+    max_queue_depth: usize,
+    max_wait: Duration,
+    retry_after: Duration,
+}
+
+#[derive(Debug)]
+struct State {
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    admitted: AtomicU64,
+    shed: AtomicU64,
+    timed_out: AtomicU64,
+    // Serialized on first use, so that a global redactor set at startup applies.
+    overloaded: OnceLock<PreserializedError>,
+    queue_timeout: OnceLock<PreserializedError>,
+}
+
+/// A snapshot of an [`AdmissionController`]'s state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AdmissionMetrics {
+    /// Requests currently running.
+    pub in_flight: usize,
+    /// Requests currently waiting to run.
+    pub queued: usize,
+    /// Requests that have been let through, in total.
+    pub admitted: u64,
+    /// Requests rejected because the queue was full, in total.
+    pub shed: u64,
+    /// Requests rejected because they waited too long in the queue, in total.
+    pub timed_out: u64,
+}
+
+impl AdmissionController {
+    /// Let `max_concurrency` requests run at a time. By default, as many requests again can wait
+    /// in the queue, for up to one second.
+    pub fn new(max_concurrency: usize) -> Self {
+        let max_concurrency = max_concurrency.max(1);
+        Self {
+            config: Config {
+                max_concurrency,
+                max_queue_depth: max_concurrency,
+                max_wait: DEFAULT_MAX_WAIT,
+                retry_after: DEFAULT_RETRY_AFTER,
+            },
+            state: Arc::new(State {
+                permits: Arc::new(Semaphore::new(max_concurrency)),
+                queued: AtomicUsize::new(0),
+                admitted: AtomicU64::new(0),
+                shed: AtomicU64::new(0),
+                timed_out: AtomicU64::new(0),
+                overloaded: OnceLock::new(),
+                queue_timeout: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// The maximum number of requests waiting to run. `0` sheds every request that can't run
+    /// right away.
+    pub fn max_queue_depth(mut self, max_queue_depth: usize) -> Self {
+        self.config.max_queue_depth = max_queue_depth;
+        self
+    }
+
+    /// How long a request can wait in the queue before it is shed.
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.config.max_wait = max_wait;
+        self
+    }
+
+    /// How long shed clients are asked to wait before retrying. Defaults to one second.
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.config.retry_after = retry_after;
+        self
+    }
+
+    /// The current state of the controller.
+    pub fn metrics(&self) -> AdmissionMetrics {
+        let state = &self.state;
+        AdmissionMetrics {
+            in_flight: self.config.max_concurrency - state.permits.available_permits(),
+            queued: state.queued.load(Ordering::Relaxed),
+            admitted: state.admitted.load(Ordering::Relaxed),
+            shed: state.shed.load(Ordering::Relaxed),
+            timed_out: state.timed_out.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Build the layer that enforces this policy.
+    pub fn layer(&self) -> AdmissionLayer {
+        AdmissionLayer {
+            controller: self.clone(),
+        }
+    }
+
+    fn error_response(&self, timed_out: bool) -> Response<Body> {
+        let (rejection, msg) = match timed_out {
+            false => (&self.state.overloaded, "server is overloaded"),
+            true => (&self.state.queue_timeout, "timed out waiting to be served"),
+        };
+        rejection
+            .get_or_init(|| {
+                error::unavailable(msg)
+                    .with_retry_after(self.config.retry_after)
+                    .preserialize()
+            })
+            .to_response()
+    }
+}
+
+// Counts a request as queued for as long as it is alive, including when its future is dropped.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Layer that applies the [`Admission`] middleware. Created by [`AdmissionController::layer`].
+#[derive(Clone, Debug)]
+pub struct AdmissionLayer {
+    controller: AdmissionController,
+}
+
+impl<S> Layer<S> for AdmissionLayer {
+    type Service = Admission<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Admission {
+            inner,
+            controller: self.controller.clone(),
+        }
+    }
+}
+
+/// Middleware that queues requests beyond the concurrency limit and sheds them with `unavailable`
+/// when the queue is full or they have waited too long.
+#[derive(Clone, Debug)]
+pub struct Admission<S> {
+    inner: S,
+    controller: AdmissionController,
+}
+
+impl<S> Service<Request<Body>> for Admission<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let controller = self.controller.clone();
+
+        Box::pin(async move {
+            let (config, state) = (&controller.config, &controller.state);
+            let permit = match state.permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let queued = state.queued.fetch_add(1, Ordering::Relaxed);
+                    let _slot = QueueSlot(&state.queued);
+                    if queued >= config.max_queue_depth {
+                        state.shed.fetch_add(1, Ordering::Relaxed);
+                        return Ok(controller.error_response(false));
+                    }
+                    let acquire = state.permits.clone().acquire_owned();
+                    match tokio::time::timeout(config.max_wait, acquire).await {
+                        Ok(Ok(permit)) => permit,
+                        // The semaphore is never closed, so this is a timeout.
+                        _ => {
+                            state.timed_out.fetch_add(1, Ordering::Relaxed);
+                            return Ok(controller.error_response(true));
+                        }
+                    }
+                }
+            };
+            state.admitted.fetch_add(1, Ordering::Relaxed);
+            let resp = inner.call(req).await;
+            drop(permit);
+            resp
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::post;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test::*;
+    use crate::TwirpErrorCode;
+
+    fn slow_app(controller: &AdmissionController) -> axum::Router {
+        axum::Router::new()
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    "done"
+                }),
+            )
+            .layer(controller.layer())
+    }
+
+    async fn call(app: axum::Router) -> Response<Body> {
+        let req = Request::post("/slow").body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_queue_and_shed() {
+        let controller = AdmissionController::new(1).max_queue_depth(1);
+        let app = slow_app(&controller);
+
+        let running = tokio::spawn(call(app.clone()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let queued = tokio::spawn(call(app.clone()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(
+            controller.metrics(),
+            AdmissionMetrics {
+                in_flight: 1,
+                queued: 1,
+                admitted: 1,
+                ..Default::default()
+            }
+        );
+
+        let resp = call(app).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers()[http::header::RETRY_AFTER], "1");
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Unavailable);
+
+        assert!(running.await.unwrap().status().is_success());
+        assert!(queued.await.unwrap().status().is_success());
+        assert_eq!(
+            controller.metrics(),
+            AdmissionMetrics {
+                admitted: 2,
+                shed: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_wait() {
+        let controller = AdmissionController::new(1).max_wait(Duration::from_millis(50));
+        let app = slow_app(&controller);
+
+        let running = tokio::spawn(call(app.clone()));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let resp = call(app).await;
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.msg, "timed out waiting to be served");
+        assert!(running.await.unwrap().status().is_success());
+        assert_eq!(controller.metrics().timed_out, 1);
+    }
+}
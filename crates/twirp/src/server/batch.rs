@@ -0,0 +1,422 @@
+//! Multiplexing several RPCs into one HTTP request.
+//!
+//! [`Batch`] adds a `$batch` route next to the Twirp services (e.g. `/twirp/$batch`). It accepts
+//! a JSON envelope of sub-requests, runs them through the app's router with a concurrency limit,
+//! and returns the responses in the same order:
+//!
+//! ```json
+//! {"requests": [
+//!     {"service": "example.v1.Haberdasher", "method": "MakeHat", "body": {"inches": 1}},
+//!     {"service": "example.v1.Haberdasher", "method": "MakeHat", "proto": "CAE"}
+//! ]}
+//! ```
+//!
+//! ```json
+//! {"responses": [
+//!     {"body": {"size": 1, "color": "red"}},
+//!     {"error": {"code": "invalid_argument", "msg": "..."}}
+//! ]}
+//! ```
+//!
+//! A sub-request's message is either JSON (`body`) or base64-encoded protobuf (`proto`), and its
+//! response uses the same format. Headers of the batch request (e.g. `Authorization`) are copied
+//! to every sub-request, except those that describe the body of the batch itself. Batches can't be
+//! compressed, and are limited to [`Batch::max_request_size`].
+//!
+//! # Usage
+//!
+//! ```
+//! use twirp::server::batch::Batch;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router) -> Router {
+//! let app = Router::new().nest("/twirp", twirp_routes);
+//! let app = Batch::new("/twirp").max_concurrency(4).add_to(app);
+//! # app }
+//! ```
+
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::response::IntoResponse;
+use axum::routing::post;
+use axum::Router;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures::StreamExt;
+use http::{header, HeaderMap, HeaderValue, Method};
+use http_body_util::BodyExt;
+use hyper::{Request, Response};
+use serde::{Deserialize, Serialize};
+use tower::ServiceExt;
+
+use super::read_request_body;
+use crate::{error, TwirpErrorResponse};
+
+/// Configuration of the `$batch` route. See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct Batch {
+    path: String,
+    max_concurrency: usize,
+    max_requests: usize,
+    max_request_size: usize,
+}
+
+impl Batch {
+    /// Serve batches at `{prefix}/$batch`, where `prefix` is the path the Twirp services are
+    /// mounted under (usually `/twirp`).
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            path: format!("{}/$batch", prefix.trim_end_matches('/')),
+            max_concurrency: 8,
+            max_requests: 100,
+            max_request_size: 4 * 1024 * 1024,
+        }
+    }
+
+    /// The maximum number of sub-requests of one batch that run at the same time. Defaults to 8.
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+
+    /// The maximum number of sub-requests in one batch; larger batches are rejected. Defaults to
+    /// 100.
+    pub fn max_requests(mut self, max_requests: usize) -> Self {
+        self.max_requests = max_requests;
+        self
+    }
+
+    /// Reject batch requests longer than `bytes` with a `resource_exhausted` error. Defaults to 4
+    /// MiB.
+    pub fn max_request_size(mut self, bytes: usize) -> Self {
+        self.max_request_size = bytes;
+        self
+    }
+
+    /// Add the `$batch` route to `app`. Sub-requests are dispatched to `app` as it is at this
+    /// point, so batches can't be nested.
+    pub fn add_to(self, app: Router) -> Router {
+        let path = self.path.clone();
+        let batch = Arc::new(self);
+        let inner = app.clone();
+        app.route(
+            &path,
+            post(move |req: Request<Body>| handle_batch(inner.clone(), batch.clone(), req)),
+        )
+    }
+}
+
+async fn handle_batch(app: Router, batch: Arc<Batch>, req: Request<Body>) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    if parts
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|encoding| !encoding.as_bytes().eq_ignore_ascii_case(b"identity"))
+    {
+        return error::malformed("batch requests can't be compressed").into_response();
+    }
+    let bytes = match read_request_body(body, Some(batch.max_request_size)).await {
+        Ok(bytes) => bytes,
+        Err(resp) => return resp,
+    };
+    let envelope = match serde_json::from_slice::<BatchRequest>(&bytes) {
+        Ok(envelope) => envelope,
+        Err(err) => {
+            let mut twirp_err = error::malformed("bad batch request");
+            twirp_err.insert_meta("error".to_string(), err.to_string());
+            return twirp_err.into_response();
+        }
+    };
+    if envelope.requests.len() > batch.max_requests {
+        return error::invalid_argument(format!(
+            "batch has {} requests, the limit is {}",
+            envelope.requests.len(),
+            batch.max_requests
+        ))
+        .into_response();
+    }
+
+    let prefix = batch.path.trim_end_matches("$batch");
+    let dispatches = envelope
+        .requests
+        .into_iter()
+        .map(|sub| dispatch(app.clone(), sub.into_request(prefix, &parts.headers)));
+    let responses = futures::stream::iter(dispatches)
+        .buffered(batch.max_concurrency)
+        .collect()
+        .await;
+
+    match serde_json::to_vec(&BatchResponse { responses }) {
+        Ok(body) => {
+            let mut resp = Response::new(Body::from(body));
+            resp.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            resp
+        }
+        Err(err) => error::internal(format!("error serializing batch: {err}")).into_response(),
+    }
+}
+
+async fn dispatch(
+    app: Router,
+    req: Result<(Request<Body>, bool), TwirpErrorResponse>,
+) -> SubResponse {
+    match req {
+        Ok((req, proto)) => match app.oneshot(req).await {
+            Ok(resp) => SubResponse::from_response(resp, proto).await,
+            Err(never) => match never {},
+        },
+        Err(err) => SubResponse::error(err),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    requests: Vec<SubRequest>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubRequest {
+    service: String,
+    method: String,
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+    #[serde(default)]
+    proto: Option<String>,
+}
+
+impl SubRequest {
+    /// Build the request for the inner service, and whether it uses protobuf.
+    fn into_request(
+        self,
+        prefix: &str,
+        headers: &HeaderMap,
+    ) -> Result<(Request<Body>, bool), TwirpErrorResponse> {
+        let valid = |name: &str| !name.is_empty() && !name.contains('/');
+        if !valid(&self.service) || !valid(&self.method) {
+            return Err(error::invalid_argument(
+                "batch requests need a service and a method",
+            ));
+        }
+        let (body, content_type, proto) = match (self.body, self.proto) {
+            (Some(_), Some(_)) => {
+                return Err(error::invalid_argument(
+                    "batch requests can't have both a body and a proto",
+                ))
+            }
+            (_, Some(proto)) => {
+                let bytes = STANDARD.decode(proto).map_err(|err| {
+                    let mut twirp_err = error::malformed("proto is not valid base64");
+                    twirp_err.insert_meta("error".to_string(), err.to_string());
+                    twirp_err
+                })?;
+                (Body::from(bytes), "application/protobuf", true)
+            }
+            (body, None) => {
+                let body = body.unwrap_or_else(|| serde_json::Value::Object(Default::default()));
+                (Body::from(body.to_string()), "application/json", false)
+            }
+        };
+
+        let uri = format!("{prefix}{}/{}", self.service, self.method);
+        let mut req = Request::new(body);
+        *req.method_mut() = Method::POST;
+        *req.uri_mut() = uri
+            .parse()
+            .map_err(|_| error::invalid_argument(format!("invalid route: {uri}")))?;
+        // The headers that describe the batch's body don't apply to the sub-request's, and the
+        // response is read here, so it must not be compressed.
+        let sub_headers = req.headers_mut();
+        *sub_headers = headers.clone();
+        sub_headers.remove(header::CONTENT_LENGTH);
+        sub_headers.remove(header::CONTENT_ENCODING);
+        sub_headers.remove(header::ACCEPT_ENCODING);
+        #[cfg(feature = "checksum")]
+        sub_headers.remove(crate::checksum::X_CONTENT_SHA256);
+        sub_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        Ok((req, proto))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    responses: Vec<SubResponse>,
+}
+
+#[derive(Debug, Serialize)]
+struct SubResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proto: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<TwirpErrorResponse>,
+}
+
+impl SubResponse {
+    fn error(err: TwirpErrorResponse) -> Self {
+        Self {
+            body: None,
+            proto: None,
+            error: Some(err),
+        }
+    }
+
+    async fn from_response(resp: Response<Body>, proto: bool) -> Self {
+        let status = resp.status();
+        let bytes = match resp.into_body().collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(err) => return Self::error(error::internal(err.to_string())),
+        };
+        if !status.is_success() {
+            return Self::error(serde_json::from_slice(&bytes).unwrap_or_else(|_| {
+                error::internal(format!("sub-request failed with status {status}"))
+            }));
+        }
+        if proto {
+            return Self {
+                body: None,
+                proto: Some(STANDARD.encode(&bytes)),
+                error: None,
+            };
+        }
+        match serde_json::from_slice(&bytes) {
+            Ok(body) => Self {
+                body: Some(body),
+                proto: None,
+                error: None,
+            },
+            Err(err) => Self::error(error::internal(format!("invalid JSON response: {err}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::Service;
+
+    use super::*;
+    use crate::test::*;
+
+    fn batch_request(body: &str) -> Request<Body> {
+        Request::post("/twirp/$batch")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let mut router = Batch::new("/twirp")
+            .max_concurrency(2)
+            .add_to(test_api_router());
+        let proto = STANDARD.encode(crate::serialize_proto_message(PingRequest {
+            name: "proto".to_string(),
+        }));
+        let req = batch_request(&format!(
+            r#"{{"requests": [
+                {{"service": "test.TestAPI", "method": "Ping", "body": {{"name": "json"}}}},
+                {{"service": "test.TestAPI", "method": "Boom"}},
+                {{"service": "test.TestAPI", "method": "Nope"}},
+                {{"service": "test.TestAPI", "method": "Ping", "proto": "{proto}"}},
+                {{"service": "test.TestAPI/Ping/..", "method": "Ping"}}
+            ]}}"#
+        ));
+
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_success());
+        let data: serde_json::Value = read_json_body(resp.into_body()).await;
+        let responses = data["responses"].as_array().unwrap();
+        assert_eq!(responses.len(), 5);
+        assert_eq!(responses[0]["body"]["name"], "json");
+        assert_eq!(responses[1]["error"]["code"], "internal");
+        assert_eq!(responses[2]["error"]["code"], "bad_route");
+        let bytes = STANDARD
+            .decode(responses[3]["proto"].as_str().unwrap())
+            .unwrap();
+        let resp = <PingResponse as prost::Message>::decode(&bytes[..]).unwrap();
+        assert_eq!(resp.name, "proto");
+        assert_eq!(responses[4]["error"]["code"], "invalid_argument");
+    }
+
+    #[tokio::test]
+    async fn test_batch_limits() {
+        let mut router = Batch::new("/twirp/")
+            .max_requests(1)
+            .add_to(test_api_router());
+
+        let resp = router.call(batch_request("not json")).await.unwrap();
+        assert_eq!(
+            read_err_body(resp.into_body()).await.code,
+            crate::TwirpErrorCode::Malformed
+        );
+
+        let resp = router
+            .call(batch_request(
+                r#"{"requests": [
+                    {"service": "test.TestAPI", "method": "Ping"},
+                    {"service": "test.TestAPI", "method": "Ping"}
+                ]}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_err_body(resp.into_body()).await.code,
+            crate::TwirpErrorCode::InvalidArgument
+        );
+
+        let mut router = Batch::new("/twirp")
+            .max_request_size(16)
+            .add_to(test_api_router());
+        let resp = router
+            .call(batch_request(
+                r#"{"requests": [{"service": "test.TestAPI", "method": "Ping"}]}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            read_err_body(resp.into_body()).await.code,
+            crate::TwirpErrorCode::ResourceExhausted
+        );
+
+        let mut req = batch_request(r#"{"requests": []}"#);
+        req.headers_mut()
+            .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(
+            read_err_body(resp.into_body()).await.code,
+            crate::TwirpErrorCode::Malformed
+        );
+    }
+
+    #[cfg(all(feature = "gzip", feature = "checksum"))]
+    #[tokio::test]
+    async fn test_batch_body_headers() {
+        use crate::checksum::{Checksums, X_CONTENT_SHA256};
+        use crate::compression::Gzip;
+
+        let routes = test_api_router_builder()
+            .gzip(Gzip::new().min_size(0))
+            .checksums(Checksums::new())
+            .build();
+        let app = axum::Router::new().nest("/twirp/test.TestAPI", routes);
+        let mut router = Batch::new("/twirp").add_to(app);
+
+        // Neither the batch's checksum nor its `Accept-Encoding` apply to the sub-requests.
+        let mut req = batch_request(
+            r#"{"requests": [{"service": "test.TestAPI", "method": "Ping", "body": {"name": "a"}}]}"#,
+        );
+        req.headers_mut().insert(
+            X_CONTENT_SHA256,
+            HeaderValue::from_static("bm90IHRoZSBjaGVja3N1bQ"),
+        );
+        req.headers_mut()
+            .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let resp = router.call(req).await.unwrap();
+        let data: serde_json::Value = read_json_body(resp.into_body()).await;
+        assert_eq!(data["responses"][0]["body"]["name"], "a", "{data}");
+    }
+}
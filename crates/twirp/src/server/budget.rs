@@ -0,0 +1,294 @@
+//! A memory budget for the request and response bodies a router holds at once.
+//!
+//! Twirp buffers each request body before decoding it, and each response body before sending it.
+//! A [`MemoryBudget`] counts those bytes for as long as a request is being served, and sheds
+//! requests that would take the total over the budget with an `unavailable` error, so that a
+//! spike of large requests can't run the process out of memory. Clones share the same budget, so
+//! one budget can be given to the routers of several services:
+//!
+//! ```
+//! use twirp::server::budget::MemoryBudget;
+//!
+//! # fn example<T>(builder: twirp::details::TwirpRouterBuilder<T>) -> twirp::Router
+//! # where T: Clone + Send + Sync + 'static {
+//! let budget = MemoryBudget::new(512 * 1024 * 1024);
+//! builder.memory_budget(budget.clone()).build()
+//! # }
+//! ```
+//!
+//! Bodies are counted in whole KiB. The messages decoded from request bodies, and the bodies of
+//! server-streaming responses, are not counted.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use bytes::{Bytes, BytesMut};
+use http_body_util::BodyExt;
+use hyper::body::{Body as _, Frame, SizeHint};
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{error, GenericError, TwirpErrorResponse};
+
+const KIB: usize = 1024;
+
+/// A number of bytes that the bodies held by routers can add up to. See the
+/// [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    kib: usize,
+    permits: Arc<Semaphore>,
+    shed: Arc<AtomicU64>,
+}
+
+impl MemoryBudget {
+    /// A budget of `bytes`, rounded up to a whole KiB.
+    pub fn new(bytes: usize) -> Self {
+        let kib = bytes.div_ceil(KIB).min(Semaphore::MAX_PERMITS);
+        Self {
+            kib,
+            permits: Arc::new(Semaphore::new(kib)),
+            shed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The bytes currently held by bodies.
+    pub fn in_use(&self) -> usize {
+        (self.kib - self.permits.available_permits()) * KIB
+    }
+
+    /// The number of requests that have been shed for going over the budget, in total.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+}
+
+/// The error for a request that would go over its router's [`MemoryBudget`].
+#[derive(Debug, Error)]
+#[error("memory budget exceeded")]
+pub(crate) struct OverBudget;
+
+impl From<OverBudget> for TwirpErrorResponse {
+    fn from(_: OverBudget) -> Self {
+        error::unavailable("server is out of memory for request bodies")
+    }
+}
+
+/// The error for a request body longer than its router's
+/// [`max_request_size`](crate::details::TwirpRouterBuilder::max_request_size).
+#[derive(Debug, Error)]
+#[error("request body is larger than {0} bytes")]
+pub(crate) struct TooLarge(pub(crate) usize);
+
+impl From<TooLarge> for TwirpErrorResponse {
+    fn from(err: TooLarge) -> Self {
+        error::resource_exhausted(err.to_string())
+    }
+}
+
+/// The part of a [`MemoryBudget`] held for one request, returned when it is dropped. Without a
+/// budget, nothing is counted.
+#[derive(Debug, Default)]
+pub(crate) struct Reservation {
+    budget: Option<MemoryBudget>,
+    bytes: usize,
+    permits: Option<OwnedSemaphorePermit>,
+}
+
+impl Reservation {
+    pub(crate) fn new(budget: Option<&MemoryBudget>) -> Self {
+        Self {
+            budget: budget.cloned(),
+            ..Default::default()
+        }
+    }
+
+    /// Count `bytes` more, or fail if that would go over the budget.
+    pub(crate) fn grow(&mut self, bytes: usize) -> Result<(), OverBudget> {
+        let Some(budget) = &self.budget else {
+            return Ok(());
+        };
+        let total = self.bytes.saturating_add(bytes);
+        let held = self.permits.as_ref().map_or(0, |p| p.num_permits());
+        let needed = total.div_ceil(KIB) - held;
+        if needed > 0 {
+            let permits = u32::try_from(needed)
+                .ok()
+                .and_then(|n| budget.permits.clone().try_acquire_many_owned(n).ok());
+            let Some(permits) = permits else {
+                budget.shed.fetch_add(1, Ordering::Relaxed);
+                return Err(OverBudget);
+            };
+            match &mut self.permits {
+                Some(held) => held.merge(permits),
+                None => self.permits = Some(permits),
+            }
+        }
+        self.bytes = total;
+        Ok(())
+    }
+
+    /// Read all of `body`, counting it as it arrives. Stops reading, and fails, once it is
+    /// longer than `limit`.
+    pub(crate) async fn collect(
+        &mut self,
+        body: Body,
+        limit: Option<usize>,
+    ) -> Result<Bytes, GenericError> {
+        if let Some(limit) = limit {
+            // The `Content-Length`, if the client sent one.
+            if body.size_hint().lower() > limit as u64 {
+                return Err(TooLarge(limit).into());
+            }
+        }
+        if self.budget.is_none() && limit.is_none() {
+            return Ok(body.collect().await?.to_bytes());
+        }
+        let mut body = body;
+        let mut buf = BytesMut::new();
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame?.into_data() {
+                let len = buf.len() + data.len();
+                if let Some(limit) = limit.filter(|limit| len > *limit) {
+                    return Err(TooLarge(limit).into());
+                }
+                self.grow(data.len())?;
+                buf.extend_from_slice(&data);
+            }
+        }
+        Ok(buf.freeze())
+    }
+
+    /// Count the body of `resp` too, and keep the reservation until the body has been sent.
+    pub(crate) fn hold_response(
+        mut self,
+        resp: http::Response<Body>,
+    ) -> Result<http::Response<Body>, OverBudget> {
+        if self.budget.is_none() {
+            return Ok(resp);
+        }
+        let len = resp.body().size_hint().exact().unwrap_or_default();
+        self.grow(usize::try_from(len).unwrap_or(usize::MAX))?;
+        Ok(resp.map(|body| {
+            Body::new(ReservedBody {
+                inner: body,
+                _reservation: self,
+            })
+        }))
+    }
+}
+
+// A body that holds a reservation until it is dropped.
+struct ReservedBody {
+    inner: Body,
+    _reservation: Reservation,
+}
+
+impl hyper::body::Body for ReservedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        std::pin::Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::Service;
+
+    use super::*;
+    use crate::test::*;
+    use crate::TwirpErrorCode;
+
+    #[test]
+    fn test_reservation() {
+        let budget = MemoryBudget::new(4 * KIB);
+        let mut first = Reservation::new(Some(&budget));
+        first.grow(100).unwrap();
+        first.grow(1000).unwrap();
+        assert_eq!(budget.in_use(), 2 * KIB);
+
+        let mut second = Reservation::new(Some(&budget));
+        second.grow(2 * KIB).unwrap();
+        assert!(second.grow(1).is_err());
+        assert_eq!(budget.shed(), 1);
+
+        drop(first);
+        second.grow(1).unwrap();
+        assert_eq!(budget.in_use(), 3 * KIB);
+        drop(second);
+        assert_eq!(budget.in_use(), 0);
+
+        Reservation::new(None).grow(usize::MAX).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_request_size() {
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder().max_request_size(KIB).build(),
+        );
+
+        let resp = router.call(gen_ping_request("small")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        let resp = router
+            .call(gen_ping_request(&"x".repeat(2 * KIB)))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 429);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::ResourceExhausted);
+        assert_eq!(err.msg, "request body is larger than 1024 bytes");
+
+        // Bodies without a length are cut off as they arrive.
+        let chunk = || Ok::<_, std::io::Error>(Bytes::from(vec![b' '; KIB]));
+        let chunks = vec![chunk(), chunk()];
+        let mut req = gen_ping_request("streamed");
+        *req.body_mut() = Body::from_stream(futures::stream::iter(chunks));
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), 429);
+    }
+
+    #[tokio::test]
+    async fn test_router_budget() {
+        let budget = MemoryBudget::new(KIB);
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder()
+                .memory_budget(budget.clone())
+                .build(),
+        );
+
+        let resp = router.call(gen_ping_request("small")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(budget.in_use(), KIB);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "small");
+        assert_eq!(budget.in_use(), 0);
+
+        let resp = router
+            .call(gen_ping_request(&"x".repeat(2 * KIB)))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 503);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Unavailable);
+        assert_eq!(budget.shed(), 1);
+        assert_eq!(budget.in_use(), 0);
+    }
+}
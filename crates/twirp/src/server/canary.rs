@@ -0,0 +1,179 @@
+//! Gradual rollouts between two implementations of the same Twirp service.
+//!
+//! [`Canary`] combines two routers generated for the same service (e.g. the current
+//! implementation and a rewrite of it) and dispatches each call to one of them, either by weight or
+//! because the caller asked for a specific variant with a header.
+//!
+//! # Usage
+//!
+//! ```
+//! use twirp::server::canary::Canary;
+//! use twirp::http::HeaderName;
+//! use twirp::Router;
+//!
+//! # fn build_app(current: Router, rewrite: Router) -> Router {
+//! // `current` and `rewrite` would be `haberdash::router(OldImpl)` and `haberdash::router(NewImpl)`.
+//! let routes = Canary::new(current, rewrite)
+//!     .weight(5.0)
+//!     .header(HeaderName::from_static("x-canary"))
+//!     .into_router();
+//! let app = Router::new().nest("/twirp/service.haberdash.v1.HaberdasherAPI", routes);
+//! # app }
+//! ```
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use axum::Router;
+use futures::future::BoxFuture;
+use http::HeaderName;
+use hyper::{Request, Response};
+use tower::Service;
+
+use super::Sampler;
+
+/// Which of the two implementations served a request. Inserted into the request extensions (and
+/// so visible to handlers through [`Context`](crate::Context)) and the response extensions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    Primary,
+    Canary,
+}
+
+/// Router combinator that sends a fraction of calls to an alternate implementation.
+#[derive(Clone)]
+pub struct Canary {
+    primary: Router,
+    canary: Router,
+    sampler: Arc<Sampler>,
+    header: Option<HeaderName>,
+}
+
+impl Canary {
+    /// Combine `primary` and `canary`. By default every call goes to `primary`.
+    pub fn new(primary: Router, canary: Router) -> Self {
+        Self {
+            primary,
+            canary,
+            sampler: Arc::new(Sampler::new(0.0)),
+            header: None,
+        }
+    }
+
+    /// Send `percent` percent (`0.0..=100.0`) of calls to the canary implementation.
+    pub fn weight(mut self, percent: f64) -> Self {
+        self.sampler = Arc::new(Sampler::new(percent));
+        self
+    }
+
+    /// Let callers pick the implementation with a request header. A value of `canary`, `true` or
+    /// `1` selects the canary and `primary`, `false` or `0` selects the primary implementation.
+    /// Any other value (or no header at all) falls back to the configured weight.
+    pub fn header(mut self, name: HeaderName) -> Self {
+        self.header = Some(name);
+        self
+    }
+
+    /// Turn the combinator into a router that can be nested like a generated service router.
+    pub fn into_router(self) -> Router {
+        Router::new().fallback_service(self)
+    }
+
+    fn select(&self, req: &Request<Body>) -> Variant {
+        let requested = self
+            .header
+            .as_ref()
+            .and_then(|name| req.headers().get(name))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| match v.trim().to_ascii_lowercase().as_str() {
+                "canary" | "true" | "1" => Some(Variant::Canary),
+                "primary" | "false" | "0" => Some(Variant::Primary),
+                _ => None,
+            });
+        match requested {
+            Some(variant) => variant,
+            None if self.sampler.sample() => Variant::Canary,
+            None => Variant::Primary,
+        }
+    }
+}
+
+impl Service<Request<Body>> for Canary {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // `axum::Router` is always ready.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let variant = self.select(&req);
+        req.extensions_mut().insert(variant);
+        let router = match variant {
+            Variant::Primary => &mut self.primary,
+            Variant::Canary => &mut self.canary,
+        };
+        let fut = <Router as Service<Request<Body>>>::call(router, req);
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            resp.extensions_mut().insert(variant);
+            Ok(resp)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::*;
+
+    fn canary_router() -> Router {
+        // The "canary" implementation here is one that always fails, which makes it easy to tell
+        // which side served a request.
+        let primary = test_api_service_router();
+        let canary = Router::new().fallback(|| async { crate::error::unavailable("canary") });
+        Router::new().nest(
+            "/twirp/test.TestAPI",
+            Canary::new(primary, canary)
+                .weight(25.0)
+                .header(HeaderName::from_static("x-canary"))
+                .into_router(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_weighted() {
+        let mut router = canary_router();
+        let mut canaries = 0;
+        for _ in 0..8 {
+            let resp = router.call(gen_ping_request("hi")).await.unwrap();
+            if resp.status().is_server_error() {
+                canaries += 1;
+            }
+        }
+        assert_eq!(canaries, 2);
+    }
+
+    #[tokio::test]
+    async fn test_header_selection() {
+        let mut router = canary_router();
+        for _ in 0..4 {
+            let mut req = gen_ping_request("hi");
+            req.headers_mut()
+                .insert("x-canary", "primary".parse().unwrap());
+            let resp = router.call(req).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+            assert_eq!(resp.extensions().get(), Some(&Variant::Primary));
+        }
+
+        let mut req = gen_ping_request("hi");
+        req.headers_mut().insert("x-canary", "1".parse().unwrap());
+        let resp = router.call(req).await.unwrap();
+        assert!(resp.status().is_server_error(), "{:?}", resp);
+        assert_eq!(resp.extensions().get(), Some(&Variant::Canary));
+    }
+}
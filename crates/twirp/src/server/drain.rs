@@ -0,0 +1,228 @@
+//! Connection draining for graceful shutdown.
+//!
+//! Once a [`Drain`] has started, requests passing through its layer are answered with
+//! `unavailable` and a `Retry-After` header, so load balancers fail over to other instances, while
+//! requests that were already in flight are allowed to finish. See
+//! [`serve_with_shutdown`](super::serve_with_shutdown) for the usual way to use this.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Body;
+use futures::future::BoxFuture;
+use hyper::{Request, Response};
+use tokio::sync::Notify;
+use tower::{Layer, Service};
+
+use crate::error::{self, PreserializedError};
+
+/// Shared draining state. Clones share the same state.
+#[derive(Clone, Debug)]
+pub struct Drain {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    window: Duration,
+    retry_after: Duration,
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    idle: Notify,
+    // Serialized on first use, so that a global redactor set at startup applies.
+    rejection: OnceLock<PreserializedError>,
+}
+
+impl Drain {
+    /// Create a drain that waits up to `window` for in-flight requests to finish. Rejected
+    /// requests are asked to retry after `window` too, unless changed with [`Drain::retry_after`].
+    pub fn new(window: Duration) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                window,
+                retry_after: window,
+                draining: AtomicBool::new(false),
+                in_flight: AtomicUsize::new(0),
+                idle: Notify::new(),
+                rejection: OnceLock::new(),
+            }),
+        }
+    }
+
+    /// Set the `Retry-After` sent to requests rejected while draining.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after the drain has been cloned or its layer built.
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        Arc::get_mut(&mut self.inner)
+            .expect("retry_after must be set before the drain is shared")
+            .retry_after = retry_after;
+        self
+    }
+
+    /// Build the layer that tracks in-flight requests and rejects new ones while draining.
+    pub fn layer(&self) -> DrainLayer {
+        DrainLayer {
+            drain: self.clone(),
+        }
+    }
+
+    /// Whether [`Drain::drain`] has been called, i.e. new requests are being rejected.
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::Acquire)
+    }
+
+    /// The number of requests currently being handled.
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Start rejecting new requests, then wait until all in-flight requests have finished or the
+    /// drain window has elapsed, whichever comes first.
+    pub fn drain(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.inner.draining.store(true, Ordering::Release);
+        let inner = self.inner.clone();
+        async move {
+            let idle = async {
+                loop {
+                    // Register interest before checking, so a request finishing in between isn't
+                    // missed.
+                    let notified = inner.idle.notified();
+                    if inner.in_flight.load(Ordering::Acquire) == 0 {
+                        return;
+                    }
+                    notified.await;
+                }
+            };
+            let _ = tokio::time::timeout(inner.window, idle).await;
+        }
+    }
+
+    pub(crate) fn window(&self) -> Duration {
+        self.inner.window
+    }
+
+    fn error_response(&self) -> Response<Body> {
+        self.inner
+            .rejection
+            .get_or_init(|| {
+                error::unavailable("server is shutting down")
+                    .with_retry_after(self.inner.retry_after)
+                    .preserialize()
+            })
+            .to_response()
+    }
+}
+
+// Decrements the in-flight count when a request finishes (or its future is dropped).
+struct InFlight(Arc<Inner>);
+
+impl InFlight {
+    fn new(inner: &Arc<Inner>) -> Self {
+        inner.in_flight.fetch_add(1, Ordering::AcqRel);
+        Self(inner.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// Layer that applies the [`Draining`] middleware. Created by [`Drain::layer`].
+#[derive(Clone, Debug)]
+pub struct DrainLayer {
+    drain: Drain,
+}
+
+impl<S> Layer<S> for DrainLayer {
+    type Service = Draining<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Draining {
+            inner,
+            drain: self.drain.clone(),
+        }
+    }
+}
+
+/// Middleware that counts in-flight requests and rejects new ones once draining has started.
+#[derive(Clone, Debug)]
+pub struct Draining<S> {
+    inner: S,
+    drain: Drain,
+}
+
+impl<S> Service<Request<Body>> for Draining<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if self.drain.is_draining() {
+            let resp = self.drain.error_response();
+            return Box::pin(async move { Ok(resp) });
+        }
+
+        let guard = InFlight::new(&self.drain.inner);
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let resp = fut.await;
+            drop(guard);
+            resp
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::test::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let drain = Drain::new(Duration::from_secs(5));
+        let slow = tower::service_fn(|_req: Request<Body>| async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok::<_, Infallible>(Response::new(Body::empty()))
+        });
+        let svc = drain.layer().layer(slow);
+
+        let in_flight = tokio::spawn({
+            let mut svc = svc.clone();
+            async move { svc.call(gen_ping_request("hi")).await }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(drain.in_flight(), 1);
+
+        let start = Instant::now();
+        let done = drain.drain();
+        let resp = svc.clone().call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers()[http::header::RETRY_AFTER], "5");
+
+        done.await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(drain.in_flight(), 0);
+        assert!(in_flight.await.unwrap().unwrap().status().is_success());
+    }
+}
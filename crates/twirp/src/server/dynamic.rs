@@ -0,0 +1,432 @@
+//! Serving services from their descriptors, without generated code.
+//!
+//! [`DynamicServer`] routes every unary method of the services in a descriptor pool, like the
+//! `router()` that `twirp-build` generates would, and hands each request to one handler as a
+//! [`DynamicMessage`] along with the method's descriptor. That is enough for protocol gateways,
+//! stubs of services under development and traffic simulators, which work with any service they
+//! are given the descriptors of:
+//!
+//! ```
+//! use twirp::prost_reflect::{DescriptorPool, DynamicMessage};
+//! use twirp::server::dynamic::DynamicServer;
+//! use twirp::Router;
+//!
+//! # fn build_app(descriptors: &[u8]) -> Router {
+//! let pool = DescriptorPool::decode(descriptors).expect("valid descriptors");
+//! // A stub that answers every method with an empty response.
+//! let server = DynamicServer::new(pool, |_ctx, method, _req| async move {
+//!     Ok(DynamicMessage::new(method.output()))
+//! });
+//! let app = Router::new().nest("/twirp", server.router());
+//! # app }
+//! ```
+//!
+//! Requests are decoded and responses encoded in the format given by the request's content type,
+//! as protobuf or JSON. JSON is written the way serde derives for prost messages write it (see
+//! [`DynamicServer::protojson`]). Streaming methods are not routed. Hooks, timeouts, error
+//! redaction and request size limits are configured on the server like on the routers of
+//! generated services.
+//!
+//! Requires the [`reflect` feature](crate#the-reflect-feature).
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::{Extension, Router};
+use futures::future::BoxFuture;
+use http::{header, Extensions, Response};
+use prost::Message;
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, MethodDescriptor, ReflectMessage, SerializeOptions,
+};
+use tokio::time::Instant;
+
+use super::budget::{MemoryBudget, Reservation};
+use super::hooks::ServerHooks;
+use super::{
+    add_response_extensions, call_handler, method_not_allowed_handler, not_found_handler,
+    options_handler, parse_error, set_peer_info, AllowedMethods, BodyFormat, RouterConfig, Timings,
+};
+use crate::context::{CancelOnDrop, RequestSpan, RpcMethod};
+use crate::{
+    Compatibility, Context, GenericError, IntoTwirpResponse, Redactor, TwirpErrorResponse,
+};
+
+type Handler = dyn Fn(
+        Context,
+        MethodDescriptor,
+        DynamicMessage,
+    ) -> BoxFuture<'static, Result<DynamicMessage, TwirpErrorResponse>>
+    + Send
+    + Sync;
+
+/// The routes of the services in a descriptor pool, all served by one handler. See the
+/// [module documentation](self).
+#[derive(Clone)]
+pub struct DynamicServer {
+    pool: DescriptorPool,
+    handler: Arc<Handler>,
+    protojson: bool,
+    config: RouterConfig,
+}
+
+impl std::fmt::Debug for DynamicServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DynamicServer")
+            .field("pool", &self.pool)
+            .field("protojson", &self.protojson)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DynamicServer {
+    /// Serve the services in `pool` with `handler`, which is called with the request's
+    /// [`Context`], the descriptor of the method that was called (its
+    /// [`parent_service`](MethodDescriptor::parent_service) is the service) and the request
+    /// message, and resolves to a message of the method's output type.
+    pub fn new<F, Fut>(pool: DescriptorPool, handler: F) -> Self
+    where
+        F: Fn(Context, MethodDescriptor, DynamicMessage) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<DynamicMessage, TwirpErrorResponse>> + Send + 'static,
+    {
+        Self {
+            pool,
+            handler: Arc::new(move |ctx, method, req| Box::pin(handler(ctx, method, req))),
+            protojson: false,
+            config: RouterConfig::default(),
+        }
+    }
+
+    /// Write JSON responses as proto3 JSON: with the fields' JSON names (`lowerCamelCase`), enums
+    /// as the names of their values and 64-bit integers as strings. Requests are accepted either
+    /// way.
+    pub fn protojson(mut self, protojson: bool) -> Self {
+        self.protojson = protojson;
+        self
+    }
+
+    /// Set the version of the Twirp protocol to stay compatible with. See [`Compatibility`].
+    pub fn compatibility(mut self, compatibility: Compatibility) -> Self {
+        self.config.compatibility = compatibility;
+        self
+    }
+
+    /// Send the underlying Rust error of failed requests to clients in the `debug` meta value.
+    /// This exposes internals, so only enable it where all callers are trusted.
+    pub fn debug_errors(mut self, enabled: bool) -> Self {
+        self.config.debug_errors = enabled;
+        self
+    }
+
+    /// Add the service, method and request id to the `meta` of the errors the server sends, as
+    /// `service`, `method` and `request_id`. Values set by the handler are kept.
+    pub fn annotate_errors(mut self, enabled: bool) -> Self {
+        self.config.annotate_errors = enabled;
+        self
+    }
+
+    /// Count the request and response bodies the server holds against `budget`, shedding
+    /// requests that go over it. See [`super::budget`].
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.config.memory_budget = Some(budget);
+        self
+    }
+
+    /// Reject requests with bodies longer than `bytes` with a `resource_exhausted` error, without
+    /// reading the rest of the body.
+    pub fn max_request_size(mut self, bytes: usize) -> Self {
+        self.config.max_request_size = Some(bytes);
+        self
+    }
+
+    /// Stop handlers that run for longer than `timeout` and respond with `deadline_exceeded`.
+    /// Requests can set a shorter timeout with the `Twirp-Timeout` header, but not a longer one.
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Scrub the errors the server sends with `redactor`, before the
+    /// [global redactor](crate::set_global_redactor), if any.
+    pub fn redactor(mut self, redactor: Redactor) -> Self {
+        self.config.redactor = Some(redactor);
+        self
+    }
+
+    /// Call `hooks` at each phase of the requests the server handles; see [`super::hooks`].
+    /// Hooks added later are called after those added earlier.
+    pub fn hooks(mut self, hooks: impl ServerHooks) -> Self {
+        self.config.hooks.push(hooks);
+        self
+    }
+
+    /// The router, with a route for each unary method at `/{package}.{Service}/{Method}`. Nest it
+    /// under the prefix the services are served at (usually `/twirp`).
+    ///
+    /// The names of the services and methods are kept for as long as the program runs, since
+    /// hooks and errors refer to them by `&'static str`; build the router once.
+    pub fn router(self) -> Router {
+        let allowed = AllowedMethods::Post;
+        let mut router = Router::new();
+        for service in self.pool.services() {
+            let service_fqn = leak(format!("/{}", service.full_name()));
+            let config = Arc::new(RouterConfig {
+                service_fqn,
+                ..self.config.clone()
+            });
+            let methods = service
+                .methods()
+                .filter(|m| !m.is_client_streaming() && !m.is_server_streaming());
+            for method in methods {
+                let url = leak(format!("/{}", method.name()));
+                let server = self.clone();
+                let method_router = axum::routing::post(move |req: Request| async move {
+                    server.handle(method, req).await
+                })
+                .options(move |req: Request| options_handler(req, allowed))
+                .fallback(move |req: Request| method_not_allowed_handler(req, allowed))
+                .layer((
+                    Extension(RpcMethod::new(service_fqn, url)),
+                    Extension(config.clone()),
+                ));
+                router = router.route(&format!("{service_fqn}{url}"), method_router);
+            }
+        }
+        router.fallback(not_found_handler)
+    }
+
+    async fn handle(&self, method: MethodDescriptor, mut req: Request) -> Response<Body> {
+        let mut timings = Timings::new(Instant::now());
+        let config = RouterConfig::from_request(&req);
+        let span = RequestSpan::start(req.extensions_mut());
+        let hooks = config.hooks.start(config.rpc(&req), span.clone(), &timings);
+        let error_context = config.error_context(&req);
+        let deadline = config.set_deadline(&mut req, &timings);
+        let cancel = CancelOnDrop::new(req.extensions_mut());
+        set_peer_info(&mut req);
+        let format = BodyFormat::from_content_type(&req, &config);
+        let (parts, body) = req.into_parts();
+        let mut reservation = Reservation::new(config.memory_budget.as_ref());
+        let bytes = reservation.collect(body, config.max_request_size).await;
+        let request = match bytes.and_then(|bytes| decode(&method, &format, bytes)) {
+            Ok(request) => request,
+            Err(err) => {
+                let err = parse_error(err).into_twirp_response();
+                return config.fail(&error_context, &hooks, &timings, err);
+            }
+        };
+        timings.set_parsed();
+        hooks.routed(&timings);
+
+        let resp_exts = Arc::new(Mutex::new(Extensions::new()));
+        let ctx = Context::new(parts.extensions, resp_exts.clone()).with_headers(parts.headers);
+        let handler = span.instrument((self.handler)(ctx, method.clone(), request));
+        let res = call_handler(deadline, cancel, handler)
+            .await
+            .and_then(|response| {
+                self.encode(&method, &format, &response)
+                    .map_err(IntoTwirpResponse::into_twirp_response)
+            });
+        timings.set_response_handled();
+        let data = match res {
+            Ok(data) => data,
+            Err(err) => return config.fail(&error_context, &hooks, &timings, err),
+        };
+        hooks.prepared(&timings);
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, format.codec().content_type())
+            .body(Body::from(data))
+            .expect("valid response");
+        timings.set_response_written();
+        let mut response = match reservation.hold_response(response) {
+            Ok(response) => response,
+            Err(err) => {
+                let err = TwirpErrorResponse::from(err).into_twirp_response();
+                return config.fail(&error_context, &hooks, &timings, err);
+            }
+        };
+        add_response_extensions(&mut response, &resp_exts);
+        response.extensions_mut().insert(timings);
+        hooks.sent(&timings, response.status());
+        response
+    }
+
+    // The response message, which must be of the method's output type.
+    fn encode(
+        &self,
+        method: &MethodDescriptor,
+        format: &BodyFormat,
+        message: &DynamicMessage,
+    ) -> Result<Vec<u8>, TwirpErrorResponse> {
+        if message.descriptor() != method.output() {
+            let err = crate::internal(format!(
+                "handler returned a {}, but {} returns a {}",
+                message.descriptor().full_name(),
+                method.full_name(),
+                method.output().full_name(),
+            ));
+            return Err(err);
+        }
+        if let BodyFormat::Pb = format {
+            return Ok(message.encode_to_vec());
+        }
+        let options = SerializeOptions::new()
+            .use_proto_field_name(!self.protojson)
+            .use_enum_numbers(!self.protojson)
+            .stringify_64_bit_integers(self.protojson);
+        let mut serializer = serde_json::Serializer::new(vec![]);
+        match message.serialize_with_options(&mut serializer, &options) {
+            Ok(()) => Ok(serializer.into_inner()),
+            Err(err) => Err(crate::unknown("error serializing response").with_meta("error", err)),
+        }
+    }
+}
+
+// The request message, as protobuf or as JSON (with proto or JSON field names).
+fn decode(
+    method: &MethodDescriptor,
+    format: &BodyFormat,
+    bytes: bytes::Bytes,
+) -> Result<DynamicMessage, GenericError> {
+    match format {
+        BodyFormat::Pb => Ok(DynamicMessage::decode(method.input(), bytes)?),
+        _ => {
+            let mut deserializer = serde_json::Deserializer::from_slice(&bytes);
+            let message = DynamicMessage::deserialize(method.input(), &mut deserializer)?;
+            deserializer.end()?;
+            Ok(message)
+        }
+    }
+}
+
+fn leak(name: String) -> &'static str {
+    Box::leak(name.into_boxed_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+    use http_body_util::BodyExt;
+    use prost_reflect::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test::{descriptor_pool, read_err_body, read_json_body};
+    use crate::TwirpErrorCode;
+
+    // Makes the hat one inch bigger; `Broken` fails.
+    fn server() -> DynamicServer {
+        DynamicServer::new(descriptor_pool(), |_ctx, method, mut hat| async move {
+            if method.name() == "Broken" {
+                return Err(crate::unavailable("out of hats"));
+            }
+            let size = hat
+                .get_field_by_name("size_inches")
+                .unwrap()
+                .as_i64()
+                .unwrap();
+            hat.set_field_by_name("size_inches", Value::I64(size + 1));
+            Ok(hat)
+        })
+    }
+
+    fn request(path: &str, content_type: &str, body: impl Into<Body>) -> Request {
+        Request::post(path)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let req = request(
+            "/test.Haberdasher/MakeHat",
+            "application/json",
+            r#"{"name": "fedora", "sizeInches": 7}"#,
+        );
+        let resp = server().router().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let hat: serde_json::Value = read_json_body(resp.into_body()).await;
+        assert_eq!(hat, serde_json::json!({"name": "fedora", "size_inches": 8}));
+
+        let req = request(
+            "/test.Haberdasher/MakeHat",
+            "application/json",
+            r#"{"size_inches": 1}"#,
+        );
+        let resp = server()
+            .protojson(true)
+            .router()
+            .oneshot(req)
+            .await
+            .unwrap();
+        let hat: serde_json::Value = read_json_body(resp.into_body()).await;
+        assert_eq!(hat, serde_json::json!({"sizeInches": "2"}));
+    }
+
+    #[tokio::test]
+    async fn test_protobuf() {
+        let desc = descriptor_pool().get_message_by_name("test.Hat").unwrap();
+        let mut hat = DynamicMessage::new(desc.clone());
+        hat.set_field_by_name("name", Value::String("beret".to_string()));
+        let req = request(
+            "/test.Haberdasher/MakeHat",
+            "application/protobuf",
+            hat.encode_to_vec(),
+        );
+        let resp = server().router().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let hat = DynamicMessage::decode(desc, body).unwrap();
+        assert_eq!(
+            hat.get_field_by_name("name").unwrap().as_str(),
+            Some("beret")
+        );
+        assert_eq!(
+            hat.get_field_by_name("size_inches").unwrap().as_i64(),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let req = request("/test.Haberdasher/Broken", "application/json", "{}");
+        let resp = server().router().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Unavailable);
+
+        let req = request("/test.Haberdasher/MakeHat", "application/json", "{\"size\"");
+        let resp = server().router().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Malformed);
+
+        let req = request("/test.Haberdasher/BuyHat", "application/json", "{}");
+        let resp = server().router().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+    }
+
+    #[tokio::test]
+    async fn test_config() {
+        let server = server().max_request_size(16).annotate_errors(true);
+
+        let body = r#"{"name": "a very tall top hat"}"#;
+        let req = request("/test.Haberdasher/MakeHat", "application/json", body);
+        let resp = server.clone().router().oneshot(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::ResourceExhausted);
+
+        let req = request("/test.Haberdasher/Broken", "application/json", "{}");
+        let resp = server.router().oneshot(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Unavailable);
+        assert_eq!(err.meta["service"], "test.Haberdasher");
+        assert_eq!(err.meta["method"], "Broken");
+    }
+}
@@ -0,0 +1,154 @@
+//! Serving one implementation of a service over both Twirp and gRPC, with tonic.
+//!
+//! The Twirp router and the tonic service are both built from the same `Arc<MyServer>`. The
+//! tonic service implements each method by handing the request to the Twirp implementation with
+//! [`unary`], which gives it a [`Context`] built from the gRPC request, and turns its response or
+//! error into a gRPC one:
+//!
+//! ```ignore
+//! struct Grpc(Arc<MyServer>);
+//!
+//! #[tonic::async_trait]
+//! impl haberdasher_server::Haberdasher for Grpc {
+//!     async fn make_hat(
+//!         &self,
+//!         request: tonic::Request<MakeHatRequest>,
+//!     ) -> Result<tonic::Response<Hat>, tonic::Status> {
+//!         twirp::server::grpc::unary(request, |ctx, req| self.0.make_hat(ctx, req)).await
+//!     }
+//! }
+//!
+//! let api = Arc::new(MyServer::default());
+//! let twirp_routes = Router::new().nest(haberdash::SERVICE_FQN, haberdash::router(api.clone()));
+//! let grpc_service = haberdasher_server::HaberdasherServer::new(Grpc(api));
+//! ```
+//!
+//! The handler sees the gRPC metadata as the request [headers](Context::headers) and the request
+//! extensions of the tonic request, and a `grpc-timeout` as its [deadline](Context::deadline).
+//! Headers it sets with [`Context::set_response_header`] are sent as response metadata. Error
+//! codes are mapped with [`TwirpErrorCode::to_grpc_status`](crate::TwirpErrorCode::to_grpc_status);
+//! error meta values are not sent, as gRPC statuses have no equivalent.
+//!
+//! Requires the `tonic` feature.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use http::{Extensions, HeaderMap};
+use tokio::time::Instant;
+
+use crate::context::{Deadline, ResponseOverrides};
+use crate::server::grpc_web::parse_grpc_timeout;
+use crate::{Context, TwirpErrorResponse};
+
+/// Handle a gRPC request with `handler`, a method of a Twirp service implementation. See the
+/// [module documentation](self).
+pub async fn unary<Req, Resp, F, Fut>(
+    request: tonic::Request<Req>,
+    handler: F,
+) -> Result<tonic::Response<Resp>, tonic::Status>
+where
+    F: FnOnce(Context, Req) -> Fut,
+    Fut: Future<Output = Result<Resp, TwirpErrorResponse>>,
+{
+    let (metadata, mut extensions, req) = request.into_parts();
+    let headers = metadata.into_headers();
+    let deadline = headers
+        .get("grpc-timeout")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_grpc_timeout)
+        .map(|timeout| Instant::now() + timeout);
+    if let Some(deadline) = deadline {
+        extensions.insert(Deadline(deadline));
+    }
+    let resp_extensions = Arc::new(Mutex::new(Extensions::new()));
+    let ctx = Context::new(extensions, resp_extensions.clone()).with_headers(headers);
+
+    let fut = handler(ctx, req);
+    let res = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut)
+            .await
+            .unwrap_or_else(|elapsed| Err(elapsed.into())),
+        None => fut.await,
+    };
+
+    let mut headers = HeaderMap::new();
+    let resp_extensions = std::mem::take(&mut *resp_extensions.lock().expect("mutex poisoned"));
+    if let Some(overrides) = resp_extensions.get::<ResponseOverrides>() {
+        overrides.apply_headers(&mut headers);
+    }
+    let metadata = tonic::metadata::MetadataMap::from_headers(headers);
+    match res {
+        Ok(resp) => Ok(tonic::Response::from_parts(metadata, resp, resp_extensions)),
+        Err(err) => {
+            let code = tonic::Code::from(err.code.to_grpc_status());
+            Err(tonic::Status::with_metadata(code, err.msg, metadata))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::HeaderValue;
+
+    use super::*;
+    use crate::test::{PingRequest, PingResponse, RequestId, TestApi, TestApiServer};
+
+    #[tokio::test]
+    async fn test_unary() {
+        let mut request = tonic::Request::new(PingRequest {
+            name: "grpc".to_string(),
+        });
+        request
+            .extensions_mut()
+            .insert(RequestId("r-1".to_string()));
+        let resp = unary(request, |ctx, req| TestApiServer.ping(ctx, req))
+            .await
+            .unwrap();
+        assert_eq!(resp.get_ref().name, "grpc-r-1");
+
+        let status = unary(tonic::Request::new(PingRequest::default()), |ctx, req| {
+            TestApiServer.boom(ctx, req)
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+        assert_eq!(status.message(), "boom!");
+    }
+
+    #[tokio::test]
+    async fn test_metadata() {
+        let mut request = tonic::Request::new(PingRequest::default());
+        request
+            .metadata_mut()
+            .insert("x-name", "from-metadata".parse().unwrap());
+        let resp = unary(request, |ctx, _: PingRequest| async move {
+            ctx.set_response_header("x-served-by", HeaderValue::from_static("twirp"));
+            Ok(PingResponse {
+                name: ctx.headers()["x-name"].to_str().unwrap().to_string(),
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(resp.get_ref().name, "from-metadata");
+        assert_eq!(resp.metadata().get("x-served-by").unwrap(), "twirp");
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let mut request = tonic::Request::new(PingRequest::default());
+        request
+            .metadata_mut()
+            .insert("grpc-timeout", "10m".parse().unwrap());
+        let status = unary(request, |ctx, _: PingRequest| async move {
+            assert!(ctx.deadline().is_some());
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(PingResponse::default())
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+    }
+}
@@ -0,0 +1,474 @@
+//! A bridge that serves unary [gRPC-Web](https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-WEB.md)
+//! requests with Twirp handlers, so one binary can keep serving existing gRPC-Web frontends while
+//! they move to Twirp.
+//!
+//! [`GrpcWebLayer`] recognizes requests with an `application/grpc-web` (or
+//! `application/grpc-web+proto`) content type, and their base64 `application/grpc-web-text`
+//! variants. It unframes the request message and passes it on as a Twirp protobuf request to the
+//! same route, then frames the response message and the trailers (`grpc-status` and
+//! `grpc-message`, mapped from the Twirp error code) in the gRPC-Web response. Other requests pass
+//! through untouched.
+//!
+//! gRPC-Web clients call `/<package>.<Service>/<Method>` with no prefix, so add the layer where
+//! the service is mounted at the root:
+//!
+//! ```
+//! use twirp::server::grpc_web::GrpcWebLayer;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router) -> Router {
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes.clone())
+//!     .merge(twirp_routes.layer(GrpcWebLayer::new()));
+//! # app }
+//! ```
+//!
+//! Only unary calls with uncompressed messages are supported, and request bodies are limited to
+//! [`GrpcWebLayer::max_request_size`]. The router doesn't compress the Twirp responses it sends to
+//! the layer. A `grpc-timeout` is passed on as the Twirp timeout. Browsers need CORS headers that
+//! allow and expose the gRPC-Web headers, e.g. from `tower_http::cors`.
+
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Body;
+use base64::Engine;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future::BoxFuture;
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{HeaderMap, HeaderValue, StatusCode};
+use http_body_util::BodyExt;
+use hyper::{Request, Response};
+use tower::{Layer, Service};
+
+use super::read_request_body;
+use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF, TWIRP_TIMEOUT};
+use crate::TwirpErrorResponse;
+
+/// The time the client allows for a request, as an integer and a unit (e.g. `100m`).
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+/// The flag of frames that carry trailers rather than a message.
+const TRAILERS_FLAG: u8 = 0x80;
+/// The flag of frames with a compressed message.
+const COMPRESSED_FLAG: u8 = 0x01;
+
+/// Layer that applies the [`GrpcWeb`] middleware. See the [module documentation](self).
+#[derive(Clone, Copy, Debug)]
+pub struct GrpcWebLayer {
+    max_request_size: usize,
+}
+
+impl GrpcWebLayer {
+    /// A layer with the default settings.
+    pub fn new() -> Self {
+        Self {
+            max_request_size: 4 * 1024 * 1024,
+        }
+    }
+
+    /// Reject gRPC-Web requests with bodies (as sent, so base64 for `grpc-web-text`) longer than
+    /// `bytes` with a `resource_exhausted` status. Defaults to 4 MiB.
+    pub fn max_request_size(mut self, bytes: usize) -> Self {
+        self.max_request_size = bytes;
+        self
+    }
+}
+
+impl Default for GrpcWebLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> Layer<S> for GrpcWebLayer {
+    type Service = GrpcWeb<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcWeb {
+            inner,
+            max_request_size: self.max_request_size,
+        }
+    }
+}
+
+/// Middleware that serves gRPC-Web requests with a Twirp service.
+#[derive(Clone, Debug)]
+pub struct GrpcWeb<S> {
+    inner: S,
+    max_request_size: usize,
+}
+
+// The encoding of a gRPC-Web body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Binary,
+    Text,
+}
+
+impl Encoding {
+    // The encoding of a request with `headers`, if it is a gRPC-Web request.
+    fn of(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(CONTENT_TYPE)?.as_bytes();
+        match content_type {
+            b"application/grpc-web" | b"application/grpc-web+proto" => Some(Encoding::Binary),
+            b"application/grpc-web-text" | b"application/grpc-web-text+proto" => {
+                Some(Encoding::Text)
+            }
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> HeaderValue {
+        match self {
+            Encoding::Binary => HeaderValue::from_static("application/grpc-web+proto"),
+            Encoding::Text => HeaderValue::from_static("application/grpc-web-text+proto"),
+        }
+    }
+
+    fn decode(self, body: Bytes) -> Result<Bytes, TwirpErrorResponse> {
+        match self {
+            Encoding::Binary => Ok(body),
+            Encoding::Text => base64::engine::general_purpose::STANDARD
+                .decode(&body)
+                .map(Bytes::from)
+                .map_err(|e| crate::malformed("invalid base64 body").with_source(e)),
+        }
+    }
+
+    fn encode(self, body: Bytes) -> Bytes {
+        match self {
+            Encoding::Binary => body,
+            Encoding::Text => base64::engine::general_purpose::STANDARD
+                .encode(&body)
+                .into(),
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for GrpcWeb<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let Some(encoding) = Encoding::of(req.headers()) else {
+            return Box::pin(self.inner.call(req));
+        };
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let max_request_size = self.max_request_size;
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            let body = match read_request_body(body, Some(max_request_size)).await {
+                Ok(body) => body,
+                Err(resp) => return Ok(to_grpc_web(resp, encoding).await),
+            };
+            let message = match read_message(body, encoding) {
+                Ok(message) => message,
+                Err(err) => return Ok(grpc_web_response(encoding, HeaderMap::new(), Err(err))),
+            };
+            // The headers that describe the gRPC-Web body don't apply to the Twirp request's, and
+            // the response is reframed here, so it must not be compressed.
+            parts.headers.insert(
+                CONTENT_TYPE,
+                HeaderValue::from_bytes(CONTENT_TYPE_PROTOBUF).expect("valid header value"),
+            );
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.remove(CONTENT_ENCODING);
+            parts.headers.remove(ACCEPT_ENCODING);
+            #[cfg(feature = "checksum")]
+            parts.headers.remove(crate::checksum::X_CONTENT_SHA256);
+            let timeout = parts
+                .headers
+                .get(GRPC_TIMEOUT)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_grpc_timeout);
+            if let Some(timeout) = timeout {
+                parts
+                    .headers
+                    .insert(TWIRP_TIMEOUT, HeaderValue::from(timeout.as_millis() as u64));
+            }
+
+            let resp = inner
+                .call(Request::from_parts(parts, Body::from(message)))
+                .await?;
+            Ok(to_grpc_web(resp, encoding).await)
+        })
+    }
+}
+
+// The message in a gRPC-Web request body: a single uncompressed message frame.
+fn read_message(body: Bytes, encoding: Encoding) -> Result<Bytes, TwirpErrorResponse> {
+    let mut body = encoding.decode(body)?;
+    if body.len() < 5 {
+        return Err(crate::malformed(
+            "request body is not a gRPC-Web message frame",
+        ));
+    }
+    let flags = body.get_u8();
+    let len = body.get_u32() as usize;
+    if flags & COMPRESSED_FLAG != 0 {
+        return Err(crate::unimplemented(
+            "compressed messages are not supported",
+        ));
+    }
+    if flags & TRAILERS_FLAG != 0 || body.len() != len {
+        return Err(crate::malformed(
+            "request body is not a single gRPC-Web message frame",
+        ));
+    }
+    Ok(body)
+}
+
+// Rewrite a Twirp response as a gRPC-Web response.
+async fn to_grpc_web(resp: Response<Body>, encoding: Encoding) -> Response<Body> {
+    let (mut parts, body) = resp.into_parts();
+    let body = match body.collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => {
+            let err = crate::internal("failed to read the response").with_source(e);
+            return grpc_web_response(encoding, parts.headers, Err(err));
+        }
+    };
+    let content_type = parts.headers.remove(CONTENT_TYPE);
+    let content_type = content_type.as_ref().map(|ct| ct.as_bytes());
+    // The headers that describe the Twirp body don't apply to the gRPC-Web one.
+    parts.headers.remove(CONTENT_ENCODING);
+    #[cfg(feature = "checksum")]
+    parts.headers.remove(crate::checksum::X_CONTENT_SHA256);
+    if parts.status.is_success() && content_type == Some(CONTENT_TYPE_PROTOBUF) {
+        return grpc_web_response(encoding, parts.headers, Ok(body));
+    }
+    let err = match serde_json::from_slice::<TwirpErrorResponse>(&body) {
+        Ok(err) if content_type == Some(CONTENT_TYPE_JSON) => err,
+        _ => crate::unknown(format!("unexpected response with status {}", parts.status)),
+    };
+    grpc_web_response(encoding, parts.headers, Err(err))
+}
+
+// A gRPC-Web response with the message, or the status of the error.
+fn grpc_web_response(
+    encoding: Encoding,
+    mut headers: HeaderMap,
+    result: Result<Bytes, TwirpErrorResponse>,
+) -> Response<Body> {
+    let mut body = BytesMut::new();
+    let trailers = match result {
+        Ok(message) => {
+            put_frame(&mut body, 0, &message);
+            "grpc-status:0\r\n".to_string()
+        }
+        Err(err) => format!(
+            "grpc-status:{}\r\ngrpc-message:{}\r\n",
+            err.code.to_grpc_status(),
+            percent_encode(&err.msg)
+        ),
+    };
+    put_frame(&mut body, TRAILERS_FLAG, trailers.as_bytes());
+
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(CONTENT_TYPE, encoding.content_type());
+    let mut resp = Response::new(Body::from(encoding.encode(body.freeze())));
+    *resp.status_mut() = StatusCode::OK;
+    *resp.headers_mut() = headers;
+    resp
+}
+
+fn put_frame(buf: &mut BytesMut, flags: u8, payload: &[u8]) {
+    buf.put_u8(flags);
+    buf.put_u32(payload.len() as u32);
+    buf.put_slice(payload);
+}
+
+// `grpc-message` is percent-encoded, except for printable ASCII other than `%`.
+fn percent_encode(msg: &str) -> String {
+    let mut encoded = String::with_capacity(msg.len());
+    for byte in msg.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+// A `grpc-timeout`: up to 8 digits and a unit, `H`, `M`, `S`, `m`, `u` or `n`.
+pub(crate) fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{test_api_service_router, PingRequest, PingResponse};
+    use crate::Router;
+
+    // The test service mounted for Twirp clients at `/twirp` and for gRPC-Web clients at the root.
+    fn app() -> Router {
+        let routes = Router::new().nest("/test.TestAPI", test_api_service_router());
+        Router::new()
+            .nest("/twirp", routes.clone())
+            .merge(routes.layer(GrpcWebLayer::new()))
+    }
+
+    fn grpc_web_request(path: &str, content_type: &'static str, body: Bytes) -> Request<Body> {
+        Request::post(path)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn frame(flags: u8, payload: &[u8]) -> Bytes {
+        let mut buf = BytesMut::new();
+        put_frame(&mut buf, flags, payload);
+        buf.freeze()
+    }
+
+    // The message (if any) and the trailers of a gRPC-Web response body.
+    async fn read_frames(resp: Response<Body>) -> (Option<Bytes>, String) {
+        assert_eq!(resp.status(), StatusCode::OK);
+        let encoding = Encoding::of(resp.headers()).unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let mut body = encoding.decode(body).unwrap();
+        let mut message = None;
+        loop {
+            let flags = body.get_u8();
+            let len = body.get_u32() as usize;
+            let payload = body.split_to(len);
+            if flags == TRAILERS_FLAG {
+                assert!(body.is_empty());
+                return (message, String::from_utf8(payload.to_vec()).unwrap());
+            }
+            message = Some(payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_grpc_web() {
+        let mut router = app();
+        let ping = PingRequest {
+            name: "grpc-web".to_string(),
+        };
+        let body = frame(0, &crate::serialize_proto_message(ping));
+
+        let req = grpc_web_request(
+            "/test.TestAPI/Ping",
+            "application/grpc-web+proto",
+            body.clone(),
+        );
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/grpc-web+proto");
+        let (message, trailers) = read_frames(resp).await;
+        let resp = <PingResponse as prost::Message>::decode(message.unwrap()).unwrap();
+        assert_eq!(resp.name, "grpc-web");
+        assert_eq!(trailers, "grpc-status:0\r\n");
+
+        let text = base64::engine::general_purpose::STANDARD.encode(&body);
+        let req = grpc_web_request(
+            "/test.TestAPI/Ping",
+            "application/grpc-web-text",
+            text.into(),
+        );
+        let (message, _) = read_frames(router.call(req).await.unwrap()).await;
+        assert!(message.is_some());
+
+        let req = grpc_web_request("/test.TestAPI/Boom", "application/grpc-web", body.clone());
+        let (message, trailers) = read_frames(router.call(req).await.unwrap()).await;
+        assert_eq!(message, None);
+        assert_eq!(trailers, "grpc-status:13\r\ngrpc-message:boom!\r\n");
+
+        let req = grpc_web_request("/test.TestAPI/Pong", "application/grpc-web", body);
+        let (_, trailers) = read_frames(router.call(req).await.unwrap()).await;
+        assert!(trailers.starts_with("grpc-status:12\r\n"));
+
+        // Twirp clients are served as before.
+        let resp: PingResponse =
+            crate::test::call(&router, "/twirp/test.TestAPI/Ping", PingRequest::default())
+                .await
+                .unwrap();
+        assert_eq!(resp.name, "");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_frames() {
+        let mut router = app();
+        for (body, status) in [
+            (frame(COMPRESSED_FLAG, b"x"), 12),
+            (frame(TRAILERS_FLAG, b""), 3),
+            (Bytes::from_static(&[0, 0, 0, 0, 5, 1]), 3),
+            (Bytes::new(), 3),
+        ] {
+            let req = grpc_web_request("/test.TestAPI/Ping", "application/grpc-web", body);
+            let (_, trailers) = read_frames(router.call(req).await.unwrap()).await;
+            assert!(trailers.starts_with(&format!("grpc-status:{status}\r\n")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_request_size() {
+        let routes = Router::new().nest("/test.TestAPI", test_api_service_router());
+        let mut router = routes.layer(GrpcWebLayer::new().max_request_size(8));
+        let body = frame(0, b"longer than eight bytes");
+        let req = grpc_web_request("/test.TestAPI/Ping", "application/grpc-web", body);
+        let (message, trailers) = read_frames(router.call(req).await.unwrap()).await;
+        assert_eq!(message, None);
+        assert!(trailers.starts_with("grpc-status:8\r\n"), "{trailers}");
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_compressing_router() {
+        use crate::compression::Gzip;
+
+        let routes = crate::test::test_api_router_builder()
+            .gzip(Gzip::new().min_size(0))
+            .build();
+        let mut router = Router::new()
+            .nest("/test.TestAPI", routes)
+            .layer(GrpcWebLayer::new());
+        let ping = PingRequest {
+            name: "grpc-web".to_string(),
+        };
+        let body = frame(0, &crate::serialize_proto_message(ping));
+        let mut req = grpc_web_request("/test.TestAPI/Ping", "application/grpc-web", body);
+        req.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let resp = router.call(req).await.unwrap();
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+        let (message, trailers) = read_frames(resp).await;
+        let resp = <PingResponse as prost::Message>::decode(message.unwrap()).unwrap();
+        assert_eq!(resp.name, "grpc-web");
+        assert_eq!(trailers, "grpc-status:0\r\n");
+    }
+
+    #[test]
+    fn test_helpers() {
+        assert_eq!(percent_encode("50% off: ☂"), "50%25 off: %E2%98%82");
+        assert_eq!(parse_grpc_timeout("100m"), Some(Duration::from_millis(100)));
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+        assert_eq!(parse_grpc_timeout("5x"), None);
+    }
+}
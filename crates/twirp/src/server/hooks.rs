@@ -0,0 +1,237 @@
+//! Callbacks at each phase of the requests a router serves, like the `ServerHooks` of Twirp's Go
+//! implementation.
+//!
+//! A [`ServerHooks`] implementation added to a router with
+//! [`TwirpRouterBuilder::hooks`](crate::details::TwirpRouterBuilder::hooks) is called as each
+//! request is received, decoded, answered and sent, and when it fails, with the service and
+//! method and the request's [`Timings`]. That is enough to wire up logging, metrics or auditing
+//! for every service without writing a middleware for each:
+//!
+//! ```
+//! use twirp::server::hooks::{HookInfo, ServerHooks};
+//! use twirp::TwirpErrorResponse;
+//!
+//! struct LogErrors;
+//!
+//! impl ServerHooks for LogErrors {
+//!     fn error(&self, info: &HookInfo, err: &TwirpErrorResponse) {
+//!         eprintln!("{} failed: {:?} {}", info.rpc, err.code, err.msg);
+//!     }
+//! }
+//! ```
+//!
+//! Hooks run on the request's task, so they should return quickly. The hooks of a router are
+//! called in the order they were added.
+
+use std::sync::Arc;
+
+use http::StatusCode;
+
+use super::Timings;
+use crate::context::{RequestSpan, RpcMethod};
+use crate::TwirpErrorResponse;
+
+/// Callbacks at each phase of a request. All of them do nothing by default. See the
+/// [module documentation](self).
+pub trait ServerHooks: Send + Sync + 'static {
+    /// A request was received, before its body is read.
+    fn request_received(&self, _info: &HookInfo) {}
+
+    /// The request was decoded, and is about to be handed to the handler.
+    fn request_routed(&self, _info: &HookInfo) {}
+
+    /// The handler returned a response message, which is about to be serialized.
+    fn response_prepared(&self, _info: &HookInfo) {}
+
+    /// The response was written, with [`HookInfo::status`]. Called for errors too, after
+    /// [`error`](Self::error). For server-streaming methods, the messages are still being sent.
+    fn response_sent(&self, _info: &HookInfo) {}
+
+    /// The request failed with `err`, which is about to be sent.
+    fn error(&self, _info: &HookInfo, _err: &TwirpErrorResponse) {}
+}
+
+/// What hooks are told about a request.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct HookInfo {
+    /// The service and method of the request.
+    pub rpc: RpcMethod,
+    /// The request's timings so far.
+    pub timings: Timings,
+    /// The status of the response, once it is written.
+    pub status: Option<StatusCode>,
+}
+
+/// The hooks of a router, in the order they were added.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(Vec<Arc<dyn ServerHooks>>);
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Hooks").field(&self.0.len()).finish()
+    }
+}
+
+impl Hooks {
+    pub(crate) fn push(&mut self, hooks: impl ServerHooks) {
+        self.0.push(Arc::new(hooks));
+    }
+
+    /// Call the hooks for a request for `rpc` that was just received. The outcome of the request
+    /// is recorded in its `span` too.
+    pub(crate) fn start(
+        &self,
+        rpc: RpcMethod,
+        span: RequestSpan,
+        timings: &Timings,
+    ) -> RequestHooks<'_> {
+        let hooks = RequestHooks {
+            hooks: &self.0,
+            rpc,
+            span,
+        };
+        hooks.call(timings, None, |h, info| h.request_received(info));
+        hooks
+    }
+}
+
+/// The hooks of one request.
+pub(crate) struct RequestHooks<'a> {
+    hooks: &'a [Arc<dyn ServerHooks>],
+    rpc: RpcMethod,
+    span: RequestSpan,
+}
+
+impl RequestHooks<'_> {
+    pub(crate) fn routed(&self, timings: &Timings) {
+        self.call(timings, None, |h, info| h.request_routed(info));
+    }
+
+    pub(crate) fn prepared(&self, timings: &Timings) {
+        self.call(timings, None, |h, info| h.response_prepared(info));
+    }
+
+    pub(crate) fn error(&self, timings: &Timings, err: &TwirpErrorResponse) {
+        self.span.error(err);
+        self.call(timings, None, |h, info| h.error(info, err));
+    }
+
+    pub(crate) fn sent(&self, timings: &Timings, status: StatusCode) {
+        self.span.finish(timings, status);
+        self.call(timings, Some(status), |h, info| h.response_sent(info));
+    }
+
+    fn call(
+        &self,
+        timings: &Timings,
+        status: Option<StatusCode>,
+        f: impl Fn(&dyn ServerHooks, &HookInfo),
+    ) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let info = HookInfo {
+            rpc: self.rpc,
+            timings: *timings,
+            status,
+        };
+        for hooks in self.hooks {
+            f(hooks.as_ref(), &info);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tower::Service;
+
+    use super::*;
+    use crate::test::*;
+
+    #[derive(Clone, Default)]
+    struct Record(Arc<Mutex<Vec<String>>>);
+
+    impl Record {
+        fn push(&self, event: String) {
+            self.0.lock().expect("mutex poisoned").push(event);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().expect("mutex poisoned"))
+        }
+    }
+
+    impl ServerHooks for Record {
+        fn request_received(&self, info: &HookInfo) {
+            self.push(format!("received {}", info.rpc));
+        }
+
+        fn request_routed(&self, _info: &HookInfo) {
+            self.push("routed".to_string());
+        }
+
+        fn response_prepared(&self, _info: &HookInfo) {
+            self.push("prepared".to_string());
+        }
+
+        fn response_sent(&self, info: &HookInfo) {
+            self.push(format!("sent {}", info.status.unwrap().as_u16()));
+        }
+
+        fn error(&self, _info: &HookInfo, err: &TwirpErrorResponse) {
+            self.push(format!("error {:?}", err.code));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let record = Record::default();
+        let mut router = axum::Router::new().nest(
+            "/twirp/test.TestAPI",
+            test_api_router_builder().hooks(record.clone()).build(),
+        );
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(
+            record.take(),
+            [
+                "received test.TestAPI/Ping",
+                "routed",
+                "prepared",
+                "sent 200"
+            ]
+        );
+
+        let req = encode_request(
+            "/twirp/test.TestAPI/Boom",
+            &PingRequest::default(),
+            crate::codec::Format::Json,
+        );
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), 500);
+        assert_eq!(
+            record.take(),
+            [
+                "received test.TestAPI/Boom",
+                "routed",
+                "error Internal",
+                "sent 500"
+            ]
+        );
+
+        let req = http::Request::post("/twirp/test.TestAPI/Ping")
+            .header(http::header::CONTENT_TYPE, "text/plain")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = router.call(req).await.unwrap();
+        assert_eq!(resp.status(), 400);
+        assert_eq!(
+            record.take(),
+            ["received test.TestAPI/Ping", "error Malformed", "sent 400"]
+        );
+    }
+}
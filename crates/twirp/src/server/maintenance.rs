@@ -0,0 +1,167 @@
+//! A runtime switch for putting a service into maintenance mode.
+//!
+//! While a [`MaintenanceFlag`] is enabled, every request that reaches the layer returned by
+//! [`MaintenanceFlag::layer`] is answered with an `unavailable` error and a `Retry-After` header,
+//! without being passed on to the service. Operators can flip the flag (e.g. from an admin
+//! endpoint or a signal handler) to drain or freeze a service without redeploying it.
+//!
+//! # Usage
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use twirp::server::maintenance::MaintenanceFlag;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router) -> Router {
+//! let maintenance = MaintenanceFlag::new();
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .layer(maintenance.layer());
+//!
+//! // Later, from anywhere holding a clone of the flag:
+//! maintenance.enable(Duration::from_secs(30), "down for scheduled maintenance");
+//! # app }
+//! ```
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::response::IntoResponse;
+use futures::future::{ready, Either, Ready};
+use hyper::{Request, Response};
+use tower::{Layer, Service};
+
+use crate::error::{self, PreserializedError};
+
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+const DEFAULT_MESSAGE: &str = "service is under maintenance";
+
+/// Shared handle for turning maintenance mode on and off. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct MaintenanceFlag {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    enabled: AtomicBool,
+    // The error sent while enabled, serialized once per `enable`.
+    rejection: RwLock<Option<PreserializedError>>,
+}
+
+impl MaintenanceFlag {
+    /// Create a new flag, initially disabled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turn maintenance mode on. Clients are asked to retry after `retry_after`.
+    pub fn enable(&self, retry_after: Duration, message: impl Into<String>) {
+        let rejection = error::unavailable(message.into())
+            .with_retry_after(retry_after)
+            .preserialize();
+        *self.inner.rejection.write().expect("lock poisoned") = Some(rejection);
+        self.inner.enabled.store(true, Ordering::Release);
+    }
+
+    /// Turn maintenance mode off.
+    pub fn disable(&self) {
+        self.inner.enabled.store(false, Ordering::Release);
+    }
+
+    /// Whether maintenance mode is on.
+    pub fn is_enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::Acquire)
+    }
+
+    /// Build the layer that enforces this flag.
+    pub fn layer(&self) -> MaintenanceLayer {
+        MaintenanceLayer { flag: self.clone() }
+    }
+
+    fn error_response(&self) -> Response<Body> {
+        match self.inner.rejection.read().expect("lock poisoned").as_ref() {
+            Some(rejection) => rejection.to_response(),
+            None => error::unavailable(DEFAULT_MESSAGE)
+                .with_retry_after(DEFAULT_RETRY_AFTER)
+                .into_response(),
+        }
+    }
+}
+
+/// Layer that applies the [`Maintenance`] middleware. Created by [`MaintenanceFlag::layer`].
+#[derive(Clone, Debug)]
+pub struct MaintenanceLayer {
+    flag: MaintenanceFlag,
+}
+
+impl<S> Layer<S> for MaintenanceLayer {
+    type Service = Maintenance<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Maintenance {
+            inner,
+            flag: self.flag.clone(),
+        }
+    }
+}
+
+/// Middleware that rejects all requests with `unavailable` while its flag is enabled.
+#[derive(Clone, Debug)]
+pub struct Maintenance<S> {
+    inner: S,
+    flag: MaintenanceFlag,
+}
+
+impl<S> Service<Request<Body>> for Maintenance<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response<Body>, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if self.flag.is_enabled() {
+            Either::Left(ready(Ok(self.flag.error_response())))
+        } else {
+            Either::Right(self.inner.call(req))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::*;
+    use crate::TwirpErrorCode;
+
+    #[tokio::test]
+    async fn test_maintenance_toggle() {
+        let flag = MaintenanceFlag::new();
+        let mut router = test_api_router().layer(flag.layer());
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+
+        flag.enable(Duration::from_secs(5), "frozen");
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers()[http::header::RETRY_AFTER], "5");
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Unavailable);
+        assert_eq!(err.msg, "frozen");
+
+        flag.disable();
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+    }
+}
@@ -0,0 +1,274 @@
+//! Prometheus metrics for the RPCs a router serves, labeled by service and method.
+//!
+//! [`PrometheusLayer`] registers its metrics with a [`prometheus::Registry`] and records each
+//! request in them:
+//!
+//! ```
+//! use twirp::prometheus::Registry;
+//! use twirp::server::metrics::PrometheusLayer;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router) -> Router {
+//! let registry = Registry::new();
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .layer(PrometheusLayer::new(&registry).expect("metrics are registered once"));
+//! // Serve `registry.gather()` with `prometheus::TextEncoder` on a metrics endpoint.
+//! # app }
+//! ```
+//!
+//! The metrics, all with `service` (e.g. `example.v1.Haberdasher`) and `method` (e.g. `MakeHat`)
+//! labels, are:
+//!
+//! - `twirp_requests_total`, with a `code` label: `ok`, or the Twirp error code of the response.
+//! - `twirp_request_duration_seconds`: the time until the response was returned (not until its
+//!   body was sent).
+//! - `twirp_handler_duration_seconds`: the time spent in the handler, from the response's
+//!   [`Timings`].
+//! - `twirp_request_size_bytes` and `twirp_response_size_bytes`: the sizes of the bodies, as sent.
+//!   Bodies of unknown length, e.g. chunked requests and server-streaming responses, aren't
+//!   counted.
+//!
+//! Requests that don't match a route (`bad_route` errors) are recorded with empty `service` and
+//! `method` labels, so that clients can't create label values at will. Error codes are read from
+//! the bodies of error responses, which are buffered to do so.
+//!
+//! Requires the `prometheus` feature.
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use futures::future::BoxFuture;
+use hyper::body::Body as _;
+use hyper::{Request, Response};
+use prometheus::{exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+use tokio::time::Instant;
+use tower::{Layer, Service};
+
+use super::{read_error_code, Timings};
+use crate::context::split_route;
+use crate::TwirpErrorCode;
+
+/// A layer that records the requests of the inner service in Prometheus metrics. Clones share
+/// the metrics. See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct PrometheusLayer {
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Debug)]
+struct Metrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+    handler_duration: HistogramVec,
+    request_size: HistogramVec,
+    response_size: HistogramVec,
+}
+
+impl PrometheusLayer {
+    /// Create the metrics and register them with `registry`. Fails if metrics with the same
+    /// names are already registered.
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        const LABELS: &[&str] = &["service", "method"];
+        // From 64 bytes to 16 MiB.
+        let sizes = exponential_buckets(64.0, 4.0, 10)?;
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("twirp_requests_total", "Twirp requests served."),
+                &["service", "method", "code"],
+            )?,
+            duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "twirp_request_duration_seconds",
+                    "Time until Twirp responses were returned.",
+                ),
+                LABELS,
+            )?,
+            handler_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "twirp_handler_duration_seconds",
+                    "Time spent in Twirp handlers.",
+                ),
+                LABELS,
+            )?,
+            request_size: HistogramVec::new(
+                HistogramOpts::new("twirp_request_size_bytes", "Sizes of Twirp request bodies.")
+                    .buckets(sizes.clone()),
+                LABELS,
+            )?,
+            response_size: HistogramVec::new(
+                HistogramOpts::new(
+                    "twirp_response_size_bytes",
+                    "Sizes of Twirp response bodies.",
+                )
+                .buckets(sizes),
+                LABELS,
+            )?,
+        };
+        registry.register(Box::new(metrics.requests.clone()))?;
+        registry.register(Box::new(metrics.duration.clone()))?;
+        registry.register(Box::new(metrics.handler_duration.clone()))?;
+        registry.register(Box::new(metrics.request_size.clone()))?;
+        registry.register(Box::new(metrics.response_size.clone()))?;
+        Ok(Self {
+            metrics: Arc::new(metrics),
+        })
+    }
+}
+
+impl<S> Layer<S> for PrometheusLayer {
+    type Service = Prometheus<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Prometheus {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// Middleware that records the requests of the inner service in Prometheus metrics. See
+/// [`PrometheusLayer`].
+#[derive(Clone, Debug)]
+pub struct Prometheus<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Service<Request<Body>> for Prometheus<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let start = Instant::now();
+        let (service, method) = split_route(req.uri().path()).unwrap_or_default();
+        let (service, method) = (service.to_string(), method.to_string());
+        let request_size = req.body().size_hint().exact();
+
+        let metrics = self.metrics.clone();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let resp = fut.await?;
+            let duration = start.elapsed();
+            let (resp, error) = read_error_code(resp).await;
+            let code = match &error {
+                Some(code) => code.twirp_code(),
+                None if resp.status().is_success() => "ok",
+                None => "unknown",
+            };
+            let labels = match error {
+                Some(TwirpErrorCode::BadRoute) => ["", ""],
+                _ => [service.as_str(), method.as_str()],
+            };
+            metrics
+                .requests
+                .with_label_values(&[labels[0], labels[1], code])
+                .inc();
+            metrics
+                .duration
+                .with_label_values(&labels)
+                .observe(duration.as_secs_f64());
+            let timings = resp.extensions().get::<Timings>();
+            if let Some(handled) = timings.and_then(Timings::response_handled) {
+                metrics
+                    .handler_duration
+                    .with_label_values(&labels)
+                    .observe(handled.as_secs_f64());
+            }
+            if let Some(size) = request_size {
+                metrics
+                    .request_size
+                    .with_label_values(&labels)
+                    .observe(size as f64);
+            }
+            if let Some(size) = resp.body().size_hint().exact() {
+                metrics
+                    .response_size
+                    .with_label_values(&labels)
+                    .observe(size as f64);
+            }
+            Ok(resp)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{call, test_api_router, PingRequest, PingResponse};
+
+    // The value of the metric `name` with `labels`: the count for counters, the sample count for
+    // histograms.
+    fn sample(registry: &Registry, name: &str, labels: &[(&str, &str)]) -> u64 {
+        let family = registry.gather().into_iter().find(|f| f.name() == name);
+        let Some(family) = family else {
+            return 0;
+        };
+        let metric = family.get_metric().iter().find(|m| {
+            labels.iter().all(|(name, value)| {
+                m.get_label()
+                    .iter()
+                    .any(|l| l.name() == *name && l.value() == *value)
+            })
+        });
+        match metric {
+            Some(m) if m.get_counter().get_value() > 0.0 => m.get_counter().get_value() as u64,
+            Some(m) => m.get_histogram().get_sample_count(),
+            None => 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prometheus() {
+        let registry = Registry::new();
+        let router = test_api_router().layer(PrometheusLayer::new(&registry).unwrap());
+        assert!(PrometheusLayer::new(&registry).is_err());
+
+        let req = PingRequest {
+            name: "hi".to_string(),
+        };
+        let resp: PingResponse = call(&router, "/twirp/test.TestAPI/Ping", req.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+        let ping = [("service", "test.TestAPI"), ("method", "Ping")];
+        assert_eq!(
+            sample(
+                &registry,
+                "twirp_requests_total",
+                &[ping[0], ping[1], ("code", "ok")]
+            ),
+            1
+        );
+        for name in [
+            "twirp_request_duration_seconds",
+            "twirp_handler_duration_seconds",
+            "twirp_request_size_bytes",
+            "twirp_response_size_bytes",
+        ] {
+            assert_eq!(sample(&registry, name, &ping), 1, "{name}");
+        }
+
+        let _ = call::<_, PingResponse>(&router, "/twirp/test.TestAPI/Boom", req.clone()).await;
+        let boom = [
+            ("service", "test.TestAPI"),
+            ("method", "Boom"),
+            ("code", "internal"),
+        ];
+        assert_eq!(sample(&registry, "twirp_requests_total", &boom), 1);
+
+        let _ = call::<_, PingResponse>(&router, "/twirp/test.TestAPI/Random123", req).await;
+        let bad_route = [("service", ""), ("method", ""), ("code", "bad_route")];
+        assert_eq!(sample(&registry, "twirp_requests_total", &bad_route), 1);
+    }
+}
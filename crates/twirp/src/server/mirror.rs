@@ -0,0 +1,437 @@
+//! Traffic mirroring ("shadowing") for Twirp services.
+//!
+//! [`MirrorLayer`] forwards a configurable percentage of the requests it sees to a secondary
+//! service in the background. The primary service's response is always the one returned to the
+//! caller; the mirrored response (or error) is discarded. This makes it possible to exercise a new
+//! implementation of a service with real production traffic before switching over to it.
+//!
+//! Mirrored requests are buffered, so only requests with bodies up to
+//! [`MirrorLayer::max_request_size`] are mirrored, and at most [`MirrorLayer::max_in_flight`] of
+//! them run at once. Other sampled requests, and those whose body can't be read, are passed to the
+//! primary service as they are, without a copy.
+//!
+//! # Usage
+//!
+//! ```
+//! use twirp::server::mirror::{HttpUpstream, MirrorLayer};
+//! use twirp::url::Url;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router, shadow_routes: Router) -> Router {
+//! // Shadow 10% of traffic to another implementation mounted in the same process...
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes.clone())
+//!     .layer(MirrorLayer::new(shadow_routes, 10.0));
+//!
+//! // ...or to an upstream server.
+//! let upstream = HttpUpstream::new(Url::parse("http://shadow.internal:3000/").unwrap());
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .layer(MirrorLayer::new(upstream, 10.0));
+//! # app }
+//! ```
+
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use bytes::{Bytes, BytesMut};
+use futures::future::BoxFuture;
+use futures::{stream, StreamExt};
+use http::{header, HeaderName};
+use http_body_util::BodyExt;
+use hyper::{Request, Response};
+use tokio::sync::Semaphore;
+use tower::{Layer, Service, ServiceExt};
+use url::Url;
+
+use super::proxy::forwarded_headers;
+use super::Sampler;
+
+/// Layer that applies the [`Mirror`] middleware.
+#[derive(Clone)]
+pub struct MirrorLayer<M> {
+    mirror: M,
+    sampler: Arc<Sampler>,
+    max_request_size: usize,
+    in_flight: Arc<Semaphore>,
+}
+
+impl<M> MirrorLayer<M> {
+    /// Mirror `percent` percent (`0.0..=100.0`) of requests to `mirror`.
+    pub fn new(mirror: M, percent: f64) -> Self {
+        Self {
+            mirror,
+            sampler: Arc::new(Sampler::new(percent)),
+            max_request_size: 4 * 1024 * 1024,
+            in_flight: Arc::new(Semaphore::new(64)),
+        }
+    }
+
+    /// Don't mirror requests with bodies longer than `bytes`; they are only passed to the primary
+    /// service, with the part of the body read so far. Defaults to 4 MiB.
+    pub fn max_request_size(mut self, bytes: usize) -> Self {
+        self.max_request_size = bytes;
+        self
+    }
+
+    /// The maximum number of mirrored requests that run at the same time; requests sampled while
+    /// that many are running are not mirrored. Defaults to 64.
+    pub fn max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.in_flight = Arc::new(Semaphore::new(max_in_flight));
+        self
+    }
+}
+
+impl<S, M> Layer<S> for MirrorLayer<M>
+where
+    M: Clone,
+{
+    type Service = Mirror<S, M>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Mirror {
+            inner,
+            mirror: self.mirror.clone(),
+            sampler: self.sampler.clone(),
+            max_request_size: self.max_request_size,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+/// Middleware that asynchronously copies a sample of requests to a secondary service.
+///
+/// Mirrored requests are spawned onto the tokio runtime and are never awaited by the caller.
+#[derive(Clone)]
+pub struct Mirror<S, M> {
+    inner: S,
+    mirror: M,
+    sampler: Arc<Sampler>,
+    max_request_size: usize,
+    in_flight: Arc<Semaphore>,
+}
+
+impl<S, M> Service<Request<Body>> for Mirror<S, M>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+    M: Service<Request<Body>> + Clone + Send + 'static,
+    M::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // Take the service that was driven to readiness and leave a fresh clone in its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        if !self.sampler.sample() {
+            return Box::pin(inner.call(req));
+        }
+        // Drop the copy rather than queue it when the mirror is busy.
+        let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+            return Box::pin(inner.call(req));
+        };
+
+        let mirror = self.mirror.clone();
+        let max_request_size = self.max_request_size;
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let bytes = match read_bounded(&parts.headers, body, max_request_size).await {
+                Ok(bytes) => bytes,
+                // Only the copy is dropped: the primary service gets the request as it came in.
+                Err(body) => return inner.call(Request::from_parts(parts, body)).await,
+            };
+
+            let mut shadow = Request::new(Body::from(bytes.clone()));
+            *shadow.method_mut() = parts.method.clone();
+            *shadow.uri_mut() = parts.uri.clone();
+            *shadow.version_mut() = parts.version;
+            *shadow.headers_mut() = parts.headers.clone();
+            *shadow.extensions_mut() = parts.extensions.clone();
+            tokio::spawn(async move {
+                // Responses from the mirror are intentionally discarded.
+                let _ = mirror.oneshot(shadow).await;
+                drop(permit);
+            });
+
+            inner
+                .call(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        })
+    }
+}
+
+/// Read `body` if it is at most `limit` bytes long. Otherwise, or if it can't be read, the body as
+/// it would have been without reading it: the bytes read so far followed by the rest of it.
+async fn read_bounded(
+    headers: &http::HeaderMap,
+    mut body: Body,
+    limit: usize,
+) -> Result<Bytes, Body> {
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return Err(body);
+    }
+    let mut buf = BytesMut::new();
+    let rest = loop {
+        match body.frame().await {
+            None => return Ok(buf.freeze()),
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    buf.extend_from_slice(data);
+                }
+                if buf.len() > limit {
+                    break StreamExt::boxed(body.into_data_stream());
+                }
+            }
+            Some(Err(err)) => break StreamExt::boxed(stream::once(async { Err(err) })),
+        }
+    };
+    let read = stream::once(async move { Ok(buf.freeze()) });
+    Err(Body::from_stream(read.chain(rest)))
+}
+
+/// A mirror target that forwards requests to another HTTP server.
+///
+/// The request's path and query are resolved against `base_url`, so a request to
+/// `/twirp/pkg.Service/Method` mirrored to `http://shadow:3000/` is sent to
+/// `http://shadow:3000/twirp/pkg.Service/Method`. Like with a [`Proxy`](super::proxy::Proxy), only
+/// the headers that describe the body and the propagated ones (e.g. `traceparent`) are forwarded,
+/// and those added with [`forward_header`](Self::forward_header).
+#[derive(Clone, Debug)]
+pub struct HttpUpstream {
+    base_url: Url,
+    http_client: reqwest::Client,
+    headers: Vec<HeaderName>,
+}
+
+impl HttpUpstream {
+    /// Forward mirrored requests to `base_url` using a default `reqwest::Client`.
+    pub fn new(base_url: Url) -> Self {
+        Self::with_client(base_url, reqwest::Client::new())
+    }
+
+    /// Forward mirrored requests to `base_url` using the given `reqwest::Client`.
+    pub fn with_client(base_url: Url, http_client: reqwest::Client) -> Self {
+        Self {
+            base_url,
+            http_client,
+            headers: vec![],
+        }
+    }
+
+    /// Also forward the request header `name`, e.g. `Authorization`.
+    pub fn forward_header(mut self, name: HeaderName) -> Self {
+        self.headers.push(name);
+        self
+    }
+}
+
+impl Service<Request<Body>> for HttpUpstream {
+    type Response = reqwest::Response;
+    type Error = crate::GenericError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let base_url = self.base_url.clone();
+        let http_client = self.http_client.clone();
+        let headers = forwarded_headers(req.headers(), &self.headers);
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let path = parts
+                .uri
+                .path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or_default();
+            let url = base_url.join(path.trim_start_matches('/'))?;
+            let body = body.collect().await?.to_bytes();
+            let resp = http_client
+                .request(parts.method, url)
+                .headers(headers)
+                .body(body)
+                .send()
+                .await?;
+            Ok(resp)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::test::*;
+
+    #[tokio::test]
+    async fn test_mirror_percentage() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = {
+            let seen = seen.clone();
+            tower::service_fn(move |req: Request<Body>| {
+                let seen = seen.clone();
+                async move {
+                    let body = read_string_body(req.into_body()).await;
+                    seen.lock().unwrap().push(body);
+                    Ok::<_, Infallible>(())
+                }
+            })
+        };
+
+        let mut router = test_api_router().layer(MirrorLayer::new(recorder, 50.0));
+        for i in 0..10 {
+            let resp = router
+                .call(gen_ping_request(&format!("hi-{i}")))
+                .await
+                .unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, format!("hi-{i}"));
+        }
+
+        // Mirrored requests run in the background.
+        for _ in 0..50 {
+            if seen.lock().unwrap().len() == 5 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 5);
+        assert!(seen[0].contains("hi-1"), "{:?}", seen);
+    }
+
+    // A mirror that counts the requests it gets.
+    fn counting_mirror() -> (
+        Arc<Mutex<usize>>,
+        impl Service<Request<Body>, Response = (), Error = Infallible, Future: Send> + Clone,
+    ) {
+        let seen = Arc::new(Mutex::new(0));
+        let mirror = {
+            let seen = seen.clone();
+            tower::service_fn(move |_: Request<Body>| {
+                *seen.lock().unwrap() += 1;
+                async { Ok::<_, Infallible>(()) }
+            })
+        };
+        (seen, mirror)
+    }
+
+    #[tokio::test]
+    async fn test_mirror_unreadable_body() {
+        let (seen, mirror) = counting_mirror();
+        let mut router = test_api_router().layer(MirrorLayer::new(mirror, 100.0));
+
+        // The primary service fails to read the body, as it would without the mirror.
+        let (parts, _) = gen_ping_request("hi").into_parts();
+        let body = Body::from_stream(stream::once(async {
+            Err::<Bytes, _>(std::io::Error::other("connection reset"))
+        }));
+        let resp = router.call(Request::from_parts(parts, body)).await.unwrap();
+        assert_eq!(resp.status(), http::StatusCode::BAD_REQUEST);
+        let err: crate::TwirpErrorResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::Malformed);
+        tokio::task::yield_now().await;
+        assert_eq!(*seen.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_limits() {
+        let seen = Arc::new(Mutex::new(0));
+        let release = Arc::new(tokio::sync::Notify::new());
+        let stuck = {
+            let (seen, release) = (seen.clone(), release.clone());
+            tower::service_fn(move |_: Request<Body>| {
+                *seen.lock().unwrap() += 1;
+                let release = release.clone();
+                async move {
+                    release.notified().await;
+                    Ok::<_, Infallible>(())
+                }
+            })
+        };
+        let layer = MirrorLayer::new(stuck, 100.0)
+            .max_request_size(64)
+            .max_in_flight(1);
+        let mut router = test_api_router().layer(layer);
+
+        // Only one copy runs at a time; the others are dropped, but the primary still answers.
+        for _ in 0..3 {
+            let resp = router.call(gen_ping_request("hi")).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(*seen.lock().unwrap(), 1);
+        release.notify_one();
+
+        // Requests over the size limit aren't mirrored, whether or not they say how long they
+        // are, but the primary gets all of their body.
+        let (seen, mirror) = counting_mirror();
+        let mut router =
+            test_api_router().layer(MirrorLayer::new(mirror, 100.0).max_request_size(64));
+        let name = "x".repeat(100);
+        let (parts, body) = gen_ping_request(&name).into_parts();
+        let body = read_string_body(body).await;
+        let mut sized = Request::from_parts(parts.clone(), Body::from(body.clone()));
+        sized
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, body.len().into());
+        let chunks: Vec<Result<_, Infallible>> = body
+            .as_bytes()
+            .chunks(10)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let streamed = Request::from_parts(parts, Body::from_stream(stream::iter(chunks)));
+        for req in [sized, streamed] {
+            let resp = router.call(req).await.unwrap();
+            assert!(resp.status().is_success(), "{:?}", resp);
+            let data: PingResponse = read_json_body(resp.into_body()).await;
+            assert_eq!(data.name, name);
+        }
+        tokio::task::yield_now().await;
+        assert_eq!(*seen.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_http_upstream_headers() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let app = axum::Router::new().fallback(move |req: Request<Body>| {
+            let _ = tx.send(req.headers().clone());
+            async { "" }
+        });
+        let server = spawn_server(app).await;
+
+        let upstream =
+            HttpUpstream::new(server.url("/")).forward_header(http::header::AUTHORIZATION);
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(http::header::HOST, "primary.example")
+            .header(http::header::CONNECTION, "x-hop")
+            .header("x-hop", "1")
+            .header(http::header::AUTHORIZATION, "Bearer abc")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{}"))
+            .unwrap();
+        upstream.oneshot(req).await.unwrap();
+        let headers = rx.recv().await.unwrap();
+        assert_eq!(headers[http::header::CONTENT_TYPE], "application/json");
+        assert_eq!(headers[http::header::AUTHORIZATION], "Bearer abc");
+        assert_eq!(headers[http::header::HOST], server.addr().to_string());
+        assert!(!headers.contains_key("x-hop"));
+        server.shutdown().await.unwrap();
+    }
+}
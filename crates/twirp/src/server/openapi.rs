@@ -0,0 +1,358 @@
+//! An OpenAPI document of the Twirp JSON endpoints, generated at runtime from the services'
+//! descriptors, and a Swagger UI to explore and try them.
+//!
+//! [`OpenApi`] describes each unary method of the services in a descriptor pool as a `POST` of
+//! its JSON request message, with its JSON response message or a Twirp error in return. Added to
+//! an app, it serves the document at `{prefix}/$docs/openapi.json` and a Swagger UI at
+//! `{prefix}/$docs`:
+//!
+//! ```
+//! use twirp::prost_reflect::DescriptorPool;
+//! use twirp::server::openapi::OpenApi;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router, descriptors: &[u8]) -> Router {
+//! let pool = DescriptorPool::decode(descriptors).expect("valid descriptors");
+//! let app = Router::new().nest("/twirp", twirp_routes);
+//! let app = OpenApi::new(pool, "/twirp").title("Haberdasher").add_to(app);
+//! # app }
+//! ```
+//!
+//! Messages are described the way serde derives for prost messages serialize them: with the
+//! fields' proto names, enums as numbers and 64-bit integers as numbers. Use
+//! [`OpenApi::protojson`] for services whose messages are serialized as proto3 JSON instead
+//! (e.g. with `pbjson`). The Swagger UI loads its scripts from unpkg.com.
+//!
+//! Requires the [`reflect` feature](crate#the-reflect-feature).
+
+use std::collections::BTreeMap;
+
+use axum::response::Html;
+use axum::routing::get;
+use axum::{Json, Router};
+use prost_reflect::{DescriptorPool, FieldDescriptor, Kind, MessageDescriptor};
+use serde_json::{json, Map, Value};
+
+/// The name of the schema of Twirp errors.
+const ERROR_SCHEMA: &str = "twirp.Error";
+
+/// Configuration of the OpenAPI document and the `$docs` routes. See the
+/// [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct OpenApi {
+    pool: DescriptorPool,
+    prefix: String,
+    title: String,
+    version: String,
+    protojson: bool,
+    swagger_ui: bool,
+}
+
+impl OpenApi {
+    /// Describe the services in `pool`, mounted under `prefix` (usually `/twirp`).
+    pub fn new(pool: DescriptorPool, prefix: &str) -> Self {
+        Self {
+            pool,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            title: "Twirp API".to_string(),
+            version: "1.0.0".to_string(),
+            protojson: false,
+            swagger_ui: true,
+        }
+    }
+
+    /// The title of the API. Defaults to `Twirp API`.
+    pub fn title(mut self, title: &str) -> Self {
+        self.title = title.to_string();
+        self
+    }
+
+    /// The version of the API. Defaults to `1.0.0`.
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Describe messages as proto3 JSON: with the fields' JSON names (`lowerCamelCase`), enums as
+    /// the names of their values, 64-bit integers as strings and bytes as base64.
+    pub fn protojson(mut self, protojson: bool) -> Self {
+        self.protojson = protojson;
+        self
+    }
+
+    /// Whether to serve a Swagger UI at `{prefix}/$docs`. Defaults to true.
+    pub fn swagger_ui(mut self, enabled: bool) -> Self {
+        self.swagger_ui = enabled;
+        self
+    }
+
+    /// The OpenAPI 3 document.
+    pub fn document(&self) -> Value {
+        let mut paths = Map::new();
+        let mut schemas = BTreeMap::new();
+        schemas.insert(ERROR_SCHEMA.to_string(), error_schema());
+        for service in self.pool.services() {
+            let methods = service
+                .methods()
+                .filter(|m| !m.is_client_streaming() && !m.is_server_streaming());
+            for method in methods {
+                let path = format!("{}/{}/{}", self.prefix, service.full_name(), method.name());
+                let operation = json!({
+                    "operationId": format!("{}.{}", service.full_name(), method.name()),
+                    "tags": [service.full_name()],
+                    "requestBody": {
+                        "required": true,
+                        "content": json_content(schema_ref(method.input().full_name())),
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "OK",
+                            "content": json_content(schema_ref(method.output().full_name())),
+                        },
+                        "default": {
+                            "description": "Twirp error",
+                            "content": json_content(schema_ref(ERROR_SCHEMA)),
+                        },
+                    },
+                });
+                paths.insert(path, json!({ "post": operation }));
+                self.add_schema(&method.input(), &mut schemas);
+                self.add_schema(&method.output(), &mut schemas);
+            }
+        }
+        json!({
+            "openapi": "3.0.3",
+            "info": { "title": self.title, "version": self.version },
+            "paths": paths,
+            "components": { "schemas": schemas },
+        })
+    }
+
+    /// Add the `$docs` routes to `app`.
+    pub fn add_to(self, app: Router) -> Router {
+        let docs = format!("{}/$docs", self.prefix);
+        let document_path = format!("{docs}/openapi.json");
+        let document = self.document();
+        let app = app.route(
+            &document_path,
+            get(move || async move { Json(document.clone()) }),
+        );
+        if !self.swagger_ui {
+            return app;
+        }
+        let page = swagger_ui(&self.title, &document_path);
+        app.route(&docs, get(move || async move { Html(page.clone()) }))
+    }
+
+    // Add the schema of `message`, and of the messages it refers to, unless already there.
+    fn add_schema(&self, message: &MessageDescriptor, schemas: &mut BTreeMap<String, Value>) {
+        if schemas.contains_key(message.full_name()) || well_known(message).is_some() {
+            return;
+        }
+        // Inserted before the fields are described, so recursive messages terminate.
+        schemas.insert(message.full_name().to_string(), Value::Null);
+        let mut properties = Map::new();
+        for field in message.fields() {
+            let name = match self.protojson {
+                true => field.json_name(),
+                false => field.name(),
+            };
+            properties.insert(name.to_string(), self.field_schema(&field, schemas));
+        }
+        let schema = json!({ "type": "object", "properties": properties });
+        schemas.insert(message.full_name().to_string(), schema);
+    }
+
+    fn field_schema(
+        &self,
+        field: &FieldDescriptor,
+        schemas: &mut BTreeMap<String, Value>,
+    ) -> Value {
+        if field.is_map() {
+            let Kind::Message(entry) = field.kind() else {
+                unreachable!("map fields are messages");
+            };
+            let value = entry.map_entry_value_field();
+            return json!({
+                "type": "object",
+                "additionalProperties": self.kind_schema(&value.kind(), schemas),
+            });
+        }
+        let schema = self.kind_schema(&field.kind(), schemas);
+        match field.is_list() {
+            true => json!({ "type": "array", "items": schema }),
+            false => schema,
+        }
+    }
+
+    fn kind_schema(&self, kind: &Kind, schemas: &mut BTreeMap<String, Value>) -> Value {
+        match kind {
+            Kind::Double | Kind::Float => json!({ "type": "number" }),
+            Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => {
+                json!({ "type": "integer", "format": "int32" })
+            }
+            Kind::Uint32 | Kind::Fixed32 => json!({ "type": "integer", "minimum": 0 }),
+            Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 | Kind::Uint64 | Kind::Fixed64 => {
+                match self.protojson {
+                    true => json!({ "type": "string", "format": "int64" }),
+                    false => json!({ "type": "integer", "format": "int64" }),
+                }
+            }
+            Kind::Bool => json!({ "type": "boolean" }),
+            Kind::String => json!({ "type": "string" }),
+            Kind::Bytes => match self.protojson {
+                true => json!({ "type": "string", "format": "byte" }),
+                false => json!({ "type": "array", "items": { "type": "integer" } }),
+            },
+            Kind::Enum(desc) => match self.protojson {
+                true => json!({
+                    "type": "string",
+                    "enum": desc.values().map(|v| v.name().to_string()).collect::<Vec<_>>(),
+                }),
+                false => json!({
+                    "type": "integer",
+                    "enum": desc.values().map(|v| v.number()).collect::<Vec<_>>(),
+                    "description": desc
+                        .values()
+                        .map(|v| format!("{} = {}", v.number(), v.name()))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                }),
+            },
+            Kind::Message(desc) => match well_known(desc) {
+                Some(schema) => schema,
+                None => {
+                    self.add_schema(desc, schemas);
+                    schema_ref(desc.full_name())
+                }
+            },
+        }
+    }
+}
+
+// The schema of well-known types that are serialized as strings.
+fn well_known(message: &MessageDescriptor) -> Option<Value> {
+    match message.full_name() {
+        "google.protobuf.Timestamp" => Some(json!({ "type": "string", "format": "date-time" })),
+        "google.protobuf.Duration" => Some(json!({ "type": "string", "example": "1.5s" })),
+        _ => None,
+    }
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn error_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["code", "msg"],
+        "properties": {
+            "code": { "type": "string", "example": "not_found" },
+            "msg": { "type": "string" },
+            "meta": { "type": "object", "additionalProperties": { "type": "string" } },
+        },
+    })
+}
+
+// A Swagger UI page for the document at `document_path`.
+fn swagger_ui(title: &str, document_path: &str) -> String {
+    let title = title
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    let url = serde_json::to_string(document_path).expect("string can be serialized");
+    format!(
+        r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>
+window.onload = () => {{
+  window.ui = SwaggerUIBundle({{ url: {url}, dom_id: "#swagger-ui" }});
+}};
+</script>
+</body>
+</html>
+"##
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test::{descriptor_pool, read_json_body, read_string_body};
+
+    #[test]
+    fn test_document() {
+        let doc = OpenApi::new(descriptor_pool(), "/twirp/")
+            .title("Hats")
+            .document();
+        assert_eq!(doc["info"]["title"], "Hats");
+        let op = &doc["paths"]["/twirp/test.Haberdasher/MakeHat"]["post"];
+        assert_eq!(op["operationId"], "test.Haberdasher.MakeHat");
+        assert_eq!(
+            op["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/test.Hat"
+        );
+        assert_eq!(
+            op["responses"]["default"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/twirp.Error"
+        );
+
+        let hat = &doc["components"]["schemas"]["test.Hat"]["properties"];
+        assert_eq!(hat["name"], json!({ "type": "string" }));
+        assert_eq!(hat["color"]["enum"], json!([0, 1]));
+        assert_eq!(hat["color"]["description"], "0 = RED, 1 = BLUE");
+        assert_eq!(
+            hat["parts"]["items"]["$ref"],
+            "#/components/schemas/test.Hat"
+        );
+        assert_eq!(hat["size_inches"]["type"], "integer");
+
+        let doc = OpenApi::new(descriptor_pool(), "/twirp")
+            .protojson(true)
+            .document();
+        let hat = &doc["components"]["schemas"]["test.Hat"]["properties"];
+        assert_eq!(hat["color"]["enum"], json!(["RED", "BLUE"]));
+        assert_eq!(hat["sizeInches"]["type"], "string");
+    }
+
+    #[tokio::test]
+    async fn test_routes() {
+        let app = OpenApi::new(descriptor_pool(), "/twirp").add_to(Router::new());
+        let get = |path: &str| Request::get(path).body(axum::body::Body::empty()).unwrap();
+
+        let resp = app
+            .clone()
+            .oneshot(get("/twirp/$docs/openapi.json"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let doc: Value = read_json_body(resp.into_body()).await;
+        assert_eq!(doc["openapi"], "3.0.3");
+
+        let resp = app.oneshot(get("/twirp/$docs")).await.unwrap();
+        let page = read_string_body(resp.into_body()).await;
+        assert!(page.contains(r#"url: "/twirp/$docs/openapi.json""#));
+
+        let app = OpenApi::new(descriptor_pool(), "/twirp")
+            .swagger_ui(false)
+            .add_to(Router::new());
+        let resp = app.oneshot(get("/twirp/$docs")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
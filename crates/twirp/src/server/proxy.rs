@@ -0,0 +1,407 @@
+//! A reverse proxy for Twirp services: a minimal Twirp-aware API gateway.
+//!
+//! [`Proxy`] forwards each request to an upstream server of its service, picked round-robin from
+//! the base URLs configured for the service's fully qualified name. Requests and responses are
+//! forwarded without decoding them, so any service can be proxied, and the upstream's Twirp
+//! errors reach the client unchanged:
+//!
+//! ```
+//! use twirp::server::proxy::Proxy;
+//! use twirp::url::Url;
+//! use twirp::Router;
+//!
+//! # fn build_app() -> Router {
+//! let hats = ["http://hats-1:3000/twirp/", "http://hats-2:3000/twirp/"];
+//! let proxy = Proxy::new()
+//!     .upstream(
+//!         "example.v1.Haberdasher",
+//!         hats.iter().map(|url| Url::parse(url).unwrap()),
+//!     )
+//!     .max_attempts(2)
+//!     .forward_header(twirp::http::header::AUTHORIZATION);
+//! let app = Router::new().nest("/twirp", proxy.router());
+//! # app }
+//! ```
+//!
+//! The request's deadline (its `Twirp-Timeout`) applies to the whole call: each attempt is sent
+//! with the time that is left, and the proxy answers `deadline_exceeded` when it runs out.
+//! Attempts that fail to connect, or that the upstream answers with `503 Service Unavailable`
+//! (e.g. while [draining](super::drain)), are retried on the next upstream, up to
+//! [`Proxy::max_attempts`]. Requests for services without an upstream get a `bad_route` error,
+//! and requests with bodies over [`Proxy::max_request_size`] a `resource_exhausted` error.
+//!
+//! Besides the headers that describe the body, only the headers that
+//! [`Context::propagation_headers`](crate::Context::propagation_headers) forwards, the
+//! `Idempotency-Key` and those added with [`Proxy::forward_header`] are sent upstream.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::Request;
+use axum::response::IntoResponse;
+use axum::Router;
+use http::header::{self, HeaderName};
+use http::{HeaderMap, Method, Response, StatusCode};
+use tokio::time::Instant;
+use url::Url;
+
+use super::budget::{Reservation, TooLarge};
+use super::{method_not_allowed_handler, not_found_handler, AllowedMethods};
+use crate::context::split_route;
+use crate::headers::{
+    IDEMPOTENCY_KEY, TRACEPARENT, TRACESTATE, TWIRP_ATTEMPT, TWIRP_TIMEOUT, X_TENANT_ID,
+};
+use crate::server::request_id::X_REQUEST_ID;
+use crate::TwirpErrorResponse;
+
+/// The request headers that are always forwarded.
+const FORWARDED_HEADERS: [HeaderName; 4] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::ACCEPT,
+    header::ACCEPT_ENCODING,
+];
+
+/// Routes that forward requests to upstream Twirp servers. See the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    http_client: reqwest::Client,
+    upstreams: HashMap<String, Arc<Upstream>>,
+    headers: Vec<HeaderName>,
+    max_attempts: u32,
+    max_request_size: Option<usize>,
+}
+
+// The base URLs of a service's upstream servers, and the index of the next one to use.
+#[derive(Debug)]
+struct Upstream {
+    base_urls: Vec<Url>,
+    next: AtomicUsize,
+}
+
+impl Upstream {
+    fn next(&self) -> &Url {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        &self.base_urls[next % self.base_urls.len()]
+    }
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Proxy {
+    /// A proxy without upstreams that sends requests with a default `reqwest::Client`.
+    pub fn new() -> Self {
+        Self::with_client(reqwest::Client::new())
+    }
+
+    /// A proxy without upstreams that sends requests with `http_client`.
+    pub fn with_client(http_client: reqwest::Client) -> Self {
+        Self {
+            http_client,
+            upstreams: HashMap::new(),
+            headers: vec![],
+            max_attempts: 1,
+            max_request_size: None,
+        }
+    }
+
+    /// Forward requests for the service `service_fqn` (e.g. `example.v1.Haberdasher`) to the
+    /// servers at `base_urls`, in turn. A base URL is where the server's Twirp routes are mounted,
+    /// e.g. `http://hats:3000/twirp/`, as for a [`Client`](crate::Client).
+    ///
+    /// # Panics
+    ///
+    /// If `base_urls` is empty.
+    pub fn upstream(mut self, service_fqn: &str, base_urls: impl IntoIterator<Item = Url>) -> Self {
+        let base_urls: Vec<Url> = base_urls
+            .into_iter()
+            .map(|mut url| {
+                if !url.path().ends_with('/') {
+                    url.set_path(&format!("{}/", url.path()));
+                }
+                url
+            })
+            .collect();
+        assert!(!base_urls.is_empty(), "an upstream needs a base URL");
+        let upstream = Upstream {
+            base_urls,
+            next: AtomicUsize::new(0),
+        };
+        self.upstreams.insert(
+            service_fqn.trim_start_matches('/').to_string(),
+            Arc::new(upstream),
+        );
+        self
+    }
+
+    /// Also forward the request header `name` upstream, e.g. `Authorization`.
+    pub fn forward_header(mut self, name: HeaderName) -> Self {
+        self.headers.push(name);
+        self
+    }
+
+    /// How many times a request is sent, over as many upstreams, before giving up on one that
+    /// can't be connected to or is unavailable. Defaults to 1 (no retries).
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Reject requests with bodies longer than `bytes` with a `resource_exhausted` error, without
+    /// reading the rest of the body. The proxy holds each body until it is forwarded, so set this
+    /// for proxies that face untrusted clients.
+    pub fn max_request_size(mut self, bytes: usize) -> Self {
+        self.max_request_size = Some(bytes);
+        self
+    }
+
+    /// The router, which forwards requests to `/{service}/{method}`. Nest it under the prefix the
+    /// services are served at (usually `/twirp`).
+    pub fn router(self) -> Router {
+        let proxy = Arc::new(self);
+        Router::new().fallback(move |req: Request| {
+            let proxy = proxy.clone();
+            async move { proxy.handle(req).await }
+        })
+    }
+
+    async fn handle(&self, req: Request) -> Response<Body> {
+        let route = split_route(req.uri().path());
+        let upstream = route.and_then(|(service, _)| self.upstreams.get(service));
+        let (Some((service, method)), Some(upstream)) = (route, upstream) else {
+            return not_found_handler(req).await;
+        };
+        if req.method() != Method::POST {
+            return method_not_allowed_handler(req, AllowedMethods::Post).await;
+        }
+        let path = format!("{service}/{method}");
+        match self.forward(upstream, &path, req).await {
+            Ok(resp) => resp,
+            Err(err) => err.into_response(),
+        }
+    }
+
+    async fn forward(
+        &self,
+        upstream: &Upstream,
+        path: &str,
+        req: Request,
+    ) -> Result<Response<Body>, TwirpErrorResponse> {
+        let (parts, body) = req.into_parts();
+        let deadline = parts
+            .headers
+            .get(TWIRP_TIMEOUT)
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .map(|ms| Instant::now() + Duration::from_millis(ms));
+        let headers = forwarded_headers(&parts.headers, &self.headers);
+        let body = Reservation::new(None)
+            .collect(body, self.max_request_size)
+            .await
+            .map_err(|err| match err.downcast::<TooLarge>() {
+                Ok(err) => TwirpErrorResponse::from(*err),
+                Err(err) => {
+                    crate::malformed("failed to read the request body").with_meta("error", err)
+                }
+            })?;
+
+        let mut attempt = 1;
+        loop {
+            let url = upstream
+                .next()
+                .join(path)
+                .map_err(|e| crate::malformed("invalid route").with_meta("error", e))?;
+            let mut req = self
+                .http_client
+                .post(url)
+                .headers(headers.clone())
+                .header(TWIRP_ATTEMPT, attempt)
+                .body(body.clone());
+            if let Some(deadline) = deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(crate::deadline_exceeded("deadline exceeded"));
+                }
+                req = req
+                    .timeout(remaining)
+                    .header(TWIRP_TIMEOUT, remaining.as_millis() as u64);
+            }
+            let res = req.send().await;
+            let retry = match &res {
+                Ok(resp) => resp.status() == StatusCode::SERVICE_UNAVAILABLE,
+                Err(err) => err.is_connect(),
+            };
+            if retry && attempt < self.max_attempts {
+                attempt += 1;
+                continue;
+            }
+            return match res {
+                Ok(resp) => Ok(response(resp).await?),
+                Err(err) if err.is_timeout() => {
+                    Err(crate::deadline_exceeded("deadline exceeded").with_meta("error", err))
+                }
+                Err(err) => Err(crate::unavailable("upstream unavailable").with_meta("error", err)),
+            };
+        }
+    }
+}
+
+// The headers of `headers` to send upstream: those that describe the body, the propagated ones and
+// `extra`. Others, e.g. `Host` or `Connection`, only apply to the connection they came in on.
+pub(super) fn forwarded_headers(headers: &HeaderMap, extra: &[HeaderName]) -> HeaderMap {
+    let propagated = [
+        X_REQUEST_ID,
+        HeaderName::from_static(TRACEPARENT),
+        HeaderName::from_static(TRACESTATE),
+        HeaderName::from_static(X_TENANT_ID),
+        HeaderName::from_static(IDEMPOTENCY_KEY),
+    ];
+    let names = FORWARDED_HEADERS.iter().chain(&propagated).chain(extra);
+    let mut forwarded = HeaderMap::new();
+    for name in names {
+        for value in headers.get_all(name) {
+            forwarded.append(name.clone(), value.clone());
+        }
+    }
+    forwarded
+}
+
+// The upstream's response, without the headers that only apply to its connection.
+async fn response(resp: reqwest::Response) -> Result<Response<Body>, TwirpErrorResponse> {
+    let status = resp.status();
+    let mut headers = resp.headers().clone();
+    for name in [
+        header::CONNECTION,
+        header::TRANSFER_ENCODING,
+        header::CONTENT_LENGTH,
+    ] {
+        headers.remove(name);
+    }
+    let body = resp.bytes().await.map_err(|e| {
+        crate::unavailable("failed to read the upstream response").with_meta("error", e)
+    })?;
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    *resp.headers_mut() = headers;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test::*;
+    use crate::TwirpErrorCode;
+
+    fn ping() -> Request {
+        encode_request(
+            "/test.TestAPI/Ping",
+            &PingRequest {
+                name: "proxied".to_string(),
+            },
+            crate::codec::Format::Json,
+        )
+    }
+
+    // The URL of a port nothing listens on.
+    async fn closed_port() -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        Url::parse(&format!("http://{addr}/twirp/")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let server = spawn_server(test_api_router()).await;
+        let proxy = Proxy::new()
+            .upstream("test.TestAPI", [server.url("/twirp")])
+            .router();
+
+        let resp = proxy.clone().oneshot(ping()).await.unwrap();
+        let resp: PingResponse = decode_response(resp).await.unwrap();
+        assert_eq!(resp.name, "proxied");
+
+        let req = encode_request(
+            "/test.TestAPI/Boom",
+            &PingRequest::default(),
+            crate::codec::Format::Protobuf,
+        );
+        let resp = proxy.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let err = decode_response::<PingResponse>(resp).await.unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+        assert_eq!(err.msg, "boom!");
+
+        let req = encode_request(
+            "/test.OtherAPI/Ping",
+            &PingRequest::default(),
+            crate::codec::Format::Json,
+        );
+        let resp = proxy.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_request_size() {
+        // Rejected before anything is sent upstream.
+        let proxy = Proxy::new()
+            .upstream("test.TestAPI", [closed_port().await])
+            .max_request_size(8)
+            .router();
+        let resp = proxy.oneshot(ping()).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_and_retries() {
+        let server = spawn_server(test_api_router()).await;
+        let upstreams = [closed_port().await, server.url("/twirp/")];
+
+        // Without retries, every other request goes to the closed port.
+        let proxy = Proxy::new()
+            .upstream("test.TestAPI", upstreams.clone())
+            .router();
+        let resp = proxy.clone().oneshot(ping()).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::Unavailable);
+        let resp = proxy.oneshot(ping()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let proxy = Proxy::new()
+            .upstream("test.TestAPI", upstreams)
+            .max_attempts(2)
+            .router();
+        for _ in 0..2 {
+            let resp = proxy.clone().oneshot(ping()).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        server.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_headers() {
+        let proxy = Proxy::new().forward_header(header::AUTHORIZATION);
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        headers.insert(header::AUTHORIZATION, "Bearer t".parse().unwrap());
+        headers.insert(TRACEPARENT, "00-01-02-01".parse().unwrap());
+        headers.insert(header::COOKIE, "secret=1".parse().unwrap());
+        headers.insert(TWIRP_TIMEOUT, "100".parse().unwrap());
+        let forwarded = forwarded_headers(&headers, &proxy.headers);
+        assert_eq!(forwarded.len(), 3);
+        assert!(!forwarded.contains_key(header::COOKIE));
+        assert!(!forwarded.contains_key(TWIRP_TIMEOUT));
+    }
+}
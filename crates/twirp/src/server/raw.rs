@@ -0,0 +1,236 @@
+//! Handlers that receive the request body undecoded.
+//!
+//! Routes added with
+//! [`TwirpRouterBuilder::route_raw`](crate::details::TwirpRouterBuilder::route_raw) (generated for
+//! the methods passed to `twirp_build::ServiceGenerator::raw_request`) are called with the
+//! `http::Request<Bytes>` as it was received, after any `Content-Encoding` is removed but before
+//! the codec runs. Handlers that only forward the payload skip decoding it; others can decode it
+//! on demand with [`DecodeRequest::decode`]. Likewise, they return a [`RawResponse`]: either a
+//! message for the router to encode, or a body that is already encoded and is sent as it is.
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Body;
+use bytes::Bytes;
+use http::{header, Extensions, Request, Response};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use super::budget::Reservation;
+use super::{
+    call_handler, parse_error, set_peer_info, AcceptEncoding, BodyFormat, Reply, RouterConfig,
+    Timings,
+};
+use crate::codec::MessageType;
+use crate::context::{CancelOnDrop, RequestSpan, RpcMethod};
+use crate::headers::CONTENT_TYPE_JSON;
+use crate::{codec, Context, GenericError, IntoTwirpResponse, TwirpErrorResponse};
+
+/// The format of an undecoded request, as determined by the router.
+#[derive(Clone, Debug)]
+pub(crate) struct RequestFormat(pub(crate) BodyFormat);
+
+/// The response of a handler added with
+/// [`TwirpRouterBuilder::route_raw`](crate::details::TwirpRouterBuilder::route_raw).
+#[derive(Clone, Debug, PartialEq)]
+pub enum RawResponse<T> {
+    /// A response message, encoded by the router as other handlers' responses are.
+    Message(T),
+    /// A response message that is already encoded with the request's codec (the one its
+    /// `Content-Type` names), e.g. the body of a response from the server the request was
+    /// forwarded to. The router sends it without decoding it, compressing it if the client
+    /// accepts compression.
+    Encoded(Bytes),
+}
+
+impl<T> From<T> for RawResponse<T> {
+    fn from(message: T) -> Self {
+        RawResponse::Message(message)
+    }
+}
+
+/// Decode the message of an undecoded request. See [`crate::details::TwirpRouterBuilder::route_raw`].
+pub trait DecodeRequest {
+    /// Decode the request message with the codec the router picked for the request, or a
+    /// `malformed` error if it can't be decoded.
+    fn decode<T>(&self) -> Result<T, TwirpErrorResponse>
+    where
+        T: prost::Message + Default + DeserializeOwned;
+}
+
+impl DecodeRequest for Request<Bytes> {
+    fn decode<T>(&self) -> Result<T, TwirpErrorResponse>
+    where
+        T: prost::Message + Default + DeserializeOwned,
+    {
+        // Requests built by hand (e.g. in tests) are protobuf unless they say otherwise.
+        let format = match self.extensions().get::<RequestFormat>() {
+            Some(RequestFormat(format)) => format.clone(),
+            None => match self.headers().get(header::CONTENT_TYPE) {
+                Some(ct) if ct.as_bytes() == CONTENT_TYPE_JSON => BodyFormat::JsonPb,
+                _ => BodyFormat::Pb,
+            },
+        };
+        let ty = match self.extensions().get::<RpcMethod>() {
+            Some(rpc) => MessageType::request(rpc.service, rpc.method),
+            None => MessageType::from_path(self.uri().path(), false),
+        };
+        codec::decode_message(format.codec(), ty, self.body().clone()).map_err(parse_error)
+    }
+}
+
+/// Like [`handle_request`](super::handle_request), but `f` gets the request with its body
+/// undecoded.
+pub(crate) async fn handle_raw_request<S, F, Fut, Resp, Err>(
+    service: S,
+    mut req: Request<Body>,
+    f: F,
+) -> Response<Body>
+where
+    F: FnOnce(S, Context, Request<Bytes>) -> Fut + Clone + Sync + Send + 'static,
+    Fut: Future<Output = Result<RawResponse<Resp>, Err>> + Send,
+    Resp: prost::Message + Serialize + Send + 'static,
+    Err: IntoTwirpResponse,
+{
+    let mut timings = req
+        .extensions()
+        .get::<Timings>()
+        .copied()
+        .unwrap_or_else(|| Timings::new(Instant::now()));
+
+    let config = RouterConfig::from_request(&req);
+    let rpc = config.rpc(&req);
+    let span = RequestSpan::start(req.extensions_mut());
+    let hooks = config.hooks.start(rpc, span.clone(), &timings);
+    let error_context = config.error_context(&req);
+    let accept_encoding = AcceptEncoding::new(req.headers());
+    let deadline = config.set_deadline(&mut req, &timings);
+    let cancel = CancelOnDrop::new(req.extensions_mut());
+    set_peer_info(&mut req);
+    let format = BodyFormat::from_content_type(&req, &config);
+    let (mut parts, body) = req.into_parts();
+    let mut reservation = Reservation::new(config.memory_budget.as_ref());
+    let body = match read_body(body, &parts.headers, &config, &mut reservation).await {
+        Ok(body) => body,
+        Err(err) => {
+            let err = parse_error(err).into_twirp_response();
+            return config.fail(&error_context, &hooks, &timings, err);
+        }
+    };
+    timings.set_received();
+    hooks.routed(&timings);
+    parts.extensions.insert(RequestFormat(format.clone()));
+
+    let resp_exts = Arc::new(Mutex::new(Extensions::new()));
+    let ctx = Context::new(parts.extensions.clone(), resp_exts.clone())
+        .with_headers(parts.headers.clone());
+    let res = call_handler(
+        deadline,
+        cancel,
+        span.instrument(f(service, ctx, Request::from_parts(parts, body))),
+    )
+    .await;
+    let reply = Reply {
+        rpc,
+        format,
+        config: &config,
+        error_context: &error_context,
+        hooks,
+        accept_encoding,
+        resp_exts,
+        reservation,
+    };
+    reply.finish(res, timings).await
+}
+
+async fn read_body(
+    body: Body,
+    headers: &http::HeaderMap,
+    config: &RouterConfig,
+    reservation: &mut Reservation,
+) -> Result<Bytes, GenericError> {
+    let bytes = reservation.collect(body, config.max_request_size).await?;
+    config.decode_body(headers, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use tower::Service;
+
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::serialize_proto_message;
+    use crate::test::*;
+
+    fn router() -> axum::Router {
+        let router = TwirpRouterBuilder::new("/test.TestAPI", ())
+            .route_raw("/Ping", |_, _ctx, req: Request<Bytes>| async move {
+                let ping: PingRequest = req.decode()?;
+                Ok::<_, TwirpErrorResponse>(RawResponse::Message(PingResponse {
+                    name: format!("{} ({} bytes)", ping.name, req.body().len()),
+                }))
+            })
+            .route_raw("/Echo", |_, _ctx, req: Request<Bytes>| async move {
+                // `PingRequest` and `PingResponse` have the same fields, so the request is also
+                // a valid response, in whatever format it was sent.
+                Ok::<_, TwirpErrorResponse>(RawResponse::<PingResponse>::Encoded(req.into_body()))
+            })
+            .build();
+        axum::Router::new().nest("/twirp/test.TestAPI", router)
+    }
+
+    #[tokio::test]
+    async fn test_raw_request() {
+        let resp = router().call(gen_ping_request("raw")).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "raw (14 bytes)");
+
+        let req = Request::post("/twirp/test.TestAPI/Ping")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("not json"))
+            .unwrap();
+        let resp = router().call(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::Malformed);
+    }
+
+    #[tokio::test]
+    async fn test_encoded_response() {
+        let req = Request::post("/twirp/test.TestAPI/Echo")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"echo"}"#))
+            .unwrap();
+        let resp = router().call(req).await.unwrap();
+        assert!(resp.status().is_success(), "{:?}", resp);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            read_string_body(resp.into_body()).await,
+            r#"{"name":"echo"}"#
+        );
+
+        let ping = PingRequest {
+            name: "echo".to_string(),
+        };
+        let req = Request::post("/twirp/test.TestAPI/Echo")
+            .header(header::CONTENT_TYPE, "application/protobuf")
+            .body(Body::from(serialize_proto_message(ping)))
+            .unwrap();
+        let resp = router().call(req).await.unwrap();
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        let data = <PingResponse as prost::Message>::decode(bytes).unwrap();
+        assert_eq!(data.name, "echo");
+    }
+
+    #[test]
+    fn test_decode_without_router() {
+        let ping = PingRequest {
+            name: "hi".to_string(),
+        };
+        let req = Request::new(serialize_proto_message(ping.clone()));
+        assert_eq!(req.decode::<PingRequest>().unwrap(), ping);
+    }
+}
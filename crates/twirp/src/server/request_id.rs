@@ -0,0 +1,176 @@
+//! Request ids for correlating logs and errors across services.
+//!
+//! [`RequestIdLayer`] reads the request id from a header (`x-request-id` by default), generating a
+//! random UUID when the caller didn't send one. The id is inserted into the request extensions as
+//! a [`RequestId`], where handlers can read it with `ctx.get::<RequestId>()`, and echoed back on
+//! the response.
+//!
+//! # Usage
+//!
+//! ```
+//! use twirp::server::request_id::RequestIdLayer;
+//! use twirp::Router;
+//!
+//! # fn build_app(twirp_routes: Router) -> Router {
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .layer(RequestIdLayer::new());
+//! # app }
+//! ```
+
+use std::fmt;
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use futures::future::BoxFuture;
+use http::{HeaderName, HeaderValue};
+use hyper::{Request, Response};
+use tower::{Layer, Service};
+
+/// The default header used to carry request ids.
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The id of the current request. Available as a request extension (and through
+/// [`Context::get`](crate::Context::get)) when [`RequestIdLayer`] is in use.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Default, Hash)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Layer that applies the [`SetRequestId`] middleware.
+#[derive(Clone, Debug)]
+pub struct RequestIdLayer {
+    header: HeaderName,
+}
+
+impl Default for RequestIdLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestIdLayer {
+    /// Use the `x-request-id` header.
+    pub fn new() -> Self {
+        Self {
+            header: X_REQUEST_ID,
+        }
+    }
+
+    /// Use a different header to read and echo the request id.
+    pub fn header_name(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+}
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = SetRequestId<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SetRequestId {
+            inner,
+            header: self.header.clone(),
+        }
+    }
+}
+
+/// Middleware that reads or generates a [`RequestId`] for each request.
+#[derive(Clone, Debug)]
+pub struct SetRequestId<S> {
+    inner: S,
+    header: HeaderName,
+}
+
+impl<S> Service<Request<Body>> for SetRequestId<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let id = req
+            .headers()
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let value = HeaderValue::from_str(&id).ok();
+        req.extensions_mut().insert(RequestId(id));
+
+        let header = self.header.clone();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            if let Some(value) = value {
+                resp.headers_mut().insert(header, value);
+            }
+            Ok(resp)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::post;
+    use axum::{Extension, Router};
+
+    use super::*;
+
+    fn echo_service() -> Router {
+        Router::new().route(
+            "/",
+            post(|Extension(id): Extension<RequestId>| async move { id.0 }),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_existing_id() {
+        let mut svc = RequestIdLayer::new()
+            .header_name(HeaderName::from_static("x-trace"))
+            .layer(echo_service());
+        let req = Request::post("/")
+            .header("x-trace", "abcd")
+            .body(Body::empty())
+            .unwrap();
+        let resp = svc.call(req).await.unwrap();
+        assert_eq!(resp.headers()["x-trace"], "abcd");
+        assert_eq!(
+            crate::test::read_string_body(resp.into_body()).await,
+            "abcd"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_generated_id() {
+        let mut svc = RequestIdLayer::new().layer(echo_service());
+        let resp = svc
+            .call(Request::post("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let header = resp.headers()[X_REQUEST_ID].to_str().unwrap().to_string();
+        assert!(uuid::Uuid::parse_str(&header).is_ok(), "{header}");
+        assert_eq!(
+            crate::test::read_string_body(resp.into_body()).await,
+            header
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Validated assembly of an app out of several Twirp services and other routes.
+//!
+//! axum panics on some overlapping routes and silently shadows others. [`RouteTable`] checks the
+//! whole table up front and reports every problem at once, so that a misconfigured app fails at
+//! startup with a useful message.
+//!
+//! # Usage
+//!
+//! ```
+//! use axum::routing::get;
+//! use twirp::server::routes::RouteTable;
+//! use twirp::Router;
+//!
+//! # fn build_app(haberdasher: Router, tailor: Router) -> Result<Router, Box<dyn std::error::Error>> {
+//! // With generated code, e.g. `.service(haberdash::SERVICE_FQN, haberdash::METHODS, router)`.
+//! let app = RouteTable::new("/twirp")
+//!     .service("example.v1.Haberdasher", &["MakeHat"], haberdasher)
+//!     .service("example.v1.Tailor", &["Measure", "Fit"], tailor)
+//!     .route("/_ping", get(|| async { "pong" }))
+//!     .build()?;
+//! # Ok(app) }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use axum::routing::MethodRouter;
+use axum::{Extension, Router};
+use thiserror::Error;
+
+use super::{not_found_handler, RouteCandidates};
+
+/// A problem found by [`RouteTable::build`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RouteConflict {
+    /// The path is claimed more than once, or a route is shadowed by a nested router.
+    DuplicatePath(String),
+    /// The service has an `rpc` with an empty name.
+    EmptyMethodName { service: String },
+    /// A non-Twirp route is mounted under the Twirp prefix.
+    PrefixMismatch { path: String, prefix: String },
+    /// The path is empty, doesn't start with `/`, or ends with `/`.
+    InvalidPath(String),
+}
+
+impl fmt::Display for RouteConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteConflict::DuplicatePath(path) => write!(f, "duplicate path {path}"),
+            RouteConflict::EmptyMethodName { service } => {
+                write!(f, "service {service} has a method with an empty name")
+            }
+            RouteConflict::PrefixMismatch { path, prefix } => {
+                write!(
+                    f,
+                    "{path} is not a Twirp route but is under the prefix {prefix}"
+                )
+            }
+            RouteConflict::InvalidPath(path) => write!(f, "invalid path {path:?}"),
+        }
+    }
+}
+
+/// The error returned by [`RouteTable::build`], listing every conflict found.
+#[derive(Debug, Error)]
+#[error("invalid route table: {}", .conflicts.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+pub struct RouteTableError {
+    pub conflicts: Vec<RouteConflict>,
+}
+
+/// Builder for an app that serves several Twirp services under a common prefix, along with other
+/// axum routes. See the [module documentation](self).
+pub struct RouteTable {
+    prefix: String,
+    services: Vec<(String, Vec<String>, Router)>,
+    routes: Vec<(String, MethodRouter)>,
+    nested: Vec<(String, Router)>,
+}
+
+impl RouteTable {
+    /// Start a table whose Twirp services are mounted under `prefix` (e.g. `/twirp`, or `""` to
+    /// mount them at the root).
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            services: vec![],
+            routes: vec![],
+            nested: vec![],
+        }
+    }
+
+    /// Mount the generated `router` of the service named `service_fqn` (its `SERVICE_FQN`, with
+    /// or without the leading `/`) at `{prefix}/{service_fqn}`. `methods` are the names of its
+    /// `rpc`s (the generated `METHODS`), which are checked along with the routes.
+    pub fn service(mut self, service_fqn: &str, methods: &[&str], router: Router) -> Self {
+        let fqn = service_fqn.trim_start_matches('/').to_string();
+        let methods = methods.iter().map(ToString::to_string).collect();
+        self.services.push((fqn, methods, router));
+        self
+    }
+
+    /// Add a non-Twirp route, as with [`Router::route`].
+    pub fn route(mut self, path: &str, method_router: MethodRouter) -> Self {
+        self.routes.push((path.to_string(), method_router));
+        self
+    }
+
+    /// Add a nested non-Twirp router, as with [`Router::nest`].
+    pub fn nest(mut self, path: &str, router: Router) -> Self {
+        self.nested.push((path.to_string(), router));
+        self
+    }
+
+    /// Check the table for conflicts and build the app. Requests that match no route get
+    /// [`not_found_handler`], which suggests the services of the table.
+    pub fn build(self) -> Result<Router, RouteTableError> {
+        let conflicts = self.conflicts();
+        if !conflicts.is_empty() {
+            return Err(RouteTableError { conflicts });
+        }
+
+        let mut app = Router::new();
+        let mut candidates = RouteCandidates::default();
+        for (fqn, _, router) in self.services {
+            let service_path = format!("{}/{fqn}", self.prefix);
+            app = app.nest(&service_path, router);
+            candidates.add_service(service_path);
+        }
+        for (path, method_router) in self.routes {
+            app = app.route(&path, method_router);
+        }
+        for (path, router) in self.nested {
+            app = app.nest(&path, router);
+        }
+        Ok(app
+            .fallback(not_found_handler)
+            .layer(Extension(Arc::new(candidates))))
+    }
+
+    fn conflicts(&self) -> Vec<RouteConflict> {
+        let mut conflicts = vec![];
+        let valid_path = |path: &str| path.starts_with('/') && !path.ends_with('/');
+        if !self.prefix.is_empty() && !valid_path(&self.prefix) {
+            conflicts.push(RouteConflict::InvalidPath(self.prefix.clone()));
+        }
+
+        // Every path that is routed, and whether it is a prefix for nested routes.
+        let mut claimed: BTreeMap<String, bool> = BTreeMap::new();
+        let mut claim = |path: String, nested: bool, conflicts: &mut Vec<RouteConflict>| {
+            if claimed.insert(path.clone(), nested).is_some() {
+                conflicts.push(RouteConflict::DuplicatePath(path));
+            }
+        };
+
+        for (fqn, methods, _) in &self.services {
+            if fqn.is_empty() {
+                conflicts.push(RouteConflict::InvalidPath(fqn.clone()));
+                continue;
+            }
+            let service_path = format!("{}/{fqn}", self.prefix);
+            claim(service_path.clone(), true, &mut conflicts);
+            if methods.iter().any(String::is_empty) {
+                conflicts.push(RouteConflict::EmptyMethodName {
+                    service: fqn.clone(),
+                });
+            }
+        }
+
+        let extra_routes = self.routes.iter().map(|(path, _)| (path, false));
+        let extra_nested = self.nested.iter().map(|(path, _)| (path, true));
+        for (path, nested) in extra_routes.chain(extra_nested) {
+            if !valid_path(path) {
+                conflicts.push(RouteConflict::InvalidPath(path.clone()));
+                continue;
+            }
+            if !self.prefix.is_empty() && path.starts_with(&format!("{}/", self.prefix)) {
+                conflicts.push(RouteConflict::PrefixMismatch {
+                    path: path.clone(),
+                    prefix: self.prefix.clone(),
+                });
+            }
+            claim(path.clone(), nested, &mut conflicts);
+        }
+
+        // Routes under a nested router are shadowed by it (or shadow it).
+        for (path, _) in claimed.iter() {
+            let shadowed = claimed
+                .iter()
+                .any(|(other, nested)| *nested && path.starts_with(&format!("{other}/")));
+            if shadowed {
+                conflicts.push(RouteConflict::DuplicatePath(path.clone()));
+            }
+        }
+        conflicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::routing::get;
+    use tower::Service;
+
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::Context;
+
+    #[tokio::test]
+    async fn test_build() {
+        let mut app = RouteTable::new("/twirp")
+            .service("/test.TestAPI", TEST_API_METHODS, test_api_service_router())
+            .route("/_ping", get(|| async { "pong" }))
+            .build()
+            .unwrap();
+        let resp = app.call(gen_ping_request("hi")).await.unwrap();
+        assert!(resp.status().is_success());
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let empty_method = TwirpRouterBuilder::new("/test.EmptyMethod", Arc::new(TestApiServer))
+            .route(
+                "/",
+                |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+
+        let err = RouteTable::new("/twirp")
+            .service("test.TestAPI", TEST_API_METHODS, test_api_service_router())
+            .service("/test.TestAPI", TEST_API_METHODS, test_api_service_router())
+            .service("test.EmptyMethod", &[""], empty_method)
+            .route("/twirp/health", get(|| async { "ok" }))
+            .route("/admin/users", get(|| async { "ok" }))
+            .nest("/admin", Router::new())
+            .route("health", get(|| async { "ok" }))
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.conflicts,
+            vec![
+                RouteConflict::DuplicatePath("/twirp/test.TestAPI".to_string()),
+                RouteConflict::EmptyMethodName {
+                    service: "test.EmptyMethod".to_string()
+                },
+                RouteConflict::PrefixMismatch {
+                    path: "/twirp/health".to_string(),
+                    prefix: "/twirp".to_string(),
+                },
+                RouteConflict::InvalidPath("health".to_string()),
+                RouteConflict::DuplicatePath("/admin/users".to_string()),
+            ]
+        );
+        assert!(err
+            .to_string()
+            .starts_with("invalid route table: duplicate path /twirp/test.TestAPI, "));
+    }
+}
@@ -0,0 +1,560 @@
+//! Validation of requests against the services' protobuf schema.
+//!
+//! [`SchemaValidationLayer`] decodes each request with the descriptors of the method it is for and
+//! rejects requests that don't match the schema with an `invalid_argument` error, before they
+//! reach the service:
+//!
+//! - `required` fields (proto2) must be set.
+//! - Enum fields must hold one of the values defined by the enum.
+//! - JSON bodies can't nest deeper than [`SchemaValidationLayer::max_json_depth`].
+//!
+//! The error's `field` meta value is the path of the offending field (e.g. `hat.sizes[2].unit`)
+//! and `reason` says what is wrong with it.
+//!
+//! Both `POST` bodies and the messages of `GET` requests (`?proto=` or `?json=`) are validated.
+//! Bodies are read up to [`SchemaValidationLayer::max_request_size`], and compressed bodies are
+//! decompressed with the [`zstd`](SchemaValidationLayer::zstd) and
+//! [`gzip`](SchemaValidationLayer::gzip) settings of the layer. Requests that the layer can't read
+//! (in a content type other than JSON and protobuf, such as that of an
+//! [encrypted](crate::encryption) codec, or with an unsupported `Content-Encoding`) are rejected,
+//! so the layer can't be bypassed. Requests for methods that aren't in the descriptor pool, and
+//! messages that don't decode at all, are passed through for the service to reject.
+//!
+//! Requires the [`reflect` feature](crate#the-reflect-feature).
+//!
+//! # Usage
+//!
+//! ```
+//! use twirp::server::validation::SchemaValidationLayer;
+//! use twirp::Router;
+//! use twirp::prost_reflect::DescriptorPool;
+//!
+//! # fn build_app(twirp_routes: Router, descriptors: &[u8]) -> Router {
+//! let pool = DescriptorPool::decode(descriptors).expect("valid descriptors");
+//! let app = Router::new()
+//!     .nest("/twirp", twirp_routes)
+//!     .layer(SchemaValidationLayer::new(pool));
+//! # app }
+//! ```
+
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use axum::response::IntoResponse;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http::{header, HeaderMap, Method};
+use hyper::{Request, Response};
+use prost_reflect::{
+    Cardinality, DescriptorPool, DeserializeOptions, DynamicMessage, Kind, MapKey,
+    MessageDescriptor, ReflectMessage, Value,
+};
+use tower::{Layer, Service};
+
+use super::{decode_base64, read_request_body};
+use crate::context::split_route;
+use crate::headers::{CONTENT_TYPE_PROTOBUF, CONTENT_TYPE_X_PROTOBUF};
+use crate::{error, TwirpErrorResponse};
+
+const DEFAULT_MAX_JSON_DEPTH: usize = 32;
+const DEFAULT_MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+
+/// Layer that applies the [`SchemaValidation`] middleware.
+#[derive(Clone, Debug)]
+pub struct SchemaValidationLayer {
+    pool: DescriptorPool,
+    max_json_depth: usize,
+    max_request_size: usize,
+    #[cfg(feature = "zstd")]
+    zstd: Option<crate::compression::Zstd>,
+    #[cfg(feature = "gzip")]
+    gzip: Option<crate::compression::Gzip>,
+}
+
+impl SchemaValidationLayer {
+    /// Validate requests against the methods described in `pool`.
+    pub fn new(pool: DescriptorPool) -> Self {
+        Self {
+            pool,
+            max_json_depth: DEFAULT_MAX_JSON_DEPTH,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            #[cfg(feature = "zstd")]
+            zstd: None,
+            #[cfg(feature = "gzip")]
+            gzip: None,
+        }
+    }
+
+    /// The maximum nesting depth of objects and arrays in JSON requests. Defaults to 32.
+    pub fn max_json_depth(mut self, max_json_depth: usize) -> Self {
+        self.max_json_depth = max_json_depth;
+        self
+    }
+
+    /// Reject request bodies longer than `bytes` with a `resource_exhausted` error. Defaults to 4
+    /// MiB.
+    pub fn max_request_size(mut self, bytes: usize) -> Self {
+        self.max_request_size = bytes;
+        self
+    }
+
+    /// Decompress zstd-compressed requests with these settings (e.g. the router's dictionary).
+    /// Without them, such requests are rejected.
+    #[cfg(feature = "zstd")]
+    pub fn zstd(mut self, zstd: crate::compression::Zstd) -> Self {
+        self.zstd = Some(zstd);
+        self
+    }
+
+    /// Decompress gzip-compressed requests with these settings. Without them, such requests are
+    /// rejected.
+    #[cfg(feature = "gzip")]
+    pub fn gzip(mut self, gzip: crate::compression::Gzip) -> Self {
+        self.gzip = Some(gzip);
+        self
+    }
+
+    // The body of a request with `headers` as it was before it was compressed.
+    #[cfg_attr(not(any(feature = "zstd", feature = "gzip")), allow(unused_mut))]
+    fn decompress(&self, headers: &HeaderMap, body: Bytes) -> Result<Bytes, TwirpErrorResponse> {
+        let Some(encoding) = headers.get(header::CONTENT_ENCODING) else {
+            return Ok(body);
+        };
+        if encoding.as_bytes().eq_ignore_ascii_case(b"identity") {
+            return Ok(body);
+        }
+        let mut decompressed: Option<std::io::Result<Bytes>> = None;
+        #[cfg(feature = "zstd")]
+        if let Some(zstd) = &self.zstd {
+            if crate::compression::is_encoded(headers, crate::compression::ZSTD) {
+                decompressed = Some(zstd.decompress(&body));
+            }
+        }
+        #[cfg(feature = "gzip")]
+        if let Some(gzip) = &self.gzip {
+            if crate::compression::is_encoded(headers, crate::compression::GZIP) {
+                decompressed = Some(gzip.decompress(&body));
+            }
+        }
+        match decompressed {
+            Some(Ok(body)) => Ok(body),
+            Some(Err(err)) => {
+                Err(error::malformed("failed to decompress the request body")
+                    .with_meta("error", err))
+            }
+            None => Err(error::malformed(format!(
+                "unsupported Content-Encoding {:?}",
+                String::from_utf8_lossy(encoding.as_bytes())
+            ))),
+        }
+    }
+}
+
+impl<S> Layer<S> for SchemaValidationLayer {
+    type Service = SchemaValidation<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        SchemaValidation {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Middleware that rejects requests that don't match the schema of the method they call.
+#[derive(Clone, Debug)]
+pub struct SchemaValidation<S> {
+    inner: S,
+    layer: SchemaValidationLayer,
+}
+
+impl<S> Service<Request<Body>> for SchemaValidation<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let Some(input) = input_descriptor(&self.layer.pool, req.uri().path()) else {
+            return Box::pin(inner.call(req));
+        };
+        let max_json_depth = self.layer.max_json_depth;
+        match *req.method() {
+            Method::GET => match validate_query(input, req.uri(), max_json_depth) {
+                Some(violation) => Box::pin(async move { Ok(violation.into_response()) }),
+                None => Box::pin(inner.call(req)),
+            },
+            Method::POST => {
+                let layer = self.layer.clone();
+                Box::pin(async move {
+                    let (parts, body) = req.into_parts();
+                    let bytes = match read_request_body(body, Some(layer.max_request_size)).await {
+                        Ok(bytes) => bytes,
+                        Err(resp) => return Ok(resp),
+                    };
+                    let message = match layer.decompress(&parts.headers, bytes.clone()) {
+                        Ok(message) => message,
+                        Err(err) => return Ok(err.into_response()),
+                    };
+                    let content_type = parts.headers.get(header::CONTENT_TYPE);
+                    let violation = match content_type.map(|ct| ct.as_bytes()) {
+                        // Routers read bodies without a content type as JSON.
+                        None => validate_json(input, &message, max_json_depth),
+                        Some(ct) if ct.starts_with(b"application/json") => {
+                            validate_json(input, &message, max_json_depth)
+                        }
+                        Some(CONTENT_TYPE_PROTOBUF | CONTENT_TYPE_X_PROTOBUF) => {
+                            validate_protobuf(input, message)
+                        }
+                        Some(ct) => {
+                            let ct = String::from_utf8_lossy(ct);
+                            let err = error::bad_route(format!(
+                                "unexpected Content-Type {ct:?}: the request can't be validated"
+                            ));
+                            return Ok(err.into_response());
+                        }
+                    };
+                    match violation {
+                        Some(violation) => Ok(violation.into_response()),
+                        None => {
+                            inner
+                                .call(Request::from_parts(parts, Body::from(bytes)))
+                                .await
+                        }
+                    }
+                })
+            }
+            _ => Box::pin(inner.call(req)),
+        }
+    }
+}
+
+/// The request message of the method a Twirp path (`[<prefix>]/<package>.<Service>/<Method>`)
+/// routes to.
+fn input_descriptor(pool: &DescriptorPool, path: &str) -> Option<MessageDescriptor> {
+    let (service, method) = split_route(path)?;
+    let service = pool.get_service_by_name(service)?;
+    let method = service.methods().find(|m| m.name() == method)?;
+    Some(method.input())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Violation {
+    field: String,
+    reason: String,
+}
+
+impl IntoResponse for Violation {
+    fn into_response(self) -> axum::response::Response {
+        let Violation { field, reason } = self;
+        let msg = if field.is_empty() {
+            format!("invalid request: {reason}")
+        } else {
+            format!("invalid request: {field}: {reason}")
+        };
+        let mut twirp_err = error::invalid_argument(msg);
+        twirp_err.insert_meta("field".to_string(), field);
+        twirp_err.insert_meta("reason".to_string(), reason);
+        twirp_err.into_response()
+    }
+}
+
+/// Validate the message of a `GET` request, read from the query string like routers do.
+fn validate_query(
+    input: MessageDescriptor,
+    uri: &http::Uri,
+    max_depth: usize,
+) -> Option<Violation> {
+    let query = uri.query().unwrap_or_default();
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "proto" => return validate_protobuf(input, decode_base64(&value).ok()?.into()),
+            "json" => return validate_json(input, value.as_bytes(), max_depth),
+            _ => {}
+        }
+    }
+    validate_message(&DynamicMessage::new(input), "")
+}
+
+fn validate_protobuf(input: MessageDescriptor, bytes: Bytes) -> Option<Violation> {
+    let msg = DynamicMessage::decode(input, bytes).ok()?;
+    validate_message(&msg, "")
+}
+
+fn validate_json(input: MessageDescriptor, bytes: &[u8], max_depth: usize) -> Option<Violation> {
+    if json_depth(bytes) > max_depth {
+        return Some(Violation {
+            field: String::new(),
+            reason: format!("JSON is nested deeper than {max_depth} levels"),
+        });
+    }
+    let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+    // Like the services' serde decoders, skip fields that aren't in the schema.
+    let options = DeserializeOptions::new().deny_unknown_fields(false);
+    let msg = DynamicMessage::deserialize_with_options(input, &mut deserializer, &options).ok()?;
+    validate_message(&msg, "")
+}
+
+/// The maximum nesting depth of objects and arrays in `bytes`.
+fn json_depth(bytes: &[u8]) -> usize {
+    let (mut depth, mut max, mut in_string, mut escaped) = (0usize, 0, false, false);
+    for &b in bytes {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            b'{' | b'[' if !in_string => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b'}' | b']' if !in_string => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
+
+fn validate_message(msg: &DynamicMessage, path: &str) -> Option<Violation> {
+    for field in msg.descriptor().fields() {
+        let field_path = if path.is_empty() {
+            field.name().to_string()
+        } else {
+            format!("{path}.{}", field.name())
+        };
+        if field.cardinality() == Cardinality::Required && !msg.has_field(&field) {
+            return Some(Violation {
+                field: field_path,
+                reason: "required field is missing".to_string(),
+            });
+        }
+        if !msg.has_field(&field) {
+            continue;
+        }
+
+        let kind = if field.is_map() {
+            match field.kind() {
+                Kind::Message(entry) => entry.map_entry_value_field().kind(),
+                kind => kind,
+            }
+        } else {
+            field.kind()
+        };
+        let violation = match &*msg.get_field(&field) {
+            Value::List(values) => values
+                .iter()
+                .enumerate()
+                .find_map(|(i, value)| validate_value(value, &kind, &format!("{field_path}[{i}]"))),
+            Value::Map(entries) => entries.iter().find_map(|(key, value)| {
+                validate_value(value, &kind, &format!("{field_path}[{}]", map_key(key)))
+            }),
+            value => validate_value(value, &kind, &field_path),
+        };
+        if violation.is_some() {
+            return violation;
+        }
+    }
+    None
+}
+
+fn validate_value(value: &Value, kind: &Kind, path: &str) -> Option<Violation> {
+    match (value, kind) {
+        (Value::Message(msg), _) => validate_message(msg, path),
+        (Value::EnumNumber(n), Kind::Enum(desc)) if desc.get_value(*n).is_none() => {
+            Some(Violation {
+                field: path.to_string(),
+                reason: format!("{n} is not a value of {}", desc.full_name()),
+            })
+        }
+        _ => None,
+    }
+}
+
+fn map_key(key: &MapKey) -> String {
+    match key {
+        MapKey::Bool(b) => b.to_string(),
+        MapKey::I32(n) => n.to_string(),
+        MapKey::I64(n) => n.to_string(),
+        MapKey::U32(n) => n.to_string(),
+        MapKey::U64(n) => n.to_string(),
+        MapKey::String(s) => s.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::post;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::test::{descriptor_pool, read_err_body};
+    use crate::TwirpErrorCode;
+
+    fn app(layer: SchemaValidationLayer) -> axum::Router {
+        axum::Router::new()
+            .route("/twirp/test.Haberdasher/MakeHat", post(|| async { "ok" }))
+            .layer(layer)
+    }
+
+    async fn call_json(layer: SchemaValidationLayer, body: &str) -> Response<Body> {
+        let req = Request::post("/twirp/test.Haberdasher/MakeHat")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app(layer).oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_valid_requests() {
+        let layer = SchemaValidationLayer::new(descriptor_pool());
+        let resp = call_json(layer.clone(), r#"{"name": "fedora", "color": "BLUE"}"#).await;
+        assert!(resp.status().is_success());
+
+        let hat = descriptor_pool().get_message_by_name("test.Hat").unwrap();
+        let mut msg = DynamicMessage::new(hat);
+        msg.set_field_by_name("name", Value::String("fedora".to_string()));
+        let req = Request::post("/twirp/test.Haberdasher/MakeHat")
+            .header(header::CONTENT_TYPE, "application/protobuf")
+            .body(Body::from(prost::Message::encode_to_vec(&msg)))
+            .unwrap();
+        let resp = app(layer).oneshot(req).await.unwrap();
+        assert!(resp.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn test_violations() {
+        let cases = [
+            (r#"{"color": "RED"}"#, "name", "required field is missing"),
+            (
+                r#"{"color": "RED", "x": 1}"#,
+                "name",
+                "required field is missing",
+            ),
+            (
+                r#"{"name": "fedora", "parts": [{"name": "brim"}, {"color": "BLUE"}]}"#,
+                "parts[1].name",
+                "required field is missing",
+            ),
+            (
+                r#"{"name": "fedora", "color": 7}"#,
+                "color",
+                "7 is not a value of test.Color",
+            ),
+            (
+                r#"{"name": "fedora", "parts": [{"name": "a", "parts": [{"name": "b"}]}]}"#,
+                "",
+                "JSON is nested deeper than 3 levels",
+            ),
+        ];
+        for (body, field, reason) in cases {
+            let layer = SchemaValidationLayer::new(descriptor_pool()).max_json_depth(3);
+            let resp = call_json(layer, body).await;
+            let err = read_err_body(resp.into_body()).await;
+            assert_eq!(err.code, crate::TwirpErrorCode::InvalidArgument, "{body}");
+            assert_eq!(err.meta["field"], field, "{body}");
+            assert_eq!(err.meta["reason"], reason, "{body}");
+        }
+
+        let body = Body::from_stream(futures::stream::once(async {
+            Err::<bytes::Bytes, _>(std::io::Error::other("connection reset"))
+        }));
+        let req = Request::post("/twirp/test.Haberdasher/MakeHat")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap();
+        let resp = app(SchemaValidationLayer::new(descriptor_pool()))
+            .oneshot(req)
+            .await
+            .unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::Malformed);
+    }
+
+    #[tokio::test]
+    async fn test_unvalidated_requests_are_rejected() {
+        let layer = SchemaValidationLayer::new(descriptor_pool()).max_request_size(16);
+        let resp = call_json(layer.clone(), r#"{"name": "a very long name for a hat"}"#).await;
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, crate::TwirpErrorCode::ResourceExhausted);
+
+        let cases = [
+            (
+                "application/vnd.twirp.encrypted+json",
+                None,
+                TwirpErrorCode::BadRoute,
+            ),
+            ("application/json", Some("br"), TwirpErrorCode::Malformed),
+        ];
+        for (content_type, encoding, code) in cases {
+            let mut req = Request::post("/twirp/test.Haberdasher/MakeHat")
+                .header(header::CONTENT_TYPE, content_type);
+            if let Some(encoding) = encoding {
+                req = req.header(header::CONTENT_ENCODING, encoding);
+            }
+            let req = req.body(Body::from("{}")).unwrap();
+            let resp = app(layer.clone()).oneshot(req).await.unwrap();
+            let err = read_err_body(resp.into_body()).await;
+            assert_eq!(err.code, code, "{content_type}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_requests() {
+        let app = || {
+            axum::Router::new()
+                .route(
+                    "/twirp/test.Haberdasher/MakeHat",
+                    axum::routing::get(|| async { "ok" }),
+                )
+                .layer(SchemaValidationLayer::new(descriptor_pool()))
+        };
+        let get = |query: &str| {
+            Request::get(format!("/twirp/test.Haberdasher/MakeHat?{query}"))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let resp = app()
+            .oneshot(get("json=%7B%22name%22%3A%22fedora%22%7D"))
+            .await
+            .unwrap();
+        assert!(resp.status().is_success());
+
+        for query in ["", "json=%7B%7D", "proto="] {
+            let resp = app().oneshot(get(query)).await.unwrap();
+            let err = read_err_body(resp.into_body()).await;
+            assert_eq!(err.code, TwirpErrorCode::InvalidArgument, "{query}");
+            assert_eq!(err.meta["field"], "name", "{query}");
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_compressed_requests() {
+        use crate::compression::Gzip;
+
+        let body = Gzip::new()
+            .min_size(0)
+            .compress(br#"{"color": "RED"}"#)
+            .unwrap()
+            .unwrap();
+        let layer = SchemaValidationLayer::new(descriptor_pool()).gzip(Gzip::new());
+        let req = Request::post("/twirp/test.Haberdasher/MakeHat")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(Body::from(body))
+            .unwrap();
+        let resp = app(layer).oneshot(req).await.unwrap();
+        let err = read_err_body(resp.into_body()).await;
+        assert_eq!(err.code, TwirpErrorCode::InvalidArgument);
+        assert_eq!(err.meta["field"], "name");
+    }
+}
@@ -0,0 +1,434 @@
+//! Server-streaming RPCs, an extension to the Twirp protocol.
+//!
+//! Twirp itself only has unary RPCs. With this extension a handler returns a [`Stream`] of
+//! messages instead of a single one, and the server writes each message as soon as it is ready.
+//! The request is an ordinary Twirp request; the response body depends on the request format:
+//!
+//! - Protobuf requests get an `application/twirp-stream+protobuf` response: a sequence of frames,
+//!   each a flags byte and a big-endian `u32` length followed by that many bytes of payload. Data
+//!   frames (flags `0`) hold one encoded message. The stream always ends with a trailer frame
+//!   (flags `1`) whose payload is empty on success or a JSON Twirp error otherwise.
+//! - JSON requests get a `text/event-stream` response (server-sent events), with one `data`
+//!   event per message and a final `end` or `error` event.
+//!
+//! Errors returned before the stream starts are sent as regular Twirp error responses.
+//!
+//! With a [codec](crate::codec) other than plain protobuf, such as
+//! [`Encrypted`](crate::encryption::Encrypted), the payload of every frame, the trailer included,
+//! is encoded with the codec for the response message, so e.g. each frame is encrypted on its own.
+//! Server-sent events are plain JSON, so routers reject streaming requests in a JSON codec whose
+//! bodies aren't `application/json`.
+//!
+//! Streaming routes are registered with
+//! [`TwirpRouterBuilder::route_server_streaming`](crate::details::TwirpRouterBuilder::route_server_streaming)
+//! and called with [`Client::request_stream`](crate::Client::request_stream).
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::body::Body;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::stream::{self, BoxStream};
+use futures::{Stream, StreamExt};
+use hyper::{header, Response};
+use serde::Serialize;
+
+use crate::client::{ClientError, Result};
+use crate::codec::{Codec, MessageType};
+use crate::headers::{CONTENT_TYPE_EVENT_STREAM, CONTENT_TYPE_STREAM_PROTOBUF};
+use crate::{GenericError, IntoTwirpResponse, TwirpErrorResponse};
+
+/// A stream of messages received from a server-streaming RPC.
+pub type MessageStream<T> = BoxStream<'static, Result<T>>;
+
+const FLAG_DATA: u8 = 0;
+const FLAG_TRAILER: u8 = 1;
+const HEADER_LEN: usize = 5;
+
+fn encode_frame(flags: u8, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    frame.put_u8(flags);
+    frame.put_u32(payload.len() as u32);
+    frame.put_slice(payload);
+    frame.freeze()
+}
+
+// Encodes the message straight into the frame, without an intermediate buffer.
+fn encode_message_frame<T: prost::Message>(msg: &T) -> Bytes {
+    let len = msg.encoded_len();
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + len);
+    frame.put_u8(FLAG_DATA);
+    frame.put_u32(len as u32);
+    msg.encode(&mut frame)
+        .expect("can only fail if buffer does not have capacity");
+    frame.freeze()
+}
+
+/// The codec that encodes the payloads of the frames of a stream of responses of one method.
+#[derive(Clone, Debug)]
+pub(crate) struct FrameCodec {
+    codec: Arc<dyn Codec>,
+    service: String,
+    method: String,
+}
+
+impl FrameCodec {
+    pub(crate) fn new(codec: Arc<dyn Codec>, ty: MessageType<'_>) -> Self {
+        Self {
+            codec,
+            service: ty.service.to_string(),
+            method: ty.method.to_string(),
+        }
+    }
+
+    fn ty(&self) -> MessageType<'_> {
+        MessageType::response(&self.service, &self.method)
+    }
+
+    fn encode(&self, payload: Bytes) -> std::result::Result<Bytes, GenericError> {
+        self.codec.encode_as(self.ty(), payload)
+    }
+
+    fn decode(&self, payload: Bytes) -> Result<Bytes> {
+        self.codec
+            .decode_as(self.ty(), payload)
+            .map_err(ClientError::CodecError)
+    }
+}
+
+// A data frame with `msg`, its payload encoded with `codec` if there is one.
+fn data_frame<T: prost::Message>(
+    codec: Option<&FrameCodec>,
+    msg: &T,
+) -> std::result::Result<Bytes, GenericError> {
+    match codec {
+        None => Ok(encode_message_frame(msg)),
+        Some(codec) => {
+            let payload = codec.encode(msg.encode_to_vec().into())?;
+            Ok(encode_frame(FLAG_DATA, &payload))
+        }
+    }
+}
+
+// The trailer frame with `payload`, encoded with `codec` if there is one. If the codec fails, the
+// stream ends without a trailer, so the client sees that it broke off.
+fn trailer_frame(codec: Option<&FrameCodec>, payload: Bytes) -> Option<Bytes> {
+    let payload = match codec {
+        Some(codec) => codec.encode(payload).ok()?,
+        None => payload,
+    };
+    Some(encode_frame(FLAG_TRAILER, &payload))
+}
+
+fn encode_event(event: Option<&str>, data: &[u8]) -> Bytes {
+    let mut buf = event_prefix(event);
+    buf.put_slice(data);
+    buf.put_slice(b"\n\n");
+    buf.freeze()
+}
+
+// Serializes the message straight into the event, without an intermediate `String`.
+fn encode_json_event<T: Serialize>(msg: &T) -> serde_json::Result<Bytes> {
+    let mut writer = event_prefix(None).writer();
+    serde_json::to_writer(&mut writer, msg)?;
+    let mut buf = writer.into_inner();
+    buf.put_slice(b"\n\n");
+    Ok(buf.freeze())
+}
+
+fn event_prefix(event: Option<&str>) -> BytesMut {
+    let mut buf = BytesMut::new();
+    if let Some(event) = event {
+        buf.put_slice(b"event: ");
+        buf.put_slice(event.as_bytes());
+        buf.put_u8(b'\n');
+    }
+    buf.put_slice(b"data: ");
+    buf
+}
+
+fn error_json(err: TwirpErrorResponse) -> Bytes {
+    crate::serialize_json(&err).unwrap_or_else(|_| Bytes::from_static(br#"{"code":"internal"}"#))
+}
+
+/// Write a stream of messages as the body of a protobuf (`json == false`) or server-sent events
+/// (`json == true`) response. The payloads of protobuf frames are encoded with `codec`, if any.
+/// The stream ends at the first error.
+pub(crate) fn write_stream<S, T, Err>(
+    messages: S,
+    json: bool,
+    codec: Option<FrameCodec>,
+) -> Response<Body>
+where
+    S: Stream<Item = std::result::Result<T, Err>> + Send + 'static,
+    T: prost::Message + Serialize + 'static,
+    Err: IntoTwirpResponse + 'static,
+{
+    let body =
+        messages
+            .map(Some)
+            .chain(stream::once(async { None }))
+            .scan(false, move |failed, item| {
+                if *failed {
+                    return futures::future::ready(None);
+                }
+                let chunk = match item {
+                    Some(Ok(msg)) if json => Some(match encode_json_event(&msg) {
+                        Ok(event) => event,
+                        Err(err) => {
+                            *failed = true;
+                            let err = crate::internal(format!("error serializing message: {err}"));
+                            encode_event(Some("error"), &error_json(err))
+                        }
+                    }),
+                    Some(Ok(msg)) => match data_frame(codec.as_ref(), &msg) {
+                        Ok(frame) => Some(frame),
+                        Err(err) => {
+                            *failed = true;
+                            let err = crate::internal(format!("error encoding message: {err}"));
+                            trailer_frame(codec.as_ref(), error_json(err))
+                        }
+                    },
+                    Some(Err(err)) => {
+                        *failed = true;
+                        let err = error_json(err.into_twirp_response().into_body());
+                        if json {
+                            Some(encode_event(Some("error"), &err))
+                        } else {
+                            trailer_frame(codec.as_ref(), err)
+                        }
+                    }
+                    None if json => Some(encode_event(Some("end"), b"{}")),
+                    None => trailer_frame(codec.as_ref(), Bytes::new()),
+                };
+                futures::future::ready(chunk.map(Ok::<_, Infallible>))
+            });
+
+    let content_type = if json {
+        CONTENT_TYPE_EVENT_STREAM
+    } else {
+        CONTENT_TYPE_STREAM_PROTOBUF
+    };
+    let mut resp = Response::new(Body::from_stream(body));
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(content_type),
+    );
+    resp
+}
+
+/// Incrementally splits a response body into frames.
+#[derive(Debug, Default)]
+struct FrameDecoder {
+    buf: BytesMut,
+}
+
+impl FrameDecoder {
+    fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    fn next_frame(&mut self) -> Option<(u8, Bytes)> {
+        let header: [u8; HEADER_LEN] = self.buf.get(..HEADER_LEN)?.try_into().ok()?;
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        if self.buf.len() < HEADER_LEN + len {
+            return None;
+        }
+        self.buf.advance(HEADER_LEN);
+        Some((header[0], self.buf.split_to(len).freeze()))
+    }
+}
+
+/// Decode the frames of an `application/twirp-stream+protobuf` response, whose payloads are
+/// encoded with `codec`, into messages.
+pub(crate) fn read_stream<T>(resp: reqwest::Response, codec: FrameCodec) -> MessageStream<T>
+where
+    T: prost::Message + Default + 'static,
+{
+    let state = (Some(resp), FrameDecoder::default(), codec);
+    stream::unfold(state, |(mut resp, mut decoder, codec)| async move {
+        loop {
+            let body = resp.as_mut()?;
+            if let Some((flags, payload)) = decoder.next_frame() {
+                let item = match flags {
+                    FLAG_DATA => codec
+                        .decode(payload)
+                        .and_then(|payload| T::decode(payload).map_err(ClientError::from)),
+                    FLAG_TRAILER => match codec.decode(payload) {
+                        Ok(payload) if payload.is_empty() => return None,
+                        Ok(payload) => match serde_json::from_slice(&payload) {
+                            Ok(err) => Err(ClientError::TwirpError(err)),
+                            Err(err) => Err(err.into()),
+                        },
+                        Err(err) => Err(err),
+                    },
+                    flags => Err(ClientError::MalformedResponse(format!(
+                        "unknown stream frame flags: {flags}"
+                    ))),
+                };
+                if item.is_err() {
+                    resp = None;
+                }
+                return Some((item, (resp, decoder, codec)));
+            }
+            match body.chunk().await {
+                Ok(Some(chunk)) => decoder.push(&chunk),
+                Ok(None) => {
+                    let err = ClientError::MalformedResponse(
+                        "stream ended without a trailer".to_string(),
+                    );
+                    return Some((Err(err), (None, decoder, codec)));
+                }
+                Err(err) => return Some((Err(err.into()), (None, decoder, codec))),
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tower::Service;
+
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::{error, Client, Context};
+
+    fn count_router() -> axum::Router {
+        let api = Arc::new(TestApiServer);
+        let router = TwirpRouterBuilder::new("/test.TestAPI", api)
+            .route_server_streaming(
+                "/Count",
+                |_api: Arc<TestApiServer>, _ctx: Context, req: PingRequest| async move {
+                    let names = (1..=3).map(move |i| match i {
+                        3 if req.name == "fail" => Err(error::internal("count failed")),
+                        i => Ok(PingResponse {
+                            name: format!("{}-{i}", req.name),
+                        }),
+                    });
+                    Ok::<_, TwirpErrorResponse>(stream::iter(names))
+                },
+            )
+            .build();
+        axum::Router::new().nest("/twirp/test.TestAPI", router)
+    }
+
+    #[test]
+    fn test_frame_decoder() {
+        let mut decoder = FrameDecoder::default();
+        let frames = [
+            encode_frame(FLAG_DATA, b"hello"),
+            encode_frame(FLAG_TRAILER, b""),
+        ]
+        .concat();
+        decoder.push(&frames[..3]);
+        assert_eq!(decoder.next_frame(), None);
+        decoder.push(&frames[3..]);
+        assert_eq!(
+            decoder.next_frame(),
+            Some((FLAG_DATA, Bytes::from_static(b"hello")))
+        );
+        assert_eq!(decoder.next_frame(), Some((FLAG_TRAILER, Bytes::new())));
+        assert_eq!(decoder.next_frame(), None);
+    }
+
+    #[tokio::test]
+    async fn test_server_sent_events() {
+        let req = hyper::Request::post("/twirp/test.TestAPI/Count")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"fail"}"#))
+            .unwrap();
+        let resp = count_router().call(req).await.unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/event-stream");
+        assert_eq!(
+            read_string_body(resp.into_body()).await,
+            "data: {\"name\":\"fail-1\"}\n\n\
+             data: {\"name\":\"fail-2\"}\n\n\
+             event: error\ndata: {\"code\":\"internal\",\"msg\":\"count failed\"}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_stream() {
+        let server = spawn_server(count_router()).await;
+        let client = Client::from_base_url(server.url("/twirp/")).unwrap();
+
+        let names: Vec<_> = client
+            .request_stream::<_, PingResponse>(
+                "test.TestAPI/Count",
+                PingRequest {
+                    name: "ok".to_string(),
+                },
+            )
+            .await
+            .unwrap()
+            .map(|res| res.unwrap().name)
+            .collect()
+            .await;
+        assert_eq!(names, ["ok-1", "ok-2", "ok-3"]);
+
+        let results: Vec<_> = client
+            .request_stream::<_, PingResponse>(
+                "test.TestAPI/Count",
+                PingRequest {
+                    name: "fail".to_string(),
+                },
+            )
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(results.len(), 3);
+        assert!(results[1].is_ok());
+        match &results[2] {
+            Err(ClientError::TwirpError(err)) => assert_eq!(err.msg, "count failed"),
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        let res = crate::ClientBuilder::new(server.url("/twirp/"), reqwest::Client::new())
+            .codec(crate::codec::JsonCodec)
+            .build()
+            .unwrap()
+            .request_stream::<_, PingResponse>("test.TestAPI/Count", PingRequest::default())
+            .await;
+        assert!(matches!(res, Err(ClientError::CodecError(_))));
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_stream_unary_response() {
+        let server = spawn_server(test_api_router()).await;
+        let client = Client::from_base_url(server.url("/twirp/")).unwrap();
+
+        let names: Vec<_> = client
+            .request_stream::<_, PingResponse>(
+                "test.TestAPI/Ping",
+                PingRequest {
+                    name: "unary".to_string(),
+                },
+            )
+            .await
+            .unwrap()
+            .map(|res| res.unwrap().name)
+            .collect()
+            .await;
+        assert_eq!(names, ["unary"]);
+
+        let res = client
+            .request_stream::<_, PingResponse>(
+                "test.TestAPI/Boom",
+                PingRequest {
+                    name: "unary".to_string(),
+                },
+            )
+            .await;
+        match res {
+            Err(ClientError::TwirpError(err)) => assert_eq!(err.msg, "boom!"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+        server.shutdown().await.unwrap();
+    }
+}
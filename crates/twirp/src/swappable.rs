@@ -0,0 +1,117 @@
+//! A service implementation that can be replaced while the server is running.
+
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+/// Wraps an implementation of a Twirp service so that it can be swapped out at runtime, e.g. when
+/// configuration or a dependency changes, without rebuilding the router.
+///
+/// `twirp-build` implements each generated service trait for `SwappableService<T>` by delegating
+/// to the current implementation. Requests that are already running keep using the
+/// implementation they started with.
+///
+/// # Usage
+///
+/// ```
+/// use std::sync::Arc;
+/// use twirp::SwappableService;
+///
+/// struct HaberdasherApiServer {
+///     default_color: String,
+/// }
+///
+/// let service = Arc::new(SwappableService::new(HaberdasherApiServer {
+///     default_color: "red".to_string(),
+/// }));
+/// // Pass `service.clone()` to the generated `router()`, then later, e.g. after reloading the
+/// // configuration:
+/// service.swap(HaberdasherApiServer {
+///     default_color: "blue".to_string(),
+/// });
+/// assert_eq!(service.load().default_color, "blue");
+/// ```
+#[derive(Debug)]
+pub struct SwappableService<T> {
+    current: ArcSwap<T>,
+}
+
+impl<T> SwappableService<T> {
+    pub fn new(service: T) -> Self {
+        Self::from_arc(Arc::new(service))
+    }
+
+    pub fn from_arc(service: Arc<T>) -> Self {
+        Self {
+            current: ArcSwap::new(service),
+        }
+    }
+
+    /// The current implementation.
+    pub fn load(&self) -> Arc<T> {
+        self.current.load_full()
+    }
+
+    /// Replace the implementation, returning the previous one.
+    pub fn swap(&self, service: T) -> Arc<T> {
+        self.current.swap(Arc::new(service))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use tower::Service;
+
+    use super::*;
+    use crate::details::TwirpRouterBuilder;
+    use crate::test::*;
+    use crate::{Context, TwirpErrorResponse};
+
+    struct PrefixServer(&'static str);
+
+    #[async_trait]
+    impl TestApi for PrefixServer {
+        async fn ping(
+            &self,
+            _ctx: Context,
+            req: PingRequest,
+        ) -> Result<PingResponse, TwirpErrorResponse> {
+            Ok(PingResponse {
+                name: format!("{}-{}", self.0, req.name),
+            })
+        }
+
+        async fn boom(
+            &self,
+            _ctx: Context,
+            _req: PingRequest,
+        ) -> Result<PingResponse, TwirpErrorResponse> {
+            Err(crate::internal("boom!"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_swap() {
+        let service = Arc::new(SwappableService::new(PrefixServer("a")));
+        let routes = TwirpRouterBuilder::new("/test.TestAPI", service.clone())
+            .route(
+                "/Ping",
+                |api: Arc<SwappableService<PrefixServer>>, ctx: Context, req: PingRequest| async move {
+                    api.ping(ctx, req).await
+                },
+            )
+            .build();
+        let mut router = axum::Router::new().nest("/twirp/test.TestAPI", routes);
+
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "a-hi");
+
+        let previous = service.swap(PrefixServer("b"));
+        assert_eq!(previous.0, "a");
+        let resp = router.call(gen_ping_request("hi")).await.unwrap();
+        let data: PingResponse = read_json_body(resp.into_body()).await;
+        assert_eq!(data.name, "b-hi");
+    }
+}
@@ -1,37 +1,535 @@
-//! Test helpers and mini twirp api server implementation.
-use std::sync::Arc;
-use std::time::Duration;
+//! Helpers for testing twirp services and the code that calls them, and a small test service.
+//!
+//! - [`call`], [`encode_request`] and [`decode_response`] call a router without a client.
+//! - [`TestContext`] fakes the [`Context`] of a handler that is called directly.
+//! - [`TestServer`] serves a router in memory to real clients, and [`spawn_server`] over TCP.
+//! - [`Recording`], [`mock`] and [`MockService`] stand in for the services the code under test
+//!   calls.
+//!
+//! Requires the `test-support` feature, usually enabled in `[dev-dependencies]`.
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 use async_trait::async_trait;
 use axum::body::Body;
 use axum::Router;
+use bytes::Bytes;
+use http::header::{self, IntoHeaderName};
+use http::{Extensions, HeaderMap, HeaderValue, Response};
 use http_body_util::BodyExt;
 use hyper::Request;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
 use tokio::time::Instant;
+use tower::Service;
+use url::Url;
 
+use crate::client::Transport;
+use crate::codec::Format;
+use crate::context::ResponseOverrides;
 use crate::details::TwirpRouterBuilder;
+use crate::direct::{DirectHandler, DirectService};
 use crate::server::Timings;
-use crate::{error, Client, Context, Result, TwirpErrorResponse};
+use crate::{
+    error, Client, ClientBuilder, Context, Middleware, Next, Result, SwappableService,
+    TwirpErrorCode, TwirpErrorResponse,
+};
 
+pub mod chaos;
+pub mod conformance;
+pub mod fixtures;
+pub mod golden;
+pub mod metrics;
+pub mod mock;
+#[cfg(feature = "proptest")]
+pub mod props;
+pub mod replay;
+
+pub use fixtures::fixture;
+pub use mock::{MockBuilder, MockService};
+
+/// Serve the test service on `port` of localhost.
+///
+/// Prefer [`spawn_server`], which picks a free port, so that tests can run in parallel.
 pub async fn run_test_server(port: u16) -> JoinHandle<Result<(), std::io::Error>> {
-    let router = test_api_router();
-    let addr: std::net::SocketAddr = ([127, 0, 0, 1], port).into();
-    let tcp_listener = tokio::net::TcpListener::bind(addr)
+    let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+    let SpawnedServer { handle, .. } = spawn_server_on(addr, test_api_router()).await;
+    handle
+}
+
+/// Serve `router` over TCP on a free port of localhost, for tests of code that needs a real
+/// server. The server accepts connections as soon as this returns.
+///
+/// ```
+/// use twirp::test::{spawn_server, test_api_router};
+///
+/// # async fn example() {
+/// let server = spawn_server(test_api_router()).await;
+/// let client = twirp::Client::from_base_url(server.url("/twirp/")).unwrap();
+/// // ...
+/// server.shutdown().await.unwrap();
+/// # }
+/// ```
+pub async fn spawn_server(router: Router) -> SpawnedServer {
+    spawn_server_on(([127, 0, 0, 1], 0).into(), router).await
+}
+
+async fn spawn_server_on(addr: SocketAddr, router: Router) -> SpawnedServer {
+    // The listener is bound before the server is spawned, so connections are queued until the
+    // server accepts them rather than refused.
+    let listener = tokio::net::TcpListener::bind(addr)
         .await
         .expect("failed to bind to local port");
-    println!("Listening on {addr}");
-    let h = tokio::spawn(async move { axum::serve(tcp_listener, router).await });
-    tokio::time::sleep(Duration::from_millis(100)).await;
-    h
+    let addr = listener.local_addr().expect("listener has an address");
+    let shutdown = Arc::new(Notify::new());
+    let signal = shutdown.clone();
+    let handle = tokio::spawn(async move {
+        let app = router.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { signal.notified().await })
+            .await
+    });
+    SpawnedServer {
+        addr,
+        shutdown,
+        handle,
+    }
+}
+
+/// A server started with [`spawn_server`]. It runs until [`shutdown`](Self::shutdown) or the end
+/// of the runtime.
+#[derive(Debug)]
+pub struct SpawnedServer {
+    addr: SocketAddr,
+    shutdown: Arc<Notify>,
+    handle: JoinHandle<Result<(), std::io::Error>>,
+}
+
+impl SpawnedServer {
+    /// The address the server is listening on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The URL of `path` on the server, e.g. `url("/twirp/")` for the base URL of a client.
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("http://{}{path}", self.addr)).expect("valid url")
+    }
+
+    /// Stop accepting connections and wait for the open ones to finish.
+    pub async fn shutdown(self) -> Result<(), std::io::Error> {
+        self.shutdown.notify_one();
+        self.handle.await.expect("server panicked")
+    }
+}
+
+/// Serves a router in memory, for integration tests: requests made with its [`client`] go straight
+/// to the router, without TCP or ports, but through the same client code, middleware and codecs as
+/// over the network.
+///
+/// ```
+/// use twirp::test::TestServer;
+///
+/// # async fn example(twirp_routes: twirp::Router) {
+/// // `twirp_routes` is e.g. `haberdash::router(api)`.
+/// let app = twirp::Router::new().nest("/twirp", twirp_routes);
+/// let client = TestServer::new(app).client();
+/// // client.make_hat(MakeHatRequest { inches: 1 }).await
+/// # }
+/// ```
+///
+/// [`client`]: Self::client
+#[derive(Clone, Debug)]
+pub struct TestServer {
+    router: Router,
+}
+
+impl TestServer {
+    pub fn new(router: Router) -> Self {
+        Self { router }
+    }
+
+    /// A client for the routes under `/twirp/` on the router.
+    pub fn client(&self) -> Client {
+        ClientBuilder::new(Self::base_url(), reqwest::Client::new())
+            .with(self.transport())
+            .build()
+            .expect("the base URL is valid")
+    }
+
+    /// The base URL of the server, `http://twirp.test/twirp/`. It is never resolved.
+    pub fn base_url() -> Url {
+        Url::parse("http://twirp.test/twirp/").expect("valid URL")
+    }
+
+    /// The middleware that sends requests to the router, for clients configured by hand. It must
+    /// be the last middleware of the client, since it doesn't call the next one, or its
+    /// [transport](crate::ClientBuilder::transport).
+    pub fn transport(&self) -> TestTransport {
+        TestTransport {
+            router: self.router.clone(),
+        }
+    }
+}
+
+/// The in-memory transport of a [`TestServer`].
+#[derive(Clone, Debug)]
+pub struct TestTransport {
+    router: Router,
+}
+
+#[async_trait]
+impl Middleware for TestTransport {
+    async fn handle(&self, req: reqwest::Request, _next: Next<'_>) -> Result<reqwest::Response> {
+        let req = http::Request::<reqwest::Body>::try_from(req)?;
+        let req = req.map(|body| Bytes::copy_from_slice(body.as_bytes().unwrap_or_default()));
+        Ok(self.execute(req).await?.into())
+    }
+}
+
+#[async_trait]
+impl Transport for TestTransport {
+    async fn execute(&self, req: http::Request<Bytes>) -> Result<http::Response<Bytes>> {
+        let resp = match self.router.clone().call(req.map(Body::from)).await {
+            Ok(resp) => resp,
+            Err(never) => match never {},
+        };
+        let (parts, body) = resp.into_parts();
+        let body = body.collect().await.expect("invalid body").to_bytes();
+        Ok(http::Response::from_parts(parts, body))
+    }
+}
+
+/// Call the method at `path` (e.g. `/twirp/example.v1.Haberdasher/MakeHat`) of `router` with
+/// `req`, for tests of handlers that don't need a client. The request is sent as protobuf, and
+/// the response decoded, or the error if the call failed.
+///
+/// ```
+/// use twirp::test::{call, test_api_router, PingRequest, PingResponse};
+///
+/// # async fn example() {
+/// let req = PingRequest {
+///     name: "hi".to_string(),
+/// };
+/// let resp: PingResponse = call(&test_api_router(), "/twirp/test.TestAPI/Ping", req)
+///     .await
+///     .unwrap();
+/// assert_eq!(resp.name, "hi");
+/// # }
+/// ```
+pub async fn call<Req, Resp>(
+    router: &Router,
+    path: &str,
+    req: Req,
+) -> Result<Resp, TwirpErrorResponse>
+where
+    Req: prost::Message + Serialize,
+    Resp: prost::Message + Default + DeserializeOwned,
+{
+    let req = encode_request(path, &req, Format::Protobuf);
+    let resp = match router.clone().call(req).await {
+        Ok(resp) => resp,
+        Err(never) => match never {},
+    };
+    decode_response(resp).await
+}
+
+/// A request for the method at `path` of a router, with `req` encoded in `format`.
+pub fn encode_request<T>(path: &str, req: &T, format: Format) -> Request<Body>
+where
+    T: prost::Message + Serialize,
+{
+    let (content_type, body) = match format {
+        Format::Protobuf => ("application/protobuf", req.encode_to_vec()),
+        Format::Json => (
+            "application/json",
+            serde_json::to_vec(req).expect("message can be serialized"),
+        ),
+    };
+    Request::post(path)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .expect("invalid path")
+}
+
+/// The message of a response written by a router, decoded as its `Content-Type` says, or its
+/// error.
+pub async fn decode_response<T>(resp: Response<Body>) -> Result<T, TwirpErrorResponse>
+where
+    T: prost::Message + Default + DeserializeOwned,
+{
+    let status = resp.status();
+    let is_json = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|ct| ct.as_bytes() == crate::headers::CONTENT_TYPE_JSON);
+    let body = resp
+        .into_body()
+        .collect()
+        .await
+        .expect("invalid body")
+        .to_bytes();
+    if !status.is_success() {
+        return Err(serde_json::from_slice(&body).unwrap_or_else(|_| {
+            error::internal(format!("non-twirp response: {status}"))
+                .with_meta("body", String::from_utf8_lossy(&body))
+        }));
+    }
+    let invalid =
+        |err: &dyn std::fmt::Display| error::internal("invalid response").with_meta("error", err);
+    match is_json {
+        true => serde_json::from_slice(&body).map_err(|err| invalid(&err)),
+        false => T::decode(body).map_err(|err| invalid(&err)),
+    }
+}
+
+/// The [`Context`] of a handler called directly by a test, with faked request extensions and
+/// headers. The test can then check what the handler set on the response.
+///
+/// ```
+/// use twirp::test::{TestApi, TestApiServer, TestContext, PingRequest, RequestId};
+///
+/// # async fn example() {
+/// let test_ctx = TestContext::new().extension(RequestId("r-1".to_string()));
+/// let resp = TestApiServer
+///     .ping(test_ctx.context(), PingRequest { name: "hi".to_string() })
+///     .await
+///     .unwrap();
+/// assert_eq!(resp.name, "hi-r-1");
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TestContext {
+    extensions: Extensions,
+    headers: HeaderMap,
+    resp_extensions: Arc<Mutex<Extensions>>,
+}
+
+impl TestContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a request extension, as a middleware would.
+    pub fn extension<T>(mut self, val: T) -> Self
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.extensions.insert(val);
+        self
+    }
+
+    /// Add a request header.
+    pub fn header<K: IntoHeaderName>(mut self, name: K, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// A context for the handler. Contexts from the same `TestContext` share the response.
+    pub fn context(&self) -> Context {
+        Context::new(self.extensions.clone(), self.resp_extensions.clone())
+            .with_headers(self.headers.clone())
+    }
+
+    /// A response extension the handler set with [`Context::insert`].
+    pub fn response_extension<T>(&self) -> Option<T>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.resp_extensions
+            .lock()
+            .expect("mutex poisoned")
+            .get::<T>()
+            .cloned()
+    }
+
+    /// The response headers the handler set, e.g. with [`Context::set_response_header`].
+    pub fn response_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let resp_extensions = self.resp_extensions.lock().expect("mutex poisoned");
+        if let Some(overrides) = resp_extensions.get::<ResponseOverrides>() {
+            overrides.apply_headers(&mut headers);
+        }
+        headers
+    }
+
+    /// `err`, an error the handler returned, as the router would send it: with the meta values
+    /// set with [`Context::set_error_meta`].
+    pub fn response_error(&self, mut err: TwirpErrorResponse) -> TwirpErrorResponse {
+        let resp_extensions = self.resp_extensions.lock().expect("mutex poisoned");
+        if let Some(overrides) = resp_extensions.get::<ResponseOverrides>() {
+            overrides.apply_to_error(&mut err);
+        }
+        err
+    }
+}
+
+/// A [`DirectHandler`] that records the calls made to another one, so that tests can check how
+/// the code under test called a service. Clones share the recording: pass one to
+/// [`ClientBuilder::direct`] and keep one to check the calls.
+///
+/// ```
+/// use twirp::test::{at_least, test_api_direct_handler, times, PingRequest, Recording};
+/// use twirp::ClientBuilder;
+///
+/// let recording = Recording::new(test_api_direct_handler());
+/// let base_url = url::Url::parse("http://test.local/twirp/").unwrap();
+/// let client = ClientBuilder::new(base_url, reqwest::Client::new())
+///     .direct("test.local", recording.clone())
+///     .build()
+///     .unwrap();
+/// // ... code under test makes calls with `client` ...
+/// recording.assert_called("Ping", times(0));
+/// let pings: Vec<PingRequest> = recording.requests("Ping");
+/// # assert!(pings.is_empty());
+/// ```
+#[derive(Clone)]
+pub struct Recording<H> {
+    inner: Arc<H>,
+    calls: Arc<Mutex<Vec<RecordedCall>>>,
+}
+
+/// A call recorded by a [`Recording`].
+#[derive(Clone, Debug)]
+pub struct RecordedCall {
+    /// The method, e.g. `MakeHat`.
+    pub method: String,
+    /// The protobuf-encoded request.
+    pub request: Bytes,
+    /// When the call was made.
+    pub at: SystemTime,
+    /// The code of the error the call failed with, if it did.
+    pub error: Option<TwirpErrorCode>,
+}
+
+/// How many times a method should have been called, for [`Recording::assert_called`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Times {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+/// Exactly `n` times.
+pub fn times(n: usize) -> Times {
+    Times::Exactly(n)
+}
+
+/// `n` times or more.
+pub fn at_least(n: usize) -> Times {
+    Times::AtLeast(n)
+}
+
+impl<H: DirectHandler> Recording<H> {
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            calls: Default::default(),
+        }
+    }
+
+    /// The calls made so far, in order.
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.calls.lock().expect("mutex poisoned").clone()
+    }
+
+    /// The requests of the calls to `method` so far, decoded.
+    ///
+    /// # Panics
+    ///
+    /// If a request isn't a `T`.
+    pub fn requests<T: prost::Message + Default>(&self, method: &str) -> Vec<T> {
+        self.calls()
+            .into_iter()
+            .filter(|call| call.method == method)
+            .map(|call| T::decode(call.request).expect("request of another type"))
+            .collect()
+    }
+
+    /// Assert that `method` was called the given number of times.
+    #[track_caller]
+    pub fn assert_called(&self, method: &str, times: Times) {
+        let count = self
+            .calls()
+            .iter()
+            .filter(|call| call.method == method)
+            .count();
+        match times {
+            Times::Exactly(n) => assert_eq!(count, n, "`{method}` called {count} times, not {n}"),
+            Times::AtLeast(n) => assert!(count >= n, "`{method}` called {count} times, not {n}+"),
+        }
+    }
+
+    /// Forget the calls made so far.
+    pub fn clear(&self) {
+        self.calls.lock().expect("mutex poisoned").clear();
+    }
+}
+
+impl<H> std::fmt::Debug for Recording<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let calls = self.calls.lock().expect("mutex poisoned");
+        let methods: Vec<_> = calls.iter().map(|call| &call.method).collect();
+        f.debug_struct("Recording")
+            .field("calls", &methods)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl<H: DirectHandler> DirectHandler for Recording<H> {
+    fn service(&self) -> &str {
+        self.inner.service()
+    }
+
+    async fn handle(
+        &self,
+        method: &str,
+        ctx: Context,
+        req: Bytes,
+    ) -> Result<Bytes, TwirpErrorResponse> {
+        let at = SystemTime::now();
+        let res = self.inner.handle(method, ctx, req.clone()).await;
+        self.calls
+            .lock()
+            .expect("mutex poisoned")
+            .push(RecordedCall {
+                method: method.to_string(),
+                request: req,
+                at,
+                error: res.as_ref().err().map(|err| err.code.clone()),
+            });
+        res
+    }
 }
 
 pub fn test_api_router() -> Router {
+    axum::Router::new()
+        .nest("/twirp/test.TestAPI", test_api_service_router())
+        .fallback(crate::server::not_found_handler)
+}
+
+/// The methods of the test service, i.e. what `twirp-build` would generate as `METHODS`.
+pub const TEST_API_METHODS: &[&str] = &["Ping", "Boom"];
+
+/// The router for the test service alone, i.e. what `twirp-build` would generate as `router()`.
+pub fn test_api_service_router() -> Router {
+    test_api_router_builder().build()
+}
+
+/// The test service as a direct handler, i.e. what `twirp-build` would generate as
+/// `direct_handler()`.
+pub fn test_api_direct_handler() -> DirectService {
+    test_api_router_builder().build_direct()
+}
+
+pub(crate) fn test_api_router_builder() -> TwirpRouterBuilder<Arc<TestApiServer>> {
     let api = Arc::new(TestApiServer {});
 
     // NB: This part would be generated
-    let test_router = TwirpRouterBuilder::new(api)
+    TwirpRouterBuilder::new("/test.TestAPI", api)
         .route(
             "/Ping",
             |api: Arc<TestApiServer>, ctx: Context, req: PingRequest| async move {
@@ -44,29 +542,25 @@ pub fn test_api_router() -> Router {
                 api.boom(ctx, req).await
             },
         )
-        .build();
-
-    axum::Router::new()
-        .nest("/twirp/test.TestAPI", test_router)
-        .fallback(crate::server::not_found_handler)
 }
 
+/// A JSON request for the `Ping` method of the test service.
 pub fn gen_ping_request(name: &str) -> Request<Body> {
-    let req = serde_json::to_string(&PingRequest {
+    let ping = PingRequest {
         name: name.to_string(),
-    })
-    .expect("will always be valid json");
-    Request::post("/twirp/test.TestAPI/Ping")
-        .extension(Timings::new(Instant::now()))
-        .body(Body::from(req))
-        .expect("always a valid twirp request")
+    };
+    let mut req = encode_request("/twirp/test.TestAPI/Ping", &ping, Format::Json);
+    req.extensions_mut().insert(Timings::new(Instant::now()));
+    req
 }
 
+/// Read `body` as UTF-8.
 pub async fn read_string_body(body: Body) -> String {
     let data = Vec::<u8>::from(body.collect().await.expect("invalid body").to_bytes());
     String::from_utf8(data).expect("non-utf8 body")
 }
 
+/// Read `body` as JSON.
 pub async fn read_json_body<T>(body: Body) -> T
 where
     T: DeserializeOwned,
@@ -75,6 +569,7 @@ where
     serde_json::from_slice(&data).expect("twirp response isn't valid JSON")
 }
 
+/// Read `body` as a Twirp error.
 pub async fn read_err_body(body: Body) -> TwirpErrorResponse {
     read_json_body(body).await
 }
@@ -108,6 +603,29 @@ impl TestApi for TestApiServer {
     }
 }
 
+// NB: This would be generated
+#[async_trait]
+impl<T> TestApi for SwappableService<T>
+where
+    T: TestApi + Sync + Send,
+{
+    async fn ping(
+        &self,
+        ctx: Context,
+        req: PingRequest,
+    ) -> Result<PingResponse, TwirpErrorResponse> {
+        self.load().ping(ctx, req).await
+    }
+
+    async fn boom(
+        &self,
+        ctx: Context,
+        req: PingRequest,
+    ) -> Result<PingResponse, TwirpErrorResponse> {
+        self.load().boom(ctx, req).await
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Default)]
 pub struct RequestId(pub String);
 
@@ -121,11 +639,11 @@ pub trait TestApiClient {
 #[async_trait]
 impl TestApiClient for Client {
     async fn ping(&self, req: PingRequest) -> Result<PingResponse> {
-        self.request("test.TestAPI/Ping", req).await
+        self.request_with_codec("test.TestAPI/Ping", req).await
     }
 
-    async fn boom(&self, _req: PingRequest) -> Result<PingResponse> {
-        todo!()
+    async fn boom(&self, req: PingRequest) -> Result<PingResponse> {
+        self.request_with_codec("test.TestAPI/Boom", req).await
     }
 }
 
@@ -160,3 +678,251 @@ pub struct PingResponse {
     #[prost(string, tag = "2")]
     pub name: ::prost::alloc::string::String,
 }
+
+// The descriptors of `test.proto`, shared by the tests of the modules that work from descriptors:
+//
+//   syntax = "proto2";
+//   package test;
+//   enum Color { RED = 0; BLUE = 1; }
+//   message Hat {
+//     required string name = 1; optional Color color = 2; repeated Hat parts = 3;
+//     optional int64 size_inches = 4;
+//   }
+//   message PingRequest { optional string name = 2; }  // and PingResponse
+//   service Haberdasher { rpc MakeHat(Hat) returns (Hat); rpc Broken(Hat) returns (Hat); }
+//   service TestAPI { rpc Ping(PingRequest) returns (PingResponse); rpc Wear(Hat) returns (Hat); }
+#[cfg(all(test, feature = "reflect"))]
+pub(crate) fn descriptor_pool() -> prost_reflect::DescriptorPool {
+    use prost_reflect::prost_types::field_descriptor_proto::{Label, Type};
+    use prost_reflect::prost_types::{
+        DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+        FileDescriptorProto, FileDescriptorSet, MethodDescriptorProto, ServiceDescriptorProto,
+    };
+
+    let field = |name: &str, number: i32, label: Label, ty: Type, type_name: Option<&str>| {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(label as i32),
+            r#type: Some(ty as i32),
+            type_name: type_name.map(str::to_string),
+            ..Default::default()
+        }
+    };
+    let message = |name: &str, field: Vec<FieldDescriptorProto>| DescriptorProto {
+        name: Some(name.to_string()),
+        field,
+        ..Default::default()
+    };
+    let method = |name: &str, input: &str, output: &str| MethodDescriptorProto {
+        name: Some(name.to_string()),
+        input_type: Some(format!(".test.{input}")),
+        output_type: Some(format!(".test.{output}")),
+        ..Default::default()
+    };
+    let service = |name: &str, method: Vec<MethodDescriptorProto>| ServiceDescriptorProto {
+        name: Some(name.to_string()),
+        method,
+        ..Default::default()
+    };
+    let name = field("name", 2, Label::Optional, Type::String, None);
+    let file = FileDescriptorProto {
+        name: Some("test.proto".to_string()),
+        package: Some("test".to_string()),
+        syntax: Some("proto2".to_string()),
+        message_type: vec![
+            message(
+                "Hat",
+                vec![
+                    field("name", 1, Label::Required, Type::String, None),
+                    field("color", 2, Label::Optional, Type::Enum, Some(".test.Color")),
+                    field(
+                        "parts",
+                        3,
+                        Label::Repeated,
+                        Type::Message,
+                        Some(".test.Hat"),
+                    ),
+                    field("size_inches", 4, Label::Optional, Type::Int64, None),
+                ],
+            ),
+            message("PingRequest", vec![name.clone()]),
+            message("PingResponse", vec![name]),
+        ],
+        enum_type: vec![EnumDescriptorProto {
+            name: Some("Color".to_string()),
+            value: ["RED", "BLUE"]
+                .iter()
+                .zip(0..)
+                .map(|(name, number)| EnumValueDescriptorProto {
+                    name: Some(name.to_string()),
+                    number: Some(number),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }],
+        service: vec![
+            service(
+                "Haberdasher",
+                vec![
+                    method("MakeHat", "Hat", "Hat"),
+                    method("Broken", "Hat", "Hat"),
+                ],
+            ),
+            service(
+                "TestAPI",
+                vec![
+                    method("Ping", "PingRequest", "PingResponse"),
+                    method("Wear", "Hat", "Hat"),
+                ],
+            ),
+        ],
+        ..Default::default()
+    };
+    let set = FileDescriptorSet { file: vec![file] };
+    prost_reflect::DescriptorPool::from_file_descriptor_set(set).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_call() {
+        let router = test_api_router();
+        let req = PingRequest {
+            name: "oneshot".to_string(),
+        };
+        let resp: PingResponse = call(&router, "/twirp/test.TestAPI/Ping", req.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "oneshot");
+
+        let err = call::<_, PingResponse>(&router, "/twirp/test.TestAPI/Boom", req.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::Internal);
+
+        let err = call::<_, PingResponse>(&router, "/twirp/test.TestAPI/Pong", req)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+    }
+
+    #[tokio::test]
+    async fn test_encode_decode() {
+        let router = test_api_router();
+        let req = PingRequest {
+            name: "json".to_string(),
+        };
+        let req = encode_request("/twirp/test.TestAPI/Ping", &req, Format::Json);
+        let resp = router.clone().call(req).await.unwrap();
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE].as_bytes(),
+            crate::headers::CONTENT_TYPE_JSON
+        );
+        let resp: PingResponse = decode_response(resp).await.unwrap();
+        assert_eq!(resp.name, "json");
+    }
+
+    #[tokio::test]
+    async fn test_test_context() {
+        let test_ctx = TestContext::new()
+            .extension(RequestId("r-1".to_string()))
+            .header("x-test", HeaderValue::from_static("yes"));
+        let resp = TestApiServer
+            .ping(test_ctx.context(), PingRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "-r-1");
+
+        let ctx = test_ctx.context();
+        assert_eq!(ctx.headers()["x-test"], "yes");
+        ctx.insert(RequestId("resp".to_string()));
+        ctx.set_retry_after(std::time::Duration::from_secs(3));
+        assert_eq!(
+            test_ctx.response_extension::<RequestId>(),
+            Some(RequestId("resp".to_string()))
+        );
+        assert_eq!(test_ctx.response_headers()[header::RETRY_AFTER], "3");
+        let err = test_ctx.response_error(error::unavailable("busy"));
+        assert_eq!(err.meta.get("retry_after").map(String::as_str), Some("3"));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_server() {
+        let servers = [
+            spawn_server(test_api_router()).await,
+            spawn_server(test_api_router()).await,
+        ];
+        assert_ne!(servers[0].addr(), servers[1].addr());
+        for server in servers {
+            let client = Client::from_base_url(server.url("/twirp/")).unwrap();
+            let resp = client
+                .ping(PingRequest {
+                    name: "tcp".to_string(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(resp.name, "tcp");
+            let addr = server.addr();
+            server.shutdown().await.unwrap();
+            assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recording() {
+        let recording = Recording::new(test_api_direct_handler());
+        let base_url = Url::parse("http://test.local/twirp/").unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .direct("test.local", recording.clone())
+            .build()
+            .unwrap();
+        for name in ["a", "b"] {
+            let req = PingRequest {
+                name: name.to_string(),
+            };
+            client.ping(req).await.unwrap();
+        }
+        client.boom(PingRequest::default()).await.unwrap_err();
+
+        recording.assert_called("Ping", times(2));
+        recording.assert_called("Boom", at_least(1));
+        recording.assert_called("Pong", times(0));
+        let names: Vec<_> = recording
+            .requests::<PingRequest>("Ping")
+            .into_iter()
+            .map(|req| req.name)
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+        let calls = recording.calls();
+        assert_eq!(calls[2].error, Some(TwirpErrorCode::Internal));
+        assert!(calls[0].at <= calls[1].at);
+
+        recording.clear();
+        recording.assert_called("Ping", times(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "`Ping` called 0 times, not 1")]
+    fn test_recording_assertion() {
+        Recording::new(test_api_direct_handler()).assert_called("Ping", times(1));
+    }
+
+    #[tokio::test]
+    async fn test_test_server() {
+        let client = TestServer::new(test_api_router()).client();
+        let resp = client
+            .ping(PingRequest {
+                name: "in memory".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "in memory");
+
+        let err = client.boom(PingRequest::default()).await.unwrap_err();
+        assert_eq!(err.twirp_error().unwrap().code, TwirpErrorCode::Internal);
+    }
+}
@@ -0,0 +1,277 @@
+//! Fault injection, for testing how code copes with a misbehaving service.
+//!
+//! [`Chaos`] wraps a [`DirectHandler`] (e.g. `<service>::direct_handler(api)`) and, at the rates
+//! given by its [`ChaosConfig`], delays calls, fails them with errors, and truncates their
+//! responses. Randomness comes from a seeded generator, so failing tests can be replayed:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use twirp::test::chaos::{Chaos, ChaosConfig};
+//! use twirp::test::test_api_direct_handler;
+//! use twirp::TwirpErrorCode;
+//!
+//! let config = ChaosConfig::new()
+//!     .seed(42)
+//!     .latency(Duration::from_millis(5), Duration::from_millis(50))
+//!     .errors(0.1, [TwirpErrorCode::Unavailable, TwirpErrorCode::Internal])
+//!     .truncation(0.05);
+//! let handler = Chaos::wrap(test_api_direct_handler(), config);
+//! ```
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::direct::DirectHandler;
+use crate::{Context, TwirpErrorCode, TwirpErrorResponse};
+
+/// What a [`Chaos`] handler does to calls. Nothing, by default.
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+    seed: u64,
+    latency: Option<(Duration, Duration)>,
+    error_rate: f64,
+    error_codes: Vec<TwirpErrorCode>,
+    truncate_rate: f64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            latency: None,
+            error_rate: 0.0,
+            error_codes: vec![TwirpErrorCode::Unavailable],
+            truncate_rate: 0.0,
+        }
+    }
+}
+
+impl ChaosConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed the random choices, so that a run can be repeated.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Delay each call by a random time between `min` and `max`.
+    pub fn latency(mut self, min: Duration, max: Duration) -> Self {
+        self.latency = Some((min, max.max(min)));
+        self
+    }
+
+    /// Fail a `rate` (between 0 and 1) of the calls, without calling the service, with one of
+    /// `codes` picked at random.
+    ///
+    /// # Panics
+    ///
+    /// If `codes` is empty.
+    pub fn errors(mut self, rate: f64, codes: impl IntoIterator<Item = TwirpErrorCode>) -> Self {
+        self.error_rate = rate;
+        self.error_codes = codes.into_iter().collect();
+        assert!(!self.error_codes.is_empty(), "no error codes");
+        self
+    }
+
+    /// Cut the responses of a `rate` (between 0 and 1) of the calls short, so that they can't be
+    /// decoded: they end right after the key of one of their fields, without its value.
+    pub fn truncation(mut self, rate: f64) -> Self {
+        self.truncate_rate = rate;
+        self
+    }
+}
+
+/// A [`DirectHandler`] that injects faults into the calls to another one. See the
+/// [module documentation](self).
+pub struct Chaos<H> {
+    inner: H,
+    config: ChaosConfig,
+    rng: Mutex<SplitMix64>,
+}
+
+impl<H: DirectHandler> Chaos<H> {
+    pub fn wrap(inner: H, config: ChaosConfig) -> Self {
+        let rng = Mutex::new(SplitMix64(config.seed));
+        Self { inner, config, rng }
+    }
+
+    // A random number in `0.0..1.0`.
+    fn random(&self) -> f64 {
+        self.rng.lock().expect("mutex poisoned").next_f64()
+    }
+}
+
+impl<H> std::fmt::Debug for Chaos<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Chaos")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<H: DirectHandler> DirectHandler for Chaos<H> {
+    fn service(&self) -> &str {
+        self.inner.service()
+    }
+
+    async fn handle(
+        &self,
+        method: &str,
+        ctx: Context,
+        req: Bytes,
+    ) -> Result<Bytes, TwirpErrorResponse> {
+        if let Some((min, max)) = self.config.latency {
+            tokio::time::sleep(min + (max - min).mul_f64(self.random())).await;
+        }
+        if self.random() < self.config.error_rate {
+            let codes = &self.config.error_codes;
+            let code = codes[(self.random() * codes.len() as f64) as usize].clone();
+            return Err(TwirpErrorResponse::new(code, "injected by chaos"));
+        }
+        let resp = self.inner.handle(method, ctx, req).await?;
+        let cuts = cut_points(&resp);
+        if !cuts.is_empty() && self.random() < self.config.truncate_rate {
+            let len = cuts[(self.random() * cuts.len() as f64) as usize];
+            return Ok(resp.slice(..len));
+        }
+        Ok(resp)
+    }
+}
+
+// The lengths `msg` can be cut to so that it no longer decodes: the ends of the keys of its
+// fields, whose values are then missing.
+fn cut_points(mut msg: &[u8]) -> Vec<usize> {
+    use prost::encoding::{decode_key, skip_field, DecodeContext};
+
+    let len = msg.len();
+    let mut cuts = vec![];
+    while !msg.is_empty() {
+        let Ok((tag, wire_type)) = decode_key(&mut msg) else {
+            break;
+        };
+        cuts.push(len - msg.len());
+        if skip_field(wire_type, tag, &mut msg, DecodeContext::default()).is_err() {
+            break;
+        }
+    }
+    cuts
+}
+
+// https://prng.di.unimi.it/splitmix64.c: small, and good enough for picking faults.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::serialize_proto_message;
+    use crate::test::*;
+
+    async fn ping(handler: &impl DirectHandler) -> Result<Bytes, TwirpErrorResponse> {
+        let req = serialize_proto_message(PingRequest {
+            name: "chaos".to_string(),
+        });
+        handler.handle("Ping", Context::default(), req).await
+    }
+
+    #[tokio::test]
+    async fn test_no_chaos() {
+        let handler = Chaos::wrap(test_api_direct_handler(), ChaosConfig::new());
+        for _ in 0..10 {
+            assert!(ping(&handler).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_errors() {
+        let codes = [TwirpErrorCode::Unavailable, TwirpErrorCode::Aborted];
+        let config = ChaosConfig::new().errors(1.0, codes.clone());
+        let handler = Chaos::wrap(test_api_direct_handler(), config);
+        for _ in 0..10 {
+            let err = ping(&handler).await.unwrap_err();
+            assert!(codes.contains(&err.code), "{:?}", err.code);
+        }
+
+        // Half the calls fail, and the same seed fails the same calls.
+        let failures = |seed| {
+            let codes = codes.clone();
+            async move {
+                let config = ChaosConfig::new().seed(seed).errors(0.5, codes);
+                let handler = Chaos::wrap(test_api_direct_handler(), config);
+                let mut failures = vec![];
+                for _ in 0..100 {
+                    failures.push(ping(&handler).await.is_err());
+                }
+                failures
+            }
+        };
+        let first = failures(7).await;
+        let count = first.iter().filter(|failed| **failed).count();
+        assert!((30..70).contains(&count), "{count}");
+        assert_eq!(failures(7).await, first);
+        assert_ne!(failures(8).await, first);
+    }
+
+    #[tokio::test]
+    async fn test_truncation() {
+        let config = ChaosConfig::new().truncation(1.0);
+        let handler = Chaos::wrap(test_api_direct_handler(), config);
+        let full = ping(&test_api_direct_handler()).await.unwrap();
+        let resp = ping(&handler).await.unwrap();
+        assert!(resp.len() < full.len());
+        assert_eq!(resp, full.slice(..resp.len()));
+        assert!(PingResponse::decode(resp).is_err());
+
+        // Every cut of a message with several fields fails to decode.
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        struct Pair {
+            #[prost(uint64, tag = "1")]
+            number: u64,
+            #[prost(string, tag = "2")]
+            name: String,
+        }
+        let msg = serialize_proto_message(Pair {
+            number: 300,
+            name: "hi".to_string(),
+        });
+        let cuts = cut_points(&msg);
+        assert_eq!(cuts, [1, 4]);
+        for len in cuts {
+            assert!(Pair::decode(&msg[..len]).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latency() {
+        let config =
+            ChaosConfig::new().latency(Duration::from_millis(20), Duration::from_millis(40));
+        let handler = Chaos::wrap(test_api_direct_handler(), config);
+        let start = Instant::now();
+        ping(&handler).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,341 @@
+//! Checks of a server against the behaviors the Twirp spec requires of every method: routing
+//! errors, content types, the shape of error responses and the HTTP status of each error code.
+//!
+//! Point [`check`] at one method of a router, e.g. in a test of the service's crate:
+//!
+//! ```
+//! use twirp::test::{conformance, test_api_router};
+//!
+//! # async fn example() {
+//! conformance::check(&test_api_router(), "/twirp/test.TestAPI/Ping")
+//!     .await
+//!     .assert_ok();
+//! # }
+//! ```
+//!
+//! or run the checks against a deployed server with [`check_url`], or the `twirp-conformance`
+//! binary:
+//!
+//! ```text
+//! cargo run --features test-support --bin twirp-conformance -- \
+//!     http://localhost:3000/twirp/example.v1.Haberdasher/MakeHat
+//! ```
+//!
+//! The method is called with an empty message, as JSON and as protobuf. It may fail, but then
+//! its error has to be a valid Twirp error too.
+
+use std::fmt;
+
+use axum::body::Body;
+use axum::Router;
+use bytes::Bytes;
+use http::{header, HeaderMap, Method, Request, StatusCode};
+use http_body_util::BodyExt;
+use tower::Service;
+use url::Url;
+
+use crate::headers::{CONTENT_TYPE_JSON, CONTENT_TYPE_PROTOBUF};
+use crate::TwirpErrorCode;
+
+/// The result of one check.
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    /// What was wrong with the response, if anything.
+    pub failure: Option<String>,
+}
+
+/// The results of all the checks. See the [module documentation](self).
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Whether every check passed.
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|c| c.failure.is_none())
+    }
+
+    /// The checks that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks.iter().filter(|c| c.failure.is_some())
+    }
+
+    /// Panic with the report, unless every check passed.
+    #[track_caller]
+    pub fn assert_ok(&self) {
+        assert!(
+            self.is_ok(),
+            "server does not conform to the Twirp spec:\n{self}"
+        );
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.failure {
+                None => writeln!(f, "ok    {}", check.name)?,
+                Some(failure) => writeln!(f, "FAIL  {}: {failure}", check.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the method at `path` (e.g. `/twirp/example.v1.Haberdasher/MakeHat`) of `router`.
+pub async fn check(router: &Router, path: &str) -> Report {
+    Target::Router(router.clone()).run(path).await
+}
+
+/// Check the method at `url` of a running server.
+pub async fn check_url(client: &reqwest::Client, url: &Url) -> Report {
+    let base = format!("{}://{}", url.scheme(), url.authority());
+    Target::Url(client, base).run(url.path()).await
+}
+
+enum Target<'a> {
+    Router(Router),
+    Url(&'a reqwest::Client, String),
+}
+
+// The parts of a response that are checked.
+struct Reply {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Target<'_> {
+    async fn run(&self, path: &str) -> Report {
+        let (service, _) = path.rsplit_once('/').unwrap_or(("", path));
+        let unknown = format!("{service}/NoSuchMethodForConformance");
+        let checks = [
+            (
+                "unknown method is bad_route",
+                self.check_unknown_method(&unknown).await,
+            ),
+            ("GET is bad_route", self.check_get(path).await),
+            (
+                "malformed JSON is malformed",
+                self.check_malformed(path, CONTENT_TYPE_JSON, b"{").await,
+            ),
+            (
+                "malformed protobuf is malformed",
+                self.check_malformed(path, CONTENT_TYPE_PROTOBUF, &[0xff, 0xff, 0xff])
+                    .await,
+            ),
+            (
+                "JSON request gets JSON",
+                self.check_success(path, CONTENT_TYPE_JSON, b"{}").await,
+            ),
+            (
+                "protobuf request gets protobuf",
+                self.check_success(path, CONTENT_TYPE_PROTOBUF, b"").await,
+            ),
+        ];
+        Report {
+            checks: checks
+                .into_iter()
+                .map(|(name, result)| Check {
+                    name,
+                    failure: result.err(),
+                })
+                .collect(),
+        }
+    }
+
+    async fn check_unknown_method(&self, path: &str) -> Result<(), String> {
+        let reply = self
+            .send(Method::POST, path, CONTENT_TYPE_JSON, b"{}")
+            .await?;
+        expect_error(&reply, TwirpErrorCode::BadRoute)
+    }
+
+    async fn check_get(&self, path: &str) -> Result<(), String> {
+        let reply = self.send(Method::GET, path, CONTENT_TYPE_JSON, b"").await?;
+        expect_error(&reply, TwirpErrorCode::BadRoute)
+    }
+
+    async fn check_malformed(
+        &self,
+        path: &str,
+        content_type: &[u8],
+        body: &'static [u8],
+    ) -> Result<(), String> {
+        let reply = self.send(Method::POST, path, content_type, body).await?;
+        expect_error(&reply, TwirpErrorCode::Malformed)
+    }
+
+    async fn check_success(
+        &self,
+        path: &str,
+        content_type: &[u8],
+        body: &'static [u8],
+    ) -> Result<(), String> {
+        let reply = self.send(Method::POST, path, content_type, body).await?;
+        if !reply.status.is_success() {
+            // The method may fail for an empty message, as long as it fails properly.
+            return check_error(&reply).map(|_| ());
+        }
+        if reply.status != StatusCode::OK {
+            return Err(format!("status is {}, not 200", reply.status));
+        }
+        let actual = reply
+            .headers
+            .get(header::CONTENT_TYPE)
+            .map(|v| v.as_bytes());
+        if actual != Some(content_type) {
+            return Err(format!(
+                "Content-Type is {:?}, not {:?}",
+                actual.map(String::from_utf8_lossy),
+                String::from_utf8_lossy(content_type)
+            ));
+        }
+        if content_type == CONTENT_TYPE_JSON {
+            match serde_json::from_slice::<serde_json::Value>(&reply.body) {
+                Ok(serde_json::Value::Object(_)) => {}
+                _ => return Err("body is not a JSON object".to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        content_type: &[u8],
+        body: &'static [u8],
+    ) -> Result<Reply, String> {
+        match self {
+            Target::Router(router) => {
+                let req = Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .header(header::CONTENT_TYPE, content_type)
+                    .body(Body::from(body))
+                    .map_err(|e| format!("invalid request: {e}"))?;
+                let resp = match router.clone().call(req).await {
+                    Ok(resp) => resp,
+                    Err(never) => match never {},
+                };
+                let (parts, body) = resp.into_parts();
+                let body = body
+                    .collect()
+                    .await
+                    .map_err(|e| format!("failed to read the response: {e}"))?
+                    .to_bytes();
+                Ok(Reply {
+                    status: parts.status,
+                    headers: parts.headers,
+                    body,
+                })
+            }
+            Target::Url(client, base) => {
+                let resp = client
+                    .request(method, format!("{base}{path}"))
+                    .header(header::CONTENT_TYPE, content_type)
+                    .body(body)
+                    .send()
+                    .await
+                    .map_err(|e| format!("request failed: {e}"))?;
+                let status = resp.status();
+                let headers = resp.headers().clone();
+                let body = resp
+                    .bytes()
+                    .await
+                    .map_err(|e| format!("failed to read the response: {e}"))?;
+                Ok(Reply {
+                    status,
+                    headers,
+                    body,
+                })
+            }
+        }
+    }
+}
+
+// Check that `reply` is a valid Twirp error with `expected` as its code.
+fn expect_error(reply: &Reply, expected: TwirpErrorCode) -> Result<(), String> {
+    let code = check_error(reply)?;
+    if code != expected {
+        return Err(format!(
+            "error code is {}, not {}",
+            code.twirp_code(),
+            expected.twirp_code()
+        ));
+    }
+    Ok(())
+}
+
+// Check that `reply` is a valid Twirp error: a JSON object with a string `code` and `msg` and
+// optionally an object of strings as `meta`, sent with the status of its code.
+fn check_error(reply: &Reply) -> Result<TwirpErrorCode, String> {
+    let content_type = reply
+        .headers
+        .get(header::CONTENT_TYPE)
+        .map(|v| v.as_bytes());
+    if content_type != Some(CONTENT_TYPE_JSON) {
+        return Err(format!(
+            "error with status {} has Content-Type {:?}, not application/json",
+            reply.status,
+            content_type.map(String::from_utf8_lossy)
+        ));
+    }
+    let body: serde_json::Value =
+        serde_json::from_slice(&reply.body).map_err(|e| format!("error body is not JSON: {e}"))?;
+    let Some(code) = body.get("code").and_then(|c| c.as_str()) else {
+        return Err(format!("error has no string code: {body}"));
+    };
+    if !body.get("msg").is_some_and(|m| m.is_string()) {
+        return Err(format!("error has no string msg: {body}"));
+    }
+    if let Some(meta) = body.get("meta") {
+        let valid = meta
+            .as_object()
+            .is_some_and(|meta| meta.values().all(|v| v.is_string()));
+        if !valid {
+            return Err(format!("error meta is not an object of strings: {meta}"));
+        }
+    }
+    let code = TwirpErrorCode::from_twirp_code(code);
+    if reply.status != code.http_status_code() {
+        return Err(format!(
+            "error {} has status {}, not {}",
+            code.twirp_code(),
+            reply.status,
+            code.http_status_code()
+        ));
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::test_api_router;
+
+    #[tokio::test]
+    async fn test_test_api_conforms() {
+        let router = test_api_router();
+        check(&router, "/twirp/test.TestAPI/Ping").await.assert_ok();
+        // Methods that fail conform too, as long as their errors do.
+        check(&router, "/twirp/test.TestAPI/Boom").await.assert_ok();
+    }
+
+    #[tokio::test]
+    async fn test_plain_axum_fails() {
+        let router = Router::new().route(
+            "/twirp/test.TestAPI/Ping",
+            axum::routing::post(|| async { (StatusCode::BAD_REQUEST, "no") }),
+        );
+        let report = check(&router, "/twirp/test.TestAPI/Ping").await;
+        assert!(!report.is_ok());
+        assert_eq!(report.failures().count(), report.checks.len());
+        assert!(report
+            .to_string()
+            .contains("FAIL  unknown method is bad_route: error with status 404 Not Found"));
+    }
+}
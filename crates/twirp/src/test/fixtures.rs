@@ -0,0 +1,178 @@
+//! Request and response fixtures, loaded from files checked in next to the tests.
+//!
+//! [`fixture`] loads a message from a JSON file (`.json`) or a binary protobuf file (`.pb`,
+//! `.bin` or `.binpb`):
+//!
+//! ```no_run
+//! use twirp::test::{fixture, PingRequest};
+//!
+//! let req: PingRequest = fixture("tests/fixtures/ping.json");
+//! ```
+//!
+//! Fixtures that no longer match the message fail to load with an error that says what is wrong,
+//! rather than silently losing data: JSON fields the message doesn't have, fields of the wrong
+//! type, and protobuf fields with numbers the message doesn't have.
+//!
+//! Relative paths are relative to the directory of the crate being tested.
+
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+/// A fixture that couldn't be loaded. See the [module documentation](self).
+#[derive(Debug, Error)]
+#[error("{}: {msg}", path.display())]
+pub struct FixtureError {
+    pub path: PathBuf,
+    pub msg: String,
+}
+
+/// Load the message in the fixture at `path`.
+///
+/// # Panics
+///
+/// If the fixture can't be loaded; see [`try_fixture`].
+#[track_caller]
+pub fn fixture<T>(path: impl AsRef<Path>) -> T
+where
+    T: prost::Message + Default + Serialize + DeserializeOwned,
+{
+    match try_fixture(path) {
+        Ok(value) => value,
+        Err(err) => panic!("{err}"),
+    }
+}
+
+/// Load the message in the fixture at `path`, or say why it can't be.
+pub fn try_fixture<T>(path: impl AsRef<Path>) -> Result<T, FixtureError>
+where
+    T: prost::Message + Default + Serialize + DeserializeOwned,
+{
+    let path = super::golden::resolve(path.as_ref());
+    let error = |msg: String| FixtureError {
+        path: path.clone(),
+        msg,
+    };
+    let bytes = std::fs::read(&path).map_err(|e| error(e.to_string()))?;
+    let name = std::any::type_name::<T>();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => from_json(&bytes, name).map_err(error),
+        Some("pb" | "bin" | "binpb") => from_protobuf(&bytes, name).map_err(error),
+        _ => Err(error(
+            "unknown fixture format; use .json, or .pb, .bin or .binpb for protobuf".to_string(),
+        )),
+    }
+}
+
+fn from_json<T>(bytes: &[u8], name: &str) -> Result<T, String>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let json: Value = serde_json::from_slice(bytes).map_err(|e| format!("invalid JSON: {e}"))?;
+    let value: T = serde_json::from_slice(bytes).map_err(|e| format!("not a {name}: {e}"))?;
+    let known = serde_json::to_value(&value).map_err(|e| e.to_string())?;
+    let mut unknown = vec![];
+    unknown_fields(&json, &known, "", &mut unknown);
+    if !unknown.is_empty() {
+        return Err(format!(
+            "{name} has no field {}; was it renamed or removed?",
+            unknown.join(", ")
+        ));
+    }
+    Ok(value)
+}
+
+// The paths of the fields in `json` that aren't in `known`, the same message serialized again.
+fn unknown_fields(json: &Value, known: &Value, path: &str, unknown: &mut Vec<String>) {
+    match (json, known) {
+        (Value::Object(json), Value::Object(known)) => {
+            for (key, value) in json {
+                let field = match path {
+                    "" => key.clone(),
+                    path => format!("{path}.{key}"),
+                };
+                match known.get(key) {
+                    Some(known) => unknown_fields(value, known, &field, unknown),
+                    // Fields that aren't serialized when empty may have been written as null.
+                    None if value.is_null() => {}
+                    None => unknown.push(format!("`{field}`")),
+                }
+            }
+        }
+        (Value::Array(json), Value::Array(known)) => {
+            for (i, (value, known)) in json.iter().zip(known).enumerate() {
+                unknown_fields(value, known, &format!("{path}[{i}]"), unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn from_protobuf<T>(bytes: &[u8], name: &str) -> Result<T, String>
+where
+    T: prost::Message + Default,
+{
+    let value = T::decode(bytes).map_err(|e| format!("not a {name}: {e}"))?;
+    // prost skips the fields it doesn't know, so they are missing when the message is encoded
+    // again.
+    let len = value.encoded_len();
+    if len < bytes.len() {
+        return Err(format!(
+            "{} bytes are fields that {name} doesn't have; were they removed?",
+            bytes.len() - len
+        ));
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::PingRequest;
+
+    fn write(name: &str, content: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("twirp-fixtures-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_fixture() {
+        let ping = PingRequest {
+            name: "hi".to_string(),
+        };
+        let path = write("ping.json", br#"{"name": "hi"}"#);
+        assert_eq!(fixture::<PingRequest>(path), ping);
+        let path = write("ping.pb", &crate::serialize_proto_message(ping.clone()));
+        assert_eq!(fixture::<PingRequest>(path), ping);
+    }
+
+    #[test]
+    fn test_schema_drift() {
+        let err = |name: &str, content: &[u8]| {
+            try_fixture::<PingRequest>(write(name, content))
+                .unwrap_err()
+                .msg
+        };
+        assert_eq!(
+            err("renamed.json", br#"{"nmae": "hi", "hat": null}"#),
+            "twirp::test::PingRequest has no field `nmae`; was it renamed or removed?"
+        );
+        assert!(err("type.json", br#"{"name": 7}"#).starts_with("not a twirp::test::PingRequest"));
+        assert!(err("syntax.json", b"{").starts_with("invalid JSON"));
+        // Field 1 is a string PingRequest doesn't have.
+        assert_eq!(
+            err("removed.pb", &[0x0a, 0x01, b'x', 0x12, 0x02, b'h', b'i']),
+            "3 bytes are fields that twirp::test::PingRequest doesn't have; were they removed?"
+        );
+        assert!(err("ping.txt", b"").starts_with("unknown fixture format"));
+
+        let err = try_fixture::<PingRequest>("does/not/exist.json").unwrap_err();
+        assert!(err.path.ends_with("does/not/exist.json"));
+    }
+}
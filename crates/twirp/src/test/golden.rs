@@ -0,0 +1,141 @@
+//! Golden-file tests of the wire format of messages and errors.
+//!
+//! [`assert_golden`] serializes a value to canonical JSON (object keys sorted, pretty-printed) and
+//! compares it with a file checked in next to the tests, so that changes to the JSON of requests,
+//! responses and errors show up in review. Run the tests with `UPDATE_GOLDEN=1` to write the files
+//! from the current output:
+//!
+//! ```no_run
+//! use twirp::test::golden::assert_golden;
+//! use twirp::test::PingResponse;
+//!
+//! let resp = PingResponse {
+//!     name: "hi".to_string(),
+//! };
+//! assert_golden("tests/golden/ping_response.json", &resp);
+//! ```
+//!
+//! Relative paths are relative to the directory of the crate being tested.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// The environment variable that makes [`assert_golden`] write the golden files instead of
+/// checking them.
+pub const UPDATE_GOLDEN: &str = "UPDATE_GOLDEN";
+
+/// Assert that the canonical JSON of `value` (e.g. a message, or a
+/// [`TwirpErrorResponse`](crate::TwirpErrorResponse)) is the content of the file at `path`.
+#[track_caller]
+pub fn assert_golden<T: Serialize + ?Sized>(path: impl AsRef<Path>, value: &T) {
+    let path = resolve(path.as_ref());
+    let update = std::env::var_os(UPDATE_GOLDEN).is_some_and(|v| !v.is_empty() && v != "0");
+    if let Err(msg) = check_golden(&path, &canonical_json(value), update) {
+        panic!("{msg}");
+    }
+}
+
+/// `value` as JSON with object keys sorted, pretty-printed, with a trailing newline.
+pub fn canonical_json<T: Serialize + ?Sized>(value: &T) -> String {
+    let value = sort_keys(serde_json::to_value(value).expect("value can't be serialized"));
+    let mut json = serde_json::to_string_pretty(&value).expect("JSON can be serialized");
+    json.push('\n');
+    json
+}
+
+fn sort_keys(value: Value) -> Value {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, sort_keys(v)))
+                    .collect(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(sort_keys).collect()),
+        value => value,
+    }
+}
+
+pub(super) fn resolve(path: &Path) -> PathBuf {
+    match std::env::var_os("CARGO_MANIFEST_DIR") {
+        Some(dir) if path.is_relative() => Path::new(&dir).join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn check_golden(path: &Path, actual: &str, update: bool) -> Result<(), String> {
+    if update {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+        }
+        return std::fs::write(path, actual).map_err(|e| format!("{}: {e}", path.display()));
+    }
+    let expected = std::fs::read_to_string(path).map_err(|e| {
+        format!(
+            "{}: {e}; run with {UPDATE_GOLDEN}=1 to create it",
+            path.display()
+        )
+    })?;
+    if expected != actual {
+        return Err(format!(
+            "{} doesn't match; run with {UPDATE_GOLDEN}=1 to update it\n--- expected\n{expected}--- actual\n{actual}",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::PingResponse;
+    use crate::TwirpErrorResponse;
+
+    #[test]
+    fn test_canonical_json() {
+        let err = crate::invalid_argument("bad name")
+            .with_meta("zone", "b")
+            .with_meta("argument", "name");
+        assert_eq!(
+            canonical_json(&err),
+            r#"{
+  "code": "invalid_argument",
+  "meta": {
+    "argument": "name",
+    "zone": "b"
+  },
+  "msg": "bad name"
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_check_golden() {
+        let path = std::env::temp_dir()
+            .join(format!("twirp-golden-{}", std::process::id()))
+            .join("ping.json");
+        let json = canonical_json(&PingResponse {
+            name: "hi".to_string(),
+        });
+
+        let err = check_golden(&path, &json, false).unwrap_err();
+        assert!(err.contains("UPDATE_GOLDEN=1 to create it"), "{err}");
+        check_golden(&path, &json, true).unwrap();
+        check_golden(&path, &json, false).unwrap();
+
+        let other = canonical_json(&TwirpErrorResponse::new(
+            crate::TwirpErrorCode::Internal,
+            "",
+        ));
+        let err = check_golden(&path, &other, false).unwrap_err();
+        assert!(err.contains("doesn't match"), "{err}");
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+//! An in-memory recorder of the RPCs a router serves, for tests of observability wiring.
+//!
+//! [`MetricsRecorder`] is a layer that records an [`RpcEvent`] for each response: the service
+//! and method, the HTTP status, the Twirp error code and how long the request took. Tests can
+//! then assert on what a real metrics layer would have seen:
+//!
+//! ```
+//! use twirp::test::metrics::MetricsRecorder;
+//! use twirp::test::{call, test_api_router, PingRequest, PingResponse};
+//! use twirp::TwirpErrorCode;
+//!
+//! # async fn example() {
+//! let recorder = MetricsRecorder::new();
+//! let router = test_api_router().layer(recorder.clone());
+//!
+//! let req = PingRequest::default();
+//! let _ = call::<_, PingResponse>(&router, "/twirp/test.TestAPI/Boom", req).await;
+//! assert_eq!(recorder.events_for("Boom").len(), 1);
+//! assert_eq!(recorder.error_count(TwirpErrorCode::Internal), 1);
+//! # }
+//! ```
+//!
+//! Error codes are read from the bodies of error responses, which are buffered to do so. The
+//! bodies of successful responses are passed through untouched.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use axum::body::Body;
+use futures::future::BoxFuture;
+use http::StatusCode;
+use hyper::{Request, Response};
+use tokio::time::Instant;
+use tower::{Layer, Service};
+
+use crate::context::split_route;
+use crate::server::{read_error_code, Timings};
+use crate::TwirpErrorCode;
+
+/// One response, as recorded by a [`MetricsRecorder`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RpcEvent {
+    /// The fully qualified name of the service, e.g. `example.v1.Haberdasher`.
+    pub service: String,
+    /// The name of the method, e.g. `MakeHat`.
+    pub method: String,
+    pub status: StatusCode,
+    /// The error code, for error responses.
+    pub error: Option<TwirpErrorCode>,
+    /// The time until the response was returned (not until its body was sent).
+    pub duration: Duration,
+    /// The [`Timings`] of the response, for responses written by twirp handlers.
+    pub timings: Option<Timings>,
+}
+
+/// A layer that records an [`RpcEvent`] for each response. Clones share the events. See the
+/// [module documentation](self).
+#[derive(Clone, Debug, Default)]
+pub struct MetricsRecorder {
+    events: Arc<Mutex<Vec<RpcEvent>>>,
+}
+
+impl MetricsRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// All the events, in the order the responses were returned.
+    pub fn events(&self) -> Vec<RpcEvent> {
+        self.events.lock().expect("mutex poisoned").clone()
+    }
+
+    /// The events for `method` (e.g. `MakeHat`), of any service.
+    pub fn events_for(&self, method: &str) -> Vec<RpcEvent> {
+        self.events()
+            .into_iter()
+            .filter(|e| e.method == method)
+            .collect()
+    }
+
+    /// The number of error responses for each error code, keyed by the code's name (e.g.
+    /// `not_found`).
+    pub fn error_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for event in self.events.lock().expect("mutex poisoned").iter() {
+            if let Some(code) = &event.error {
+                *counts.entry(code.twirp_code().to_string()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// The number of error responses with `code`.
+    pub fn error_count(&self, code: TwirpErrorCode) -> usize {
+        self.error_counts()
+            .get(code.twirp_code())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Forget the events recorded so far.
+    pub fn clear(&self) {
+        self.events.lock().expect("mutex poisoned").clear();
+    }
+}
+
+impl<S> Layer<S> for MetricsRecorder {
+    type Service = RecordMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RecordMetrics {
+            inner,
+            recorder: self.clone(),
+        }
+    }
+}
+
+/// Middleware that records the responses of the inner service in a [`MetricsRecorder`].
+#[derive(Clone, Debug)]
+pub struct RecordMetrics<S> {
+    inner: S,
+    recorder: MetricsRecorder,
+}
+
+impl<S> Service<Request<Body>> for RecordMetrics<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let start = Instant::now();
+        let (service, method) = split_route(req.uri().path()).unwrap_or_default();
+        let (service, method) = (service.to_string(), method.to_string());
+
+        let recorder = self.recorder.clone();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let resp = fut.await?;
+            let duration = start.elapsed();
+            let timings = resp.extensions().get::<Timings>().copied();
+            let (resp, error) = read_error_code(resp).await;
+            recorder
+                .events
+                .lock()
+                .expect("mutex poisoned")
+                .push(RpcEvent {
+                    service,
+                    method,
+                    status: resp.status(),
+                    error,
+                    duration,
+                    timings,
+                });
+            Ok(resp)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{call, read_err_body, test_api_router, PingRequest, PingResponse};
+
+    #[tokio::test]
+    async fn test_metrics_recorder() {
+        let recorder = MetricsRecorder::new();
+        let router = test_api_router().layer(recorder.clone());
+        let ping = |name: &str| PingRequest {
+            name: name.to_string(),
+        };
+
+        let resp: PingResponse = call(&router, "/twirp/test.TestAPI/Ping", ping("hi"))
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "hi");
+        for _ in 0..2 {
+            let err = call::<_, PingResponse>(&router, "/twirp/test.TestAPI/Boom", ping(""))
+                .await
+                .unwrap_err();
+            assert_eq!(err.code, TwirpErrorCode::Internal);
+        }
+        let resp = router
+            .clone()
+            .call(
+                Request::post("/twirp/test.TestAPI/Nope")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        // The body is still there after the recorder read it.
+        assert_eq!(
+            read_err_body(resp.into_body()).await.code,
+            TwirpErrorCode::BadRoute
+        );
+
+        let events = recorder.events();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0].service, "test.TestAPI");
+        assert_eq!(events[0].method, "Ping");
+        assert_eq!(events[0].status, StatusCode::OK);
+        assert_eq!(events[0].error, None);
+        assert!(events[0].timings.is_some());
+
+        let booms = recorder.events_for("Boom");
+        assert_eq!(booms.len(), 2);
+        assert!(booms.iter().all(|e| e.status == 500));
+        assert_eq!(
+            recorder.error_counts(),
+            BTreeMap::from([("bad_route".to_string(), 1), ("internal".to_string(), 2)])
+        );
+        assert_eq!(recorder.error_count(TwirpErrorCode::Internal), 2);
+        assert_eq!(recorder.error_count(TwirpErrorCode::NotFound), 0);
+
+        recorder.clear();
+        assert!(recorder.events().is_empty());
+    }
+}
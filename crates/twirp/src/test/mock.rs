@@ -0,0 +1,505 @@
+//! Mock services with expectations, for tests of code that calls Twirp services.
+//!
+//! A [`Mock`] is a [`DirectHandler`] that answers the calls that match its expectations, and
+//! checks at the end of the test that each expectation was met:
+//!
+//! ```
+//! use twirp::test::mock::Mock;
+//! use twirp::test::{times, PingRequest, PingResponse};
+//! use twirp::ClientBuilder;
+//!
+//! # async fn example() {
+//! let mock = Mock::new("test.TestAPI");
+//! mock.expect("Ping")
+//!     .matching(|req: &PingRequest| req.name == "hi")
+//!     .times(times(1))
+//!     .returning(|req: PingRequest| Ok(PingResponse { name: req.name }));
+//!
+//! let base_url = url::Url::parse("http://test.local/twirp/").unwrap();
+//! let client = ClientBuilder::new(base_url, reqwest::Client::new())
+//!     .direct("test.local", mock.clone())
+//!     .build()
+//!     .unwrap();
+//! // ... code under test makes calls with `client` ...
+//! mock.verify();
+//! # }
+//! ```
+//!
+//! Calls are answered by the first expectation, in the order they were added, whose method and
+//! matcher match and that hasn't been called as many times as it allows. Calls that match no
+//! expectation fail with an `internal` error, and make [`Mock::verify`] panic.
+//!
+//! For fakes that only need to answer calls, [`MockService`] builds a router (or a
+//! [`DirectHandler`]) from a closure per method. With `twirp_build::ServiceGenerator::mocks`,
+//! `twirp-build` generates a typed builder for each service, with an `on_<method>` function per
+//! `rpc`:
+//!
+//! ```ignore
+//! use twirp::test::MockService;
+//!
+//! let router = MockService::for_::<haberdash::HaberdasherApiMock>()
+//!     .on_make_hat(|req| Ok(Hat { size: req.inches, ..Default::default() }))
+//!     .build_router();
+//! let app = twirp::Router::new().nest(haberdash::SERVICE_FQN, router);
+//! ```
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use axum::Router;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::Times;
+use crate::details::TwirpRouterBuilder;
+use crate::direct::{DirectHandler, DirectService};
+use crate::{error, serialize_proto_message, Context, TwirpErrorResponse};
+
+type Matcher = Box<dyn Fn(&Bytes) -> bool + Send + Sync>;
+type Responder = Box<dyn Fn(Bytes) -> Result<Bytes, TwirpErrorResponse> + Send + Sync>;
+
+/// A mock service. See the [module documentation](self).
+#[derive(Clone)]
+pub struct Mock {
+    service: String,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    expectations: Vec<Expectation>,
+    next_sequence: usize,
+    failures: Vec<String>,
+}
+
+struct Expectation {
+    method: String,
+    matcher: Option<Matcher>,
+    responder: Option<Responder>,
+    times: Times,
+    sequence: Option<usize>,
+    calls: usize,
+}
+
+impl Expectation {
+    fn is_saturated(&self) -> bool {
+        matches!(self.times, Times::Exactly(n) if self.calls >= n)
+    }
+
+    fn is_satisfied(&self) -> bool {
+        match self.times {
+            Times::Exactly(n) => self.calls == n,
+            Times::AtLeast(n) => self.calls >= n,
+        }
+    }
+}
+
+impl Mock {
+    /// A mock of the service named `service` (e.g. `example.v1.Haberdasher`), without any
+    /// expectations.
+    pub fn new(service: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+            state: Default::default(),
+        }
+    }
+
+    /// Expect calls to `method` (e.g. `MakeHat`). By default, the expectation matches all
+    /// requests, must be called at least once, and answers with an `unimplemented` error.
+    pub fn expect(&self, method: &str) -> ExpectationBuilder<'_> {
+        let mut state = self.state.lock().expect("mutex poisoned");
+        state.expectations.push(Expectation {
+            method: method.to_string(),
+            matcher: None,
+            responder: None,
+            times: Times::AtLeast(1),
+            sequence: None,
+            calls: 0,
+        });
+        ExpectationBuilder {
+            mock: self,
+            index: state.expectations.len() - 1,
+        }
+    }
+
+    /// Panic unless every expectation was met and every call was expected.
+    #[track_caller]
+    pub fn verify(&self) {
+        let state = self.state.lock().expect("mutex poisoned");
+        let mut problems = state.failures.clone();
+        for expectation in state.expectations.iter().filter(|e| !e.is_satisfied()) {
+            problems.push(format!(
+                "expected `{}` to be called {:?}, but it was called {} times",
+                expectation.method, expectation.times, expectation.calls
+            ));
+        }
+        if !problems.is_empty() {
+            let mut msg = format!("mock of `{}` not satisfied:", self.service);
+            for problem in problems {
+                let _ = write!(msg, "\n- {problem}");
+            }
+            panic!("{msg}");
+        }
+    }
+
+    fn call(&self, method: &str, req: Bytes) -> Result<Bytes, TwirpErrorResponse> {
+        let mut state = self.state.lock().expect("mutex poisoned");
+        let found = state.expectations.iter().position(|e| {
+            e.method == method
+                && !e.is_saturated()
+                && e.matcher.as_ref().is_none_or(|matcher| matcher(&req))
+        });
+        let Some(index) = found else {
+            let msg = format!("unexpected call to `{method}`");
+            state.failures.push(msg.clone());
+            return Err(error::internal(msg));
+        };
+        if let Some(sequence) = state.expectations[index].sequence {
+            let pending = state.expectations.iter().find(|e| {
+                e.sequence.is_some_and(|earlier| earlier < sequence) && !e.is_satisfied()
+            });
+            if let Some(pending) = pending {
+                let msg = format!(
+                    "`{method}` was called out of order, before `{}`",
+                    pending.method
+                );
+                state.failures.push(msg.clone());
+                return Err(error::internal(msg));
+            }
+        }
+        let expectation = &mut state.expectations[index];
+        expectation.calls += 1;
+        match &expectation.responder {
+            Some(responder) => responder(req),
+            None => Err(error::unimplemented(format!("no response for `{method}`"))),
+        }
+    }
+}
+
+impl std::fmt::Debug for Mock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().expect("mutex poisoned");
+        let methods: Vec<_> = state.expectations.iter().map(|e| &e.method).collect();
+        f.debug_struct("Mock")
+            .field("service", &self.service)
+            .field("expectations", &methods)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl DirectHandler for Mock {
+    fn service(&self) -> &str {
+        &self.service
+    }
+
+    async fn handle(
+        &self,
+        method: &str,
+        _ctx: Context,
+        req: Bytes,
+    ) -> Result<Bytes, TwirpErrorResponse> {
+        self.call(method, req)
+    }
+}
+
+/// Configures an expectation added with [`Mock::expect`].
+pub struct ExpectationBuilder<'a> {
+    mock: &'a Mock,
+    index: usize,
+}
+
+impl ExpectationBuilder<'_> {
+    /// Only match requests for which `matcher` returns true.
+    pub fn matching<Req, F>(self, matcher: F) -> Self
+    where
+        Req: prost::Message + Default,
+        F: Fn(&Req) -> bool + Send + Sync + 'static,
+    {
+        self.update(|e| {
+            e.matcher = Some(Box::new(move |body| {
+                Req::decode(body.clone()).is_ok_and(|req| matcher(&req))
+            }))
+        })
+    }
+
+    /// Expect the number of calls given by `times`. Once an expectation has been called
+    /// [exactly](Times::Exactly) as many times as expected, later calls go to the next matching
+    /// expectation.
+    pub fn times(self, times: Times) -> Self {
+        self.update(|e| e.times = times)
+    }
+
+    /// Answer the calls with `responder`.
+    pub fn returning<Req, Resp, F>(self, responder: F) -> Self
+    where
+        Req: prost::Message + Default,
+        Resp: prost::Message,
+        F: Fn(Req) -> Result<Resp, TwirpErrorResponse> + Send + Sync + 'static,
+    {
+        self.update(|e| {
+            e.responder = Some(Box::new(move |body| {
+                let req = Req::decode(body).map_err(|err| {
+                    error::malformed("bad request")
+                        .with_meta("error", &err)
+                        .with_source(err)
+                })?;
+                responder(req).map(serialize_proto_message)
+            }))
+        })
+    }
+
+    /// Expect the calls after those of the earlier expectations that are also in sequence: a call
+    /// that matches this expectation before they are all satisfied is a failure.
+    pub fn in_sequence(self) -> Self {
+        let mut state = self.mock.state.lock().expect("mutex poisoned");
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state.expectations[self.index].sequence = Some(sequence);
+        drop(state);
+        self
+    }
+
+    fn update(self, f: impl FnOnce(&mut Expectation)) -> Self {
+        f(&mut self.mock.state.lock().expect("mutex poisoned").expectations[self.index]);
+        self
+    }
+}
+
+/// A fake service that answers each method with a closure. See the [module documentation](self).
+///
+/// ```
+/// use twirp::test::{call, MockService, PingRequest, PingResponse};
+///
+/// # async fn example() {
+/// let router = MockService::new("/test.TestAPI")
+///     .on("/Ping", |req: PingRequest| Ok(PingResponse { name: req.name }))
+///     .build_router();
+/// let app = twirp::Router::new().nest("/twirp/test.TestAPI", router);
+///
+/// let req = PingRequest { name: "hi".to_string() };
+/// let resp: PingResponse = call(&app, "/twirp/test.TestAPI/Ping", req).await.unwrap();
+/// assert_eq!(resp.name, "hi");
+/// # }
+/// ```
+///
+/// Methods without a closure are answered with a `bad_route` error.
+pub struct MockService {
+    builder: TwirpRouterBuilder<()>,
+}
+
+/// A typed builder of a [`MockService`], generated by `twirp-build` for each service when
+/// `twirp_build::ServiceGenerator::mocks` is set.
+pub trait MockBuilder: From<MockService> {
+    /// The generated `SERVICE_FQN` of the service, e.g. `/example.v1.Haberdasher`.
+    const SERVICE_FQN: &'static str;
+}
+
+impl MockService {
+    /// A fake of the service named `service_fqn` (the generated `SERVICE_FQN`, e.g.
+    /// `/example.v1.Haberdasher`), without any methods.
+    pub fn new(service_fqn: &'static str) -> Self {
+        Self {
+            builder: TwirpRouterBuilder::new(service_fqn, ()),
+        }
+    }
+
+    /// The typed builder `M` generated for a service, e.g.
+    /// `MockService::for_::<haberdash::HaberdasherApiMock>()`.
+    pub fn for_<M: MockBuilder>() -> M {
+        M::from(Self::new(M::SERVICE_FQN))
+    }
+
+    /// Answer the method at `path` (e.g. `/MakeHat`) with `f`.
+    ///
+    /// # Panics
+    ///
+    /// If the method already has a closure.
+    pub fn on<Req, Resp, F>(mut self, path: &'static str, f: F) -> Self
+    where
+        F: Fn(Req) -> Result<Resp, TwirpErrorResponse> + Clone + Send + Sync + 'static,
+        Req: prost::Message + Default + DeserializeOwned + 'static,
+        Resp: prost::Message + Serialize + Send + 'static,
+    {
+        self.builder = self
+            .builder
+            .route(path, move |(), _ctx: Context, req: Req| {
+                let f = f.clone();
+                async move { f(req) }
+            });
+        self
+    }
+
+    /// Finish building the router of the service, to be mounted at its `SERVICE_FQN` like the
+    /// generated `router()`.
+    pub fn build_router(self) -> Router {
+        self.builder.build()
+    }
+
+    /// Finish building a handler that answers the calls in-process, for
+    /// [`ClientBuilder::direct`](crate::ClientBuilder::direct).
+    pub fn build_direct(self) -> DirectService {
+        self.builder.build_direct()
+    }
+}
+
+impl std::fmt::Debug for MockService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockService").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+    use crate::test::*;
+    use crate::{ClientBuilder, TwirpErrorCode};
+
+    fn client(mock: &Mock) -> crate::Client {
+        let base_url = Url::parse("http://test.local/twirp/").unwrap();
+        ClientBuilder::new(base_url, reqwest::Client::new())
+            .direct("test.local", mock.clone())
+            .build()
+            .unwrap()
+    }
+
+    fn ping(name: &str) -> PingRequest {
+        PingRequest {
+            name: name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expectations() {
+        let mock = Mock::new("test.TestAPI");
+        mock.expect("Ping")
+            .matching(|req: &PingRequest| req.name == "special")
+            .times(times(1))
+            .returning(|_: PingRequest| Ok(PingResponse::default()));
+        mock.expect("Ping")
+            .times(at_least(2))
+            .returning(|req: PingRequest| Ok(PingResponse { name: req.name }));
+        mock.expect("Boom")
+            .returning(|_: PingRequest| Err::<PingResponse, _>(error::unavailable("down")));
+
+        let client = client(&mock);
+        assert_eq!(client.ping(ping("special")).await.unwrap().name, "");
+        assert_eq!(client.ping(ping("special")).await.unwrap().name, "special");
+        assert_eq!(client.ping(ping("hi")).await.unwrap().name, "hi");
+        let err = client.boom(ping("")).await.unwrap_err();
+        assert_eq!(err.twirp_error().unwrap().code, TwirpErrorCode::Unavailable);
+        mock.verify();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "expected `Ping` to be called Exactly(2), but it was called 1 times")]
+    async fn test_unmet_expectation() {
+        let mock = Mock::new("test.TestAPI");
+        mock.expect("Ping")
+            .times(times(2))
+            .returning(|req: PingRequest| Ok(PingResponse { name: req.name }));
+        client(&mock).ping(ping("hi")).await.unwrap();
+        mock.verify();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "unexpected call to `Boom`")]
+    async fn test_unexpected_call() {
+        let mock = Mock::new("test.TestAPI");
+        let err = client(&mock).boom(ping("")).await.unwrap_err();
+        assert_eq!(err.twirp_error().unwrap().code, TwirpErrorCode::Internal);
+        mock.verify();
+    }
+
+    #[tokio::test]
+    async fn test_sequence() {
+        let mock = Mock::new("test.TestAPI");
+        mock.expect("Ping")
+            .in_sequence()
+            .returning(|req: PingRequest| Ok(PingResponse { name: req.name }));
+        mock.expect("Boom")
+            .in_sequence()
+            .returning(|req: PingRequest| Ok(PingResponse { name: req.name }));
+
+        let client = client(&mock);
+        let err = client.boom(ping("")).await.unwrap_err();
+        assert_eq!(
+            err.twirp_error().unwrap().msg,
+            "`Boom` was called out of order, before `Ping`"
+        );
+        client.ping(ping("")).await.unwrap();
+        client.boom(ping("")).await.unwrap();
+        let state = mock.state.lock().unwrap();
+        assert_eq!(state.failures.len(), 1);
+    }
+
+    // What `twirp-build` generates for the test service with `mocks()`.
+    struct TestApiMock(MockService);
+
+    impl From<MockService> for TestApiMock {
+        fn from(mock: MockService) -> Self {
+            Self(mock)
+        }
+    }
+
+    impl MockBuilder for TestApiMock {
+        const SERVICE_FQN: &'static str = "/test.TestAPI";
+    }
+
+    impl TestApiMock {
+        fn on_ping<F>(self, f: F) -> Self
+        where
+            F: Fn(PingRequest) -> Result<PingResponse, TwirpErrorResponse>
+                + Clone
+                + Send
+                + Sync
+                + 'static,
+        {
+            Self(self.0.on("/Ping", f))
+        }
+
+        fn build_router(self) -> axum::Router {
+            self.0.build_router()
+        }
+
+        fn build_direct(self) -> DirectService {
+            self.0.build_direct()
+        }
+    }
+
+    fn fake() -> TestApiMock {
+        MockService::for_::<TestApiMock>().on_ping(|req| {
+            Ok(PingResponse {
+                name: format!("fake {}", req.name),
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn test_mock_service() {
+        let router = axum::Router::new().nest("/twirp/test.TestAPI", fake().build_router());
+        let resp: PingResponse = call(&router, "/twirp/test.TestAPI/Ping", ping("hi"))
+            .await
+            .unwrap();
+        assert_eq!(resp.name, "fake hi");
+        let err = call::<_, PingResponse>(&router, "/twirp/test.TestAPI/Boom", ping(""))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, TwirpErrorCode::BadRoute);
+
+        let base_url = Url::parse("http://test.local/twirp/").unwrap();
+        let client = ClientBuilder::new(base_url, reqwest::Client::new())
+            .direct("test.local", fake().build_direct())
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.ping(ping("direct")).await.unwrap().name,
+            "fake direct"
+        );
+    }
+}
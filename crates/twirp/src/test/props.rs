@@ -0,0 +1,362 @@
+//! Property tests of how messages round-trip through twirp: strategies that generate arbitrary
+//! messages, and an assertion that a message comes back unchanged after being sent as protobuf
+//! and as JSON through a router and a client.
+//!
+//! A message whose serde attributes don't agree with its prost attributes (e.g. a field that is
+//! renamed or skipped only when serializing) fails the JSON round-trip:
+//!
+//! ```
+//! use twirp::proptest::prelude::*;
+//! use twirp::test::props::assert_round_trip;
+//! use twirp::test::PingRequest;
+//!
+//! proptest! {
+//!     # #![proptest_config(ProptestConfig::with_cases(4))]
+//!     #[test]
+//!     fn ping_round_trips(name in any::<String>()) {
+//!         assert_round_trip(&PingRequest { name });
+//!     }
+//! }
+//! ```
+//!
+//! Messages can be generated with `proptest-derive` (add `#[derive(Arbitrary)]` to the generated
+//! types with `prost_build::Config::type_attribute`), or, with the `reflect` feature, from their
+//! descriptors with [`arbitrary`] and [`arbitrary_message`].
+//!
+//! Requires the `proptest` feature.
+
+use axum::Router;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::codec::{decode_message, encode_message, JsonCodec, MessageType, ProtobufCodec};
+use crate::details::TwirpRouterBuilder;
+use crate::test::TestServer;
+use crate::{ClientBuilder, TwirpErrorResponse};
+
+const SERVICE: &str = "twirp.test.RoundTrip";
+
+/// Assert that `value` is unchanged after it is encoded and decoded with the protobuf and JSON
+/// codecs, and after it is sent to a router and back with a protobuf and a JSON client.
+///
+/// Runs the router on a runtime of its own, so it can't be called from async code; use
+/// [`assert_router_round_trip`] there.
+#[track_caller]
+pub fn assert_round_trip<T>(value: &T)
+where
+    T: prost::Message + Default + Serialize + DeserializeOwned + PartialEq + Clone + 'static,
+{
+    let ty = MessageType::request(SERVICE, "Echo");
+    let pb: T = decode_message(
+        &ProtobufCodec,
+        ty,
+        encode_message(&ProtobufCodec, ty, value.clone()).expect("failed to encode protobuf"),
+    )
+    .expect("failed to decode protobuf");
+    assert_eq!(&pb, value, "protobuf round-trip changed the message");
+    let json = encode_message(&JsonCodec, ty, value.clone()).expect("failed to encode JSON");
+    let decoded: T = decode_message(&JsonCodec, ty, json.clone())
+        .unwrap_or_else(|e| panic!("failed to decode {}: {e}", String::from_utf8_lossy(&json)));
+    assert_eq!(
+        &decoded,
+        value,
+        "JSON round-trip changed the message, encoded as {}",
+        String::from_utf8_lossy(&json)
+    );
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start a runtime")
+        .block_on(assert_router_round_trip(value.clone()));
+}
+
+/// Assert that `value` comes back unchanged from a router that echoes it, when sent with a
+/// protobuf and a JSON client.
+pub async fn assert_router_round_trip<T>(value: T)
+where
+    T: prost::Message + Default + Serialize + DeserializeOwned + PartialEq + Clone + 'static,
+{
+    let echo = TwirpRouterBuilder::new("/twirp.test.RoundTrip", ())
+        .route("/Echo", |(), _ctx, req: T| async move {
+            Ok::<_, TwirpErrorResponse>(req)
+        })
+        .build();
+    let server = TestServer::new(Router::new().nest(&format!("/twirp/{SERVICE}"), echo));
+    let path = format!("{SERVICE}/Echo");
+
+    let pb: T = server
+        .client()
+        .request(&path, value.clone())
+        .await
+        .expect("protobuf request failed");
+    assert_eq!(
+        pb, value,
+        "protobuf request through the router changed the message"
+    );
+
+    let json_client = ClientBuilder::new(TestServer::base_url(), reqwest::Client::new())
+        .codec(JsonCodec)
+        .with(server.transport())
+        .build()
+        .expect("the base URL is valid");
+    let json: T = json_client
+        .request_with_codec(&path, value.clone())
+        .await
+        .expect("JSON request failed");
+    assert_eq!(
+        json, value,
+        "JSON request through the router changed the message"
+    );
+}
+
+#[cfg(feature = "reflect")]
+pub use reflect::{arbitrary, arbitrary_message};
+
+#[cfg(feature = "reflect")]
+mod reflect {
+    use bytes::Bytes;
+    use proptest::collection::{hash_map, vec};
+    use proptest::prelude::*;
+    use proptest::sample::select;
+    use prost_reflect::{
+        DynamicMessage, FieldDescriptor, Kind, MapKey, MessageDescriptor, ReflectMessage, Value,
+    };
+
+    // How deep messages nest, so that recursive messages stay finite.
+    const MAX_DEPTH: u32 = 3;
+    // The most elements of repeated and map fields.
+    const MAX_LEN: usize = 4;
+
+    /// Arbitrary messages of type `T`, generated from its descriptor.
+    pub fn arbitrary<T>() -> BoxedStrategy<T>
+    where
+        T: ReflectMessage + Default + 'static,
+    {
+        arbitrary_message(T::default().descriptor())
+            .prop_map(|msg| msg.transcode_to().expect("message matches its descriptor"))
+            .boxed()
+    }
+
+    /// Arbitrary messages of the type described by `desc`. Each field may be set or not.
+    ///
+    /// Floating point fields hold finite values that JSON represents exactly, and messages nest
+    /// at most 3 deep.
+    pub fn arbitrary_message(desc: MessageDescriptor) -> BoxedStrategy<DynamicMessage> {
+        message(desc, MAX_DEPTH)
+    }
+
+    fn message(desc: MessageDescriptor, depth: u32) -> BoxedStrategy<DynamicMessage> {
+        let fields: Vec<_> = desc
+            .fields()
+            .filter(|field| depth > 0 || !has_messages(field))
+            .map(|field| {
+                proptest::option::of(field_value(&field, depth))
+                    .prop_map(move |value| value.map(|value| (field.clone(), value)))
+            })
+            .collect();
+        fields
+            .prop_map(move |values| {
+                let mut msg = DynamicMessage::new(desc.clone());
+                for (field, value) in values.into_iter().flatten() {
+                    // A default value is the same as no value for fields without presence, and
+                    // isn't encoded.
+                    if field.supports_presence() || !value.is_default_for_field(&field) {
+                        msg.set_field(&field, value);
+                    }
+                }
+                msg
+            })
+            .boxed()
+    }
+
+    fn has_messages(field: &FieldDescriptor) -> bool {
+        match field.kind() {
+            Kind::Message(desc) if field.is_map() => {
+                matches!(desc.map_entry_value_field().kind(), Kind::Message(_))
+            }
+            Kind::Message(_) => true,
+            _ => false,
+        }
+    }
+
+    fn field_value(field: &FieldDescriptor, depth: u32) -> BoxedStrategy<Value> {
+        match field.kind() {
+            Kind::Message(desc) if field.is_map() => {
+                let key = map_key(&desc.map_entry_key_field().kind());
+                let value = single(&desc.map_entry_value_field().kind(), depth);
+                hash_map(key, value, 0..MAX_LEN)
+                    .prop_map(Value::Map)
+                    .boxed()
+            }
+            kind if field.is_list() => vec(single(&kind, depth), 0..MAX_LEN)
+                .prop_map(Value::List)
+                .boxed(),
+            kind => single(&kind, depth),
+        }
+    }
+
+    fn single(kind: &Kind, depth: u32) -> BoxedStrategy<Value> {
+        match kind {
+            Kind::Double => prop_oneof![
+                any::<i32>().prop_map(f64::from),
+                any::<i16>().prop_map(|n| f64::from(n) / 8.0),
+            ]
+            .prop_map(Value::F64)
+            .boxed(),
+            Kind::Float => prop_oneof![
+                any::<i16>().prop_map(f32::from),
+                any::<i8>().prop_map(|n| f32::from(n) / 8.0),
+            ]
+            .prop_map(Value::F32)
+            .boxed(),
+            Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => {
+                any::<i32>().prop_map(Value::I32).boxed()
+            }
+            Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => {
+                any::<i64>().prop_map(Value::I64).boxed()
+            }
+            Kind::Uint32 | Kind::Fixed32 => any::<u32>().prop_map(Value::U32).boxed(),
+            Kind::Uint64 | Kind::Fixed64 => any::<u64>().prop_map(Value::U64).boxed(),
+            Kind::Bool => any::<bool>().prop_map(Value::Bool).boxed(),
+            Kind::String => any::<String>().prop_map(Value::String).boxed(),
+            Kind::Bytes => vec(any::<u8>(), 0..16)
+                .prop_map(|b| Value::Bytes(Bytes::from(b)))
+                .boxed(),
+            Kind::Enum(desc) => select(desc.values().map(|v| v.number()).collect::<Vec<_>>())
+                .prop_map(Value::EnumNumber)
+                .boxed(),
+            Kind::Message(desc) => message(desc.clone(), depth.saturating_sub(1))
+                .prop_map(Value::Message)
+                .boxed(),
+        }
+    }
+
+    fn map_key(kind: &Kind) -> BoxedStrategy<MapKey> {
+        match kind {
+            Kind::Bool => any::<bool>().prop_map(MapKey::Bool).boxed(),
+            Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => {
+                any::<i32>().prop_map(MapKey::I32).boxed()
+            }
+            Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => {
+                any::<i64>().prop_map(MapKey::I64).boxed()
+            }
+            Kind::Uint32 | Kind::Fixed32 => any::<u32>().prop_map(MapKey::U32).boxed(),
+            Kind::Uint64 | Kind::Fixed64 => any::<u64>().prop_map(MapKey::U64).boxed(),
+            _ => any::<String>().prop_map(MapKey::String).boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::test::PingRequest;
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn test_ping_round_trips(name in any::<String>()) {
+            assert_round_trip(&PingRequest { name });
+        }
+    }
+
+    // A message whose JSON form leaves out one of its fields.
+    #[derive(serde::Serialize, serde::Deserialize)]
+    #[serde(default)]
+    #[derive(Clone, PartialEq, ::prost::Message)]
+    struct Lossy {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[serde(skip_serializing)]
+        #[prost(string, tag = "2")]
+        note: String,
+    }
+
+    #[test]
+    fn test_lossy_json() {
+        let lossy = Lossy {
+            name: "hat".to_string(),
+            note: "felt".to_string(),
+        };
+        let err = std::panic::catch_unwind(|| assert_round_trip(&lossy)).unwrap_err();
+        let msg = err.downcast_ref::<String>().unwrap();
+        assert!(msg.contains("JSON round-trip changed the message"), "{msg}");
+    }
+
+    #[cfg(feature = "reflect")]
+    mod reflect {
+        use prost_reflect::prost_types::field_descriptor_proto::{Label, Type};
+        use prost_reflect::prost_types::{
+            DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+        };
+        use prost_reflect::{DescriptorPool, DynamicMessage, ReflectMessage, Value};
+
+        use super::*;
+
+        fn field(name: &str, number: i32, label: Label, ty: Type) -> FieldDescriptorProto {
+            FieldDescriptorProto {
+                name: Some(name.to_string()),
+                number: Some(number),
+                label: Some(label as i32),
+                r#type: Some(ty as i32),
+                type_name: (ty == Type::Message).then(|| ".test.Hat".to_string()),
+                ..Default::default()
+            }
+        }
+
+        // message Hat { double size = 1; repeated bytes tags = 2; Hat inner = 3;
+        //   repeated Hat parts = 4; }
+        fn hat() -> prost_reflect::MessageDescriptor {
+            let file = FileDescriptorProto {
+                name: Some("hats.proto".to_string()),
+                package: Some("test".to_string()),
+                syntax: Some("proto3".to_string()),
+                message_type: vec![DescriptorProto {
+                    name: Some("Hat".to_string()),
+                    field: vec![
+                        field("size", 1, Label::Optional, Type::Double),
+                        field("tags", 2, Label::Repeated, Type::Bytes),
+                        field("inner", 3, Label::Optional, Type::Message),
+                        field("parts", 4, Label::Repeated, Type::Message),
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            let pool =
+                DescriptorPool::from_file_descriptor_set(FileDescriptorSet { file: vec![file] })
+                    .unwrap();
+            pool.get_message_by_name("test.Hat").unwrap()
+        }
+
+        fn depth(msg: &DynamicMessage) -> u32 {
+            let nested = msg.fields().flat_map(|(_, value)| match value {
+                Value::Message(msg) => vec![depth(msg)],
+                Value::List(list) => list
+                    .iter()
+                    .filter_map(Value::as_message)
+                    .map(depth)
+                    .collect(),
+                _ => vec![],
+            });
+            1 + nested.max().unwrap_or(0)
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(32))]
+
+            #[test]
+            fn test_arbitrary_message(msg in arbitrary_message(hat())) {
+                // The message itself, and up to 3 levels of messages in it.
+                prop_assert!(depth(&msg) <= 4);
+                let encoded = prost::Message::encode_to_vec(&msg);
+                let decoded = DynamicMessage::decode(msg.descriptor(), encoded.as_slice()).unwrap();
+                prop_assert_eq!(decoded, msg);
+            }
+        }
+    }
+}
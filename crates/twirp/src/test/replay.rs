@@ -0,0 +1,447 @@
+//! Replays of recorded traffic, for checking that a rewritten handler still answers like the one
+//! it replaces.
+//!
+//! Traffic is recorded as a list of [`Exchange`]s: by the [`Capture`] client middleware, or from
+//! a HAR file exported from a browser or proxy. [`replay`] sends each recorded request to a router
+//! and reports the responses that differ from the recorded ones:
+//!
+//! ```no_run
+//! use twirp::test::replay::{load, replay};
+//!
+//! # async fn example(router: twirp::Router) {
+//! let exchanges = load("tests/traffic/make_hat.jsonl").unwrap();
+//! replay(&router, &exchanges).await.assert_ok();
+//! # }
+//! ```
+//!
+//! JSON bodies are compared as JSON, so the order of object keys doesn't matter; other bodies are
+//! compared byte for byte. Relative paths are relative to the directory of the crate being tested.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use axum::body::Body;
+use axum::Router;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, Method, Request};
+use http_body_util::BodyExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tower::Service;
+
+use crate::{ClientError, Middleware, Next, Result};
+
+/// One recorded request and its response. Serialized as one line of a `.jsonl` capture, with the
+/// bodies base64-encoded.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub method: String,
+    /// The path of the request, e.g. `/twirp/example.v1.Haberdasher/MakeHat`.
+    pub path: String,
+    pub content_type: String,
+    #[serde(with = "base64_body")]
+    pub request: Bytes,
+    pub status: u16,
+    #[serde(default)]
+    pub response_content_type: String,
+    #[serde(with = "base64_body")]
+    pub response: Bytes,
+}
+
+mod base64_body {
+    use super::*;
+
+    pub(super) fn serialize<S: serde::Serializer>(body: &Bytes, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&STANDARD.encode(body))
+    }
+
+    pub(super) fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Bytes, D::Error> {
+        let encoded = String::deserialize(d)?;
+        STANDARD
+            .decode(encoded)
+            .map(Bytes::from)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Load the exchanges in the file at `path`: a HAR file if its extension is `.har`, and a capture
+/// of one JSON [`Exchange`] per line otherwise.
+pub fn load(path: impl AsRef<Path>) -> std::result::Result<Vec<Exchange>, String> {
+    let path = super::golden::resolve(path.as_ref());
+    let content = std::fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let exchanges = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("har") => parse_har(&content),
+        _ => parse_capture(&content),
+    };
+    exchanges.map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Parse a capture of one JSON [`Exchange`] per line, as written by [`Capture::to_jsonl`].
+pub fn parse_capture(content: &str) -> std::result::Result<Vec<Exchange>, String> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line).map_err(|e| format!("line {}: {e}", i + 1)))
+        .collect()
+}
+
+/// Parse the `POST` requests of a HAR file. Bodies with `"encoding": "base64"` are decoded.
+pub fn parse_har(content: &str) -> std::result::Result<Vec<Exchange>, String> {
+    let har: Value = serde_json::from_str(content).map_err(|e| format!("invalid HAR: {e}"))?;
+    let entries = har["log"]["entries"]
+        .as_array()
+        .ok_or("invalid HAR: no log.entries")?;
+    let mut exchanges = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        let (req, resp) = (&entry["request"], &entry["response"]);
+        if req["method"] != "POST" {
+            continue;
+        }
+        let url = req["url"].as_str().unwrap_or_default();
+        let url = url::Url::parse(url).map_err(|e| format!("entry {i}: invalid url: {e}"))?;
+        let body = |content: &Value| -> std::result::Result<Bytes, String> {
+            let text = content["text"].as_str().unwrap_or_default();
+            match content["encoding"].as_str() {
+                Some("base64") => STANDARD
+                    .decode(text)
+                    .map(Bytes::from)
+                    .map_err(|e| format!("entry {i}: invalid base64 body: {e}")),
+                _ => Ok(Bytes::copy_from_slice(text.as_bytes())),
+            }
+        };
+        exchanges.push(Exchange {
+            method: "POST".to_string(),
+            path: url.path().to_string(),
+            content_type: req["postData"]["mimeType"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            request: body(&req["postData"])?,
+            status: resp["status"].as_u64().unwrap_or_default() as u16,
+            response_content_type: resp["content"]["mimeType"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            response: body(&resp["content"])?,
+        });
+    }
+    Ok(exchanges)
+}
+
+/// A client middleware that records the exchanges the client makes, for replaying them later.
+/// Clones share the recorded exchanges.
+///
+/// Add it before the middleware that sends the requests, if any.
+#[derive(Clone, Debug, Default)]
+pub struct Capture {
+    exchanges: Arc<Mutex<Vec<Exchange>>>,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The exchanges recorded so far.
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges.lock().expect("mutex poisoned").clone()
+    }
+
+    /// The exchanges recorded so far, one JSON object per line, to be read by [`load`].
+    pub fn to_jsonl(&self) -> String {
+        let mut out = String::new();
+        for exchange in self.exchanges.lock().expect("mutex poisoned").iter() {
+            out.push_str(&serde_json::to_string(exchange).expect("exchange can be serialized"));
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[async_trait]
+impl Middleware for Capture {
+    async fn handle(&self, req: reqwest::Request, next: Next<'_>) -> Result<reqwest::Response> {
+        let content_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let method = req.method().to_string();
+        let path = req.url().path().to_string();
+        let request = req
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(Bytes::copy_from_slice)
+            .unwrap_or_default();
+
+        let resp = next.run(req).await?;
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let response = resp.bytes().await.map_err(ClientError::from)?;
+        let response_content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        self.exchanges
+            .lock()
+            .expect("mutex poisoned")
+            .push(Exchange {
+                method,
+                path,
+                content_type,
+                request,
+                status: status.as_u16(),
+                response_content_type,
+                response: response.clone(),
+            });
+
+        let mut rebuilt = http::Response::new(response);
+        *rebuilt.status_mut() = status;
+        *rebuilt.headers_mut() = headers;
+        Ok(rebuilt.into())
+    }
+}
+
+/// A replayed exchange whose response differs from the recorded one.
+#[derive(Clone, Debug)]
+pub struct Mismatch {
+    /// The index of the exchange in the replayed list.
+    pub index: usize,
+    pub path: String,
+    /// What differs, e.g. `status 500, recorded 200` or `/name: "a", recorded "b"`.
+    pub differences: Vec<String>,
+}
+
+/// The result of [`replay`].
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    /// The number of exchanges replayed.
+    pub replayed: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    /// Whether every response matched the recorded one.
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    /// Panic with the report, unless every response matched the recorded one.
+    #[track_caller]
+    pub fn assert_ok(&self) {
+        assert!(self.is_ok(), "{self}");
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} of {} replayed responses differ",
+            self.mismatches.len(),
+            self.replayed
+        )?;
+        for mismatch in &self.mismatches {
+            writeln!(f, "#{} {}:", mismatch.index, mismatch.path)?;
+            for difference in &mismatch.differences {
+                writeln!(f, "  {difference}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Send each request of `exchanges` to `router`, in order, and compare the responses with the
+/// recorded ones.
+pub async fn replay(router: &Router, exchanges: &[Exchange]) -> ReplayReport {
+    let mut report = ReplayReport::default();
+    for (index, exchange) in exchanges.iter().enumerate() {
+        report.replayed += 1;
+        let differences = match send(router, exchange).await {
+            Ok((status, content_type, body)) => compare(exchange, status, &content_type, &body),
+            Err(err) => vec![err],
+        };
+        if !differences.is_empty() {
+            report.mismatches.push(Mismatch {
+                index,
+                path: exchange.path.clone(),
+                differences,
+            });
+        }
+    }
+    report
+}
+
+async fn send(
+    router: &Router,
+    exchange: &Exchange,
+) -> std::result::Result<(u16, String, Bytes), String> {
+    let method = Method::from_bytes(exchange.method.as_bytes()).map_err(|e| e.to_string())?;
+    let req = Request::builder()
+        .method(method)
+        .uri(&exchange.path)
+        .header(header::CONTENT_TYPE, &exchange.content_type)
+        .body(Body::from(exchange.request.clone()))
+        .map_err(|e| format!("invalid request: {e}"))?;
+    let resp = match router.clone().call(req).await {
+        Ok(resp) => resp,
+        Err(never) => match never {},
+    };
+    let status = resp.status().as_u16();
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let body = resp
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| format!("failed to read the response: {e}"))?
+        .to_bytes();
+    Ok((status, content_type, body))
+}
+
+fn compare(exchange: &Exchange, status: u16, content_type: &str, body: &Bytes) -> Vec<String> {
+    let mut differences = vec![];
+    if status != exchange.status {
+        differences.push(format!("status {status}, recorded {}", exchange.status));
+    }
+    let recorded_type = &exchange.response_content_type;
+    if !recorded_type.is_empty() && content_type != recorded_type {
+        differences.push(format!(
+            "Content-Type {content_type}, recorded {recorded_type}"
+        ));
+    }
+    let recorded = serde_json::from_slice::<Value>(&exchange.response);
+    let replayed = serde_json::from_slice::<Value>(body);
+    match (recorded, replayed) {
+        (Ok(recorded), Ok(replayed)) => diff_json("", &recorded, &replayed, &mut differences),
+        _ if body != &exchange.response => differences.push(format!(
+            "body of {} bytes ({content_type}) differs from the recorded {} bytes",
+            body.len(),
+            exchange.response.len()
+        )),
+        _ => {}
+    }
+    differences
+}
+
+// The differences between two JSON values, by JSON pointer.
+fn diff_json(pointer: &str, recorded: &Value, replayed: &Value, out: &mut Vec<String>) {
+    match (recorded, replayed) {
+        (Value::Object(recorded), Value::Object(replayed)) => {
+            let mut keys: Vec<_> = recorded.keys().chain(replayed.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let pointer = format!("{pointer}/{key}");
+                match (recorded.get(key), replayed.get(key)) {
+                    (Some(a), Some(b)) => diff_json(&pointer, a, b, out),
+                    (Some(a), None) => out.push(format!("{pointer}: missing, recorded {a}")),
+                    (None, Some(b)) => out.push(format!("{pointer}: {b}, not recorded")),
+                    (None, None) => {}
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                diff_json(&format!("{pointer}/{i}"), a, b, out);
+            }
+        }
+        (a, b) if a != b => {
+            let pointer = if pointer.is_empty() { "/" } else { pointer };
+            out.push(format!("{pointer}: {b}, recorded {a}"));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{test_api_router, PingRequest, TestApiClient, TestServer};
+    use crate::ClientBuilder;
+
+    async fn capture() -> Capture {
+        let server = TestServer::new(test_api_router());
+        let capture = Capture::new();
+        let client = ClientBuilder::new(TestServer::base_url(), reqwest::Client::new())
+            .codec(crate::codec::JsonCodec)
+            .with(capture.clone())
+            .with(server.transport())
+            .build()
+            .unwrap();
+        let ping = PingRequest {
+            name: "hi".to_string(),
+        };
+        assert_eq!(client.ping(ping.clone()).await.unwrap().name, "hi");
+        assert!(client.boom(ping).await.is_err());
+        capture
+    }
+
+    #[tokio::test]
+    async fn test_capture_and_replay() {
+        let capture = capture().await;
+        let exchanges = parse_capture(&capture.to_jsonl()).unwrap();
+        assert_eq!(exchanges, capture.exchanges());
+        assert_eq!(exchanges.len(), 2);
+        assert_eq!(exchanges[0].path, "/twirp/test.TestAPI/Ping");
+        assert_eq!(exchanges[1].status, 500);
+
+        let report = replay(&test_api_router(), &exchanges).await;
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.replayed, 2);
+    }
+
+    #[tokio::test]
+    async fn test_replay_mismatch() {
+        let mut exchanges = capture().await.exchanges();
+        exchanges[0].response = Bytes::from(r#"{"name": "hello"}"#);
+        exchanges[1].status = 200;
+
+        let report = replay(&test_api_router(), &exchanges).await;
+        assert_eq!(report.mismatches.len(), 2);
+        assert_eq!(
+            report.mismatches[0].differences,
+            [r#"/name: "hi", recorded "hello""#]
+        );
+        assert_eq!(
+            report.mismatches[1].differences,
+            ["status 500, recorded 200"]
+        );
+        assert!(report
+            .to_string()
+            .starts_with("2 of 2 replayed responses differ"));
+    }
+
+    #[tokio::test]
+    async fn test_har() {
+        let har = r#"{"log": {"entries": [
+            {"request": {"method": "GET", "url": "http://example.com/"}, "response": {}},
+            {
+                "request": {
+                    "method": "POST",
+                    "url": "http://example.com/twirp/test.TestAPI/Ping",
+                    "postData": {"mimeType": "application/protobuf", "text": "EgJoaQ==", "encoding": "base64"}
+                },
+                "response": {
+                    "status": 200,
+                    "content": {"mimeType": "application/protobuf", "text": "EgJoaQ==", "encoding": "base64"}
+                }
+            }
+        ]}}"#;
+        let exchanges = parse_har(har).unwrap();
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].request, Bytes::from_static(b"\x12\x02hi"));
+        replay(&test_api_router(), &exchanges).await.assert_ok();
+    }
+}
@@ -14,7 +14,7 @@ fn main() {
     }
 
     prost_build
-        .service_generator(twirp_build::service_generator())
+        .service_generator(twirp_build::service_generator().mocks_cfg("test"))
         .type_attribute(".", "#[derive(serde::Serialize,serde::Deserialize)]")
         .extern_path(".google.protobuf.Timestamp", "::prost_wkt_types::Timestamp")
         .file_descriptor_set_path(&descriptor_file)
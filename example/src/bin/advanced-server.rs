@@ -8,6 +8,7 @@ use twirp::axum::body::Body;
 use twirp::axum::http;
 use twirp::axum::middleware::{self, Next};
 use twirp::axum::routing::get;
+use twirp::server::request_id::{RequestId, RequestIdLayer};
 use twirp::{invalid_argument, Context, IntoTwirpResponse, Router, TwirpErrorResponse};
 
 pub mod service {
@@ -27,7 +28,8 @@ async fn ping() -> &'static str {
 pub async fn main() {
     let api_impl = HaberdasherApiServer {};
     let middleware = twirp::tower::builder::ServiceBuilder::new()
-        .layer(middleware::from_fn(request_id_middleware));
+        .layer(RequestIdLayer::new())
+        .layer(middleware::from_fn(response_info_middleware));
     let twirp_routes = Router::new()
         .nest(haberdash::SERVICE_FQN, haberdash::router(api_impl))
         .layer(middleware);
@@ -101,23 +103,11 @@ impl haberdash::HaberdasherApi for HaberdasherApiServer {
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Default)]
 struct ResponseInfo(u16);
 
-/// Demonstrate pulling the request id out of an http header and sharing it with the rpc handlers.
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Default)]
-struct RequestId(String);
-
-async fn request_id_middleware(
-    mut request: http::Request<Body>,
+/// Demonstrate reading the extensions set by the rpc handlers and turning them into http headers.
+async fn response_info_middleware(
+    request: http::Request<Body>,
     next: Next,
 ) -> http::Response<Body> {
-    let rid = request
-        .headers()
-        .get("x-request-id")
-        .and_then(|v| v.to_str().ok())
-        .map(|x| RequestId(x.to_string()));
-    if let Some(rid) = rid {
-        request.extensions_mut().insert(rid);
-    }
-
     let mut res = next.run(request).await;
 
     let info = res
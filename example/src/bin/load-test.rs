@@ -0,0 +1,117 @@
+//! A small load generator for Twirp servers.
+//!
+//! `cargo run --release --bin load-test -- [base_url] [concurrency] [seconds]` calls `MakeHat` as
+//! fast as it can from `concurrency` tasks (default 16) for `seconds` (default 10), then prints
+//! the throughput and latency percentiles. Without `base_url` (e.g.
+//! `http://localhost:3000/twirp/`) it starts an in-process server on a local port to call.
+
+use std::time::{Duration, Instant};
+
+use twirp::async_trait::async_trait;
+use twirp::url::Url;
+use twirp::{invalid_argument, Client, Context, GenericError, Router, TwirpErrorResponse};
+
+pub mod service {
+    pub mod haberdash {
+        pub mod v1 {
+            include!(concat!(env!("OUT_DIR"), "/service.haberdash.v1.rs"));
+        }
+    }
+}
+use service::haberdash::v1::{
+    self as haberdash, HaberdasherApiClient, MakeHatRequest, MakeHatResponse,
+};
+
+#[tokio::main]
+pub async fn main() -> Result<(), GenericError> {
+    let mut args = std::env::args().skip(1);
+    let base_url = match args.next() {
+        Some(url) => Url::parse(&url)?,
+        None => start_server().await?,
+    };
+    let concurrency: usize = args.next().map(|n| n.parse()).transpose()?.unwrap_or(16);
+    let duration = Duration::from_secs(args.next().map(|s| s.parse()).transpose()?.unwrap_or(10));
+
+    println!("Calling {base_url} from {concurrency} tasks for {duration:?}");
+    let client = Client::from_base_url(base_url)?;
+    let deadline = Instant::now() + duration;
+    let tasks: Vec<_> = (0..concurrency)
+        .map(|_| tokio::spawn(run(client.clone(), deadline)))
+        .collect();
+
+    let mut latencies = vec![];
+    let mut errors = 0;
+    for task in tasks {
+        let (task_latencies, task_errors) = task.await?;
+        latencies.extend(task_latencies);
+        errors += task_errors;
+    }
+    latencies.sort();
+
+    let percentile = |p: f64| {
+        let i = ((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1));
+        latencies.get(i).copied().unwrap_or_default()
+    };
+    println!(
+        "{} requests ({errors} errors), {:.0} requests/s",
+        latencies.len(),
+        latencies.len() as f64 / duration.as_secs_f64()
+    );
+    println!(
+        "latency p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        percentile(1.0)
+    );
+    Ok(())
+}
+
+async fn run(client: Client, deadline: Instant) -> (Vec<Duration>, usize) {
+    let mut latencies = vec![];
+    let mut errors = 0;
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        match client.make_hat(MakeHatRequest { inches: 7 }).await {
+            Ok(_) => latencies.push(start.elapsed()),
+            Err(_) => errors += 1,
+        }
+    }
+    (latencies, errors)
+}
+
+async fn start_server() -> Result<Url, GenericError> {
+    let twirp_routes = Router::new().nest(
+        haberdash::SERVICE_FQN,
+        haberdash::router(HaberdasherApiServer),
+    );
+    let app = Router::new().nest("/twirp", twirp_routes);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { twirp::axum::serve(listener, app).await });
+    Ok(Url::parse(&format!("http://{addr}/twirp/"))?)
+}
+
+#[derive(Clone)]
+struct HaberdasherApiServer;
+
+#[async_trait]
+impl haberdash::HaberdasherApi for HaberdasherApiServer {
+    type Error = TwirpErrorResponse;
+
+    async fn make_hat(
+        &self,
+        _ctx: Context,
+        req: MakeHatRequest,
+    ) -> Result<MakeHatResponse, TwirpErrorResponse> {
+        if req.inches == 0 {
+            return Err(invalid_argument("inches"));
+        }
+        Ok(MakeHatResponse {
+            color: "black".to_string(),
+            name: "top hat".to_string(),
+            size: req.inches,
+            timestamp: None,
+        })
+    }
+}
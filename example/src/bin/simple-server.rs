@@ -106,6 +106,25 @@ mod test {
         assert_eq!(err.code, TwirpErrorCode::InvalidArgument);
     }
 
+    #[tokio::test]
+    async fn mock_service() {
+        let fake =
+            twirp::test::MockService::for_::<haberdash::HaberdasherApiMock>().on_make_hat(|req| {
+                Ok(MakeHatResponse {
+                    size: req.inches,
+                    name: "fake".to_string(),
+                    ..Default::default()
+                })
+            });
+        let url = Url::parse("http://fake.local/twirp/").unwrap();
+        let client = twirp::ClientBuilder::new(url, twirp::reqwest::Client::new())
+            .direct("fake.local", fake.build_direct())
+            .build()
+            .unwrap();
+        let resp = client.make_hat(MakeHatRequest { inches: 3 }).await.unwrap();
+        assert_eq!((resp.size, resp.name.as_str()), (3, "fake"));
+    }
+
     /// A running network server task, bound to an arbitrary port on localhost, chosen by the OS
     struct NetServer {
         port: u16,